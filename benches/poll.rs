@@ -0,0 +1,93 @@
+//! Cost of one poll of the daemon: reading the panel and evaluating the
+//! triggers of every button that changed. Every button is pressed on one
+//! read and released on the next, the worst case for a poll. Commands run
+//! in observer mode, so only the daemon's own work is measured.
+
+use std::io;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use spi_button_controller::builder::{ButtonBuilder, ConfigBuilder};
+use spi_button_controller::config::{Config, SpiConfig};
+use spi_button_controller::daemon::Daemon;
+use spi_button_controller::panel::{self, Capabilities, PanelButton, PanelProtocol};
+use spi_button_controller::units::ButtonId;
+use spibuttonlib::SPIButtonState;
+
+/// Presses every button on one read and releases them on the next.
+struct TogglingPanel {
+    leds: Vec<SPIButtonState>,
+    pressed: bool,
+    capabilities: Capabilities,
+}
+
+impl PanelProtocol for TogglingPanel {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        self.pressed = !self.pressed;
+        let state = if self.pressed { SPIButtonState::On } else { SPIButtonState::Off };
+        Ok((0..self.leds.len()).map(|id| PanelButton::new(ButtonId(id as u8), state)).collect())
+    }
+
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        PanelButton::new(id, self.leds[id.index()])
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        self.leds[id.index()] = button.get_state();
+    }
+
+    fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+
+    fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Triggers {
+    /// A fixed command per button
+    Plain,
+    /// A schedule, a condition and a templated command per button
+    Conditional,
+}
+
+fn config(buttons: usize, triggers: Triggers) -> Config {
+    let mut builder = ConfigBuilder::new().observer(true);
+    for id in 0..buttons {
+        let button = match triggers {
+            Triggers::Plain => ButtonBuilder::new(ButtonId(id as u8), "echo pressed"),
+            Triggers::Conditional => ButtonBuilder::new(ButtonId(id as u8), "echo {{var.material}} {{ extruder.target + 5 }}")
+                .enabled_between("00:00-23:59")
+                .when("var.mode != 'maintenance' && extruder.temperature < 300"),
+        };
+        builder = builder.button(button.description(&format!("Button {}", id)));
+    }
+    builder.build().unwrap()
+}
+
+fn daemon(buttons: usize, triggers: Triggers) -> Daemon {
+    let config = config(buttons, triggers);
+    let panel = TogglingPanel {
+        leds: vec![SPIButtonState::Off; buttons],
+        pressed: false,
+        capabilities: panel::capabilities(&SpiConfig::default()),
+    };
+    Daemon::with_panel(config, Box::new(panel), None).unwrap()
+}
+
+fn poll(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("poll");
+    for (name, triggers) in [("plain", Triggers::Plain), ("conditional", Triggers::Conditional)] {
+        for buttons in [8, 32, 128] {
+            let mut daemon = daemon(buttons, triggers);
+            group.throughput(Throughput::Elements(buttons as u64));
+            group.bench_with_input(BenchmarkId::new(name, buttons), &buttons, |b, _| {
+                b.iter(|| runtime.block_on(daemon.step()).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, poll);
+criterion_main!(benches);
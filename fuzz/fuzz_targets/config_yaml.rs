@@ -0,0 +1,18 @@
+//! YAML config text as the daemon loads it, through the same
+//! `Config::parse_complete` as a config file, then printed again. Any input
+//! must end in an error or a config, never a panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spi_button_controller::config::{Config, ConfigFormat};
+use std::path::Path;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else { return };
+    // No conf.d fragments, the directory does not exist
+    let Ok(config) = Config::parse_complete(text, ConfigFormat::Yaml, "fuzz", Path::new("/nonexistent/conf.d")) else {
+        return;
+    };
+    let _ = config.normalized();
+    let _ = Config::default().diff(&config).to_string();
+});
@@ -0,0 +1,28 @@
+//! Bytes from the Klipper socket, arriving in chunks of the size given by
+//! the first byte, through the ETX framer and the response parser.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spi_button_controller::command::{parse_response, EtxFramer, ETX};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, stream)) = data.split_first() else { return };
+    let mut framer = EtxFramer::new();
+    let mut frames = Vec::new();
+    for bytes in stream.chunks(usize::from(chunk).max(1)) {
+        if framer.push(bytes).is_err() {
+            return;
+        }
+        while let Some(frame) = framer.next_frame() {
+            let _ = parse_response(&frame);
+            frames.push(frame);
+        }
+    }
+    let remainder = framer.into_remainder();
+    let _ = parse_response(&remainder);
+
+    // Framing loses nothing but the ETXs
+    let mut joined: Vec<u8> = frames.iter().flat_map(|f| f.iter().copied().chain([ETX])).collect();
+    joined.extend_from_slice(&remainder);
+    assert_eq!(joined, stream);
+});
@@ -20,9 +20,10 @@ fi
 
 echo "Installing binary to $INSTALL_DIR..."
 sudo install -m 755 "$BINARY_PATH" "$INSTALL_DIR/spi-button-controller"
+sudo install -m 755 ./target/release/spibuttonctl "$INSTALL_DIR/spibuttonctl"
 
 echo "Creating configuration directory..."
-sudo mkdir -p "$CONFIG_DIR"
+sudo mkdir -p "$CONFIG_DIR/conf.d"
 
 echo "Installing configuration file..."
 if [ ! -f "$CONFIG_DIR/config.yaml" ]; then
@@ -0,0 +1,94 @@
+use serde_json::{json, Value as JsonValue};
+
+/// Command prefix of the Moonraker timelapse plugin actions.
+pub const TIMELAPSE_PREFIX: &str = "timelapse:";
+
+/// Command prefix of the Moonraker job queue actions.
+pub const JOB_QUEUE_PREFIX: &str = "job_queue:";
+
+/// Command prefix of the host OS actions.
+pub const HOST_PREFIX: &str = "host:";
+
+/// Prefixes of built-in actions carried out as a single Moonraker call.
+const PREFIXES: &[&str] = &[TIMELAPSE_PREFIX, JOB_QUEUE_PREFIX, HOST_PREFIX];
+
+/// A built-in action as a Moonraker API call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoonrakerCall {
+    /// Endpoint to POST to, e.g. `/machine/timelapse/render`
+    pub path: String,
+    pub params: JsonValue,
+}
+
+/// Whether a button command is a built-in Moonraker action.
+pub fn is_action(command: &str) -> bool {
+    PREFIXES.iter().any(|prefix| command.starts_with(prefix))
+}
+
+/// Whether a command always needs a confirming second press, because it
+/// cannot be undone from the panel.
+pub fn requires_confirmation(command: &str) -> bool {
+    command.trim().starts_with(HOST_PREFIX)
+}
+
+/// Map a built-in action command such as `timelapse:render` onto its
+/// Moonraker API call.
+pub fn parse(command: &str) -> Result<MoonrakerCall, String> {
+    let call = |path: &str, params: JsonValue| MoonrakerCall {
+        path: path.to_string(),
+        params,
+    };
+    match command.trim() {
+        "timelapse:start" => Ok(call("/machine/timelapse/settings", json!({ "enabled": true }))),
+        "timelapse:stop" => Ok(call("/machine/timelapse/settings", json!({ "enabled": false }))),
+        "timelapse:render" => Ok(call("/machine/timelapse/render", json!({}))),
+        // Start the next queued job, or resume a paused queue
+        "job_queue:start" => Ok(call("/server/job_queue/start", json!({}))),
+        "job_queue:pause" => Ok(call("/server/job_queue/pause", json!({}))),
+        "host:reboot" => Ok(call("/machine/reboot", json!({}))),
+        "host:shutdown" => Ok(call("/machine/shutdown", json!({}))),
+        other => match other.strip_prefix("job_queue:jump|") {
+            // Move a job to the front of the queue: job_queue:jump|JOB_ID
+            Some(job_id) if !job_id.trim().is_empty() => {
+                Ok(call("/server/job_queue/jump", json!({ "job_id": job_id.trim() })))
+            }
+            _ => Err(format!("Unknown action: {}", other)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timelapse_actions() {
+        assert!(is_action("timelapse:render"));
+        assert!(!is_action("klipper:gcode/script|{}"));
+
+        let render = parse("timelapse:render").unwrap();
+        assert_eq!(render.path, "/machine/timelapse/render");
+        let stop = parse("timelapse:stop").unwrap();
+        assert_eq!(stop.params, json!({ "enabled": false }));
+        assert!(parse("timelapse:rewind").is_err());
+    }
+
+    #[test]
+    fn test_parse_job_queue_actions() {
+        assert!(is_action("job_queue:start"));
+        assert_eq!(parse("job_queue:start").unwrap().path, "/server/job_queue/start");
+        let jump = parse("job_queue:jump|0000000066D99C90").unwrap();
+        assert_eq!(jump.path, "/server/job_queue/jump");
+        assert_eq!(jump.params, json!({ "job_id": "0000000066D99C90" }));
+        assert!(parse("job_queue:jump|").is_err());
+    }
+
+    #[test]
+    fn test_host_actions_need_confirmation() {
+        assert_eq!(parse("host:reboot").unwrap().path, "/machine/reboot");
+        assert_eq!(parse("host:shutdown").unwrap().path, "/machine/shutdown");
+        assert!(requires_confirmation("host:shutdown"));
+        assert!(!requires_confirmation("job_queue:start"));
+        assert!(parse("host:format").is_err());
+    }
+}
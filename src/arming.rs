@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::units::ButtonId;
+
+/// Time allowed for the confirming second press when not configured.
+pub const DEFAULT_CONFIRM_WINDOW_MS: u64 = 3000;
+
+/// Two-step confirmation: the first press arms a button, a second press
+/// within the window confirms it. Used for commands that are hard to undo
+/// and for buttons set to `confirm`.
+#[derive(Debug, Default)]
+pub struct Arming {
+    armed: HashMap<ButtonId, Instant>,
+}
+
+impl Arming {
+    pub fn new() -> Self {
+        Arming::default()
+    }
+
+    /// Register a press of a button that needs confirmation. Returns `true`
+    /// when the press confirms an armed button, `false` when it arms it.
+    pub fn confirm(&mut self, button_id: ButtonId, now: Instant, window: Duration) -> bool {
+        match self.armed.remove(&button_id) {
+            Some(deadline) if now <= deadline => true,
+            _ => {
+                self.armed.insert(button_id, now + window);
+                false
+            }
+        }
+    }
+
+    /// Arm a button until `deadline`, e.g. armed on another panel.
+    pub fn arm(&mut self, button_id: ButtonId, deadline: Instant) {
+        self.armed.insert(button_id, deadline);
+    }
+
+    /// Disarm a button, e.g. confirmed on another panel.
+    pub fn disarm(&mut self, button_id: ButtonId) {
+        self.armed.remove(&button_id);
+    }
+
+    /// Buttons still armed at `now`, with how long they stay armed.
+    pub fn armed(&self, now: Instant) -> Vec<(ButtonId, Duration)> {
+        let mut armed: Vec<(ButtonId, Duration)> = self
+            .armed
+            .iter()
+            .filter(|(_, deadline)| **deadline > now)
+            .map(|(button, deadline)| (*button, *deadline - now))
+            .collect();
+        armed.sort();
+        armed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_press_confirms() {
+        let mut arming = Arming::new();
+        let window = Duration::from_secs(3);
+        let t0 = Instant::now();
+
+        assert!(!arming.confirm(ButtonId(1), t0, window));
+        assert!(arming.confirm(ButtonId(1), t0 + Duration::from_secs(2), window));
+        // Confirming disarms, the next press arms again
+        assert!(!arming.confirm(ButtonId(1), t0 + Duration::from_secs(2), window));
+    }
+
+    #[test]
+    fn test_late_press_rearms() {
+        let mut arming = Arming::new();
+        let window = Duration::from_secs(3);
+        let t0 = Instant::now();
+
+        assert!(!arming.confirm(ButtonId(1), t0, window));
+        assert!(!arming.confirm(ButtonId(1), t0 + Duration::from_secs(4), window));
+        assert!(arming.confirm(ButtonId(1), t0 + Duration::from_secs(5), window));
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::config::BackpressureConfig;
+
+/// How long a queue must stay at its threshold before the alarm is raised
+/// when `backpressure.sustain_ms` is not configured.
+pub const DEFAULT_SUSTAIN_MS: u64 = 5000;
+
+/// The queues whose depth is watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Queue {
+    /// Actions started and not finished, including those waiting for
+    /// their mutex group
+    Actions,
+    /// Klipper requests awaiting their response
+    Requests,
+    /// Klipper responses and notifications not yet taken by the main loop
+    Responses,
+}
+
+impl Queue {
+    pub const ALL: [Queue; 3] = [Queue::Actions, Queue::Requests, Queue::Responses];
+
+    /// The configured threshold of the queue, if any.
+    fn threshold(self, config: &BackpressureConfig) -> Option<usize> {
+        match self {
+            Queue::Actions => config.actions,
+            Queue::Requests => config.requests,
+            Queue::Responses => config.responses,
+        }
+    }
+}
+
+impl fmt::Display for Queue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Queue::Actions => write!(f, "actions"),
+            Queue::Requests => write!(f, "requests"),
+            Queue::Responses => write!(f, "responses"),
+        }
+    }
+}
+
+/// An alarm raised or cleared by `Alarms::check`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmChange {
+    pub queue: Queue,
+    pub depth: usize,
+    pub raised: bool,
+}
+
+/// Raises an alarm once a queue stays at or above its threshold for the
+/// sustain period, and clears it when the queue drops below again. Brief
+/// bursts do not count.
+#[derive(Debug, Default)]
+pub struct Alarms {
+    above_since: HashMap<Queue, Instant>,
+    raised: HashSet<Queue>,
+}
+
+impl Alarms {
+    pub fn new() -> Self {
+        Alarms::default()
+    }
+
+    /// Compare the current `depths` with the thresholds at `now`.
+    pub fn check(&mut self, now: Instant, depths: &[(Queue, usize)], config: &BackpressureConfig) -> Vec<AlarmChange> {
+        let sustain = Duration::from_millis(config.sustain_ms.unwrap_or(DEFAULT_SUSTAIN_MS));
+        let mut changes = Vec::new();
+        for &(queue, depth) in depths {
+            let above = queue.threshold(config).is_some_and(|threshold| depth >= threshold);
+            if !above {
+                self.above_since.remove(&queue);
+                if self.raised.remove(&queue) {
+                    changes.push(AlarmChange { queue, depth, raised: false });
+                }
+                continue;
+            }
+            let since = *self.above_since.entry(queue).or_insert(now);
+            if now.duration_since(since) >= sustain && self.raised.insert(queue) {
+                changes.push(AlarmChange { queue, depth, raised: true });
+            }
+        }
+        changes
+    }
+
+    pub fn is_raised(&self, queue: Queue) -> bool {
+        self.raised.contains(&queue)
+    }
+
+    pub fn any_raised(&self) -> bool {
+        !self.raised.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm_needs_a_sustained_backlog() {
+        let ms = Duration::from_millis;
+        let config = BackpressureConfig {
+            requests: Some(4),
+            sustain_ms: Some(1000),
+            ..BackpressureConfig::default()
+        };
+        let t0 = Instant::now();
+        let mut alarms = Alarms::new();
+        let depths = |requests| [(Queue::Actions, 100), (Queue::Requests, requests)];
+
+        // A burst shorter than the sustain period passes unnoticed
+        assert!(alarms.check(t0, &depths(5), &config).is_empty());
+        assert!(alarms.check(t0 + ms(500), &depths(2), &config).is_empty());
+        assert!(alarms.check(t0 + ms(600), &depths(4), &config).is_empty());
+        let raised = alarms.check(t0 + ms(1600), &depths(6), &config);
+        assert_eq!(raised, vec![AlarmChange { queue: Queue::Requests, depth: 6, raised: true }]);
+        assert!(alarms.check(t0 + ms(2000), &depths(6), &config).is_empty());
+        assert!(alarms.is_raised(Queue::Requests) && !alarms.is_raised(Queue::Actions));
+
+        let cleared = alarms.check(t0 + ms(2100), &depths(3), &config);
+        assert_eq!(cleared, vec![AlarmChange { queue: Queue::Requests, depth: 3, raised: false }]);
+        assert!(!alarms.any_raised());
+    }
+}
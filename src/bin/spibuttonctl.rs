@@ -0,0 +1,70 @@
+//! Command line client for the daemon's control socket.
+//!
+//! Usage: spibuttonctl [--socket PATH] COMMAND [ARGS...]
+//! Example: spibuttonctl last 5
+//!
+//! PATH may be an abstract socket name like `@spi-button-controller`.
+//! Without `--socket` the `SPIBTN_SOCKET` environment variable or the
+//! default path is used.
+
+use spi_button_controller::socket;
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+const DEFAULT_SOCKET_PATH: &str = "/run/spi-button-controller.sock";
+
+/// Environment variable naming the socket when `--socket` is not given.
+const SOCKET_PATH_VAR: &str = "SPIBTN_SOCKET";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut socket_path = std::env::var(SOCKET_PATH_VAR).unwrap_or_else(|_| DEFAULT_SOCKET_PATH.to_string());
+    if args.first().map(String::as_str) == Some("--socket") {
+        if args.len() < 2 {
+            eprintln!("error: --socket requires a path");
+            return ExitCode::FAILURE;
+        }
+        socket_path = args.remove(1);
+        args.remove(0);
+    }
+
+    let request = if args.is_empty() {
+        "help".to_string()
+    } else {
+        args.join(" ")
+    };
+
+    match send(&socket_path, &request) {
+        Ok(true) => ExitCode::FAILURE,
+        Ok(false) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: control socket {}: {}", socket_path, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Send the request and print the response as it arrives, e.g. the lines
+/// of `logs follow`. Returns whether the daemon reported an error.
+fn send(socket_path: &str, request: &str) -> std::io::Result<bool> {
+    let mut stream = socket::connect_blocking(socket_path)?;
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut stdout = std::io::stdout();
+    let mut start = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if start.len() < 6 {
+            start.extend_from_slice(&buf[..n.min(6 - start.len())]);
+        }
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+    }
+    Ok(start.starts_with(b"error:"))
+}
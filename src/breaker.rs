@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::command::ResponseStatus;
+use crate::config::{CircuitBreakerConfig, LedPattern};
+
+/// Consecutive failed requests opening a circuit when `failures` is not
+/// configured.
+pub const DEFAULT_FAILURES: u32 = 5;
+
+/// Time an open circuit refuses requests before letting a probe through
+/// when `open_ms` is not configured.
+pub const DEFAULT_OPEN_MS: u64 = 30_000;
+
+/// An integration the daemon sends requests to, each with its own circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Endpoint {
+    /// The Klipper API socket
+    Klipper,
+    /// Moonraker's HTTP API
+    Moonraker,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Klipper => write!(f, "klipper"),
+            Endpoint::Moonraker => write!(f, "moonraker"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CircuitState {
+    /// Requests are sent
+    #[default]
+    Closed,
+    /// The endpoint is down, requests are refused
+    Open,
+    /// One probe request was let through, the others are refused until it
+    /// answers
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+/// Whether a response shows its endpoint unreachable, rather than
+/// answering with an error.
+pub fn is_down(status: &ResponseStatus) -> bool {
+    matches!(status, ResponseStatus::ConnectionError(_) | ResponseStatus::Timeout)
+}
+
+/// Two short blinks and a pause, shown on a button refused because its
+/// endpoint is down when `circuit_breaker.pattern` is not configured.
+pub fn default_pattern() -> LedPattern {
+    LedPattern {
+        durations_ms: vec![100, 150, 100, 650],
+        repeat: None,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    /// When the circuit opened or let its probe through
+    since: Option<Instant>,
+    transitions: u64,
+}
+
+impl Circuit {
+    fn change(&mut self, endpoint: Endpoint, state: CircuitState, now: Instant) {
+        match state {
+            CircuitState::Open => warn!("Circuit of {} opened after {} failed request(s)", endpoint, self.failures),
+            _ => info!("Circuit of {} is {}", endpoint, state),
+        }
+        self.state = state;
+        self.since = Some(now);
+        self.transitions += 1;
+    }
+}
+
+/// A circuit breaker per endpoint. After `failures` requests in a row find
+/// an endpoint down its circuit opens and requests to it fail fast. Once
+/// `open_ms` passed one request probes it: an answer closes the circuit,
+/// another failure opens it again.
+#[derive(Debug, Default)]
+pub struct Breakers {
+    circuits: BTreeMap<Endpoint, Circuit>,
+}
+
+impl Breakers {
+    pub fn new() -> Self {
+        Breakers::default()
+    }
+
+    /// Whether a request to `endpoint` may be sent at `now`. A probe not
+    /// answered within `open_ms` lets another one through.
+    pub fn allow(&mut self, endpoint: Endpoint, now: Instant, config: &CircuitBreakerConfig) -> bool {
+        let circuit = self.circuits.entry(endpoint).or_default();
+        let open = Duration::from_millis(config.open_ms.unwrap_or(DEFAULT_OPEN_MS));
+        match circuit.state {
+            CircuitState::Closed => true,
+            _ if circuit.since.is_some_and(|since| now.duration_since(since) < open) => false,
+            _ => {
+                circuit.change(endpoint, CircuitState::HalfOpen, now);
+                true
+            }
+        }
+    }
+
+    /// Count the outcome of a request to `endpoint`, `down` when it found
+    /// the endpoint unreachable, see `is_down`.
+    pub fn record(&mut self, endpoint: Endpoint, down: bool, now: Instant, config: &CircuitBreakerConfig) {
+        let circuit = self.circuits.entry(endpoint).or_default();
+        if !down {
+            circuit.failures = 0;
+            if circuit.state != CircuitState::Closed {
+                circuit.change(endpoint, CircuitState::Closed, now);
+            }
+            return;
+        }
+        circuit.failures = circuit.failures.saturating_add(1);
+        let tripped = match circuit.state {
+            CircuitState::Closed => circuit.failures >= config.failures.unwrap_or(DEFAULT_FAILURES),
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if tripped {
+            circuit.change(endpoint, CircuitState::Open, now);
+        }
+    }
+
+    pub fn state(&self, endpoint: Endpoint) -> CircuitState {
+        self.circuits.get(&endpoint).map_or(CircuitState::Closed, |c| c.state)
+    }
+
+    /// Each endpoint requested so far with its state and how often that
+    /// changed, for `spibuttonctl stats`.
+    pub fn iter(&self) -> impl Iterator<Item = (Endpoint, CircuitState, u64)> + '_ {
+        self.circuits.iter().map(|(endpoint, c)| (*endpoint, c.state, c.transitions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_and_probes() {
+        let ms = Duration::from_millis;
+        let config = CircuitBreakerConfig {
+            failures: Some(2),
+            open_ms: Some(1000),
+            pattern: None,
+        };
+        let t0 = Instant::now();
+        let mut breakers = Breakers::new();
+        let klipper = Endpoint::Klipper;
+
+        breakers.record(klipper, true, t0, &config);
+        breakers.record(klipper, false, t0, &config);
+        breakers.record(klipper, true, t0, &config);
+        assert_eq!(breakers.state(klipper), CircuitState::Closed);
+        breakers.record(klipper, true, t0, &config);
+        assert_eq!(breakers.state(klipper), CircuitState::Open);
+        assert!(!breakers.allow(klipper, t0 + ms(500), &config));
+        assert!(breakers.allow(Endpoint::Moonraker, t0 + ms(500), &config));
+
+        // One probe, failing opens the circuit again
+        assert!(breakers.allow(klipper, t0 + ms(1000), &config));
+        assert!(!breakers.allow(klipper, t0 + ms(1100), &config));
+        breakers.record(klipper, true, t0 + ms(1200), &config);
+        assert_eq!(breakers.state(klipper), CircuitState::Open);
+
+        // An answered probe closes it
+        assert!(breakers.allow(klipper, t0 + ms(2200), &config));
+        breakers.record(klipper, false, t0 + ms(2300), &config);
+        assert_eq!(breakers.state(klipper), CircuitState::Closed);
+        let transitions: Vec<u64> = breakers.iter().map(|(_, _, n)| n).collect();
+        assert_eq!(transitions, vec![5, 0]);
+    }
+}
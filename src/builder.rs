@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+
+use crate::config::{
+    ButtonMapping, Chord, Combo, Config, ControlConfig, HoldTier, Indicator, KlipperConfig, PanelProtocolKind, PollGroup,
+    PressSequence,
+};
+use crate::error::{Error, Result};
+use crate::migrate::CONFIG_VERSION;
+use crate::units::{ButtonId, Hertz};
+
+/// Builds a `Config` in code, e.g. for a test harness or mappings generated
+/// at runtime. Unset options take the same defaults as a config file, and
+/// `build` validates like loading one does.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder::new()
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        ConfigBuilder {
+            config: Config {
+                version: Some(CONFIG_VERSION),
+                ..Config::default()
+            },
+        }
+    }
+
+    /// SPI device path, e.g. `/dev/spidev1.0`.
+    pub fn device(mut self, device: &str) -> Self {
+        self.config.spi.device = device.to_string();
+        self
+    }
+
+    pub fn speed(mut self, speed: Hertz) -> Self {
+        self.config.spi.speed_hz = speed;
+        self
+    }
+
+    /// SPI mode (0-3).
+    pub fn mode(mut self, mode: u8) -> Self {
+        self.config.spi.mode = mode;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: PanelProtocolKind) -> Self {
+        self.config.spi.protocol = Some(protocol);
+        self
+    }
+
+    /// Polling interval of buttons without a polling group.
+    pub fn interval_ms(mut self, interval_ms: u64) -> Self {
+        self.config.polling.interval_ms = interval_ms;
+        self
+    }
+
+    pub fn debounce_ms(mut self, debounce_ms: u64) -> Self {
+        self.config.polling.debounce_ms = Some(debounce_ms);
+        self
+    }
+
+    /// Ignore presses shorter than this, see `ButtonBuilder::min_press_ms`.
+    pub fn min_press_ms(mut self, min_press_ms: u64) -> Self {
+        self.config.polling.min_press_ms = Some(min_press_ms);
+        self
+    }
+
+    /// Add a polling group for `ButtonBuilder::poll_group`.
+    pub fn poll_group(mut self, name: &str, interval_ms: u64) -> Self {
+        self.config
+            .polling
+            .groups
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_string(), PollGroup { interval_ms });
+        self
+    }
+
+    /// Klipper API socket for `klipper:` commands.
+    pub fn klipper_socket(mut self, socket_path: &str) -> Self {
+        self.config.klipper = Some(KlipperConfig {
+            socket_path: socket_path.to_string(),
+            ..KlipperConfig::default()
+        });
+        self
+    }
+
+    /// Serve the control socket for `spibuttonctl` at `socket_path`.
+    pub fn control_socket(mut self, socket_path: &str) -> Self {
+        self.config.control = Some(ControlConfig {
+            socket_path: socket_path.to_string(),
+            ..ControlConfig::default()
+        });
+        self
+    }
+
+    /// Log events but run no commands.
+    pub fn observer(mut self, observer: bool) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Map a button. A later mapping of the same id is reported by `build`.
+    pub fn button(mut self, button: ButtonBuilder) -> Self {
+        self.config.buttons.push(button.mapping);
+        self
+    }
+
+    /// Run `command` when all `buttons` are pressed together.
+    pub fn chord(mut self, buttons: &[ButtonId], command: &str) -> Self {
+        self.config.chords.get_or_insert_with(Vec::new).push(Chord {
+            buttons: buttons.to_vec(),
+            description: None,
+            command: command.to_string(),
+        });
+        self
+    }
+
+    /// Run `command` when `presses` are pressed one after another.
+    pub fn combo(mut self, presses: &[ButtonId], command: &str) -> Self {
+        self.config.combos.get_or_insert_with(Vec::new).push(Combo {
+            presses: presses.to_vec(),
+            timeout_ms: None,
+            description: None,
+            command: command.to_string(),
+        });
+        self
+    }
+
+    /// The config, if it passes `Config::validate`. Every problem found is
+    /// in the error, by its path as if the config had been written out.
+    pub fn build(self) -> Result<Config> {
+        let mut config = self.config;
+        config.resolve_press_shorthands();
+        let problems = config.validate();
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+            return Err(Error::config(problems.join("; ")));
+        }
+        config.buttons.sort_by_key(|b| b.button);
+        Ok(config)
+    }
+}
+
+/// Builds one button's mapping for `ConfigBuilder::button`.
+#[derive(Debug, Clone)]
+pub struct ButtonBuilder {
+    mapping: ButtonMapping,
+}
+
+impl ButtonBuilder {
+    /// Button `id` running `command`, a shell command or e.g. `klipper:...`.
+    pub fn new(id: ButtonId, command: &str) -> Self {
+        ButtonBuilder {
+            mapping: ButtonMapping {
+                button: id,
+                command: command.to_string(),
+                ..ButtonMapping::default()
+            },
+        }
+    }
+
+    /// Feature flags, e.g. `0x68` for OnChange | OnHold | Toggle.
+    pub fn config(mut self, flags: u8) -> Self {
+        self.mapping.config = Some(flags);
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.mapping.description = Some(description.to_string());
+        self
+    }
+
+    /// Daily window in which presses are accepted, e.g. `07:00-22:00`.
+    pub fn enabled_between(mut self, window: &str) -> Self {
+        self.mapping.enabled_between = Some(window.to_string());
+        self
+    }
+
+    /// Condition for accepting presses, e.g. `extruder.temperature > 180`.
+    pub fn when(mut self, condition: &str) -> Self {
+        self.mapping.when = Some(condition.to_string());
+        self
+    }
+
+    /// Run `command` instead when pressed `presses` times in a row.
+    pub fn sequence(mut self, presses: u32, command: &str) -> Self {
+        self.mapping.sequences.get_or_insert_with(Vec::new).push(PressSequence {
+            presses,
+            description: None,
+            command: command.to_string(),
+        });
+        self
+    }
+
+    pub fn sequence_window_ms(mut self, window_ms: u64) -> Self {
+        self.mapping.sequence_window_ms = Some(window_ms);
+        self
+    }
+
+    /// Run `command` instead when released after holding for `hold_ms`.
+    pub fn hold_tier(mut self, hold_ms: u64, command: &str) -> Self {
+        self.mapping.hold_tiers.get_or_insert_with(Vec::new).push(HoldTier {
+            hold_ms,
+            description: None,
+            command: command.to_string(),
+        });
+        self
+    }
+
+    /// Run `command` when the button is released, e.g. to stop a jog.
+    pub fn on_release(mut self, command: &str) -> Self {
+        self.mapping.on_release = Some(command.to_string());
+        self
+    }
+
+    /// Run `command` instead on two presses within the sequence window.
+    pub fn double_press(mut self, command: &str) -> Self {
+        self.mapping.double_press_command = Some(command.to_string());
+        self
+    }
+
+    /// Ignore the button for `cooldown_ms` after it ran a command.
+    pub fn cooldown(mut self, cooldown_ms: u64) -> Self {
+        self.mapping.cooldown_ms = Some(cooldown_ms);
+        self
+    }
+
+    /// Run the command only on a second press within `window_ms`, the
+    /// first arms the button.
+    pub fn confirm(mut self, window_ms: u64) -> Self {
+        self.mapping.confirm = Some(true);
+        self.mapping.confirm_window_ms = Some(window_ms);
+        self
+    }
+
+    /// Run `command` instead when released after holding for `hold_ms`,
+    /// e.g. cancel on a long press of a pause button.
+    pub fn long_press(mut self, hold_ms: u64, command: &str) -> Self {
+        self.mapping.long_press_ms = Some(hold_ms);
+        self.mapping.long_press_command = Some(command.to_string());
+        self
+    }
+
+    pub fn indicator(mut self, indicator: Indicator) -> Self {
+        self.mapping.indicator = Some(indicator);
+        self
+    }
+
+    /// Concurrency group, actions of buttons sharing it run one at a time.
+    pub fn mutex(mut self, group: &str) -> Self {
+        self.mapping.mutex = Some(group.to_string());
+        self
+    }
+
+    /// Run the command this long after the press.
+    pub fn delay_ms(mut self, delay_ms: u64) -> Self {
+        self.mapping.delay_ms = Some(delay_ms);
+        self
+    }
+
+    /// Run the command at the next occurrence of this time of day, e.g. `23:30`.
+    pub fn at(mut self, time: &str) -> Self {
+        self.mapping.at = Some(time.to_string());
+        self
+    }
+
+    pub fn debounce_ms(mut self, debounce_ms: u64) -> Self {
+        self.mapping.debounce_ms = Some(debounce_ms);
+        self
+    }
+
+    /// Handle a press only once it has lasted this long, filtering out
+    /// single-sample ghosts.
+    pub fn min_press_ms(mut self, min_press_ms: u64) -> Self {
+        self.mapping.min_press_ms = Some(min_press_ms);
+        self
+    }
+
+    /// Polling group added with `ConfigBuilder::poll_group`.
+    pub fn poll_group(mut self, group: &str) -> Self {
+        self.mapping.poll_group = Some(group.to_string());
+        self
+    }
+
+    /// Make this a modifier: while held, other buttons run their shift command.
+    pub fn modifier(mut self) -> Self {
+        self.mapping.modifier = Some(true);
+        self
+    }
+
+    /// Command run instead while a modifier button is held.
+    pub fn shift_command(mut self, command: &str) -> Self {
+        self.mapping.shift_command = Some(command.to_string());
+        self
+    }
+
+    /// Latch on a press running the command, the next press running this
+    /// one and turning it off again.
+    pub fn toggle_command(mut self, command: &str) -> Self {
+        self.mapping.toggle_command = Some(command.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_matches_yaml() {
+        let built = ConfigBuilder::new()
+            .device("/dev/spidev1.0")
+            .speed(Hertz(800_000))
+            .poll_group("estop", 10)
+            .klipper_socket("/run/klipper_uds")
+            .button(ButtonBuilder::new(ButtonId(1), "echo menu").config(0x68).description("Menu"))
+            .button(ButtonBuilder::new(ButtonId(0), "klipper:printer/emergency_stop|{}").poll_group("estop"))
+            .build()
+            .unwrap();
+
+        let loaded: Config = serde_yaml::from_str(
+            r#"
+version: 2
+spi: {device: /dev/spidev1.0, speed_hz: 800000}
+polling: {groups: {estop: {interval_ms: 10}}}
+klipper: {socket_path: /run/klipper_uds}
+buttons:
+  - {button: 0, command: "klipper:printer/emergency_stop|{}", poll_group: estop}
+  - {button: 1, command: "echo menu", config: 0x68, description: Menu}
+"#,
+        )
+        .unwrap();
+        assert_eq!(built.normalized().unwrap(), loaded.normalized().unwrap());
+    }
+
+    #[test]
+    fn test_build_validates() {
+        let err = ConfigBuilder::new()
+            .mode(4)
+            .button(ButtonBuilder::new(ButtonId(0), "echo a"))
+            .button(ButtonBuilder::new(ButtonId(0), "echo b").poll_group("fast"))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "spi.mode: 4 is not an SPI mode (0-3); \
+             buttons[1].button: button 0 is already mapped by buttons[0]; \
+             buttons[1].poll_group: no polling group named fast"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use spibuttonlib::SPIButtonState;
+
+use crate::config::Chord;
+use crate::panel::PanelButton;
+use crate::units::ButtonId;
+
+/// Time the buttons of a chord may be pressed apart when
+/// `polling.chord_window_ms` is not configured.
+pub const DEFAULT_CHORD_WINDOW_MS: u64 = 80;
+
+/// Detects chords, several buttons pressed together, across the events of
+/// whole reads. Presses of chord buttons are held back for the chord
+/// window: if every button of a chord is pressed within it the chord fires
+/// and neither the presses nor their releases are handled, else the
+/// presses are handled late.
+#[derive(Debug, Default)]
+pub struct Chords {
+    pending: Vec<(PanelButton, Instant)>,
+    /// Buttons of a fired chord that are still down
+    consumed: HashSet<ButtonId>,
+}
+
+impl Chords {
+    pub fn new() -> Self {
+        Chords::default()
+    }
+
+    /// The events to handle after a read at `now`, and the indices of the
+    /// `chords` that fired. Presses held back in earlier reads come first.
+    pub fn filter(
+        &mut self,
+        now: Instant,
+        events: Vec<PanelButton>,
+        chords: &[Chord],
+        window: Duration,
+    ) -> (Vec<PanelButton>, Vec<usize>) {
+        let in_chord = |id: ButtonId| chords.iter().any(|c| c.buttons.contains(&id));
+        let mut passed = Vec::new();
+        for b in events {
+            let pending = self.pending.iter().position(|(p, _)| p.id() == b.id());
+            match b.get_state() {
+                SPIButtonState::Off if self.consumed.remove(&b.id()) => {}
+                SPIButtonState::Off => {
+                    // Released before the chord completed, a press of its own
+                    if let Some(i) = pending {
+                        passed.push(self.pending.remove(i).0);
+                    }
+                    passed.push(b);
+                }
+                _ if pending.is_some() => {}
+                _ if in_chord(b.id()) => self.pending.push((b, now)),
+                _ => passed.push(b),
+            }
+        }
+
+        let mut fired = Vec::new();
+        for (i, chord) in chords.iter().enumerate() {
+            let complete = chord.buttons.iter().all(|id| self.pending.iter().any(|(p, _)| p.id() == *id));
+            if complete {
+                debug!("Chord {:?} pressed", chord.buttons);
+                self.pending.retain(|(p, _)| !chord.buttons.contains(&p.id()));
+                self.consumed.extend(chord.buttons.iter().copied());
+                fired.push(i);
+            }
+        }
+
+        let (expired, waiting) = self.pending.drain(..).partition(|(_, since)| now.duration_since(*since) >= window);
+        self.pending = waiting;
+        let late = expired.into_iter().map(|(b, _): (PanelButton, Instant)| b);
+        (late.chain(passed).collect(), fired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chord_fires_and_swallows_its_presses() {
+        let button = |id, state| PanelButton::new(ButtonId(id), state);
+        let ids = |events: &[PanelButton]| events.iter().map(|b| b.id().0).collect::<Vec<u8>>();
+        let ms = Duration::from_millis;
+        let chords = vec![Chord {
+            buttons: vec![ButtonId(0), ButtonId(3)],
+            description: None,
+            command: "FIRMWARE_RESTART".to_string(),
+        }];
+        let window = ms(80);
+        let t0 = Instant::now();
+        let mut detector = Chords::new();
+
+        // Button 0 waits for button 3, other buttons are not held back
+        let presses = vec![button(0, SPIButtonState::On), button(5, SPIButtonState::On)];
+        let (events, fired) = detector.filter(t0, presses, &chords, window);
+        assert_eq!((ids(&events), fired), (vec![5], vec![]));
+        let (events, fired) = detector.filter(t0 + ms(30), vec![button(3, SPIButtonState::On)], &chords, window);
+        assert_eq!((ids(&events), fired), (vec![], vec![0]));
+        let releases = vec![button(0, SPIButtonState::Off), button(3, SPIButtonState::Off)];
+        assert!(detector.filter(t0 + ms(500), releases, &chords, window).0.is_empty());
+
+        // Alone, the press is handled once the window passed
+        assert!(detector.filter(t0 + ms(1000), vec![button(3, SPIButtonState::On)], &chords, window).0.is_empty());
+        let (events, fired) = detector.filter(t0 + ms(1080), vec![], &chords, window);
+        assert_eq!((ids(&events), fired), (vec![3], vec![]));
+
+        // A quick tap is handled as press and release together
+        detector.filter(t0 + ms(2000), vec![button(0, SPIButtonState::On)], &chords, window);
+        let (events, _) = detector.filter(t0 + ms(2020), vec![button(0, SPIButtonState::Off)], &chords, window);
+        let pressed: Vec<bool> = events.iter().map(|b| !matches!(b.get_state(), SPIButtonState::Off)).collect();
+        assert_eq!(pressed, vec![true, false]);
+    }
+}
@@ -0,0 +1,148 @@
+use chrono::{DateTime, Datelike, Local};
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// Wall clock jumps smaller than this are drift or a slow poll, larger ones
+/// a step, e.g. by NTP after booting with a wrong clock.
+pub const STEP_THRESHOLD_SECS: i64 = 2;
+
+/// Time the host may spend suspended before the daemon treats waking up
+/// as a resume, shorter gaps are scheduling hiccups.
+pub const SUSPEND_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Monotonic time between reads of the boot time, keeping file reads out
+/// of most polls.
+pub const SUSPEND_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A wall clock before this year has never been set, the BeagleBone has no
+/// battery backed clock.
+const EARLIEST_PLAUSIBLE_YEAR: i32 = 2020;
+
+/// Notices steps of the wall clock by comparing it against the monotonic
+/// clock. Internal intervals all use `Instant` and are not affected, only
+/// things tied to a time of day need recomputing after a step.
+#[derive(Debug, Clone)]
+pub struct ClockWatch {
+    wall: DateTime<Local>,
+    mono: Instant,
+}
+
+impl ClockWatch {
+    pub fn new(wall: DateTime<Local>, mono: Instant) -> Self {
+        ClockWatch { wall, mono }
+    }
+
+    /// How far the wall clock jumped since the last check, beyond what the
+    /// monotonic clock advanced. `None` within `STEP_THRESHOLD_SECS`.
+    pub fn check(&mut self, wall: DateTime<Local>, mono: Instant) -> Option<chrono::Duration> {
+        let elapsed = chrono::Duration::from_std(mono.saturating_duration_since(self.mono)).unwrap_or_default();
+        let step = wall - (self.wall + elapsed);
+        self.wall = wall;
+        self.mono = mono;
+        (step.num_seconds().abs() >= STEP_THRESHOLD_SECS).then_some(step)
+    }
+}
+
+/// Notices the host waking up from suspend. The boot time counts the time
+/// spent suspended, the monotonic clock behind `Instant` does not, so any
+/// difference in how far they advanced is time the host slept.
+#[derive(Debug, Clone)]
+pub struct SleepWatch {
+    boot: Option<Duration>,
+    mono: Instant,
+}
+
+impl SleepWatch {
+    pub fn new(boot: Option<Duration>, mono: Instant) -> Self {
+        SleepWatch { boot, mono }
+    }
+
+    /// How long the host was suspended since the last check, `None` below
+    /// `SUSPEND_THRESHOLD` or when the boot time cannot be read. `boot` is
+    /// only called once `SUSPEND_CHECK_INTERVAL` passed since the last
+    /// check, a resume shows right away as the monotonic clock stood still.
+    pub fn check(&mut self, mono: Instant, boot: impl FnOnce() -> Option<Duration>) -> Option<Duration> {
+        let elapsed = mono.saturating_duration_since(self.mono);
+        if elapsed < SUSPEND_CHECK_INTERVAL {
+            return None;
+        }
+        let boot = boot();
+        let slept = match (self.boot, boot) {
+            (Some(before), Some(after)) => after.saturating_sub(before).saturating_sub(elapsed),
+            _ => Duration::ZERO,
+        };
+        self.boot = boot.or(self.boot);
+        self.mono = mono;
+        (slept >= SUSPEND_THRESHOLD).then_some(slept)
+    }
+}
+
+/// Time since boot including time suspended, from `/proc/uptime`.
+pub fn boot_time() -> Option<Duration> {
+    parse_uptime(&fs::read_to_string("/proc/uptime").ok()?)
+}
+
+fn parse_uptime(text: &str) -> Option<Duration> {
+    let secs: f64 = text.split_whitespace().next()?.parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Whether the wall clock was likely never set, e.g. at boot before NTP.
+pub fn looks_unset(wall: DateTime<Local>) -> bool {
+    wall.year() < EARLIEST_PLAUSIBLE_YEAR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_steps_but_not_elapsed_time() {
+        let wall = Local::now();
+        let t0 = Instant::now();
+        let mut watch = ClockWatch::new(wall, t0);
+
+        // Both clocks advanced alike
+        let later = t0 + Duration::from_secs(30);
+        assert!(watch.check(wall + chrono::Duration::seconds(30), later).is_none());
+
+        // NTP set the clock an hour ahead, then back by 10 minutes
+        let stepped = wall + chrono::Duration::seconds(30 + 3600);
+        assert_eq!(watch.check(stepped, later).unwrap().num_seconds(), 3600);
+        let back = stepped - chrono::Duration::minutes(10);
+        assert_eq!(watch.check(back, later).unwrap().num_minutes(), -10);
+        assert!(watch.check(back + chrono::Duration::seconds(1), later + Duration::from_secs(1)).is_none());
+
+        let epoch = DateTime::from_timestamp(0, 0).unwrap().with_timezone(&Local);
+        assert!(looks_unset(epoch));
+        assert!(!looks_unset(wall));
+    }
+
+    #[test]
+    fn test_detects_suspend() {
+        let secs = Duration::from_secs;
+        let t0 = Instant::now();
+        let boot = parse_uptime("350735.47 234388.90\n");
+        assert_eq!(boot, Some(Duration::from_millis(350_735_470)));
+        let mut watch = SleepWatch::new(boot, t0);
+
+        // A slow poll advances both clocks
+        let boot = boot.map(|b| b + secs(10));
+        assert!(watch.check(t0 + secs(10), || boot).is_none());
+
+        // Suspended for an hour, only the boot time counts it
+        let boot = boot.map(|b| b + secs(3601));
+        assert_eq!(watch.check(t0 + secs(11), || boot), Some(secs(3600)));
+        assert!(watch.check(t0 + secs(12), || None).is_none());
+        assert!(watch.check(t0 + secs(13), || boot.map(|b| b + secs(2))).is_none());
+
+        // Within the check interval the boot time is not read
+        let mut read = false;
+        assert!(watch.check(t0 + secs(13) + Duration::from_millis(500), || {
+            read = true;
+            None
+        })
+        .is_none());
+        assert!(!read);
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::config::Combo;
+use crate::units::ButtonId;
+
+/// Time from the first press of a combo to its last when its `timeout_ms`
+/// is not configured.
+pub const DEFAULT_COMBO_TIMEOUT_MS: u64 = 3000;
+
+/// Matches the latest presses of all buttons against combos, buttons
+/// pressed one after another in a given order like the digits of a
+/// combination lock.
+#[derive(Debug, Default)]
+pub struct Combos {
+    recent: VecDeque<(ButtonId, Instant)>,
+}
+
+impl Combos {
+    pub fn new() -> Self {
+        Combos::default()
+    }
+
+    /// Register a press at `now` and return the index of the combo it
+    /// completes, if any. The presses of a completed combo do not count
+    /// towards another.
+    pub fn press(&mut self, button: ButtonId, now: Instant, combos: &[Combo]) -> Option<usize> {
+        let longest = combos.iter().map(|c| c.presses.len()).max().unwrap_or(0);
+        if longest == 0 {
+            return None;
+        }
+        self.recent.push_back((button, now));
+        while self.recent.len() > longest {
+            self.recent.pop_front();
+        }
+        let index = combos.iter().position(|combo| {
+            let Some(start) = self.recent.len().checked_sub(combo.presses.len()) else { return false };
+            let timeout = Duration::from_millis(combo.timeout_ms.unwrap_or(DEFAULT_COMBO_TIMEOUT_MS));
+            let tail = self.recent.range(start..);
+            tail.clone().map(|(id, _)| id).eq(combo.presses.iter())
+                && now.duration_since(self.recent[start].1) <= timeout
+        })?;
+        debug!("Combo {:?} pressed", combos[index].presses);
+        self.recent.clear();
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combo_needs_its_order_within_the_timeout() {
+        let ms = Duration::from_millis;
+        let combos = vec![Combo {
+            presses: vec![ButtonId(1), ButtonId(1), ButtonId(3)],
+            timeout_ms: Some(2000),
+            description: None,
+            command: "MAINTENANCE_MODE".to_string(),
+        }];
+        let t0 = Instant::now();
+        let mut matcher = Combos::new();
+        let mut press = |id, t| matcher.press(ButtonId(id), t0 + ms(t), &combos);
+
+        // Other presses before the combo do not matter
+        assert_eq!(press(3, 0), None);
+        assert_eq!(press(1, 100), None);
+        assert_eq!(press(1, 400), None);
+        assert_eq!(press(3, 900), Some(0));
+
+        // Its presses are used up, and the wrong order does not match
+        assert_eq!(press(1, 1000), None);
+        assert_eq!(press(3, 1100), None);
+        assert_eq!(press(1, 1200), None);
+
+        // Too slow
+        assert_eq!(press(1, 2000), None);
+        assert_eq!(press(1, 3000), None);
+        assert_eq!(press(3, 4100), None);
+    }
+}
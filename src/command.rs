@@ -1,61 +1,219 @@
-use anyhow::{Context, Result};
-use log::{debug, info, warn};
+use log::{debug, info};
+use std::fmt;
+use std::io;
 use std::process::Command;
+use std::time::Duration;
 use serde_json::Value as JsonValue;
 use tokio::sync::mpsc::Sender;
 use tokio::net::UnixStream;
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
 
+use crate::breaker::Endpoint;
 use crate::config::KlipperConfig;
+use crate::error::{Error, Result};
+use crate::notifications::Notification;
+use crate::peer::Envelope;
+use crate::ratelimit::warn_limited;
+use crate::rpc_errors::{categorize, ErrorCategory, ErrorRule};
+use crate::socket;
+use crate::units::ButtonId;
+
+/// Delay before retrying a failed Klipper request when not configured.
+const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+
+/// Ends every message on the Klipper API socket.
+pub const ETX: u8 = 0x03;
+
+/// Longest Klipper response read before giving up on it, so a broken or
+/// hostile peer cannot make the daemon buffer without bound.
+pub const MAX_RESPONSE_BYTES: usize = 1 << 20;
 
 pub struct CommandExecutor;
 
+/// How one attempt at a Klipper request went.
+enum Attempt {
+    Answered(ResponseStatus, Option<JsonValue>),
+    /// No answer within `klipper.timeout_ms`, the connection is kept to
+    /// wait for a late one
+    TimedOut(UnixStream),
+}
+
+/// Outcome of a Klipper request, as seen by the daemon
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseStatus {
+    /// Klipper answered without an error
+    Ok,
+    /// Klipper closed the connection without answering, e.g. during a restart
+    EmptyResponse,
+    /// The params part of the command was not valid JSON
+    InvalidParams(String),
+    /// Connecting, writing or reading the socket failed
+    ConnectionError(io::ErrorKind),
+    /// The answer was not valid JSON
+    ParseError(String),
+    /// No answer within `klipper.timeout_ms`
+    Timeout,
+    /// Klipper answered with an error object
+    RpcError { code: Option<i64>, message: String },
+}
+
+impl ResponseStatus {
+    /// Classify a parsed Klipper answer. Klipper reports failures as
+    /// `{"error": {"error": "WebRequestError", "message": "..."}}`, Moonraker
+    /// style JSON-RPC errors additionally carry a numeric `code`.
+    pub fn from_response(response: &JsonValue) -> Self {
+        match response.get("error") {
+            None | Some(JsonValue::Null) => ResponseStatus::Ok,
+            Some(error) => {
+                let code = error.get("code").and_then(|c| c.as_i64());
+                let message = error
+                    .get("message")
+                    .or_else(|| error.get("error"))
+                    .and_then(|m| m.as_str())
+                    .map(|m| m.to_string())
+                    .unwrap_or_else(|| error.to_string());
+                ResponseStatus::RpcError { code, message }
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResponseStatus {}
+
+impl fmt::Display for ResponseStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseStatus::Ok => write!(f, "ok"),
+            ResponseStatus::EmptyResponse => write!(f, "empty_response"),
+            ResponseStatus::InvalidParams(e) => write!(f, "invalid_params: {}", e),
+            ResponseStatus::ConnectionError(kind) => write!(f, "connection_error: {}", kind),
+            ResponseStatus::ParseError(e) => write!(f, "parse_error: {}", e),
+            ResponseStatus::Timeout => write!(f, "timeout"),
+            ResponseStatus::RpcError { code: Some(code), message } => {
+                write!(f, "rpc_error {}: {}", code, message)
+            }
+            ResponseStatus::RpcError { code: None, message } => write!(f, "rpc_error: {}", message),
+        }
+    }
+}
+
 /// Response pushed into the event response queue when a Klipper command returns
 #[derive(Debug, Clone)]
 pub struct EventResponse {
     pub request_id: u32,
-    pub success: bool,
-    pub status: Option<String>,
+    pub correlation_id: Uuid,
+    /// Where the request went, `None` when it used several services
+    pub endpoint: Option<Endpoint>,
+    pub status: ResponseStatus,
+    /// Category of a failed request, `None` on success
+    pub category: Option<ErrorCategory>,
     pub body: Option<JsonValue>,
 }
 
+impl EventResponse {
+    /// Categorize `status` and queue the response for the main loop.
+    pub async fn send(
+        response_tx: &Sender<EventMessage>,
+        request_id: u32,
+        correlation_id: Uuid,
+        endpoint: Option<Endpoint>,
+        status: ResponseStatus,
+        body: Option<JsonValue>,
+        rules: &[ErrorRule],
+    ) {
+        let category = categorize(&status, rules);
+        let _ = response_tx
+            .send(EventMessage::Response(EventResponse {
+                request_id,
+                correlation_id,
+                endpoint,
+                status,
+                category,
+                body,
+            }))
+            .await;
+    }
+}
+
+/// Step of a multi-step built-in action, shown on the button LED by the
+/// main loop until the final response arrives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressStage {
+    /// Querying Klipper's state
+    Checking,
+    /// FIRMWARE_RESTART sent
+    Restarting,
+    /// Power device switched on
+    PoweringOn,
+    /// Waiting for Klipper to report `ready`
+    WaitingReady,
+    /// Running the follow-up macro
+    RunningMacro,
+}
+
 /// Event messages sent over the event channel. `Issued` is sent when a
 /// request is created (so the main loop can persist metadata). `Response`
-/// carries the response from Klipper.
+/// carries the response from Klipper. Both carry the correlation id of the
+/// button event that caused them so one press can be traced through the logs.
 #[derive(Debug, Clone)]
 pub enum EventMessage {
-    Issued { request_id: u32, trigger_button: String },
+    Issued { request_id: u32, correlation_id: Uuid, trigger_button: ButtonId },
     Response(EventResponse),
+    /// Progress of a multi-step action such as `recovery::firmware_restart`
+    Progress { request_id: u32, stage: ProgressStage },
+    /// Moonraker notification received on its websocket
+    Notification(Notification),
+    /// Update from the daemon of another panel, see `peer`
+    Peer(Envelope),
 }
 
 impl CommandExecutor {
-    pub fn execute(command: &str) -> Result<()> {
+    pub fn execute(command: &str) -> Result<String> {
+        Self::execute_with_env(command, &[])
+    }
+
+    /// `execute_with_env` on the blocking thread pool, so the runtime keeps
+    /// serving the control socket and Klipper responses while it runs.
+    pub async fn execute_blocking(command: &str, env: Vec<(&'static str, String)>) -> Result<String> {
+        let command = command.to_string();
+        tokio::task::spawn_blocking(move || Self::execute_with_env(&command, &env))
+            .await
+            .map_err(|e| Error::internal("Command task failed").caused_by(e))?
+    }
+
+    /// Execute a shell command with extra environment variables, e.g. the
+    /// correlation id of the triggering button event. Returns the command's
+    /// standard output.
+    pub fn execute_with_env(command: &str, env: &[(&str, String)]) -> Result<String> {
         info!("Executing command: {}", command);
 
         // Execute the command through a shell
         let output = Command::new("sh")
             .arg("-c")
             .arg(command)
+            .envs(env.iter().map(|(k, v)| (k, v)))
             .output()
-            .context(format!("Failed to execute command: {}", command))?;
+            .map_err(|e| Error::action(format!("Failed to execute command: {}", command)).caused_by(e))?;
 
         if output.status.success() {
-            if !output.stdout.is_empty() {
-                let stdout = String::from_utf8_lossy(&output.stdout);
+            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+            if !stdout.is_empty() {
                 debug!("Command output: {}", stdout);
             }
             info!("Command executed successfully");
-            Ok(())
+            Ok(stdout)
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            warn!(
+            warn_limited!(
                 "Command execution failed with status: {:?}. Error: {}",
                 output.status, stderr
             );
-            Err(anyhow::anyhow!(
-                "Command failed with status: {:?}",
-                output.status
-            ))
+            Err(Error::action(format!(
+                "Command failed with status: {:?}: {}",
+                output.status, stderr.trim()
+            )))
         }
     }
 /*
@@ -98,14 +256,88 @@ impl CommandExecutor {
     /// Command string format (simple syntax):
     /// klipper:METHOD|<JSON_PARAMS>
     /// Example: klipper:gcode/script|{"script":"G28"}
+    ///
+    /// Failures categorized as retryable are sent again up to
+    /// `klipper.retries` times before the response is reported.
     pub async fn send_klipper_command(
         command: &str,
         klipper: &KlipperConfig,
         request_id: u32,
+        correlation_id: Uuid,
         response_tx: Sender<EventMessage>,
     ) {
-        info!("Preparing Klipper command: {}", command);
+        info!("[{}] Preparing Klipper command id={}: {}", correlation_id, request_id, command);
+
+        let rules = klipper.error_categories.as_deref().unwrap_or(&[]);
+        let retries = klipper.retries.unwrap_or(0);
+        let retry_delay = Duration::from_millis(klipper.retry_delay_ms.unwrap_or(DEFAULT_RETRY_DELAY_MS));
+
+        let mut attempt = 0;
+        let (status, body, category) = loop {
+            let (status, body, unanswered) = match Self::attempt(command, klipper, request_id).await {
+                Attempt::Answered(status, body) => (status, body, None),
+                Attempt::TimedOut(stream) => (ResponseStatus::Timeout, None, Some(stream)),
+            };
+            let category = categorize(&status, rules);
+            if category != Some(ErrorCategory::Retryable) || attempt >= retries {
+                break (status, body, category);
+            }
+            // A request that timed out may still be carried out, so its answer
+            // is awaited during the retry delay rather than the request sent
+            // twice, e.g. toggling a power device back off
+            match unanswered {
+                Some(mut stream) => match timeout(retry_delay, Self::read_response(&mut stream)).await {
+                    Ok(Ok(response)) if !response.is_empty() => {
+                        let (late_status, late_body) = parse_response(&response);
+                        let late_category = categorize(&late_status, rules);
+                        if late_category != Some(ErrorCategory::Retryable) {
+                            info!(
+                                "[{}] Klipper command id={} answered late, not sending it again: {}",
+                                correlation_id, request_id, late_status
+                            );
+                            break (late_status, late_body, late_category);
+                        }
+                    }
+                    // Closed or still silent, so sent again
+                    _ => {}
+                },
+                None => sleep(retry_delay).await,
+            }
+            attempt += 1;
+            info!(
+                "[{}] Retrying Klipper command id={} ({}/{}) after: {}",
+                correlation_id, request_id, attempt, retries, status
+            );
+        };
+
+        let _ = response_tx
+            .send(EventMessage::Response(EventResponse {
+                request_id,
+                correlation_id,
+                endpoint: Some(Endpoint::Klipper),
+                status,
+                category,
+                body,
+            }))
+            .await;
+    }
+
+    /// One round trip to the Klipper socket. `command` may omit the
+    /// `klipper:` prefix, e.g. `info`.
+    pub async fn klipper_request(
+        command: &str,
+        klipper: &KlipperConfig,
+        request_id: u32,
+    ) -> (ResponseStatus, Option<JsonValue>) {
+        match Self::attempt(command, klipper, request_id).await {
+            Attempt::Answered(status, body) => (status, body),
+            Attempt::TimedOut(_) => (ResponseStatus::Timeout, None),
+        }
+    }
 
+    /// Send a request and read its answer, keeping the connection of one
+    /// not answered within `klipper.timeout_ms`.
+    async fn attempt(command: &str, klipper: &KlipperConfig, request_id: u32) -> Attempt {
         // Strip prefix if present
         let payload = command.strip_prefix("klipper:").unwrap_or(command);
 
@@ -117,16 +349,8 @@ impl CommandExecutor {
         let params_json: JsonValue = match serde_json::from_str(params_str) {
             Ok(v) => v,
             Err(e) => {
-                warn!("Failed to parse Klipper params JSON: {}", e);
-                let _ = response_tx
-                    .send(EventMessage::Response(EventResponse {
-                        request_id,
-                        success: false,
-                        status: Some("invalid_params".to_string()),
-                        body: None,
-                    }))
-                    .await;
-                return;
+                warn_limited!("Failed to parse Klipper params JSON: {}", e);
+                return Attempt::Answered(ResponseStatus::InvalidParams(e.to_string()), None);
             }
         };
 
@@ -135,113 +359,127 @@ impl CommandExecutor {
         body.insert("id".to_string(), JsonValue::Number(request_id.into()));
         body.insert("method".to_string(), JsonValue::String(method.to_string()));
         body.insert("params".to_string(), params_json.clone());
+        if let Some(key) = &klipper.api_key {
+            body.insert("api_key".to_string(), JsonValue::String(key.clone()));
+        }
 
         let request_json = serde_json::to_string(&JsonValue::Object(body))
             .unwrap_or_default();
 
         // Attempt to connect to Unix domain socket
-        match UnixStream::connect(&klipper.socket_path).await {
-            Ok(mut stream) => {
-                // Send the request
-                if let Err(e) = stream.write_all(request_json.as_bytes()).await {
-                    warn!("Failed to write to Unix socket: {}", e);
-                    let _ = response_tx
-                        .send(EventMessage::Response(EventResponse {
-                            request_id,
-                            success: false,
-                            status: Some(format!("socket_write_error: {}", e)),
-                            body: None,
-                        }))
-                        .await;
-                    return;
-                }
+        let mut stream = match socket::connect(&klipper.socket_path).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn_limited!("Failed to connect to Klipper Unix socket at {}: {}", klipper.socket_path, e);
+                return Attempt::Answered(ResponseStatus::ConnectionError(e.kind()), None);
+            }
+        };
 
-                // Send ETX (ASCII 0x03) to signal end of request
-                if let Err(e) = stream.write_all(&[0x03]).await {
-                    warn!("Failed to write ETX to Unix socket: {}", e);
-                    let _ = response_tx
-                        .send(EventMessage::Response(EventResponse {
-                            request_id,
-                            success: false,
-                            status: Some(format!("socket_write_error: {}", e)),
-                            body: None,
-                        }))
-                        .await;
-                    return;
-                }
+        // Send the request
+        if let Err(e) = stream.write_all(request_json.as_bytes()).await {
+            warn_limited!("Failed to write to Unix socket: {}", e);
+            return Attempt::Answered(ResponseStatus::ConnectionError(e.kind()), None);
+        }
 
-                // Read response
-                let mut buffer = vec![0; 4096];
-                match stream.read(&mut buffer).await {
-                    Ok(n) if n > 0 => {
-                        let response_str = String::from_utf8_lossy(&buffer[..n]);
-                        let response_str = response_str.replace("\x03", "\x0A");
-                        match serde_json::from_str::<JsonValue>(&response_str) {
-                            Ok(json_response) => {
-                                let success = !response_str.contains("\"error\"");
-                                let status = if success {
-                                    "200".to_string()
-                                } else {
-                                    "error".to_string()
-                                };
-
-                                let _ = response_tx
-                                    .send(EventMessage::Response(EventResponse {
-                                        request_id,
-                                        success,
-                                        status: Some(status),
-                                        body: Some(json_response),
-                                    }))
-                                    .await;
-                            }
-                            Err(e) => {
-                                warn!("Failed to parse Klipper response JSON: {}", e);
-                                let _ = response_tx
-                                    .send(EventMessage::Response(EventResponse {
-                                        request_id,
-                                        success: false,
-                                        status: Some(format!("parse_error: {}", e)),
-                                        body: None,
-                                    }))
-                                    .await;
-                            }
-                        }
-                    }
-                    Ok(_) => {
-                        warn!("Received empty response from Klipper socket");
-                        let _ = response_tx
-                            .send(EventMessage::Response(EventResponse {
-                                request_id,
-                                success: false,
-                                status: Some("empty_response".to_string()),
-                                body: None,
-                            }))
-                            .await;
-                    }
-                    Err(e) => {
-                        warn!("Failed to read from Unix socket: {}", e);
-                        let _ = response_tx
-                            .send(EventMessage::Response(EventResponse {
-                                request_id,
-                                success: false,
-                                status: Some(format!("socket_read_error: {}", e)),
-                                body: None,
-                            }))
-                            .await;
-                    }
+        // Send ETX (ASCII 0x03) to signal end of request
+        if let Err(e) = stream.write_all(&[ETX]).await {
+            warn_limited!("Failed to write ETX to Unix socket: {}", e);
+            return Attempt::Answered(ResponseStatus::ConnectionError(e.kind()), None);
+        }
+
+        // Read the response up to its ETX, bounded by the configured timeout if any
+        let read_result = match klipper.timeout_ms {
+            Some(ms) => match timeout(Duration::from_millis(ms), Self::read_response(&mut stream)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn_limited!("Timed out after {}ms waiting for Klipper response", ms);
+                    return Attempt::TimedOut(stream);
                 }
+            },
+            None => Self::read_response(&mut stream).await,
+        };
+        let (status, body) = match read_result {
+            Ok(response) if response.is_empty() => {
+                warn_limited!("Received empty response from Klipper socket");
+                (ResponseStatus::EmptyResponse, None)
+            }
+            Ok(response) => parse_response(&response),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn_limited!("Failed to read Klipper response: {}", e);
+                (ResponseStatus::ParseError(e.to_string()), None)
             }
             Err(e) => {
-                warn!("Failed to connect to Klipper Unix socket at {}: {}", klipper.socket_path, e);
-                let _ = response_tx
-                    .send(EventMessage::Response(EventResponse {
-                        request_id,
-                        success: false,
-                        status: Some(format!("connection_error: {}", e)),
-                        body: None,
-                    }))
-                    .await;
+                warn_limited!("Failed to read from Unix socket: {}", e);
+                (ResponseStatus::ConnectionError(e.kind()), None)
             }
+        };
+        Attempt::Answered(status, body)
+    }
+
+    /// The first message read from `stream`, or what arrived before it
+    /// closed. Empty if it closed without sending anything.
+    async fn read_response(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+        let mut framer = EtxFramer::new();
+        let mut buffer = vec![0; 4096];
+        loop {
+            if let Some(frame) = framer.next_frame() {
+                return Ok(frame);
+            }
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(framer.into_remainder());
+            }
+            framer.push(&buffer[..n])?;
+        }
+    }
+}
+
+/// Splits what is read from the Klipper socket into messages ending in ETX.
+#[derive(Debug, Default)]
+pub struct EtxFramer {
+    buffer: Vec<u8>,
+}
+
+impl EtxFramer {
+    pub fn new() -> Self {
+        EtxFramer::default()
+    }
+
+    /// Add bytes read, failing once more than `MAX_RESPONSE_BYTES` wait
+    /// for their ETX.
+    pub fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        let unterminated = self.buffer.iter().position(|b| *b == ETX).unwrap_or(self.buffer.len());
+        if unterminated > MAX_RESPONSE_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("response longer than {} bytes", MAX_RESPONSE_BYTES),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The next complete message, without its ETX.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let end = self.buffer.iter().position(|b| *b == ETX)?;
+        let mut frame: Vec<u8> = self.buffer.drain(..=end).collect();
+        frame.pop();
+        Some(frame)
+    }
+
+    /// Bytes of an unfinished message.
+    pub fn into_remainder(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+/// Status and body of one Klipper response message.
+pub fn parse_response(message: &[u8]) -> (ResponseStatus, Option<JsonValue>) {
+    match serde_json::from_slice::<JsonValue>(message) {
+        Ok(json_response) => (ResponseStatus::from_response(&json_response), Some(json_response)),
+        Err(e) => {
+            warn_limited!("Failed to parse Klipper response JSON: {}", e);
+            (ResponseStatus::ParseError(e.to_string()), None)
         }
     }
 }
@@ -261,4 +499,50 @@ mod tests {
         let result = CommandExecutor::execute("false");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_framer_splits_messages_at_etx() {
+        let mut framer = EtxFramer::new();
+        framer.push(b"{\"id\": 1, \"res").unwrap();
+        assert_eq!(framer.next_frame(), None);
+        framer.push(b"ult\": {}}\x03{\"id\": 2}\x03{\"id").unwrap();
+        let first = framer.next_frame().unwrap();
+        assert_eq!(parse_response(&first).0, ResponseStatus::Ok);
+        assert_eq!(framer.next_frame().unwrap(), b"{\"id\": 2}");
+        assert_eq!(framer.next_frame(), None);
+        assert_eq!(framer.into_remainder(), b"{\"id");
+
+        assert!(matches!(parse_response(b"\xff{"), (ResponseStatus::ParseError(_), None)));
+        let mut framer = EtxFramer::new();
+        assert!(framer.push(&vec![b' '; MAX_RESPONSE_BYTES + 1]).is_err());
+    }
+
+    #[test]
+    fn test_response_status_from_response() {
+        let ok: JsonValue = serde_json::from_str(r#"{"id": 1, "result": {}}"#).unwrap();
+        assert_eq!(ResponseStatus::from_response(&ok), ResponseStatus::Ok);
+
+        let klipper_error: JsonValue = serde_json::from_str(
+            r#"{"id": 2, "error": {"error": "WebRequestError", "message": "Unknown command: G99"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ResponseStatus::from_response(&klipper_error),
+            ResponseStatus::RpcError {
+                code: None,
+                message: "Unknown command: G99".to_string()
+            }
+        );
+
+        let jsonrpc_error: JsonValue =
+            serde_json::from_str(r#"{"id": 3, "error": {"code": -32601, "message": "Method not found"}}"#)
+                .unwrap();
+        assert_eq!(
+            ResponseStatus::from_response(&jsonrpc_error),
+            ResponseStatus::RpcError {
+                code: Some(-32601),
+                message: "Method not found".to_string()
+            }
+        );
+    }
 }
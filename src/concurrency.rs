@@ -0,0 +1,154 @@
+use log::info;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+/// How often `InFlight::drain` checks whether the actions finished.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Named locks of the `mutex:` groups buttons may declare. Actions of the
+/// same group run one after the other, e.g. all motion buttons, while
+/// buttons without a group run freely.
+#[derive(Debug, Default)]
+pub struct MutexGroups {
+    groups: HashMap<String, Arc<Mutex<()>>>,
+}
+
+/// The lock of one group, taken by an action before it runs.
+#[derive(Debug, Clone)]
+pub struct GroupLock {
+    name: String,
+    lock: Arc<Mutex<()>>,
+}
+
+impl MutexGroups {
+    pub fn new() -> Self {
+        MutexGroups::default()
+    }
+
+    /// The lock of the named group, created on first use.
+    pub fn get(&mut self, name: Option<&str>) -> Option<GroupLock> {
+        let name = name?;
+        let lock = self.groups.entry(name.to_string()).or_default().clone();
+        Some(GroupLock {
+            name: name.to_string(),
+            lock,
+        })
+    }
+}
+
+/// Run `action` holding the group lock, waiting for earlier actions of the
+/// group to finish first. Without a group the action runs right away.
+pub async fn exclusive<F: Future>(group: Option<GroupLock>, correlation_id: Uuid, action: F) -> F::Output {
+    let _guard = match group {
+        Some(group) => match group.lock.clone().try_lock_owned() {
+            Ok(guard) => Some(guard),
+            Err(_) => {
+                info!("[{}] Waiting for mutex group {}", correlation_id, group.name);
+                Some(group.lock.lock_owned().await)
+            }
+        },
+        None => None,
+    };
+    action.await
+}
+
+/// Counts the actions started and not finished yet, including those
+/// waiting for their group, for the `actions` queue depth.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+/// Counts one action until dropped.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait until every action finished or `deadline` passed, returning
+    /// how many are still running.
+    pub async fn drain(&self, deadline: Instant) -> usize {
+        while !self.is_empty() && Instant::now() < deadline {
+            sleep(DRAIN_INTERVAL.min(deadline - Instant::now())).await;
+        }
+        self.len()
+    }
+
+    /// Count `action` from now until it finishes or is dropped.
+    pub fn track<F: Future>(&self, action: F) -> impl Future<Output = F::Output> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(self.0.clone());
+        async move {
+            let _guard = guard;
+            action.await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_group_serializes_actions() {
+        let mut groups = MutexGroups::new();
+        let running = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let running = running.clone();
+                let overlapped = overlapped.clone();
+                tokio::spawn(exclusive(groups.get(Some("motion")), Uuid::new_v4(), async move {
+                    if running.fetch_add(1, Ordering::SeqCst) > 0 {
+                        overlapped.fetch_add(1, Ordering::SeqCst);
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }))
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(overlapped.load(Ordering::SeqCst), 0);
+        assert!(groups.get(None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_until_the_deadline() {
+        let in_flight = InFlight::new();
+        tokio::spawn(in_flight.track(tokio::time::sleep(Duration::from_millis(20))));
+        let stuck = tokio::spawn(in_flight.track(std::future::pending::<()>()));
+        assert_eq!(in_flight.len(), 2);
+
+        let start = Instant::now();
+        assert_eq!(in_flight.drain(start + Duration::from_millis(200)).await, 1);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        stuck.abort();
+        let _ = stuck.await;
+        assert_eq!(in_flight.drain(Instant::now()).await, 0);
+    }
+}
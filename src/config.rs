@@ -1,52 +1,2685 @@
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use spibuttonlib::SPIButtonState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::actions;
+use crate::messages;
+use crate::deferred;
+use crate::error::{Error, Result};
+use crate::expr;
+use crate::migrate;
+use crate::panel::Capabilities;
+use crate::rpc_errors::ErrorRule;
+use crate::schedule::TimeWindow;
+use crate::units::{millis, ButtonId, Hertz};
+
+/// SPI clock range accepted by `Config::validate`. The AM335x McSPI runs up
+/// to 48MHz, shift registers on a long cable rarely manage more than a few.
+const SPI_SPEED_RANGE_HZ: std::ops::RangeInclusive<Hertz> = Hertz(1_000)..=Hertz(48_000_000);
+
+/// Missing sections and fields take their `Default`, so a config holding
+/// only `buttons:` is complete.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct Config {
+    /// Format version, see `migrate::CONFIG_VERSION`
+    pub version: Option<u64>,
     pub spi: SpiConfig,
     pub polling: PollingConfig,
     pub buttons: Vec<ButtonMapping>,
     pub klipper: Option<KlipperConfig>,
+    pub control: Option<ControlConfig>,
+    /// Read buttons and log events but suppress every command
+    pub observer: Option<bool>,
+    /// What to do with events from buttons missing in `buttons`
+    pub unknown_buttons: Option<UnknownButtonsConfig>,
+    pub moonraker: Option<MoonrakerConfig>,
+    /// Where `snapshot:` commands get and put the webcam image
+    pub snapshot: Option<SnapshotConfig>,
+    /// Variables set by `set_var:` and read as `{{var.NAME}}`
+    pub variables: Option<VariablesConfig>,
+    /// Named sets of command overrides, e.g. `printing` and `idle`
+    pub profiles: Option<BTreeMap<String, Vec<ProfileMapping>>>,
+    /// Profile active at startup
+    pub default_profile: Option<String>,
+    /// Buttons sharing a command template, expanded into `buttons` on load
+    pub groups: Option<BTreeMap<String, ButtonGroup>>,
+    /// Commands by name, used as `command: "!NAME"`
+    pub aliases: Option<BTreeMap<String, String>>,
+    /// Values replacing `{{NAME}}` in commands and descriptions on load,
+    /// e.g. a host name shared by many buttons
+    pub vars: Option<BTreeMap<String, String>>,
+    /// Caps on what the daemon keeps in memory
+    pub limits: Option<LimitsConfig>,
+    /// Queue depths that raise an alarm when sustained
+    pub backpressure: Option<BackpressureConfig>,
+    /// Failing fast while Klipper or Moonraker is down
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Tokio runtime the daemon runs on, read at startup only
+    pub runtime: Option<RuntimeConfig>,
+    /// Commands run by pressing several buttons together
+    pub chords: Option<Vec<Chord>>,
+    /// Commands run by pressing buttons one after another in a given
+    /// order, e.g. 1, 1, 3
+    pub combos: Option<Vec<Combo>>,
+    /// Groups of buttons of which one at a time is selected, by name,
+    /// e.g. the tools of a toolchanger
+    pub radio_groups: Option<BTreeMap<String, RadioGroup>>,
+    /// LED flash patterns by name, e.g. `sos`, shown by state machine
+    /// states
+    pub led_patterns: Option<BTreeMap<String, LedPattern>>,
+    /// How long each LED state a command leaves shows before it clears
+    /// itself, e.g. `flash2: 30s` for errors
+    pub led_timeouts: Option<LedTimeouts>,
+    /// Texts shown outside the logs by message id, replacing the English
+    /// defaults, e.g. `ready: "Bereit, {{buttons}} Tasten"`
+    pub messages: Option<BTreeMap<String, String>>,
+    /// Slowing LED animations and indicator updates while the host runs
+    /// hot or busy
+    pub throttle: Option<ThrottleConfig>,
+    /// Mirroring LEDs, armed buttons and state machine states with the
+    /// daemons of other panels on the same printer, read at startup only
+    pub sync: Option<SyncConfig>,
+}
+
+/// Syntax of a config file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Guess the format from the file extension, defaulting to YAML.
+    pub fn from_path(path: &str) -> Self {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("");
+        ext.parse().unwrap_or(ConfigFormat::Yaml)
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(Error::config(format!("Unknown config format: {} (expected yaml, toml or json)", s))),
+        }
+    }
+}
+
+impl Config {
+    /// Read a config file in the given format, or the one its extension
+    /// suggests. Configs of an older `version` are upgraded first, logging
+    /// each change.
+    pub fn load(path: &str, format: Option<ConfigFormat>) -> Result<Self> {
+        let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+        Config::parse(&read_file(path)?, format, path)
+    }
+
+    /// Parse config text the way `load` parses a file, `origin` naming it
+    /// in messages.
+    pub fn parse(content: &str, format: ConfigFormat, origin: &str) -> Result<Self> {
+        let mut raw: JsonValue = parse_text(content, format, origin)?;
+        let notes = migrate::migrate(&mut raw)
+            .map_err(|e| Error::config(format!("Failed to migrate configuration file {}", origin)).caused_by(e))?;
+        if notes.is_empty() {
+            // Parse again for error messages with line numbers
+            return parse_text(content, format, origin);
+        }
+        for note in &notes {
+            info!("Config {}: migrated {}", origin, note);
+        }
+        serde_json::from_value(raw)
+            .map_err(|e| Error::config(format!("Failed to parse migrated configuration file {}", origin)).caused_by(e))
+    }
+
+    /// Load a config file as the daemon runs it: completed by
+    /// `parse_complete` with the fragments in `conf.d` next to it, then
+    /// secrets read.
+    pub fn load_complete(path: &str, format: Option<ConfigFormat>) -> Result<Self> {
+        let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+        let conf_d = Path::new(path).parent().unwrap_or(Path::new(".")).join("conf.d");
+        let mut config = Config::parse_complete(&read_file(path)?, format, path, &conf_d)?;
+        config.load_secrets()?;
+        Ok(config)
+    }
+
+    /// Parse config text into the config the daemon runs: groups expanded,
+    /// the fragments in `conf_d` merged, press shorthands, aliases and
+    /// `vars` replaced, validated and buttons sorted by id. Each problem
+    /// found is logged.
+    pub fn parse_complete(content: &str, format: ConfigFormat, origin: &str, conf_d: &Path) -> Result<Self> {
+        let mut config = Config::parse(content, format, origin)?;
+        config.expand_groups()?;
+        for fragment in config.merge_fragments(conf_d)? {
+            info!("Merged buttons from {}", fragment);
+        }
+        config.resolve_press_shorthands();
+        config.resolve_aliases()?;
+        config.expand_vars();
+        let problems = config.validate();
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("Config {}: {}", origin, problem);
+            }
+            return Err(Error::config(format!("{} problem(s) in configuration file {}", problems.len(), origin)));
+        }
+        config.buttons.sort_by_key(|b| b.button);
+        Ok(config)
+    }
+
+    /// Add the buttons of every fragment in `dir` (e.g. `conf.d`), in file
+    /// name order. A fragment's mapping replaces an earlier one for the same
+    /// button id. Files with other extensions than yaml, yml, toml or json
+    /// are skipped, a missing directory is fine. Returns the merged files.
+    pub fn merge_fragments(&mut self, dir: &Path) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(Error::config(format!("Failed to read config directory: {}", dir.display())).caused_by(e))
+            }
+        };
+        let mut files: Vec<(String, ConfigFormat)> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                let format = path.extension()?.to_str()?.parse().ok()?;
+                Some((path.display().to_string(), format))
+            })
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (file, format) in &files {
+            let fragment: ConfigFragment = parse_file(file, *format)?;
+            // Shortened to e.g. conf.d/10-panel.yaml in messages
+            let short = Path::new(file)
+                .strip_prefix(dir.parent().unwrap_or(dir))
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| file.clone());
+            for (i, mut mapping) in fragment.buttons.into_iter().enumerate() {
+                mapping.origin = Some(format!("{}: buttons[{}]", short, i));
+                match self.buttons.iter_mut().find(|m| m.button == mapping.button) {
+                    Some(existing) => {
+                        info!("{} replaces the mapping of button {}", file, mapping.button);
+                        *existing = mapping;
+                    }
+                    None => self.buttons.push(mapping),
+                }
+            }
+        }
+        Ok(files.into_iter().map(|(file, _)| file).collect())
+    }
+
+    /// Read the secrets the config refers to by file, i.e. the Klipper
+    /// `api_key_file` and `sync.secret_file`. Warns about a file other users
+    /// can read.
+    pub fn load_secrets(&mut self) -> Result<()> {
+        if let Some(klipper) = self.klipper.as_mut() {
+            klipper.api_key = match &klipper.api_key_file {
+                Some(file) => {
+                    let secret = read_secret(file)
+                        .map_err(|e| Error::config(format!("Failed to read klipper.api_key_file {}", file)).caused_by(e))?;
+                    Some(secret)
+                }
+                None => None,
+            };
+        }
+        if let Some(sync) = self.sync.as_mut() {
+            sync.secret = match &sync.secret_file {
+                Some(file) => {
+                    let secret = read_secret(file)
+                        .map_err(|e| Error::config(format!("Failed to read sync.secret_file {}", file)).caused_by(e))?;
+                    Some(secret)
+                }
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Move the members of every group into `buttons`, filling the group's
+    /// `{{param.NAME}}` placeholders with the member's params, falling back
+    /// to the group's. Afterwards `groups` is empty, so a dump of the config
+    /// lists the expanded buttons instead.
+    pub fn expand_groups(&mut self) -> Result<()> {
+        for (name, group) in self.groups.take().unwrap_or_default() {
+            for (i, member) in group.buttons.into_iter().enumerate() {
+                let path = format!("groups.{}.buttons[{}]", name, i);
+                let mut params = group.params.clone().unwrap_or_default();
+                params.extend(member.params.unwrap_or_default());
+                let fill = |template: &str| {
+                    fill_params(template, &params)
+                        .map_err(|param| Error::config(format!("{}: no value for param.{}", path, param)))
+                };
+                let command = fill(&group.command)?;
+                let description = member.description.or_else(|| group.description.clone());
+                let description = description.map(|d| fill(&d)).transpose()?;
+                self.buttons.push(ButtonMapping {
+                    button: member.button,
+                    config: member.config.or(group.config),
+                    description,
+                    command,
+                    indicator: group.indicator,
+                    poll_group: group.poll_group.clone(),
+                    origin: Some(path),
+                    ..ButtonMapping::default()
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace every command of the form `!NAME` by the alias of that name,
+    /// in buttons, their sequences, hold tiers and shift commands,
+    /// profiles, `unknown_buttons`, chords and combos. Commands without the
+    /// form are kept, so this may run again. All unknown aliases are
+    /// reported together.
+    pub fn resolve_aliases(&mut self) -> Result<()> {
+        let aliases = self.aliases.clone().unwrap_or_default();
+        let mut unknown = Vec::new();
+        self.visit_commands(|path, command| {
+            let Some(name) = alias_name(command) else { return };
+            match aliases.get(name) {
+                Some(target) => *command = target.clone(),
+                None => unknown.push(format!("{}: no alias named {}", path, name)),
+            }
+        });
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::config(unknown.join("; ")))
+        }
+    }
+
+    /// Turn each button's `long_press_ms` and `long_press_command` into a
+    /// hold tier, its `double_press_command` into a sequence of two
+    /// presses, each of its `tap_commands` into a sequence of that many
+    /// presses and its `toggle_command` into a state machine, so they are
+    /// handled like any other. Shorthands that are incomplete or clash
+    /// with a sequence or a state machine are kept for `validate` to
+    /// report.
+    pub fn resolve_press_shorthands(&mut self) {
+        for mapping in &mut self.buttons {
+            if mapping.state_machine.is_none() {
+                if let Some(off_command) = mapping.toggle_command.take() {
+                    let on_command = std::mem::take(&mut mapping.command);
+                    mapping.state_machine = Some(toggle_machine(on_command, off_command));
+                }
+            }
+            let sequences = mapping.sequences.get_or_insert_with(Vec::new);
+            if let Some(command) = mapping.double_press_command.as_ref() {
+                if !sequences.iter().any(|s| s.presses == 2) {
+                    sequences.push(PressSequence {
+                        presses: 2,
+                        description: None,
+                        command: command.clone(),
+                    });
+                    mapping.double_press_command = None;
+                }
+            }
+            if let Some(taps) = mapping.tap_commands.as_mut() {
+                taps.retain(|presses, command| {
+                    if *presses == 0 || sequences.iter().any(|s| s.presses == *presses) {
+                        return true;
+                    }
+                    sequences.push(PressSequence {
+                        presses: *presses,
+                        description: None,
+                        command: std::mem::take(command),
+                    });
+                    false
+                });
+                if taps.is_empty() {
+                    mapping.tap_commands = None;
+                }
+            }
+            if sequences.is_empty() {
+                mapping.sequences = None;
+            }
+            let (Some(hold_ms), Some(command)) = (mapping.long_press_ms, mapping.long_press_command.clone()) else {
+                continue;
+            };
+            mapping.long_press_ms = None;
+            mapping.long_press_command = None;
+            let tiers = mapping.hold_tiers.get_or_insert_with(Vec::new);
+            tiers.push(HoldTier {
+                hold_ms,
+                description: None,
+                command,
+            });
+            tiers.sort_by_key(|t| t.hold_ms);
+        }
+    }
+
+    /// Replace `{{NAME}}` by the value of `vars.NAME` in every command and
+    /// description. Placeholders naming no entry of `vars` are left for
+    /// runtime, e.g. `{{var.material}}`. `vars` is kept, so buttons added
+    /// later can use it too.
+    pub fn expand_vars(&mut self) {
+        let vars = self.vars.clone().unwrap_or_default();
+        if vars.is_empty() {
+            return;
+        }
+        let expand = |text: &mut String| {
+            let Ok(expanded) = replace_placeholders(text, |name| Ok::<_, Infallible>(vars.get(name).cloned()));
+            *text = expanded;
+        };
+        self.visit_commands(|_, command| expand(command));
+        for mapping in &mut self.buttons {
+            mapping.description.iter_mut().for_each(expand);
+            for sequence in mapping.sequences.iter_mut().flatten() {
+                sequence.description.iter_mut().for_each(expand);
+            }
+            for tier in mapping.hold_tiers.iter_mut().flatten() {
+                tier.description.iter_mut().for_each(expand);
+            }
+        }
+        for mapping in self.profiles.iter_mut().flatten().flat_map(|(_, mappings)| mappings) {
+            mapping.description.iter_mut().for_each(expand);
+        }
+        for chord in self.chords.iter_mut().flatten() {
+            chord.description.iter_mut().for_each(expand);
+        }
+        for combo in self.combos.iter_mut().flatten() {
+            combo.description.iter_mut().for_each(expand);
+        }
+    }
+
+    /// Call `visit` with the path and text of every command: of buttons,
+    /// their sequences, hold tiers, shift and deselect commands and state
+    /// machine transitions, profiles, `unknown_buttons`, chords and combos.
+    fn visit_commands(&mut self, mut visit: impl FnMut(String, &mut String)) {
+        for (i, mapping) in self.buttons.iter_mut().enumerate() {
+            let path = mapping.origin.clone().unwrap_or_else(|| format!("buttons[{}]", i));
+            visit(format!("{}.command", path), &mut mapping.command);
+            if let Some(command) = mapping.on_release.as_mut() {
+                visit(format!("{}.on_release", path), command);
+            }
+            if let Some(command) = mapping.shift_command.as_mut() {
+                visit(format!("{}.shift_command", path), command);
+            }
+            if let Some(command) = mapping.deselect_command.as_mut() {
+                visit(format!("{}.deselect_command", path), command);
+            }
+            for (j, sequence) in mapping.sequences.iter_mut().flatten().enumerate() {
+                visit(format!("{}.sequences[{}].command", path, j), &mut sequence.command);
+            }
+            for (j, tier) in mapping.hold_tiers.iter_mut().flatten().enumerate() {
+                visit(format!("{}.hold_tiers[{}].command", path, j), &mut tier.command);
+            }
+            let transitions = mapping.state_machine.iter_mut().flat_map(|m| &mut m.transitions);
+            for (j, transition) in transitions.enumerate() {
+                if let Some(command) = transition.command.as_mut() {
+                    visit(format!("{}.state_machine.transitions[{}].command", path, j), command);
+                }
+            }
+        }
+        for (name, mappings) in self.profiles.iter_mut().flatten() {
+            for (i, mapping) in mappings.iter_mut().enumerate() {
+                visit(format!("profiles.{}[{}].command", name, i), &mut mapping.command);
+            }
+        }
+        if let Some(command) = self.unknown_buttons.as_mut().and_then(|u| u.command.as_mut()) {
+            visit("unknown_buttons.command".into(), command);
+        }
+        for (i, chord) in self.chords.iter_mut().flatten().enumerate() {
+            visit(format!("chords[{}].command", i), &mut chord.command);
+        }
+        for (i, combo) in self.combos.iter_mut().flatten().enumerate() {
+            visit(format!("combos[{}].command", i), &mut combo.command);
+        }
+    }
+
+    /// This config with the commands of the named profile applied.
+    pub fn with_profile(&self, name: &str) -> Result<Config> {
+        let overrides = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .ok_or_else(|| Error::config(format!("Unknown profile: {}", name)))?;
+        let mut config = self.clone();
+        for entry in overrides {
+            if let Some(mapping) = config.buttons.iter_mut().find(|m| m.button == entry.button) {
+                mapping.command = entry.command.clone();
+                if entry.description.is_some() {
+                    mapping.description = entry.description.clone();
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Check values serde cannot, returning every problem found rather than
+    /// stopping at the first. Paths refer to the file as written, e.g.
+    /// `buttons[3].command`, so call this before sorting the buttons.
+    /// Buttons merged from fragments are reported by their file.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        let mut problem = |path: String, message: String| problems.push(ConfigProblem { path, message });
+
+        if self.spi.device.trim().is_empty() {
+            problem("spi.device".into(), "must not be empty".into());
+        }
+        if !SPI_SPEED_RANGE_HZ.contains(&self.spi.speed_hz) {
+            problem(
+                "spi.speed_hz".into(),
+                format!(
+                    "{} is outside {}..={}",
+                    self.spi.speed_hz,
+                    SPI_SPEED_RANGE_HZ.start(),
+                    SPI_SPEED_RANGE_HZ.end()
+                ),
+            );
+        }
+        if self.spi.mode > 3 {
+            problem("spi.mode".into(), format!("{} is not an SPI mode (0-3)", self.spi.mode));
+        }
+        if let Some(shift) = &self.spi.shift_register {
+            let bits = shift.chain_length.map(|n| n * 8);
+            if bits == Some(0) {
+                problem("spi.shift_register.chain_length".into(), "must be greater than 0".into());
+            }
+            for (button, output) in shift.leds.iter().flatten() {
+                if bits.is_some_and(|bits| *output >= bits) {
+                    problem(
+                        format!("spi.shift_register.leds.{}", button),
+                        format!("output {} is beyond the {} outputs of the chain", output, bits.unwrap_or(0)),
+                    );
+                }
+            }
+        }
+        if let Some(mcp) = &self.spi.mcp23s17 {
+            if mcp.address.is_some_and(|a| a > 7) {
+                problem(
+                    "spi.mcp23s17.address".into(),
+                    format!("{} is not an address (0-7)", mcp.address.unwrap_or(0)),
+                );
+            }
+            // Buttons beyond the 16 pins are reported against the button
+            let default_inputs: BTreeMap<ButtonId, u8> = self
+                .buttons
+                .iter()
+                .filter(|b| b.button.0 < 16)
+                .map(|b| (b.button, b.button.0))
+                .collect();
+            let inputs = mcp.inputs.as_ref().unwrap_or(&default_inputs);
+            let leds = mcp.leds.iter().flatten();
+            for (section, (button, pin)) in inputs.iter().map(|i| ("inputs", i)).chain(leds.map(|l| ("leds", l))) {
+                if *pin > 15 {
+                    problem(
+                        format!("spi.mcp23s17.{}.{}", section, button),
+                        format!("pin {} is not a pin (0-15)", pin),
+                    );
+                } else if section == "leds" && inputs.values().any(|p| p == pin) {
+                    problem(
+                        format!("spi.mcp23s17.leds.{}", button),
+                        format!("pin {} is already a button input", pin),
+                    );
+                }
+            }
+        }
+        if self.spi.led_frame.as_ref().is_some_and(|f| f.full_frame_interval == Some(0)) {
+            problem("spi.led_frame.full_frame_interval".into(), "must be greater than 0".into());
+        }
+        if let Some(trace) = &self.spi.trace {
+            if trace.max_per_sec == Some(0) {
+                problem("spi.trace.max_per_sec".into(), "must be at least 1".into());
+            }
+            if self.spi.protocol.unwrap_or_default() == PanelProtocolKind::SpiButton {
+                problem(
+                    "spi.trace".into(),
+                    "the spibutton protocol transfers inside spibuttonlib and cannot be traced".into(),
+                );
+            }
+        }
+        if self.polling.interval_ms == 0 {
+            problem("polling.interval_ms".into(), "must be greater than 0".into());
+        }
+        for (name, group) in self.polling.groups.iter().flatten() {
+            if group.interval_ms == 0 {
+                problem(format!("polling.groups.{}.interval_ms", name), "must be greater than 0".into());
+            }
+        }
+        if let Some(klipper) = &self.klipper {
+            if klipper.socket_path.trim().is_empty() {
+                problem("klipper.socket_path".into(), "must not be empty".into());
+            } else if !Path::new(&klipper.socket_path).is_absolute() {
+                problem(
+                    "klipper.socket_path".into(),
+                    format!("{} is not an absolute path", klipper.socket_path),
+                );
+            }
+            if let Some(file) = klipper.api_key_file.as_ref().filter(|f| !Path::new(f).is_absolute()) {
+                problem("klipper.api_key_file".into(), format!("{} is not an absolute path", file));
+            }
+            if let Some(simulation) = &klipper.simulate {
+                let error_rate = simulation.error_rate.unwrap_or(0.0);
+                let disconnect_rate = simulation.disconnect_rate.unwrap_or(0.0);
+                for (field, rate) in [("error_rate", error_rate), ("disconnect_rate", disconnect_rate)] {
+                    if !(0.0..=1.0).contains(&rate) {
+                        problem(format!("klipper.simulate.{}", field), format!("{} is not between 0 and 1", rate));
+                    }
+                }
+                if error_rate + disconnect_rate > 1.0 {
+                    problem("klipper.simulate".into(), "error_rate and disconnect_rate add up to more than 1".into());
+                }
+            }
+        }
+
+        if self.buttons.is_empty() {
+            problem("buttons".into(), "at least one button is required".into());
+        }
+        let mut seen: HashMap<ButtonId, usize> = HashMap::new();
+        for (i, mapping) in self.buttons.iter().enumerate() {
+            let path = mapping.origin.clone().unwrap_or_else(|| format!("buttons[{}]", i));
+            if let Some(first) = seen.insert(mapping.button, i) {
+                problem(
+                    format!("{}.button", path),
+                    format!("button {} is already mapped by buttons[{}]", mapping.button, first),
+                );
+            }
+            let has_sequences = mapping.sequences.as_ref().is_some_and(|s| !s.is_empty());
+            let has_tiers = mapping.hold_tiers.as_ref().is_some_and(|t| !t.is_empty());
+            let is_modifier = mapping.modifier.unwrap_or(false);
+            let has_release = mapping.on_release.is_some();
+            let has_machine = mapping.state_machine.is_some();
+            if mapping.command.trim().is_empty()
+                && !has_sequences
+                && !has_tiers
+                && !is_modifier
+                && !has_release
+                && !has_machine
+            {
+                problem(format!("{}.command", path), "must not be empty".into());
+            }
+            if let Some(group) = &mapping.poll_group {
+                if !self.polling.groups.as_ref().is_some_and(|g| g.contains_key(group)) {
+                    problem(format!("{}.poll_group", path), format!("no polling group named {}", group));
+                }
+            }
+            if let Some(brightness) = mapping.brightness.filter(|b| *b > 100) {
+                problem(format!("{}.brightness", path), format!("{} is not a percentage (0-100)", brightness));
+            }
+            if has_sequences && has_tiers {
+                problem(format!("{}.hold_tiers", path), "cannot be combined with sequences".into());
+            }
+            if let Some(command) = &mapping.on_release {
+                if command.trim().is_empty() {
+                    problem(format!("{}.on_release", path), "must not be empty".into());
+                } else if has_tiers {
+                    problem(format!("{}.on_release", path), "cannot be combined with hold_tiers".into());
+                }
+            }
+            if mapping.toggle_command.is_some() {
+                problem(format!("{}.toggle_command", path), "cannot be combined with state_machine".into());
+            }
+            if mapping.double_press_command.is_some() {
+                problem(
+                    format!("{}.double_press_command", path),
+                    "cannot be combined with a sequence of 2 presses".into(),
+                );
+            }
+            for presses in mapping.tap_commands.iter().flat_map(|t| t.keys()) {
+                let reason = if *presses == 0 {
+                    "must be at least 1 tap".to_string()
+                } else {
+                    format!("cannot be combined with a sequence of {} presses", presses)
+                };
+                problem(format!("{}.tap_commands.{}", path, presses), reason);
+            }
+            match (mapping.long_press_ms, &mapping.long_press_command) {
+                (Some(_), None) => problem(format!("{}.long_press_command", path), "required with long_press_ms".into()),
+                (None, Some(_)) => problem(format!("{}.long_press_ms", path), "required with long_press_command".into()),
+                _ => {}
+            }
+            if let Some(machine) = &mapping.state_machine {
+                let path = format!("{}.state_machine", path);
+                let combined = [
+                    (!mapping.command.trim().is_empty(), "command"),
+                    (has_sequences, "sequences"),
+                    (has_tiers, "hold_tiers"),
+                    (has_release, "on_release"),
+                    (mapping.confirm == Some(true), "confirm"),
+                ];
+                for (_, field) in combined.iter().filter(|(set, _)| *set) {
+                    problem(path.clone(), format!("cannot be combined with {}", field));
+                }
+                for (name, state) in &machine.states {
+                    let Some(pattern) = &state.pattern else { continue };
+                    let path = format!("{}.states.{}", path, name);
+                    if state.led.is_some() {
+                        problem(format!("{}.pattern", path), "cannot be combined with led".into());
+                    }
+                    if !self.led_patterns.as_ref().is_some_and(|p| p.contains_key(pattern)) {
+                        problem(format!("{}.pattern", path), format!("no LED pattern named {}", pattern));
+                    }
+                }
+                if !machine.states.contains_key(&machine.initial) {
+                    problem(format!("{}.initial", path), format!("no state named {}", machine.initial));
+                }
+                for (j, transition) in machine.transitions.iter().enumerate() {
+                    let path = format!("{}.transitions[{}]", path, j);
+                    for (field, state) in [("from", transition.from.as_ref()), ("to", Some(&transition.to))] {
+                        if let Some(state) = state.filter(|s| !machine.states.contains_key(*s)) {
+                            problem(format!("{}.{}", path, field), format!("no state named {}", state));
+                        }
+                    }
+                    if transition.command.as_ref().is_some_and(|c| c.trim().is_empty()) {
+                        problem(format!("{}.command", path), "must not be empty".into());
+                    }
+                }
+            }
+            for state in mapping.led_timeouts.iter().flat_map(LedTimeouts::zero) {
+                problem(format!("{}.led_timeouts.{}", path, state), "must be greater than 0".into());
+            }
+            if mapping.cooldown_led.is_some() && mapping.cooldown_ms.is_none() {
+                problem(format!("{}.cooldown_ms", path), "required with cooldown_led".into());
+            }
+            match mapping.confirm_window_ms {
+                Some(0) => problem(format!("{}.confirm_window_ms", path), "must be greater than 0".into()),
+                Some(_) if mapping.confirm != Some(true) && !actions::requires_confirmation(&mapping.command) => {
+                    problem(format!("{}.confirm", path), "must be true with confirm_window_ms".into())
+                }
+                _ => {}
+            }
+            let mut previous_ms = 0;
+            for (j, tier) in mapping.hold_tiers.iter().flatten().enumerate() {
+                if tier.hold_ms <= previous_ms {
+                    problem(
+                        format!("{}.hold_tiers[{}].hold_ms", path, j),
+                        "must be greater than 0 and than the previous tier".into(),
+                    );
+                }
+                previous_ms = tier.hold_ms;
+                if tier.command.trim().is_empty() {
+                    problem(format!("{}.hold_tiers[{}].command", path, j), "must not be empty".into());
+                }
+            }
+            let mut presses_seen: HashMap<u32, usize> = HashMap::new();
+            for (j, sequence) in mapping.sequences.iter().flatten().enumerate() {
+                if sequence.presses == 0 {
+                    problem(format!("{}.sequences[{}].presses", path, j), "must be at least 1".into());
+                } else if let Some(first) = presses_seen.get(&sequence.presses) {
+                    problem(
+                        format!("{}.sequences[{}].presses", path, j),
+                        format!("{} presses are already used by sequences[{}]", sequence.presses, first),
+                    );
+                } else {
+                    presses_seen.insert(sequence.presses, j);
+                }
+                if sequence.command.trim().is_empty() {
+                    problem(format!("{}.sequences[{}].command", path, j), "must not be empty".into());
+                }
+            }
+            if let Some(when) = &mapping.when {
+                if let Err(e) = expr::parse(when) {
+                    problem(format!("{}.when", path), e.to_string());
+                }
+            }
+            if mapping.led_rules.is_some() && mapping.indicator.is_some() {
+                problem(format!("{}.led_rules", path), "cannot be combined with indicator".into());
+            }
+            for (j, rule) in mapping.led_rules.iter().flatten().enumerate() {
+                if let Err(e) = expr::parse(&rule.when) {
+                    problem(format!("{}.led_rules[{}].when", path, j), e.to_string());
+                }
+            }
+            if let Some(at) = &mapping.at {
+                if mapping.delay_ms.is_some() {
+                    problem(format!("{}.at", path), "cannot be combined with delay_ms".into());
+                } else if let Err(e) = deferred::parse_time_of_day(at) {
+                    problem(format!("{}.at", path), e.to_string());
+                }
+            }
+            if let Some(window) = &mapping.enabled_between {
+                if let Err(e) = TimeWindow::parse(window) {
+                    problem(format!("{}.enabled_between", path), e.to_string());
+                }
+            }
+        }
+        for (name, overrides) in self.profiles.iter().flatten() {
+            for (i, entry) in overrides.iter().enumerate() {
+                let path = format!("profiles.{}[{}]", name, i);
+                if !seen.contains_key(&entry.button) {
+                    problem(format!("{}.button", path), format!("button {} is not mapped", entry.button));
+                }
+                if entry.command.trim().is_empty() {
+                    problem(format!("{}.command", path), "must not be empty".into());
+                }
+            }
+        }
+        if let Some(name) = &self.default_profile {
+            if !self.profiles.as_ref().is_some_and(|p| p.contains_key(name)) {
+                problem("default_profile".into(), format!("no profile named {}", name));
+            }
+        }
+        for (i, chord) in self.chords.iter().flatten().enumerate() {
+            let path = format!("chords[{}]", i);
+            let distinct: BTreeSet<&ButtonId> = chord.buttons.iter().collect();
+            if distinct.len() < 2 || distinct.len() != chord.buttons.len() {
+                problem(format!("{}.buttons", path), "must list at least 2 different buttons".into());
+            }
+            for button in chord.buttons.iter().filter(|b| !seen.contains_key(b)) {
+                problem(format!("{}.buttons", path), format!("button {} is not mapped", button));
+            }
+            if chord.command.trim().is_empty() {
+                problem(format!("{}.command", path), "must not be empty".into());
+            }
+        }
+        for (i, combo) in self.combos.iter().flatten().enumerate() {
+            let path = format!("combos[{}]", i);
+            if combo.presses.len() < 2 {
+                problem(format!("{}.presses", path), "must list at least 2 presses".into());
+            }
+            let unmapped: BTreeSet<&ButtonId> = combo.presses.iter().filter(|b| !seen.contains_key(b)).collect();
+            for button in unmapped {
+                problem(format!("{}.presses", path), format!("button {} is not mapped", button));
+            }
+            if combo.timeout_ms == Some(0) {
+                problem(format!("{}.timeout_ms", path), "must be greater than 0".into());
+            }
+            if combo.command.trim().is_empty() {
+                problem(format!("{}.command", path), "must not be empty".into());
+            }
+        }
+        let mut radio_buttons: HashMap<ButtonId, &String> = HashMap::new();
+        for (name, group) in self.radio_groups.iter().flatten() {
+            let path = format!("radio_groups.{}", name);
+            let distinct: BTreeSet<&ButtonId> = group.buttons.iter().collect();
+            if distinct.len() < 2 || distinct.len() != group.buttons.len() {
+                problem(format!("{}.buttons", path), "must list at least 2 different buttons".into());
+            }
+            for button in distinct {
+                match seen.get(button).map(|i| &self.buttons[*i]) {
+                    None => problem(format!("{}.buttons", path), format!("button {} is not mapped", button)),
+                    Some(mapping) if mapping.state_machine.is_some() => problem(
+                        format!("{}.buttons", path),
+                        format!("button {} has a state machine, which shows on the same LED layer", button),
+                    ),
+                    Some(_) => {}
+                }
+                if let Some(other) = radio_buttons.insert(*button, name) {
+                    problem(format!("{}.buttons", path), format!("button {} is already in radio group {}", button, other));
+                }
+            }
+            if let Some(initial) = group.initial.filter(|b| !group.buttons.contains(b)) {
+                problem(format!("{}.initial", path), format!("button {} is not in the group", initial));
+            }
+            if group.led == Some(Led::Off) {
+                problem(format!("{}.led", path), "must not be off".into());
+            }
+        }
+        for (i, mapping) in self.buttons.iter().enumerate() {
+            let path = mapping.origin.clone().unwrap_or_else(|| format!("buttons[{}]", i));
+            match &mapping.deselect_command {
+                Some(command) if command.trim().is_empty() => {
+                    problem(format!("{}.deselect_command", path), "must not be empty".into())
+                }
+                Some(_) if !radio_buttons.contains_key(&mapping.button) => {
+                    problem(format!("{}.deselect_command", path), "the button is in no radio group".into())
+                }
+                _ => {}
+            }
+        }
+        if let Some(breaker) = &self.circuit_breaker {
+            if breaker.failures == Some(0) {
+                problem("circuit_breaker.failures".into(), "must be at least 1".into());
+            }
+            if breaker.open_ms == Some(0) {
+                problem("circuit_breaker.open_ms".into(), "must be greater than 0".into());
+            }
+            if let Some(pattern) = &breaker.pattern {
+                if !self.led_patterns.as_ref().is_some_and(|p| p.contains_key(pattern)) {
+                    problem("circuit_breaker.pattern".into(), format!("no LED pattern named {}", pattern));
+                }
+            }
+        }
+        for (id, template) in self.messages.iter().flatten() {
+            if let Err(e) = messages::check(id, template) {
+                problem(format!("messages.{}", id), e);
+            }
+        }
+        for state in self.led_timeouts.iter().flat_map(LedTimeouts::zero) {
+            problem(format!("led_timeouts.{}", state), "must be greater than 0".into());
+        }
+        for (name, pattern) in self.led_patterns.iter().flatten() {
+            let path = format!("led_patterns.{}", name);
+            let durations = &pattern.durations_ms;
+            if durations.is_empty() || durations.len() % 2 != 0 || durations.contains(&0) {
+                problem(format!("{}.durations_ms", path), "must be pairs of on and off times above 0".into());
+            }
+            if pattern.repeat == Some(0) {
+                problem(format!("{}.repeat", path), "must be at least 1".into());
+            }
+        }
+        if self.runtime.as_ref().and_then(|r| r.max_blocking_threads) == Some(0) {
+            problem("runtime.max_blocking_threads".into(), "must be at least 1".into());
+        }
+        if let Some(backpressure) = &self.backpressure {
+            let thresholds = [
+                ("actions", backpressure.actions),
+                ("requests", backpressure.requests),
+                ("responses", backpressure.responses),
+            ];
+            for (field, _) in thresholds.iter().filter(|(_, t)| *t == Some(0)) {
+                problem(format!("backpressure.{}", field), "must be at least 1".into());
+            }
+            for button in backpressure.buttons.iter().flatten().filter(|b| !seen.contains_key(b)) {
+                problem("backpressure.buttons".into(), format!("button {} is not mapped", button));
+            }
+        }
+        if let Some(throttle) = &self.throttle {
+            let thresholds = [("temperature_c", throttle.temperature_c), ("load", throttle.load)];
+            for (field, _) in thresholds.iter().filter(|(_, t)| t.is_some_and(|t| t <= 0.0)) {
+                problem(format!("throttle.{}", field), "must be greater than 0".into());
+            }
+            if throttle.led_interval_ms == Some(0) {
+                problem("throttle.led_interval_ms".into(), "must be greater than 0".into());
+            }
+        }
+        if let Some(sync) = &self.sync {
+            if let Some(listen) = sync.listen.as_deref().filter(|l| l.parse::<std::net::SocketAddr>().is_err()) {
+                problem("sync.listen".into(), format!("{} is not an address and port, e.g. 0.0.0.0:7130", listen));
+            }
+            if sync.listen.is_none() && sync.peers.as_ref().is_none_or(Vec::is_empty) {
+                problem("sync".into(), "needs a listen address or peers to connect to".into());
+            }
+            match &sync.secret_file {
+                None => problem("sync.secret_file".into(), "is required, peers must greet with a shared secret".into()),
+                Some(file) if !Path::new(file).is_absolute() => {
+                    problem("sync.secret_file".into(), format!("{} is not an absolute path", file))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.limits.as_ref().and_then(|l| l.pending_requests) == Some(0) {
+            problem("limits.pending_requests".into(), "must be at least 1".into());
+        }
+        problems.extend(self.check_capabilities(&crate::panel::capabilities(&self.spi)));
+        problems
+    }
+
+    /// Mappings `panel` cannot satisfy, e.g. a button beyond its inputs.
+    /// Part of `validate`, and run again when a different panel is attached.
+    pub fn check_capabilities(&self, panel: &Capabilities) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        let protocol = serde_json::to_value(self.spi.protocol.unwrap_or_default()).unwrap_or_default();
+        let protocol = protocol.as_str().unwrap_or_default();
+        for (i, mapping) in self.buttons.iter().enumerate() {
+            let path = mapping.origin.clone().unwrap_or_else(|| format!("buttons[{}]", i));
+            if let Some(max) = panel.max_buttons.filter(|max| mapping.button.index() >= *max) {
+                problems.push(ConfigProblem {
+                    path: format!("{}.button", path),
+                    message: format!("button {} is beyond the {} buttons of the {} panel", mapping.button, max, protocol),
+                });
+            }
+            let led_fields = [("indicator", mapping.indicator.is_some()), ("led_rules", mapping.led_rules.is_some())];
+            for (field, _) in led_fields.iter().filter(|(_, set)| *set && !panel.has_led(mapping.button)) {
+                problems.push(ConfigProblem {
+                    path: format!("{}.{}", path, field),
+                    message: format!("button {} has no LED on the {} panel", mapping.button, protocol),
+                });
+            }
+            if mapping.brightness.is_some_and(|b| b < 100) && !(panel.dimming && panel.has_led(mapping.button)) {
+                problems.push(ConfigProblem {
+                    path: format!("{}.brightness", path),
+                    message: format!("the LED of button {} cannot be dimmed on the {} panel", mapping.button, protocol),
+                });
+            }
+        }
+        problems
+    }
+
+    /// Everything that differs in `new`, for reporting a reload.
+    pub fn diff(&self, new: &Config) -> ConfigDiff {
+        let mut changes = Vec::new();
+        let (mut old_value, mut new_value) = (to_json(self), to_json(new));
+        for value in [&mut old_value, &mut new_value] {
+            if let Some(map) = value.as_object_mut() {
+                map.remove("buttons");
+            }
+        }
+        diff_values("", &old_value, &new_value, &mut changes);
+
+        let buttons = self.diff_buttons(new);
+        for id in &buttons.added {
+            changes.push(format!("+ button {}", id));
+        }
+        for id in &buttons.removed {
+            changes.push(format!("- button {}", id));
+        }
+        let mapping_of = |config: &Config, id: &ButtonId| {
+            config.buttons.iter().find(|m| m.button == *id).map(to_json).unwrap_or_default()
+        };
+        for id in &buttons.changed {
+            let path = format!("button {}", id);
+            diff_values(&path, &mapping_of(self, id), &mapping_of(new, id), &mut changes);
+        }
+        ConfigDiff { buttons, changes }
+    }
+
+    /// How the button mappings of `new` differ from these, by button id.
+    pub fn diff_buttons(&self, new: &Config) -> ButtonChanges {
+        let mut changes = ButtonChanges::default();
+        for mapping in &new.buttons {
+            match self.buttons.iter().find(|m| m.button == mapping.button) {
+                None => changes.added.push(mapping.button),
+                Some(old) if old == mapping => changes.unchanged.push(mapping.button),
+                Some(_) => changes.changed.push(mapping.button),
+            }
+        }
+        changes.removed = self
+            .buttons
+            .iter()
+            .map(|m| m.button)
+            .filter(|id| !new.buttons.iter().any(|m| m.button == *id))
+            .collect();
+        changes
+    }
+
+    /// Number of panel positions the mapped buttons span, the highest id
+    /// plus one. Ids need not be consecutive.
+    pub fn panel_size(&self) -> usize {
+        self.buttons.iter().map(|m| m.button.index() + 1).max().unwrap_or(0)
+    }
+
+    /// The config as YAML with every default filled in and unset options
+    /// left out, for `--check-config`.
+    pub fn normalized(&self) -> Result<String> {
+        let mut value = serde_json::to_value(self).map_err(|e| Error::internal("Failed to serialize the config").caused_by(e))?;
+        strip_nulls(&mut value);
+        serde_yaml::to_string(&value).map_err(|e| Error::internal("Failed to serialize the config").caused_by(e))
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> JsonValue {
+    let mut value = serde_json::to_value(value).unwrap_or_default();
+    strip_nulls(&mut value);
+    value
+}
+
+/// Add a line to `out` for each field under `path` that differs, going
+/// into objects present in both.
+fn diff_values(path: &str, old: &JsonValue, new: &JsonValue, out: &mut Vec<String>) {
+    let field = |key: &str| if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+    match (old, new) {
+        (JsonValue::Object(old), JsonValue::Object(new)) => {
+            for (key, value) in old {
+                match new.get(key) {
+                    Some(new_value) => diff_values(&field(key), value, new_value, out),
+                    None => out.push(format!("- {}: {}", field(key), value)),
+                }
+            }
+            for (key, value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+                out.push(format!("+ {}: {}", field(key), value));
+            }
+        }
+        (old, new) if old != new => out.push(format!("~ {}: {} -> {}", path, old, new)),
+        _ => {}
+    }
+}
+
+fn strip_nulls(value: &mut JsonValue) {
+    match value {
+        JsonValue::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        JsonValue::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// Button ids by how a reload changes their mapping.
+#[derive(Debug, Default, PartialEq)]
+pub struct ButtonChanges {
+    pub added: Vec<ButtonId>,
+    pub changed: Vec<ButtonId>,
+    pub removed: Vec<ButtonId>,
+    pub unchanged: Vec<ButtonId>,
+}
+
+impl fmt::Display for ButtonChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} changed, {} removed, {} unchanged",
+            self.added.len(),
+            self.changed.len(),
+            self.removed.len(),
+            self.unchanged.len()
+        )
+    }
+}
+
+/// What a reload changed, one line per change, e.g.
+/// `~ klipper.socket_path: "/run/a" -> "/run/b"` or `+ button 4`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub buttons: ButtonChanges,
+    pub changes: Vec<String>,
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "no changes");
+        }
+        write!(f, "{}", self.changes.join("\n"))
+    }
+}
+
+/// A drop-in config file, e.g. one button panel's mappings.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFragment {
+    #[serde(default)]
+    buttons: Vec<ButtonMapping>,
+}
+
+/// Replace the mapping of button `id` in the `buttons` of the config file
+/// at `path` with `mapping`, or remove it with `None`. The rest of the file
+/// is kept, though comments and key order are lost. A button mapped by a
+/// group or a drop-in file cannot be removed here.
+pub fn persist_mapping(path: &str, format: Option<ConfigFormat>, id: ButtonId, mapping: Option<&ButtonMapping>) -> Result<()> {
+    let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+    let mut raw: JsonValue = parse_file(path, format)?;
+    let Some(file) = raw.as_object_mut() else {
+        return Err(Error::config(format!("{} does not hold a config", path)));
+    };
+    let buttons = file.entry("buttons").or_insert_with(|| JsonValue::Array(vec![]));
+    let Some(buttons) = buttons.as_array_mut() else {
+        return Err(Error::config(format!("{}: buttons is not a list", path)));
+    };
+    let before = buttons.len();
+    buttons.retain(|b| b["button"].as_u64() != Some(u64::from(id.0)));
+    match mapping {
+        Some(mapping) => {
+            let mut value = serde_json::to_value(mapping).map_err(|e| Error::internal("Failed to serialize the mapping").caused_by(e))?;
+            strip_nulls(&mut value);
+            buttons.push(value);
+        }
+        None if buttons.len() == before => {
+            return Err(Error::config(format!("button {} is not mapped in the buttons of {}", id, path)));
+        }
+        None => {}
+    }
+
+    let content = match format {
+        ConfigFormat::Yaml => serde_yaml::to_string(&raw).map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::to_string(&raw).map_err(|e| e.to_string()),
+        ConfigFormat::Json => serde_json::to_string_pretty(&raw).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| Error::internal(format!("Failed to write {}", path)).caused_by(e))?;
+    // Written next to it and renamed, so a crash never leaves half a config
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, content)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| Error::config(format!("Failed to write configuration file {}", path)).caused_by(e))
+}
+
+fn parse_file<T: DeserializeOwned>(path: &str, format: ConfigFormat) -> Result<T> {
+    parse_text(&read_file(path)?, format, path)
+}
+
+fn read_file(path: &str) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| Error::config(format!("Failed to read config file: {}", path)).caused_by(e))
+}
+
+fn parse_text<T: DeserializeOwned>(content: &str, format: ConfigFormat, origin: &str) -> Result<T> {
+    let parsed = match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        ConfigFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|e| Error::config(format!("Failed to parse configuration file {}", origin)).caused_by(e))
+}
+
+/// NAME of a `!NAME` alias reference. Shell commands starting with `!`
+/// followed by a space or anything but a name are not references.
+fn alias_name(command: &str) -> Option<&str> {
+    let name = command.trim().strip_prefix('!')?;
+    let is_name = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    is_name.then_some(name)
+}
+
+/// The state machine of a `toggle_command` button: `off` and `on`, each
+/// press moving to the other and running its command. The LED is on while
+/// latched on.
+fn toggle_machine(on_command: String, off_command: String) -> StateMachine {
+    let press = |from: &str, to: &str, command: String| Transition {
+        from: Some(from.to_string()),
+        on: MachineEvent::Press,
+        to: to.to_string(),
+        command: Some(command).filter(|c| !c.trim().is_empty()),
+    };
+    let on = MachineState {
+        led: Some(Led::On),
+        ..MachineState::default()
+    };
+    StateMachine {
+        initial: "off".to_string(),
+        states: BTreeMap::from([("off".to_string(), MachineState::default()), ("on".to_string(), on)]),
+        transitions: vec![press("off", "on", on_command), press("on", "off", off_command)],
+        long_press_ms: None,
+    }
+}
+
+/// `template` with every `{{param.NAME}}` replaced by its value, or the
+/// first NAME without one. Other `{{...}}` templates are left for runtime.
+fn fill_params(template: &str, params: &BTreeMap<String, String>) -> std::result::Result<String, String> {
+    replace_placeholders(template, |inner| match inner.strip_prefix("param.") {
+        Some(name) => params.get(name).cloned().map(Some).ok_or_else(|| name.to_string()),
+        None => Ok(None),
+    })
+}
+
+/// `template` with every `{{...}}` for which `value` returns a value
+/// replaced by it. `value` gets the placeholder's trimmed content; the
+/// others are kept as written.
+fn replace_placeholders<E>(
+    template: &str,
+    mut value: impl FnMut(&str) -> std::result::Result<Option<String>, E>,
+) -> std::result::Result<String, E> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else { break };
+        let end = start + len + 2;
+        out.push_str(&rest[..start]);
+        match value(rest[start + 2..start + len].trim())? {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The first line of a secret file, warning when group or others may read it.
+fn read_secret(path: &str) -> std::io::Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        warn!("{} is readable by other users (mode {:o}), restrict it to its owner", path, mode & 0o777);
+    }
+    let content = fs::read_to_string(path)?;
+    let secret = content.lines().next().unwrap_or("").trim();
+    if secret.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "file is empty"));
+    }
+    Ok(secret.to_string())
+}
+
+/// A problem found by `Config::validate`, located by its path in the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub message: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct SpiConfig {
     pub device: String,
-    pub speed_hz: u32,
+    pub speed_hz: Hertz,
     pub mode: u8,
+    /// Wire protocol of the button board, spibutton when unset
+    pub protocol: Option<PanelProtocolKind>,
+    /// Layout of a `shift_register` panel
+    pub shift_register: Option<ShiftRegisterConfig>,
+    /// Setup and pin mapping of an `mcp23s17` expander
+    pub mcp23s17: Option<Mcp23s17Config>,
+    /// Frame options of an `led_frame` panel
+    pub led_frame: Option<LedFrameConfig>,
+    /// Log every transfer's bytes at debug level, for bringing up panel
+    /// firmware
+    pub trace: Option<SpiTraceConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PanelProtocolKind {
+    /// The spibuttonlib register protocol of the original panel firmware
+    #[default]
+    #[serde(rename = "spibutton")]
+    SpiButton,
+    /// Plain input shift registers, one bit per button, no LEDs
+    ShiftIn,
+    /// Chained 74HC165 inputs and 74HC595 LED outputs
+    ShiftRegister,
+    /// MCP23S17 16-bit I/O expander
+    Mcp23s17,
+    /// Panel firmware taking one byte per LED in full or delta frames
+    LedFrame,
+}
+
+/// A DIY panel of chained 74HC165 input and 74HC595 output shift registers
+/// sharing the clock, with the load and latch lines on chip select.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ShiftRegisterConfig {
+    /// Registers in each chain, 8 bits each. Enough for the mapped
+    /// buttons when unset
+    pub chain_length: Option<usize>,
+    /// Which end of each byte holds the lowest button and output
+    pub bit_order: Option<BitOrder>,
+    /// Inputs read 0 while pressed, for buttons pulling to ground
+    pub active_low: Option<bool>,
+    /// Output bit driving each button's LED, the button's own number
+    /// when not listed
+    pub leds: Option<BTreeMap<ButtonId, usize>>,
+}
+
+/// An MCP23S17 expander. Pins are numbered 0-7 for GPA0-GPA7 and 8-15 for
+/// GPB0-GPB7.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Mcp23s17Config {
+    /// Hardware address set by the A0-A2 pins, 0 when unset
+    pub address: Option<u8>,
+    /// Use the IOCON.BANK register layout, ports A and B in separate banks
+    pub bank: Option<bool>,
+    /// Enable the internal pull-ups on button pins, for buttons pulling to
+    /// ground. On by default, and then a pin reading 0 is pressed
+    pub pull_ups: Option<bool>,
+    /// Drive INTA and INTB together on any change, so one interrupt line
+    /// covers both ports
+    pub mirror_interrupts: Option<bool>,
+    /// Pin read for each button, the button's own number when unset
+    pub inputs: Option<BTreeMap<ButtonId, u8>>,
+    /// Pin driving each button's LED
+    pub leds: Option<BTreeMap<ButtonId, u8>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A panel whose firmware takes every LED as a byte, for panels with many
+/// LEDs such as progress bars.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LedFrameConfig {
+    /// Send only the LED bytes that changed, with a small header per run
+    pub delta: Option<bool>,
+    /// With `delta`, every this many frames all LEDs are sent anyway, so
+    /// a panel that missed a frame catches up
+    pub full_frame_interval: Option<u32>,
+}
+
+/// Hexdumps of the bytes sent and received by each SPI transfer.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SpiTraceConfig {
+    /// Transfers dumped per second at most, the others are counted
+    pub max_per_sec: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BitOrder {
+    /// Lowest number in the most significant bit, shifted first
+    #[default]
+    MsbFirst,
+    LsbFirst,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct PollingConfig {
+    /// Polling interval of buttons without a `poll_group`
+    #[serde(deserialize_with = "millis::deserialize")]
+    #[schemars(with = "millis::Schema")]
+    pub interval_ms: u64,
+    /// Named polling groups with their own interval, e.g. a fast one for an
+    /// e-stop button
+    pub groups: Option<BTreeMap<String, PollGroup>>,
+    /// How often panels with an ID register are checked for having been
+    /// swapped, 0 to never check
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub panel_check_ms: Option<u64>,
+    /// Ignore state changes within this long of the previous one, for
+    /// every button without its own `debounce_ms`
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub debounce_ms: Option<u64>,
+    /// After startup and every reload, take button states this long as the
+    /// baseline instead of handling them
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub startup_grace_ms: Option<u64>,
+    /// Handle a press only once a later read still finds the button down
+    /// and it has been down this long, for every button without its own
+    /// `min_press_ms`
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub min_press_ms: Option<u64>,
+    /// Longest time between the presses of the buttons of a chord
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub chord_window_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PollGroup {
+    /// How often events of the group's buttons are handled
+    #[serde(deserialize_with = "millis::deserialize")]
+    #[schemars(with = "millis::Schema")]
     pub interval_ms: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct KlipperConfig {
     /// Path to the Klipper API Unix domain socket, e.g. /run/klipper_uds
     pub socket_path: String,
+    /// Give up waiting for a response after this long. Unset waits forever,
+    /// since gcode/script only answers once the script has finished.
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub timeout_ms: Option<u64>,
+    /// How often a request failing with a retryable error is sent again
+    pub retries: Option<u32>,
+    /// Delay before each retry
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub retry_delay_ms: Option<u64>,
+    /// Overrides mapping RPC errors onto categories, checked before the
+    /// built-in rules
+    pub error_categories: Option<Vec<ErrorRule>>,
+    /// File holding the API key sent with every request, e.g. for a proxy
+    /// in front of the socket that checks it. Keep it readable by root only.
+    pub api_key_file: Option<String>,
+    /// The key read from `api_key_file` by `Config::load_secrets`
+    #[serde(skip)]
+    pub api_key: Option<String>,
+    /// Serve a simulated Klipper on `socket_path` instead of using a
+    /// printer, for trying out retries, timeouts and LED feedback
+    pub simulate: Option<KlipperSimulation>,
+    /// Wait at startup until Klipper answers an `info` request, reading
+    /// the panel only then
+    pub required: Option<bool>,
+}
+
+/// How the simulated Klipper of `klipper.simulate` answers. Outcomes are
+/// drawn in the order requests arrive, from a seeded generator, so the
+/// same seed and requests give the same run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct KlipperSimulation {
+    /// Delay before each answer
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub latency_ms: Option<u64>,
+    /// Up to this much more delay, uniformly distributed
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub jitter_ms: Option<u64>,
+    /// Share of requests answered with `error_message`, 0.0-1.0
+    pub error_rate: Option<f64>,
+    /// Share of requests whose connection is closed without an answer
+    pub disconnect_rate: Option<f64>,
+    /// Error answered to failing requests, a retryable "Klippy not ready"
+    /// by default
+    pub error_message: Option<String>,
+    /// Outcomes of the first requests, in order, before the rates apply
+    pub script: Option<Vec<SimulatedOutcome>>,
+    /// Seed of the random outcomes and delays
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SimulatedOutcome {
+    Ok,
+    /// Answer with `error_message`
+    Error,
+    /// Close the connection without answering
+    Disconnect,
+    /// Keep the connection open without ever answering
+    Hang,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MoonrakerConfig {
+    /// Base URL of the Moonraker API, e.g. http://localhost:7125
+    pub url: String,
+    /// Give up on a request after this long
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub timeout_ms: Option<u64>,
+    /// Let Klipper macros change button mappings with `spibtn_add_mapping`
+    /// and `spibtn_remove_mapping`, off by default
+    pub allow_remote_mappings: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SnapshotConfig {
+    /// Snapshot URL to fetch instead of asking Moonraker for the webcam's
+    pub url: Option<String>,
+    /// Moonraker webcam name, the first webcam when unset
+    pub webcam: Option<String>,
+    /// Directory the snapshot is saved to with a timestamped filename
+    pub directory: Option<String>,
+    /// URL the snapshot is POSTed to as image/jpeg
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct VariablesConfig {
+    /// Values the variables start with
+    pub initial: Option<BTreeMap<String, JsonValue>>,
+    /// JSON file keeping the values across restarts
+    pub persist_path: Option<String>,
+}
+
+/// Caps on the buffers that grow with use rather than with the config, so
+/// a daemon running for months stays within its memory. Unset caps take
+/// the defaults of the modules owning the buffers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LimitsConfig {
+    /// Klipper requests tracked while awaiting their response
+    pub pending_requests: Option<usize>,
+    /// Which request to stop tracking when `pending_requests` is full
+    pub pending_overflow: Option<Overflow>,
+    /// Variables `set_var:` may create, setting a new one beyond fails
+    pub variables: Option<usize>,
+}
+
+/// Alarms on queues backing up, warned about and shown on the LEDs
+/// before events get dropped. Queues without a threshold are only
+/// reported by `spibuttonctl stats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BackpressureConfig {
+    /// Actions started and not finished, e.g. waiting for a mutex group
+    pub actions: Option<usize>,
+    /// Klipper requests awaiting their response
+    pub requests: Option<usize>,
+    /// Klipper messages waiting for the main loop, of 32 at most
+    pub responses: Option<usize>,
+    /// How long a queue must stay at its threshold before the alarm
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub sustain_ms: Option<u64>,
+    /// LED shown during an alarm, flash2 when unset
+    pub led: Option<Led>,
+    /// Buttons showing the alarm, every mapped one when unset
+    pub buttons: Option<Vec<ButtonId>>,
+}
+
+/// Thresholds at which LED animations and indicator updates slow down to
+/// leave the CPU to Klipper. Button reads keep their interval.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ThrottleConfig {
+    /// CPU temperature in degrees Celsius, 75 when unset
+    pub temperature_c: Option<f64>,
+    /// 1 minute load average, 3.0 when unset
+    pub load: Option<f64>,
+    /// How often animations and indicators are updated while throttled,
+    /// 500ms when unset
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub led_interval_ms: Option<u64>,
+}
+
+/// Direct connections to the daemons of other panels driving the same
+/// printer. Updates go to every connected peer, whichever side dialed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SyncConfig {
+    /// Address peers connect to, e.g. 0.0.0.0:7130
+    pub listen: Option<String>,
+    /// Addresses of the other daemons, e.g. printer-b.local:7130
+    pub peers: Option<Vec<String>>,
+    /// File holding the secret every daemon greets its peers with, the
+    /// same on all of them. Keep it readable by root only.
+    pub secret_file: Option<String>,
+    /// The secret read from `secret_file` by `Config::load_secrets`
+    #[serde(skip)]
+    pub secret: Option<String>,
+}
+
+/// Tokio runtime of the daemon.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RuntimeConfig {
+    pub flavor: Option<RuntimeFlavor>,
+    /// Threads running blocking work such as shell commands at once,
+    /// further work waits for one
+    pub max_blocking_threads: Option<usize>,
+    /// How long running actions may take to finish after SIGTERM or
+    /// SIGINT before the daemon exits anyway
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub shutdown_timeout_ms: Option<u64>,
+}
+
+/// Tokio scheduler of the daemon.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    /// Everything on the main thread, enough for a single-core board such
+    /// as the BeagleBone Black and without context switches between workers
+    #[default]
+    CurrentThread,
+    /// A worker thread per core
+    MultiThread,
+}
+
+/// What a full buffer drops to stay within its cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Forget the oldest entry to make room for the new one
+    #[default]
+    DropOldest,
+    /// Keep the entries and forget the new one
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Path of the Unix socket served for `spibuttonctl`
+    pub socket_path: String,
+    /// Number of executed actions kept for `spibuttonctl last`
+    pub history_size: Option<usize>,
+    /// Users besides root and the daemon's own allowed to connect to an
+    /// abstract socket, which has no file permissions
+    pub allowed_uids: Option<Vec<u32>>,
+    /// Groups allowed to connect to an abstract socket
+    pub allowed_gids: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownButtonPolicy {
+    /// Drop the event silently
+    Ignore,
+    /// Drop the event with a warning
+    Log,
+    /// Run `command` with the button id in SPIBTN_BUTTON
+    DefaultCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Indicator {
+    /// On while jobs are queued, Flash1 when the queue is paused
+    JobQueue,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UnknownButtonsConfig {
+    pub policy: UnknownButtonPolicy,
+    /// Shell command for the `default_command` policy
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct ButtonMapping {
-    pub button: u8,
+    pub button: ButtonId,
+    pub config: Option<u8>,
+    pub description: Option<String>,
+    /// Run on press, also accepted as `on_press`
+    #[serde(alias = "on_press")]
+    pub command: String,
+    /// Run on release, e.g. stopping a jog started by `command`
+    pub on_release: Option<String>,
+    /// Daily window in which presses are accepted, e.g. "07:00-22:00"
+    pub enabled_between: Option<String>,
+    /// Condition for accepting presses, e.g. "extruder.temperature > 180"
+    pub when: Option<String>,
+    /// Commands fired by repeated presses, e.g. a triple press
+    pub sequences: Option<Vec<PressSequence>>,
+    /// Maximum gap between presses of a sequence
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub sequence_window_ms: Option<u64>,
+    /// Printer state shown on the button's LED while it is idle
+    pub indicator: Option<Indicator>,
+    /// LED brightness in percent, e.g. 20 for a dim status light
+    pub brightness: Option<u8>,
+    /// Concurrency group, actions of buttons sharing it run one at a time
+    pub mutex: Option<String>,
+    /// Run the command this long after the press instead of right away
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub delay_ms: Option<u64>,
+    /// Run the command at the next occurrence of this time of day, e.g. "23:30"
+    pub at: Option<String>,
+    /// Debounce window of this button, overriding `polling.debounce_ms`
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub debounce_ms: Option<u64>,
+    /// Minimum press duration of this button, overriding
+    /// `polling.min_press_ms`
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub min_press_ms: Option<u64>,
+    /// Polling group from `polling.groups` setting how often the button's
+    /// events are handled, instead of `polling.interval_ms`
+    pub poll_group: Option<String>,
+    /// While held, other buttons run their `shift_command` instead. The
+    /// modifier itself runs nothing.
+    pub modifier: Option<bool>,
+    /// Alternate command run while a modifier button is held
+    pub shift_command: Option<String>,
+    /// Commands fired by releasing after a long hold. The longest tier
+    /// reached fires, a release before the first runs `command`.
+    pub hold_tiers: Option<Vec<HoldTier>>,
+    /// How long a press must last to run `long_press_command` instead of
+    /// `command`, a hold tier of its own once loaded
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub long_press_ms: Option<u64>,
+    /// Command run when released after `long_press_ms`
+    pub long_press_command: Option<String>,
+    /// Command run by two presses within `sequence_window_ms`, a sequence
+    /// of its own once loaded
+    pub double_press_command: Option<String>,
+    /// Commands by number of taps within `sequence_window_ms`, e.g.
+    /// `{2: PARK, 3: HOME}`, sequences of their own once loaded
+    pub tap_commands: Option<BTreeMap<u32, String>>,
+    /// After running a command the button is ignored this long, e.g. so a
+    /// double-tapped "start print" does not queue the job twice
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub cooldown_ms: Option<u64>,
+    /// Flash the LED briefly on presses ignored during the cooldown
+    pub cooldown_led: Option<bool>,
+    /// Run the command only on a second press within `confirm_window_ms`,
+    /// the first press arms the button, e.g. for cancelling a print
+    pub confirm: Option<bool>,
+    /// Time allowed for the confirming press, 3s when unset
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub confirm_window_ms: Option<u64>,
+    /// States, their LEDs and the transitions between them, run instead
+    /// of `command`
+    pub state_machine: Option<StateMachine>,
+    /// Command of every second press, latching the button off again
+    /// after `command` latched it on. A state machine of its own once
+    /// loaded
+    pub toggle_command: Option<String>,
+    /// LED shown while the button is idle by the first rule whose
+    /// condition holds, e.g. on while `print_stats.state == "printing"`
+    pub led_rules: Option<Vec<LedRule>>,
+    /// Timeouts of the LED states this button's commands leave, overriding
+    /// the top-level `led_timeouts` state by state
+    pub led_timeouts: Option<LedTimeouts>,
+    /// Run when another button of this button's radio group is selected
+    /// while this one was
+    pub deselect_command: Option<String>,
+    /// Where a mapping merged from a drop-in file came from, for messages
+    #[serde(skip)]
+    pub origin: Option<String>,
+}
+
+/// Buttons that differ only in some parameters of their command, e.g. a
+/// row of macro buttons.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ButtonGroup {
+    /// Command of every member, with `{{param.NAME}}` placeholders
+    pub command: String,
+    /// Feature flags of every member unless it sets its own
     pub config: Option<u8>,
+    /// Description of members without their own, may use placeholders too
+    pub description: Option<String>,
+    /// Default values of the placeholders
+    pub params: Option<BTreeMap<String, String>>,
+    pub indicator: Option<Indicator>,
+    pub poll_group: Option<String>,
+    pub buttons: Vec<GroupMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct GroupMember {
+    pub button: ButtonId,
+    pub config: Option<u8>,
+    pub description: Option<String>,
+    /// Placeholder values overriding the group's
+    pub params: Option<BTreeMap<String, String>>,
+}
+
+/// A button's command while a profile is active. Buttons a profile does
+/// not list keep their normal mapping.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProfileMapping {
+    pub button: ButtonId,
+    pub description: Option<String>,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HoldTier {
+    /// How long the button must be held, e.g. 3000 for three seconds
+    #[serde(deserialize_with = "millis::deserialize")]
+    #[schemars(with = "millis::Schema")]
+    pub hold_ms: u64,
+    pub description: Option<String>,
+    pub command: String,
+}
+
+/// A button with states of its own, e.g. idle, armed and heating, moved
+/// between by presses and the results of the commands it runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StateMachine {
+    /// State the button starts in
+    pub initial: String,
+    pub states: BTreeMap<String, MachineState>,
+    pub transitions: Vec<Transition>,
+    /// How long a press must last to be a `long_press`
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub long_press_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MachineState {
+    /// LED shown while in the state, off when unset
+    pub led: Option<Led>,
+    /// One of `led_patterns` shown instead of `led`
+    pub pattern: Option<String>,
+    pub description: Option<String>,
+}
+
+/// A move to state `to` on `on`, running `command` if given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Transition {
+    /// State the transition leaves, any state when unset
+    pub from: Option<String>,
+    pub on: MachineEvent,
+    pub to: String,
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineEvent {
+    Press,
+    /// Released after at least `long_press_ms`
+    LongPress,
+    /// The command of the last transition succeeded
+    Success,
+    /// The command of the last transition failed
+    Failure,
+}
+
+/// An LED state in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Led {
+    Off,
+    On,
+    /// Slow flashing
+    Flash1,
+    /// Fast flashing
+    Flash2,
+}
+
+impl Led {
+    pub fn state(self) -> SPIButtonState {
+        match self {
+            Led::Off => SPIButtonState::Off,
+            Led::On => SPIButtonState::On,
+            Led::Flash1 => SPIButtonState::Flash1,
+            Led::Flash2 => SPIButtonState::Flash2,
+        }
+    }
+}
+
+/// How long an LED state left by a command shows before the LED falls
+/// back to what it showed before, by state. Unset states show until the
+/// next command.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LedTimeouts {
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub on: Option<u64>,
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub flash1: Option<u64>,
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub flash2: Option<u64>,
+}
+
+impl LedTimeouts {
+    /// The timeout of `state`, from `self` or else from `fallback`.
+    pub fn of(&self, state: SPIButtonState, fallback: Option<&LedTimeouts>) -> Option<u64> {
+        let pick = |t: &LedTimeouts| match state {
+            SPIButtonState::On => t.on,
+            SPIButtonState::Flash1 => t.flash1,
+            SPIButtonState::Flash2 => t.flash2,
+            _ => None,
+        };
+        pick(self).or_else(|| fallback.and_then(pick))
+    }
+
+    /// The names of the states whose timeout is 0, for `validate`.
+    fn zero(&self) -> impl Iterator<Item = &'static str> {
+        [("on", self.on), ("flash1", self.flash1), ("flash2", self.flash2)]
+            .into_iter()
+            .filter(|(_, ms)| *ms == Some(0))
+            .map(|(name, _)| name)
+    }
+}
+
+/// An LED state shown while a condition over the printer state holds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LedRule {
+    /// Condition like `when`, e.g. `print_stats.state == "paused"`
+    pub when: String,
+    pub led: Led,
+}
+
+/// LED flashing beyond Flash1 and Flash2, driven by the daemon.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LedPattern {
+    /// Alternating on and off times in ms, starting with on
+    pub durations_ms: Vec<u64>,
+    /// Times the durations run before the LED stays off, forever when unset
+    pub repeat: Option<u32>,
+}
+
+/// Commands to an endpoint that stopped answering fail at once instead of
+/// waiting on it, see `breaker::Breakers`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CircuitBreakerConfig {
+    /// Requests in a row finding the endpoint down that open its circuit,
+    /// 5 when unset
+    pub failures: Option<u32>,
+    /// How long an open circuit refuses requests before one probes the
+    /// endpoint, 30s when unset
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub open_ms: Option<u64>,
+    /// One of `led_patterns` shown on a refused button, two short blinks
+    /// when unset
+    pub pattern: Option<String>,
+}
+
+/// A command run by pressing several buttons together, e.g. 0 and 3 for a
+/// firmware restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Chord {
+    /// Mapped buttons pressed within `polling.chord_window_ms` of each other
+    pub buttons: Vec<ButtonId>,
     pub description: Option<String>,
     pub command: String,
 }
 
-impl Default for Config {
+/// Buttons of which pressing one selects it and deselects the others, e.g.
+/// tool or preset selection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RadioGroup {
+    /// Mapped buttons of the group, a button is in one group at most
+    pub buttons: Vec<ButtonId>,
+    /// LED of the selected button, on when unset
+    pub led: Option<Led>,
+    /// Button selected on startup, without running its command
+    pub initial: Option<ButtonId>,
+}
+
+/// A command run by pressing buttons one after another in a given order,
+/// e.g. 1, 1, 3 for a maintenance mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Combo {
+    /// Mapped buttons in the order they are pressed, a button may repeat
+    pub presses: Vec<ButtonId>,
+    /// Longest time from the first press to the last
+    #[serde(default, deserialize_with = "millis::option")]
+    #[schemars(with = "Option<millis::Schema>")]
+    pub timeout_ms: Option<u64>,
+    pub description: Option<String>,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PressSequence {
+    /// Number of presses within the window, e.g. 3 for a triple press
+    pub presses: u32,
+    pub description: Option<String>,
+    pub command: String,
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            device: "/dev/spidev0.0".to_string(),
+            speed_hz: Hertz(1_000_000),
+            mode: 0,
+            protocol: None,
+            shift_register: None,
+            mcp23s17: None,
+            led_frame: None,
+            trace: None,
+        }
+    }
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 100,
+            groups: None,
+            panel_check_ms: None,
+            debounce_ms: None,
+            startup_grace_ms: None,
+            min_press_ms: None,
+            chord_window_ms: None,
+        }
+    }
+}
+
+impl Default for ControlConfig {
     fn default() -> Self {
         Self {
-            spi: SpiConfig {
-                device: "/dev/spidev0.0".to_string(),
-                speed_hz: 1_000_000,
-                mode: 0,
-            },
-            polling: PollingConfig {
-                interval_ms: 100,
-            },
-            buttons: vec![],
-            klipper: None,
+            socket_path: "/run/spi-button-controller.sock".to_string(),
+            history_size: None,
+            allowed_uids: None,
+            allowed_gids: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_toml() {
+        let path = std::env::temp_dir().join(format!("spibtn-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+[spi]
+device = "/dev/spidev1.0"
+speed_hz = 800000
+mode = 0
+
+[polling]
+interval_ms = 100
+
+[[buttons]]
+button = 0
+config = 0x68
+description = "Home X"
+command = 'klipper:gcode/script|{"script":"G28 X"}'
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(&path.display().to_string(), None).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(config.spi.speed_hz, Hertz(800_000));
+        assert_eq!(config.buttons[0].config, Some(0x68));
+        assert_eq!(config.buttons[0].command, r#"klipper:gcode/script|{"script":"G28 X"}"#);
+    }
+
+    #[test]
+    fn test_load_json_with_explicit_format() {
+        // Provisioning output may not carry a .json extension
+        let path = std::env::temp_dir().join(format!("spibtn-config-{}.conf", std::process::id()));
+        fs::write(
+            &path,
+            r#"{
+                "spi": {"device": "/dev/spidev1.0", "speed_hz": 800000, "mode": 0},
+                "polling": {"interval_ms": 100},
+                "buttons": [{"button": 0, "description": "Home X", "command": "echo home"}]
+            }"#,
+        )
+        .unwrap();
+
+        let path_str = path.display().to_string();
+        let config = Config::load(&path_str, Some(ConfigFormat::Json)).unwrap();
+        assert_eq!(config.buttons[0].command, "echo home");
+        assert_eq!(ConfigFormat::from_path("/etc/sbc/config.JSON"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path("/etc/sbc/config"), ConfigFormat::Yaml);
+        assert!(matches!("xml".parse::<ConfigFormat>(), Err(Error::Config { .. })));
+        let err = Config::load(&path_str, Some(ConfigFormat::Toml)).unwrap_err();
+        assert!(matches!(err, Error::Config { ref context, .. } if context.contains(&path_str)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+spi: {device: /dev/spidev1.0, speed_hz: 800000, mode: 4}
+polling: {interval_ms: 100}
+klipper: {socket_path: klippy_uds}
+buttons:
+  - {button: 0, command: "echo a"}
+  - {button: 2, command: " ", when: "extruder.temperature >"}
+  - {button: 0, command: "echo c", enabled_between: "7am-10pm"}
+"#,
+        )
+        .unwrap();
+
+        let paths: Vec<String> = config.validate().into_iter().map(|p| p.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "spi.mode",
+                "klipper.socket_path",
+                "buttons[1].command",
+                "buttons[1].when",
+                "buttons[2].button",
+                "buttons[2].enabled_between",
+            ]
+        );
+
+        // Gaps in the ids are fine, e.g. wiring that skips positions 3 and 5
+        config.buttons[1].button = ButtonId(4);
+        config.buttons[2].button = ButtonId(6);
+        assert!(config.validate().iter().all(|p| !p.path.ends_with(".button")));
+        assert_eq!(config.panel_size(), 7);
+    }
+
+    #[test]
+    fn test_partial_config_takes_defaults() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+spi: {device: /dev/spidev1.0}
+control: {}
+buttons: [{button: 0, command: "echo a"}]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.spi.device, "/dev/spidev1.0");
+        assert_eq!(config.spi.speed_hz, Hertz(1_000_000));
+        assert_eq!(config.polling.interval_ms, 100);
+        assert_eq!(config.control.as_ref().unwrap().socket_path, "/run/spi-button-controller.sock");
+        assert!(config.validate().is_empty());
+
+        let config: Config = toml::from_str("[[buttons]]\nbutton = 0\ncommand = \"echo a\"\n").unwrap();
+        assert_eq!(config.buttons.len(), 1);
+        assert_eq!(config.spi.device, "/dev/spidev0.0");
+    }
+
+    #[test]
+    fn test_validate_poll_groups() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+polling:
+  groups:
+    estop: {interval_ms: 10}
+    menu: {interval_ms: 0}
+buttons:
+  - {button: 0, command: "echo stop", poll_group: estop}
+  - {button: 1, command: "echo menu", poll_group: menus}
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "polling.groups.menu.interval_ms: must be greater than 0",
+                "buttons[1].poll_group: no polling group named menus",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalized_fills_defaults() {
+        let config: Config = serde_yaml::from_str(r#"buttons: [{button: 0, command: "echo a"}]"#).unwrap();
+        let normalized = config.normalized().unwrap();
+        assert!(normalized.contains("interval_ms: 100"));
+        assert!(normalized.contains("device: /dev/spidev0.0"));
+        assert!(!normalized.contains("null"));
+
+        let reparsed: Config = serde_yaml::from_str(&normalized).unwrap();
+        assert_eq!(reparsed.buttons[0].command, "echo a");
+    }
+
+    #[test]
+    fn test_load_secrets_reads_api_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("spibtn-api-key-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let yaml = format!(
+            "klipper: {{socket_path: /run/klipper_uds, api_key_file: {}}}\nbuttons: [{{button: 0, command: echo}}]",
+            path.display()
+        );
+        let mut config: Config = serde_yaml::from_str(&yaml).unwrap();
+        config.load_secrets().unwrap();
+        assert_eq!(config.klipper.as_ref().unwrap().api_key.as_deref(), Some("s3cret"));
+        // The key never ends up in a dump of the config
+        assert!(!config.normalized().unwrap().contains("s3cret"));
+
+        fs::write(&path, "").unwrap();
+        assert!(matches!(config.load_secrets(), Err(Error::Config { .. })));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_validate_mcp23s17_pins() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+spi:
+  protocol: mcp23s17
+  mcp23s17: {address: 9, leds: {0: 8, 1: 1, 2: 16}}
+buttons:
+  - {button: 0, command: "echo a"}
+  - {button: 1, command: "echo b"}
+  - {button: 2, command: "echo c"}
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "spi.mcp23s17.address: 9 is not an address (0-7)",
+                "spi.mcp23s17.leds.1: pin 1 is already a button input",
+                "spi.mcp23s17.leds.2: pin 16 is not a pin (0-15)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_against_panel_capabilities() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+spi:
+  protocol: shift_register
+  shift_register: {chain_length: 1}
+buttons:
+  - {button: 0, command: "echo a", indicator: job_queue}
+  - {button: 8, command: "echo b"}
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert!(problems.contains(&"buttons[1].button: button 8 is beyond the 8 buttons of the shift_register panel".to_string()));
+
+        config.spi.protocol = Some(PanelProtocolKind::ShiftIn);
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert!(problems.contains(&"buttons[0].indicator: button 0 has no LED on the shift_in panel".to_string()));
+
+        config.buttons[0].brightness = Some(30);
+        config.spi.protocol = Some(PanelProtocolKind::SpiButton);
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert!(problems
+            .contains(&"buttons[0].brightness: the LED of button 0 cannot be dimmed on the spibutton panel".to_string()));
+        config.spi.protocol = Some(PanelProtocolKind::ShiftRegister);
+        assert!(!config.validate().iter().any(|p| p.path.ends_with("brightness")));
+
+        config.spi.trace = Some(SpiTraceConfig { max_per_sec: None });
+        assert!(!config.validate().iter().any(|p| p.path.starts_with("spi.trace")));
+        config.spi.protocol = Some(PanelProtocolKind::SpiButton);
+        assert!(config.validate().iter().any(|p| p.path == "spi.trace"));
+    }
+
+    #[test]
+    fn test_diff_buttons() {
+        let parse = |yaml: &str| -> Config { serde_yaml::from_str(yaml).unwrap() };
+        let old = parse(r#"buttons: [{button: 0, command: a}, {button: 1, command: b}, {button: 2, command: c}]"#);
+        let new = parse(r#"buttons: [{button: 0, command: a}, {button: 1, command: b, config: 0x68}, {button: 3, command: d}]"#);
+
+        let changes = old.diff_buttons(&new);
+        assert_eq!(
+            changes,
+            ButtonChanges {
+                added: vec![ButtonId(3)],
+                changed: vec![ButtonId(1)],
+                removed: vec![ButtonId(2)],
+                unchanged: vec![ButtonId(0)],
+            }
+        );
+        assert_eq!(changes.to_string(), "1 added, 1 changed, 1 removed, 1 unchanged");
+    }
+
+    #[test]
+    fn test_validate_shift_register_chain() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+spi:
+  device: /dev/spidev1.0
+  speed_hz: 800000
+  mode: 0
+  protocol: shift_register
+  shift_register: {chain_length: 1, bit_order: lsb_first, leds: {0: 3, 1: 8}}
+polling: {interval_ms: 100}
+buttons: [{button: 0, command: "echo a"}, {button: 1, command: "echo b"}]
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec!["spi.shift_register.leds.1: output 8 is beyond the 8 outputs of the chain"]
+        );
+    }
+
+    #[test]
+    fn test_profiles_override_commands() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+spi: {device: /dev/spidev1.0, speed_hz: 800000, mode: 0}
+polling: {interval_ms: 100}
+default_profile: idle
+buttons:
+  - {button: 0, description: "Home", command: "echo home"}
+  - {button: 1, description: "Lights", command: "echo lights"}
+profiles:
+  idle: []
+  printing:
+    - {button: 0, description: "Pause", command: "echo pause"}
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_empty());
+
+        let printing = config.with_profile("printing").unwrap();
+        assert_eq!(printing.buttons[0].command, "echo pause");
+        assert_eq!(printing.buttons[0].description.as_deref(), Some("Pause"));
+        assert_eq!(printing.buttons[1].command, "echo lights");
+        assert_eq!(config.with_profile("idle").unwrap().buttons[0].command, "echo home");
+        assert!(config.with_profile("maintenance").is_err());
+
+        let mut broken = config.clone();
+        broken.default_profile = Some("maintenance".to_string());
+        broken.profiles.as_mut().unwrap().get_mut("printing").unwrap()[0].button = ButtonId(7);
+        let paths: Vec<String> = broken.validate().into_iter().map(|p| p.path).collect();
+        assert_eq!(paths, vec!["profiles.printing[0].button", "default_profile"]);
+    }
+
+    #[test]
+    fn test_expand_groups() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+buttons: [{button: 0, command: "echo menu"}]
+groups:
+  macros:
+    command: 'klipper:gcode/script|{"script":"{{param.macro}} T={{ param.temp }}"}'
+    config: 0x28
+    description: "Run {{param.macro}}"
+    params: {temp: "200"}
+    buttons:
+      - {button: 4, params: {macro: PREHEAT}}
+      - {button: 5, config: 0x68, description: Cool down, params: {macro: COOLDOWN, temp: "0"}}
+"#,
+        )
+        .unwrap();
+        config.expand_groups().unwrap();
+        assert!(config.groups.is_none());
+        let preheat = &config.buttons[1];
+        assert_eq!(preheat.command, r#"klipper:gcode/script|{"script":"PREHEAT T=200"}"#);
+        assert_eq!(preheat.config, Some(0x28));
+        assert_eq!(preheat.description.as_deref(), Some("Run PREHEAT"));
+        assert_eq!(preheat.origin.as_deref(), Some("groups.macros.buttons[0]"));
+        let cooldown = &config.buttons[2];
+        assert_eq!(cooldown.command, r#"klipper:gcode/script|{"script":"COOLDOWN T=0"}"#);
+        assert_eq!(cooldown.config, Some(0x68));
+        assert_eq!(cooldown.description.as_deref(), Some("Cool down"));
+
+        let mut config: Config = serde_yaml::from_str(
+            "groups: {g: {command: 'echo {{param.x}}', buttons: [{button: 1}]}}",
+        )
+        .unwrap();
+        let err = config.expand_groups().unwrap_err();
+        assert_eq!(err.to_string(), "groups.g.buttons[0]: no value for param.x");
+    }
+
+    #[test]
+    fn test_persist_mapping_keeps_the_rest() {
+        let path = std::env::temp_dir().join(format!("spibtn-persist-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "spi: {device: /dev/spidev1.0}\nbuttons:\n  - {button: 0, command: echo a}\n  - {button: 1, command: echo b}\n",
+        )
+        .unwrap();
+        let path_str = path.display().to_string();
+        let mapping = ButtonMapping {
+            button: ButtonId(5),
+            command: "echo new".to_string(),
+            ..ButtonMapping::default()
+        };
+
+        persist_mapping(&path_str, None, ButtonId(5), Some(&mapping)).unwrap();
+        persist_mapping(&path_str, None, ButtonId(0), None).unwrap();
+        let config = Config::load(&path_str, None).unwrap();
+        assert_eq!(config.spi.device, "/dev/spidev1.0");
+        let buttons: Vec<(u8, &str)> = config.buttons.iter().map(|m| (m.button.0, m.command.as_str())).collect();
+        assert_eq!(buttons, vec![(1, "echo b"), (5, "echo new")]);
+        assert!(persist_mapping(&path_str, None, ButtonId(0), None).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_diff_reports_each_change() {
+        let old: Config = serde_yaml::from_str(
+            r#"
+klipper: {socket_path: /run/klipper_uds}
+buttons:
+  - {button: 0, command: "echo a"}
+  - {button: 1, command: "echo b", description: B}
+  - {button: 2, command: "echo c"}
+"#,
+        )
+        .unwrap();
+        let new: Config = serde_yaml::from_str(
+            r#"
+klipper: {socket_path: /tmp/klipper_uds, timeout_ms: 500}
+buttons:
+  - {button: 0, command: "echo a"}
+  - {button: 1, command: "echo B"}
+  - {button: 3, command: "echo d"}
+"#,
+        )
+        .unwrap();
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.to_string(),
+            r#"~ klipper.socket_path: "/run/klipper_uds" -> "/tmp/klipper_uds"
++ klipper.timeout_ms: 500
++ button 3
+- button 2
+~ button 1.command: "echo b" -> "echo B"
+- button 1.description: "B""#
+        );
+        assert_eq!(diff.buttons.unchanged, vec![ButtonId(0)]);
+        assert_eq!(old.diff(&old).to_string(), "no changes");
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+vars:
+  host: printer.local
+  macro: PREHEAT
+buttons:
+  - button: 0
+    description: "{{macro}} on {{ host }}"
+    command: "curl http://{{host}}/printer/gcode/script?script={{macro}}_{{var.material}}"
+    hold_tiers: [{hold_ms: 3000, command: "ssh {{host}} reboot"}]
+"#,
+        )
+        .unwrap();
+        config.expand_vars();
+        let mapping = &config.buttons[0];
+        assert_eq!(mapping.description.as_deref(), Some("PREHEAT on printer.local"));
+        assert_eq!(mapping.command, "curl http://printer.local/printer/gcode/script?script=PREHEAT_{{var.material}}");
+        assert_eq!(mapping.hold_tiers.as_ref().unwrap()[0].command, "ssh printer.local reboot");
+    }
+
+    #[test]
+    fn test_long_press_becomes_a_hold_tier() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - {button: 0, command: PAUSE, long_press_ms: 2s, long_press_command: CANCEL,
+     hold_tiers: [{hold_ms: 5s, command: FIRMWARE_RESTART}]}
+  - {button: 1, command: RESUME, long_press_ms: 1500}
+"#,
+        )
+        .unwrap();
+        config.resolve_press_shorthands();
+        let tiers: Vec<(u64, &str)> = config.buttons[0]
+            .hold_tiers
+            .iter()
+            .flatten()
+            .map(|t| (t.hold_ms, t.command.as_str()))
+            .collect();
+        assert_eq!(tiers, vec![(2000, "CANCEL"), (5000, "FIRMWARE_RESTART")]);
+        assert_eq!(config.buttons[0].long_press_ms, None);
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["buttons[1].long_press_command: required with long_press_ms"]);
+    }
+
+    #[test]
+    fn test_press_and_release_commands() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - {button: 0, on_press: "klipper:gcode/script|{\"script\":\"JOG_START\"}", on_release: "!stop"}
+  - {button: 1, command: "", on_release: "echo released"}
+  - {button: 2, command: HOME, on_release: PARK, hold_tiers: [{hold_ms: 1000, command: STOP}]}
+"#,
+        )
+        .unwrap();
+        assert!(config.buttons[0].command.contains("JOG_START"));
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["buttons[2].on_release: cannot be combined with hold_tiers"]);
+    }
+
+    #[test]
+    fn test_validate_state_machine() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - button: 0
+    command: HOME
+    state_machine:
+      initial: idel
+      states: {idle: {}, heating: {led: flash2}, error: {pattern: sos}, done: {pattern: triple_blink}}
+      transitions:
+        - {from: idle, on: press, to: heating, command: PREHEAT}
+        - {from: heating, on: failure, to: errror}
+led_patterns:
+  sos: {durations_ms: [150, 150, 150, 150, 150, 450, 450, 150, 450, 150, 450, 450, 150, 150, 150, 150, 150, 1500]}
+  blink: {durations_ms: [100, 100, 100], repeat: 0}
+"#,
+        )
+        .unwrap();
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "buttons[0].state_machine: cannot be combined with command",
+                "buttons[0].state_machine.states.done.pattern: no LED pattern named triple_blink",
+                "buttons[0].state_machine.initial: no state named idel",
+                "buttons[0].state_machine.transitions[1].to: no state named errror",
+                "led_patterns.blink.durations_ms: must be pairs of on and off times above 0",
+                "led_patterns.blink.repeat: must be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_double_press_becomes_a_sequence() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - {button: 0, command: LIGHTS_ON, double_press_command: LIGHTS_OFF, sequence_window_ms: 300}
+  - {button: 1, command: HOME, double_press_command: PARK, sequences: [{presses: 2, command: PARK}]}
+"#,
+        )
+        .unwrap();
+        config.resolve_press_shorthands();
+        let sequences = config.buttons[0].sequences.as_ref().unwrap();
+        assert_eq!((sequences[0].presses, sequences[0].command.as_str()), (2, "LIGHTS_OFF"));
+        assert_eq!(config.buttons[0].double_press_command, None);
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec!["buttons[1].double_press_command: cannot be combined with a sequence of 2 presses"]
+        );
+    }
+
+    #[test]
+    fn test_tap_commands_become_sequences() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - {button: 0, command: "", tap_commands: {1: LIGHTS_ON, 2: LIGHTS_OFF, 4: FIRMWARE_RESTART}}
+  - {button: 1, command: HOME, tap_commands: {0: PARK, 3: PARK}, sequences: [{presses: 3, command: M84}]}
+  - {button: 2, command: HOME, sequences: [{presses: 2, command: PARK}, {presses: 2, command: M84}]}
+"#,
+        )
+        .unwrap();
+        config.resolve_press_shorthands();
+        let taps: Vec<(u32, &str)> =
+            config.buttons[0].sequences.iter().flatten().map(|s| (s.presses, s.command.as_str())).collect();
+        assert_eq!(taps, vec![(1, "LIGHTS_ON"), (2, "LIGHTS_OFF"), (4, "FIRMWARE_RESTART")]);
+        assert_eq!(config.buttons[0].tap_commands, None);
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "buttons[1].tap_commands.0: must be at least 1 tap",
+                "buttons[1].tap_commands.3: cannot be combined with a sequence of 3 presses",
+                "buttons[2].sequences[1].presses: 2 presses are already used by sequences[0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_led_timeouts_fall_back_to_the_global_ones() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+led_timeouts: {flash1: 10s, flash2: 30s}
+buttons:
+  - {button: 0, command: HOME, led_timeouts: {flash2: 2min, on: 0}}
+  - {button: 1, command: PARK}
+"#,
+        )
+        .unwrap();
+        let global = config.led_timeouts.as_ref();
+        let own = config.buttons[0].led_timeouts.as_ref().unwrap();
+        assert_eq!(own.of(SPIButtonState::Flash2, global), Some(120_000));
+        assert_eq!(own.of(SPIButtonState::Flash1, global), Some(10_000));
+        assert_eq!(own.of(SPIButtonState::Off, global), None);
+        assert_eq!(global.unwrap().of(SPIButtonState::On, None), None);
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["buttons[0].led_timeouts.on: must be greater than 0"]);
+    }
+
+    #[test]
+    fn test_confirm_window_needs_confirm() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - {button: 0, command: CANCEL_PRINT, confirm: true, confirm_window_ms: 5s}
+  - {button: 1, command: M84, confirm_window_ms: 2s}
+  - {button: 2, command: "host:reboot", confirm_window_ms: 10s}
+  - {button: 3, command: M84, confirm: true, confirm_window_ms: 0}
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.buttons[0].confirm_window_ms, Some(5000));
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "buttons[1].confirm: must be true with confirm_window_ms",
+                "buttons[3].confirm_window_ms: must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_toggle_becomes_a_state_machine() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+buttons:
+  - {button: 0, command: LIGHTS_ON, toggle_command: LIGHTS_OFF}
+  - button: 1
+    command: ""
+    toggle_command: LIGHTS_OFF
+    state_machine: {initial: idle, states: {idle: {}}, transitions: []}
+"#,
+        )
+        .unwrap();
+        config.resolve_press_shorthands();
+        let mapping = &config.buttons[0];
+        assert_eq!((mapping.command.as_str(), mapping.toggle_command.as_deref()), ("", None));
+        let machine = mapping.state_machine.as_ref().unwrap();
+        assert_eq!(machine.states["on"].led, Some(Led::On));
+        let on = machine.transition("off", MachineEvent::Press).unwrap();
+        assert_eq!((on.to.as_str(), on.command.as_deref()), ("on", Some("LIGHTS_ON")));
+        let off = machine.transition("on", MachineEvent::Press).unwrap();
+        assert_eq!((off.to.as_str(), off.command.as_deref()), ("off", Some("LIGHTS_OFF")));
+
+        let problems: Vec<String> = config.validate().iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems, vec!["buttons[1].toggle_command: cannot be combined with state_machine"]);
+    }
+
+    #[test]
+    fn test_resolve_aliases() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+aliases:
+  home: 'klipper:gcode/script|{"script":"G28"}'
+  stop: "klipper:printer/emergency_stop|{}"
+buttons:
+  - {button: 0, command: "!home", hold_tiers: [{hold_ms: 3000, command: "!stop"}]}
+  - {button: 1, command: "! false"}
+profiles:
+  printing: [{button: 0, command: "!stop"}]
+"#,
+        )
+        .unwrap();
+        config.resolve_aliases().unwrap();
+        assert_eq!(config.buttons[0].command, r#"klipper:gcode/script|{"script":"G28"}"#);
+        assert_eq!(config.buttons[0].hold_tiers.as_ref().unwrap()[0].command, "klipper:printer/emergency_stop|{}");
+        assert_eq!(config.buttons[1].command, "! false");
+        assert_eq!(config.profiles.as_ref().unwrap()["printing"][0].command, "klipper:printer/emergency_stop|{}");
+
+        config.buttons[1].command = "!reboot".to_string();
+        config.buttons[1].shift_command = Some("!park".to_string());
+        let err = config.resolve_aliases().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "buttons[1].command: no alias named reboot; buttons[1].shift_command: no alias named park"
+        );
+    }
+
+    #[test]
+    fn test_merge_fragments_in_name_order() {
+        let dir = std::env::temp_dir().join(format!("spibtn-conf-{}", std::process::id()));
+        let conf_d = dir.join("conf.d");
+        fs::create_dir_all(&conf_d).unwrap();
+        fs::write(conf_d.join("20-panel.yaml"), "buttons:\n  - {button: 1, command: \"echo panel\"}\n").unwrap();
+        fs::write(conf_d.join("10-lights.toml"), "[[buttons]]\nbutton = 2\ncommand = \"echo lights\"\n\n[[buttons]]\nbutton = 1\ncommand = \"\"\n").unwrap();
+        fs::write(conf_d.join("README.md"), "not a fragment").unwrap();
+
+        let mut config: Config = serde_yaml::from_str(
+            "spi: {device: /dev/spidev1.0, speed_hz: 800000, mode: 0}\npolling: {interval_ms: 100}\nbuttons:\n  - {button: 0, command: \"echo main\"}\n  - {button: 1, command: \"echo main\"}\n",
+        )
+        .unwrap();
+        let merged = config.merge_fragments(&conf_d).unwrap();
+        let missing = config.clone().merge_fragments(&dir.join("missing.d")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(merged.len(), 2);
+        assert!(missing.is_empty());
+        let commands: Vec<(u8, &str)> = config.buttons.iter().map(|m| (m.button.0, m.command.as_str())).collect();
+        // 20-panel.yaml is merged last, so its button 1 wins
+        assert_eq!(commands, vec![(0, "echo main"), (1, "echo panel"), (2, "echo lights")]);
+        assert!(config.validate().is_empty());
+        assert_eq!(config.buttons[2].origin.as_deref(), Some("conf.d/10-lights.toml: buttons[0]"));
+    }
+}
@@ -0,0 +1,330 @@
+use log::{debug, info, warn, Level, LevelFilter};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{ButtonMapping, ControlConfig};
+use crate::daemon::Daemon;
+use crate::error::{Error, Result};
+use crate::logs;
+use crate::ratelimit;
+use crate::socket;
+use crate::units::ButtonId;
+
+/// A single command line received on the control socket. The main loop
+/// answers it through `reply` since only it may touch the daemon.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub line: String,
+    pub reply: oneshot::Sender<String>,
+}
+
+/// Bind the control socket and forward each client's request line to the
+/// main loop. One request and one response per connection, except for
+/// `logs`, answered here so `logs follow` can stream.
+pub fn spawn_server(config: &ControlConfig, request_tx: mpsc::Sender<ControlRequest>) -> Result<()> {
+    let socket_path = config.socket_path.as_str();
+    // A stale socket from a previous run would make bind fail
+    if socket::file_exists(socket_path) {
+        std::fs::remove_file(socket_path)
+            .map_err(|e| Error::internal(format!("Failed to remove stale control socket: {}", socket_path)).caused_by(e))?;
+    }
+    let listener = socket::bind(socket_path)
+        .map_err(|e| Error::internal(format!("Failed to bind control socket: {}", socket_path)).caused_by(e))?;
+    info!("Control socket listening on {}", socket_path);
+    let allowed = socket::is_abstract(socket_path).then(|| Allowed::of(config));
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    if let Some(allowed) = &allowed {
+                        if let Err(e) = allowed.check(&stream) {
+                            warn!("Refused control client: {}", e);
+                            continue;
+                        }
+                    }
+                    let tx = request_tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_client(stream, tx).await {
+                            debug!("Control client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Control socket accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Who may connect to an abstract control socket. Such a socket has no file
+/// permissions, anyone in the network namespace could reach it otherwise.
+#[derive(Debug)]
+struct Allowed {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl Allowed {
+    /// Root, the daemon's own user and those `control` adds.
+    fn of(config: &ControlConfig) -> Self {
+        let mut uids = vec![0];
+        // The process directory belongs to the daemon's effective user
+        uids.extend(fs::metadata("/proc/self").ok().map(|m| m.uid()));
+        uids.extend(config.allowed_uids.iter().flatten());
+        Allowed { uids, gids: config.allowed_gids.clone().unwrap_or_default() }
+    }
+
+    fn permits(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+
+    fn check(&self, stream: &UnixStream) -> io::Result<()> {
+        let cred = stream.peer_cred()?;
+        if self.permits(cred.uid(), cred.gid()) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("uid {} gid {} is not in control.allowed_uids or allowed_gids", cred.uid(), cred.gid()),
+            ))
+        }
+    }
+}
+
+async fn serve_client(stream: UnixStream, request_tx: mpsc::Sender<ControlRequest>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    if line.split_whitespace().next() == Some("logs") {
+        return serve_logs(&line, writer).await;
+    }
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    request_tx
+        .send(ControlRequest {
+            line: line.trim().to_string(),
+            reply: reply_tx,
+        })
+        .await
+        .map_err(|_| io::Error::other("main loop stopped"))?;
+    let response = reply_rx.await.map_err(|_| io::Error::other("main loop dropped the request"))?;
+
+    writer.write_all(response.as_bytes()).await?;
+    if !response.ends_with('\n') {
+        writer.write_all(b"\n").await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// `logs [follow] [LEVEL]`: the recent log lines at `LEVEL` or more
+/// severe, then with `follow` the live ones until the client hangs up.
+async fn serve_logs(line: &str, mut writer: OwnedWriteHalf) -> io::Result<()> {
+    let mut follow = false;
+    let mut level = LevelFilter::Trace;
+    for word in line.split_whitespace().skip(1) {
+        match word {
+            "follow" => follow = true,
+            other => match other.parse::<Level>() {
+                Ok(l) => level = l.to_level_filter(),
+                Err(_) => {
+                    let reply = format!("error: unknown log level: {} (error, warn, info, debug or trace)\n", other);
+                    writer.write_all(reply.as_bytes()).await?;
+                    return writer.shutdown().await;
+                }
+            },
+        }
+    }
+
+    // Subscribed first, so no line falls between the recent and live ones
+    let mut live = logs::tap().subscribe();
+    for entry in logs::tap().recent(level) {
+        writer.write_all(format!("{}\n", entry.text).as_bytes()).await?;
+    }
+    if !follow {
+        return writer.shutdown().await;
+    }
+    loop {
+        match live.recv().await {
+            Ok(entry) if entry.level <= level => writer.write_all(format!("{}\n", entry.text).as_bytes()).await?,
+            Ok(_) => {}
+            Err(RecvError::Lagged(n)) => writer.write_all(format!("... {} log lines skipped\n", n).as_bytes()).await?,
+            Err(RecvError::Closed) => break,
+        }
+    }
+    writer.shutdown().await
+}
+
+/// Execute a control command against the daemon and return the text reply.
+pub fn handle(daemon: &mut Daemon, line: &str) -> String {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("last") => {
+            let n = match words.next() {
+                Some(n) => match n.parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_) => return format!("error: invalid count: {}", n),
+                },
+                None => 10,
+            };
+            let lines: Vec<String> = daemon.history().last(n).map(|r| r.to_string()).collect();
+            if lines.is_empty() {
+                "no actions executed yet".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        Some("stats") => {
+            let stats = daemon.stats();
+            let mut lines = vec![
+                format!("unknown_button_events={}", stats.unknown_button_events),
+                format!("dropped_requests={}", stats.dropped_requests),
+            ];
+            lines.extend(daemon.buffers().iter().map(|b| b.to_string()));
+            lines.push(format!("suppressed_warnings={}", ratelimit::suppressed()));
+            lines.push(format!("throttled={}", daemon.throttle()));
+            for (queue, depth) in daemon.queue_depths() {
+                let alarm = if daemon.queue_alarm(queue) { " (backed up)" } else { "" };
+                lines.push(format!("queue.{}={}{}", queue, depth, alarm));
+            }
+            for (endpoint, state, changes) in daemon.circuits() {
+                lines.push(format!("circuit.{}={} ({} changes)", endpoint, state, changes));
+            }
+            lines.join("\n")
+        }
+        Some("health") => {
+            let problems = daemon.health_problems();
+            if problems.is_empty() {
+                "ok".to_string()
+            } else {
+                format!("error: unhealthy: {}", problems.join(", "))
+            }
+        }
+        Some("tuning") => daemon.noise().report(Instant::now()).to_string(),
+        Some("vars") => {
+            let lines: Vec<String> = daemon
+                .variables()
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            if lines.is_empty() {
+                "no variables set".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        Some("pending") => {
+            let lines: Vec<String> = daemon.deferred().pending().iter().map(|a| a.to_string()).collect();
+            if lines.is_empty() {
+                "no pending actions".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        Some("leds") => {
+            let lines: Vec<String> = daemon
+                .led_owners()
+                .iter()
+                .map(|(button, layer)| format!("button {}: {:?}", button, layer).to_lowercase())
+                .collect();
+            if lines.is_empty() {
+                "no LEDs lit".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        Some("states") => {
+            let mut lines: Vec<String> = daemon
+                .machine_states()
+                .iter()
+                .map(|(button, state)| format!("button {}: {}", button, state))
+                .collect();
+            lines.extend(
+                daemon
+                    .radio_selections()
+                    .iter()
+                    .map(|(group, button)| format!("radio group {}: button {}", group, button)),
+            );
+            if lines.is_empty() {
+                "no state machines or radio groups".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        Some("profile") => match words.next() {
+            Some(name) => match daemon.set_profile(name) {
+                Ok(()) => format!("profile={}", name),
+                Err(e) => format!("error: {}", e),
+            },
+            None => {
+                let names: Vec<&str> = daemon.profile_names().iter().map(|n| n.as_str()).collect();
+                format!("profile={}\navailable={}", daemon.profile().unwrap_or(""), names.join(","))
+            }
+        },
+        Some("reload") => match daemon.reload() {
+            Ok(diff) => format!("reloaded\n{}", diff),
+            Err(e) => format!("error: keeping the current configuration: {}", e),
+        },
+        Some(op @ ("add-mapping" | "remove-mapping")) => {
+            // The rest of the line, keeping the spacing of the mapping
+            let rest = line.trim_start()[op.len()..].trim_start();
+            let (persist, rest) = match rest.strip_prefix("--persist") {
+                Some(rest) => (true, rest.trim_start()),
+                None => (false, rest),
+            };
+            let result = if op == "add-mapping" {
+                match serde_yaml::from_str::<ButtonMapping>(rest) {
+                    Ok(mapping) => {
+                        let id = mapping.button;
+                        daemon.add_mapping(mapping, persist).map(|_| format!("mapped button {}", id))
+                    }
+                    Err(e) => return format!("error: invalid mapping: {}", e),
+                }
+            } else {
+                match rest.parse::<u8>() {
+                    Ok(id) => daemon
+                        .remove_mapping(ButtonId(id), persist)
+                        .map(|_| format!("unmapped button {}", id)),
+                    Err(_) => return format!("error: invalid button id: {}", rest),
+                }
+            };
+            match result {
+                Ok(reply) if persist => format!("{}, saved to the config file", reply),
+                Ok(reply) => reply,
+                Err(e) => format!("error: {}", e),
+            }
+        }
+        Some("help") | None => "commands:\n  last [n]  show the last n executed actions (default 10)\n  stats     show daemon counters, buffer sizes and circuit breakers\n  health    report ok, or an error while the panel or a queue is failing\n  tuning    show button noise and suggested debounce_ms and min_press_ms\n  vars      show variables\n  pending   show scheduled actions\n  leds      show which layer owns each lit LED\n  states    show the state of each state machine button\n  profile [name]  show or switch the active profile\n  add-mapping [--persist] MAPPING  map a button, e.g. {button: 7, command: echo hi}\n  remove-mapping [--persist] ID  unmap a button\n  reload    reload the config file and show what changed\n  logs [follow] [level]  show recent log lines, with follow keep streaming new ones".to_string(),
+        Some(other) => format!("error: unknown command: {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_abstract_socket_admits_only_allowed_users() {
+        let config = ControlConfig { allowed_uids: Some(vec![1000]), allowed_gids: Some(vec![27]), ..ControlConfig::default() };
+        let allowed = Allowed::of(&config);
+        assert!(allowed.permits(0, 0));
+        assert!(allowed.permits(1000, 1000));
+        assert!(allowed.permits(1001, 27));
+        assert!(!allowed.permits(65534, 65534));
+
+        // A client of the daemon's own user gets in
+        let name = format!("@spibtn-control-test-{}", std::process::id());
+        let listener = socket::bind(&name).unwrap();
+        let _client = socket::connect(&name).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(Allowed::of(&ControlConfig::default()).check(&stream).is_ok());
+    }
+}
@@ -0,0 +1,218 @@
+use futures_util::stream::{self, Stream};
+use log::warn;
+use spibuttonlib::SPIButtonState;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::sleep;
+
+use crate::backpressure::Queue;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::history::{ActionRecord, Outcome};
+use crate::lock::DeviceLock;
+use crate::panel::{self, PanelButton, PanelProtocol};
+use crate::units::ButtonId;
+
+/// Events buffered per `Daemon::events` subscriber before it misses some.
+pub const EVENT_BUFFER: usize = 64;
+
+/// Something that happened on the panel, for programs embedding the library
+/// that react to it themselves instead of through configured commands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControllerEvent {
+    Pressed(ButtonId),
+    /// Only reported for buttons whose `config` reports releases
+    Released(ButtonId),
+    /// A button's command finished, only sent by the daemon
+    ActionFinished {
+        button: ButtonId,
+        command: String,
+        outcome: Outcome,
+    },
+    /// Reading the panel failed after working, with the error
+    TransportFailed(String),
+    /// Reading the panel works again
+    TransportRecovered,
+    /// A different panel was plugged in and has been re-initialized
+    PanelChanged,
+    /// The host woke up after being suspended for this long and the panel
+    /// has been re-initialized, only sent by the daemon
+    Resumed(Duration),
+    /// A queue stayed at its `backpressure` threshold, with its depth
+    QueueBackedUp { queue: Queue, depth: usize },
+    /// A backed up queue dropped below its threshold again
+    QueueDrained(Queue),
+}
+
+impl ControllerEvent {
+    /// The event a button read from the panel stands for, if any.
+    pub fn from_panel(button: &PanelButton) -> Option<Self> {
+        match button.get_state() {
+            SPIButtonState::On => Some(ControllerEvent::Pressed(button.id())),
+            SPIButtonState::Off => Some(ControllerEvent::Released(button.id())),
+            _ => None,
+        }
+    }
+
+    /// The event announcing a finished action.
+    pub fn finished(record: &ActionRecord) -> Self {
+        ControllerEvent::ActionFinished {
+            button: record.button,
+            command: record.command.clone(),
+            outcome: record.outcome.clone(),
+        }
+    }
+}
+
+/// The panel without the daemon's actions: reads it and reports what
+/// happened as `ControllerEvent`s.
+pub struct Controller {
+    panel: Box<dyn PanelProtocol>,
+    interval: Duration,
+    failing: bool,
+    /// Keeps other instances off the SPI device when opened by `open`
+    _lock: Option<DeviceLock>,
+}
+
+impl Controller {
+    /// Open the panel of `config` and apply the `config` flags of its
+    /// buttons. It is read every `polling.interval_ms`.
+    pub fn open(config: &Config) -> Result<Self> {
+        let lock = DeviceLock::acquire(&config.spi.device)?;
+        let mut panel = panel::open(&config.spi, config.panel_size())
+            .map_err(|e| Error::spi(format!("Failed to open {}", config.spi.device), e))?;
+        for mapping in &config.buttons {
+            panel.configure(mapping.button, mapping.config.unwrap_or(SPIButtonState::OnChange as u8));
+        }
+        let mut controller = Controller::new(panel, Duration::from_millis(config.polling.interval_ms));
+        controller._lock = Some(lock);
+        Ok(controller)
+    }
+
+    /// A controller for an already opened panel, e.g. a custom protocol.
+    pub fn new(panel: Box<dyn PanelProtocol>, interval: Duration) -> Self {
+        Controller {
+            panel,
+            interval,
+            failing: false,
+            _lock: None,
+        }
+    }
+
+    /// Set a button's LED, sent with the next read.
+    pub fn set_led(&mut self, id: ButtonId, state: SPIButtonState) {
+        self.panel.set_button(id, PanelButton::new(id, state));
+    }
+
+    /// Read the panel once. A failing read is reported as an event when it
+    /// starts failing, not as an error, so the caller can keep reading
+    /// until it recovers.
+    pub fn read(&mut self) -> Vec<ControllerEvent> {
+        match self.panel.loop_once() {
+            Ok(buttons) => {
+                let recovered = std::mem::replace(&mut self.failing, false);
+                recovered
+                    .then_some(ControllerEvent::TransportRecovered)
+                    .into_iter()
+                    .chain(buttons.iter().filter_map(ControllerEvent::from_panel))
+                    .collect()
+            }
+            Err(_) if self.failing => vec![],
+            Err(e) => {
+                self.failing = true;
+                vec![ControllerEvent::TransportFailed(e.to_string())]
+            }
+        }
+    }
+
+    /// Events as they happen, reading the panel every polling interval.
+    pub fn events(&mut self) -> impl Stream<Item = ControllerEvent> + '_ {
+        stream::unfold((self, Vec::new(), false), |(controller, mut pending, mut read)| async move {
+            while pending.is_empty() {
+                if read {
+                    sleep(controller.interval).await;
+                }
+                pending = controller.read();
+                pending.reverse();
+                read = true;
+            }
+            let event = pending.pop()?;
+            Some((event, (controller, pending, read)))
+        })
+    }
+}
+
+/// Events sent on `tx` from now on. A subscriber falling more than
+/// `EVENT_BUFFER` events behind skips the oldest.
+pub fn subscribe(tx: &broadcast::Sender<ControllerEvent>) -> impl Stream<Item = ControllerEvent> {
+    stream::unfold(tx.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, rx)),
+                Err(RecvError::Lagged(missed)) => warn!("Event subscriber too slow, skipped {} event(s)", missed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SpiConfig;
+    use crate::panel::Capabilities;
+    use futures_util::StreamExt;
+    use std::collections::VecDeque;
+    use std::io;
+
+    /// Replays scripted reads, `None` failing.
+    struct ScriptedPanel {
+        reads: VecDeque<Option<Vec<PanelButton>>>,
+        capabilities: Capabilities,
+    }
+
+    impl PanelProtocol for ScriptedPanel {
+        fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+            match self.reads.pop_front() {
+                Some(Some(buttons)) => Ok(buttons),
+                Some(None) => Err(io::Error::other("no answer")),
+                None => Ok(vec![]),
+            }
+        }
+        fn get_button(&self, id: ButtonId) -> PanelButton {
+            PanelButton::new(id, SPIButtonState::Off)
+        }
+        fn set_button(&mut self, _id: ButtonId, _button: PanelButton) {}
+        fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+        fn capabilities(&self) -> &Capabilities {
+            &self.capabilities
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_report_presses_and_transport_health() {
+        let button = |id, state| PanelButton::new(ButtonId(id), state);
+        let panel = ScriptedPanel {
+            reads: VecDeque::from([
+                Some(vec![button(2, SPIButtonState::On)]),
+                None,
+                None,
+                Some(vec![button(2, SPIButtonState::Off), button(3, SPIButtonState::On)]),
+            ]),
+            capabilities: panel::capabilities(&SpiConfig::default()),
+        };
+        let mut controller = Controller::new(Box::new(panel), Duration::from_millis(1));
+
+        let events: Vec<ControllerEvent> = controller.events().take(5).collect().await;
+        assert_eq!(
+            events,
+            vec![
+                ControllerEvent::Pressed(ButtonId(2)),
+                ControllerEvent::TransportFailed("no answer".to_string()),
+                ControllerEvent::TransportRecovered,
+                ControllerEvent::Released(ButtonId(2)),
+                ControllerEvent::Pressed(ButtonId(3)),
+            ]
+        );
+    }
+}
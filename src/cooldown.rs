@@ -0,0 +1,67 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::units::ButtonId;
+
+/// Buttons locked out for their `cooldown_ms` after running a command, so
+/// a double-tapped "start print" does not queue the job twice.
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    until: HashMap<ButtonId, Instant>,
+    /// Buttons whose press was refused, their release is refused as well
+    refused: HashSet<ButtonId>,
+}
+
+impl Cooldowns {
+    pub fn new() -> Self {
+        Cooldowns::default()
+    }
+
+    /// Lock the button out for `cooldown` from `now`.
+    pub fn start(&mut self, button: ButtonId, now: Instant, cooldown: Duration) {
+        self.until.insert(button, now + cooldown);
+    }
+
+    /// Whether a press at `now` falls within the button's cooldown.
+    pub fn refuse_press(&mut self, button: ButtonId, now: Instant) -> bool {
+        match self.until.get(&button) {
+            Some(until) if now < *until => {
+                self.refused.insert(button);
+                true
+            }
+            Some(_) => {
+                self.until.remove(&button);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a release ends a refused press.
+    pub fn refuse_release(&mut self, button: ButtonId) -> bool {
+        self.refused.remove(&button)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presses_refused_until_cooldown_ends() {
+        let mut cooldowns = Cooldowns::new();
+        let t0 = Instant::now();
+        let button = ButtonId(2);
+        assert!(!cooldowns.refuse_press(button, t0));
+
+        cooldowns.start(button, t0, Duration::from_secs(5));
+        assert!(cooldowns.refuse_press(button, t0 + Duration::from_millis(300)));
+        assert!(cooldowns.refuse_release(button));
+        assert!(!cooldowns.refuse_release(button));
+
+        // Other buttons and later presses are not affected
+        assert!(!cooldowns.refuse_press(ButtonId(3), t0));
+        assert!(!cooldowns.refuse_press(button, t0 + Duration::from_secs(5)));
+        assert!(!cooldowns.refuse_release(button));
+    }
+}
@@ -1,54 +1,1097 @@
-use crate::command::{CommandExecutor, EventMessage};
-use crate::config::{Config, ButtonMapping};
-use spibuttonlib::{SPIButtonController, SPIButtonState, SPIButton};
-use anyhow::Result;
-use log::{info, warn};
-use std::time::{Duration};
+use crate::actions;
+use crate::arming::{Arming, DEFAULT_CONFIRM_WINDOW_MS};
+use crate::chord::{Chords, DEFAULT_CHORD_WINDOW_MS};
+use crate::combo::Combos;
+use crate::command::{CommandExecutor, EventMessage, ResponseStatus};
+use crate::backpressure::{self, Alarms, Queue};
+use crate::breaker::{self, Breakers, CircuitState, Endpoint};
+use crate::concurrency::{self, InFlight, MutexGroups};
+use crate::config::{self, Config, ConfigDiff, ConfigFormat, ButtonMapping, MachineEvent, Overflow, SyncConfig, UnknownButtonPolicy};
+use crate::controller::{self, ControllerEvent, EVENT_BUFFER};
+use crate::debounce::Debouncer;
+use crate::clock::{self, ClockWatch, SleepWatch};
+use crate::cooldown::Cooldowns;
+use crate::deferred::{self, Deferral, Deferred};
+use crate::frame::FrameBuffer;
+use crate::leds::{LedLayer, LedStack};
+use crate::lock::DeviceLock;
+use crate::machine::Machines;
+use crate::messages;
+use crate::pattern::Patterns;
+use crate::peer::{self, Envelope, PeerState, PeerUpdate, SharedLayer};
+use crate::error::{DaemonError, Error, Result};
+use crate::expr::{self, Value};
+use crate::grace::StartupGrace;
+use crate::ghost::PressFilter;
+use crate::gesture::{Gestures, DEFAULT_SEQUENCE_WINDOW_MS};
+use crate::hold::{self, Holds};
+use crate::history::{ActionRecord, History, Outcome, DEFAULT_HISTORY_SIZE};
+use crate::indicator::{self, IndicatorState};
+use crate::moonraker;
+use crate::noise::NoiseStats;
+use crate::notifications::Notification;
+use crate::polling::PollTimers;
+use crate::power::{self, PowerOn, POWER_ON_PREFIX};
+use crate::press::Presses;
+use crate::printer::{PrinterState, Scope};
+use crate::radio::Radio;
+use crate::ratelimit::{self, warn_limited};
+use crate::remote::RemoteCall;
+use crate::recovery::{self, RECOVER_COMMAND};
+use crate::requests::{PendingRequest, PendingRequests, DEFAULT_PENDING_REQUESTS};
+use crate::schedule::TimeWindow;
+use crate::service;
+use crate::script::{self, ScriptInput, SCRIPT_PREFIX};
+use crate::snapshot::{self, SNAPSHOT_PREFIX};
+use crate::throttle::{self, Throttle};
+use crate::units::ButtonId;
+use crate::vars::{self, Variables, SET_VAR_PREFIX};
+use crate::panel::{self, PanelButton, PanelProtocol};
+use spibuttonlib::SPIButtonState;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+use futures_util::Stream;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
 use tokio::time::sleep;
+use uuid::Uuid;
 
 pub struct Daemon {
-    spi: SPIButtonController,
+    /// LED writes are composed here and reach the panel with the next read
+    spi: FrameBuffer,
+    /// The configuration with the active profile applied
     config: Config,
+    /// Its button mappings by id, ids need not be consecutive
+    mappings: HashMap<ButtonId, ButtonMapping>,
+    /// The configuration as loaded, profiles are applied to it
+    base_config: Config,
+    profile: Option<String>,
     response_tx: Option<tokio::sync::mpsc::Sender<EventMessage>>,
     id_next: u32,
+    history: History,
+    /// Klipper requests awaiting their response
+    requests: PendingRequests,
+    /// Layers holding each LED, the panel shows the highest
+    leds: LedStack,
+    gestures: Gestures,
+    stats: DaemonStats,
+    indicators: IndicatorState,
+    arming: Arming,
+    /// Buttons disabled by Klipper through `spibtn_disable`
+    disabled: HashSet<ButtonId>,
+    variables: Variables,
+    printer: PrinterState,
+    mutex_groups: MutexGroups,
+    deferred: Deferred,
+    cooldowns: Cooldowns,
+    /// Actions running or waiting for their mutex group
+    in_flight: InFlight,
+    /// Queue depth alarms, see `backpressure`
+    alarms: Alarms,
+    /// Circuit breakers of Klipper and Moonraker, see `circuit_breaker`
+    breakers: Breakers,
+    /// Keeps other instances off the SPI device, `None` for a panel
+    /// passed to `with_panel`
+    device_lock: Option<DeviceLock>,
+    machines: Machines,
+    /// Selected buttons of the radio groups, shown on the State layer
+    radio: Radio,
+    /// Flash patterns of the states shown on the State layer
+    patterns: Patterns,
+    /// Results of state machine commands, handled on the next poll
+    machine_results: VecDeque<(ButtonId, MachineEvent)>,
+    /// Notices NTP stepping the wall clock, for the `at` actions
+    clock: ClockWatch,
+    /// Notices the host waking up from suspend
+    sleep: SleepWatch,
+    /// Slows LED animations and indicators while the host is hot or busy
+    throttle: Throttle,
+    /// Indicators and LED rules changed while throttled, shown with the
+    /// next LED update
+    idle_leds_stale: bool,
+    /// What is shared with the daemons of other panels, see `sync`
+    peers: PeerState,
+    holds: Holds,
+    /// Modifier buttons currently held down
+    modifiers_held: HashSet<ButtonId>,
+    /// Mapped buttons currently held down, for scripts
+    held: HashSet<ButtonId>,
+    /// The latest press of each button, told to the commands it runs
+    presses: Presses,
+    debouncer: Debouncer,
+    poll_timers: PollTimers,
+    grace: StartupGrace,
+    press_filter: PressFilter,
+    chords: Chords,
+    combos: Combos,
+    noise: NoiseStats,
+    /// Identity of the attached panel, for protocols with an ID register
+    panel_id: Option<u32>,
+    panel_checked: Instant,
+    /// Buttons the panel was opened for, higher ids are not read
+    panel_size: usize,
+    /// Sends what happens to `events` subscribers
+    events: broadcast::Sender<ControllerEvent>,
+    transport_failing: bool,
+    /// File runtime mapping changes are saved to, with its format
+    config_file: Option<(String, Option<ConfigFormat>)>,
 }
 
+/// Counters reported by `spibuttonctl stats`.
+#[derive(Debug, Default, Clone)]
+pub struct DaemonStats {
+    /// Events from button ids that have no mapping in the config
+    pub unknown_button_events: u64,
+    /// Klipper requests no longer awaited because too many were pending
+    pub dropped_requests: u64,
+}
+
+/// How full a buffer kept in memory is, reported by `spibuttonctl stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferUsage {
+    pub name: &'static str,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl fmt::Display for BufferUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}/{}", self.name, self.len, self.capacity)
+    }
+}
+
+/// How long a button flashes after a press is refused.
+const REFUSAL_FLASH: Duration = Duration::from_secs(2);
+
+/// How often the panel identity is checked unless `polling.panel_check_ms`
+/// says otherwise.
+const DEFAULT_PANEL_CHECK_MS: u64 = 5000;
+
 impl Daemon {
     pub fn new(config: Config, response_tx: Option<tokio::sync::mpsc::Sender<EventMessage>>) -> Result<Self> {
-        let spi_res = SPIButtonController::new(config.buttons.len(), &config.spi.device, config.spi.speed_hz, config.spi.mode);
-        match spi_res {
-            Ok(mut spi) => {
-                info!("SPI device initialized: {}", config.spi.device);
-                info!("Polling interval: {}ms", config.polling.interval_ms);
-                info!("Monitoring {} buttons(s)", config.buttons.len());
-        
-                Daemon::init(&config, &mut spi);
-
-                Ok(Daemon {
-                    spi,
-                    config,
-                    response_tx,
-                    id_next: 0,
-                })        
+        let lock = DeviceLock::acquire(&config.spi.device)?;
+        let spi = panel::open(&config.spi, config.panel_size())
+            .map_err(|e| Error::spi(format!("Failed to open {}", config.spi.device), e))?;
+        info!("SPI device initialized: {}", config.spi.device);
+        let mut daemon = Daemon::with_panel(config, spi, response_tx)?;
+        daemon.device_lock = Some(lock);
+        Ok(daemon)
+    }
+
+    /// A daemon driving `spi` instead of the panel `config.spi` describes,
+    /// e.g. a simulated one in tests and benchmarks.
+    pub fn with_panel(
+        config: Config,
+        mut spi: Box<dyn PanelProtocol>,
+        response_tx: Option<tokio::sync::mpsc::Sender<EventMessage>>,
+    ) -> Result<Self> {
+        let base_config = config.clone();
+        let panel_size = config.panel_size();
+        let profile = config.default_profile.clone();
+        let config = match &profile {
+            Some(name) => {
+                info!("Active profile: {}", name);
+                config.with_profile(name)?
             }
-            Err(e) => {
-                println!("error: {}", e);
-                panic!("SPI initialization error.")
+            None => config,
+        };
+        info!("Panel capabilities: {}", spi.capabilities());
+        info!("Polling interval: {}ms", config.polling.interval_ms);
+        info!("Monitoring {} buttons(s)", config.buttons.len());
+        if config.observer.unwrap_or(false) {
+            info!("Observer mode: commands will be logged but not executed");
+        }
+
+        Daemon::init(&config, spi.as_mut());
+        let panel_id = spi.identify().unwrap_or_else(|e| {
+            warn!("Failed to read the panel identity: {}", e);
+            None
+        });
+
+        let history_size = config
+            .control
+            .as_ref()
+            .and_then(|c| c.history_size)
+            .unwrap_or(DEFAULT_HISTORY_SIZE);
+        let mut variables = Variables::new(config.variables.as_ref());
+        variables.set_limit(config.limits.as_ref().and_then(|l| l.variables));
+        let (request_capacity, overflow) = Daemon::request_limits(&config);
+        let now = chrono::Local::now();
+        if clock::looks_unset(now) {
+            warn!("The wall clock reads {}, likely not synchronized yet", now.format("%Y-%m-%d %H:%M"));
+        }
+
+        let mut daemon = Daemon {
+            spi: FrameBuffer::new(spi),
+            mappings: mappings_by_id(&config),
+            poll_timers: PollTimers::new(&config.polling, Instant::now()),
+            grace: Daemon::grace(&config),
+            press_filter: PressFilter::new(),
+            chords: Chords::new(),
+            combos: Combos::new(),
+            noise: NoiseStats::new(),
+            config,
+            base_config,
+            profile,
+            response_tx,
+            id_next: 0,
+            history: History::new(history_size),
+            requests: PendingRequests::new(request_capacity, overflow),
+            leds: LedStack::new(),
+            gestures: Gestures::new(),
+            stats: DaemonStats::default(),
+            indicators: IndicatorState::default(),
+            arming: Arming::new(),
+            disabled: HashSet::new(),
+            variables,
+            printer: PrinterState::default(),
+            mutex_groups: MutexGroups::new(),
+            deferred: Deferred::new(),
+            cooldowns: Cooldowns::new(),
+            in_flight: InFlight::new(),
+            alarms: Alarms::new(),
+            breakers: Breakers::new(),
+            device_lock: None,
+            machines: Machines::new(),
+            radio: Radio::new(),
+            patterns: Patterns::new(),
+            machine_results: VecDeque::new(),
+            clock: ClockWatch::new(now, Instant::now()),
+            sleep: SleepWatch::new(clock::boot_time(), Instant::now()),
+            throttle: Throttle::new(),
+            idle_leds_stale: false,
+            peers: PeerState::new(Uuid::new_v4().to_string()),
+            holds: Holds::new(),
+            modifiers_held: HashSet::new(),
+            held: HashSet::new(),
+            presses: Presses::new(),
+            debouncer: Debouncer::new(),
+            panel_id,
+            panel_checked: Instant::now(),
+            panel_size,
+            events: broadcast::channel(EVENT_BUFFER).0,
+            transport_failing: false,
+            config_file: None,
+        };
+        daemon.show_machine_states();
+        daemon.show_radio_groups();
+        daemon.refresh_led_rules();
+        Ok(daemon)
+    }
+
+    /// Show the progress or outcome of a button's action on its LED. `Off`
+    /// hands the LED back, e.g. to the button's indicator, and so does a
+    /// state whose `led_timeouts` entry ran out.
+    pub fn set_button_state(&mut self, button_id: ButtonId, new_state: SPIButtonState) {
+        self.show_action(button_id, new_state);
+        self.share_led(button_id, SharedLayer::Action, new_state);
+    }
+
+    fn show_action(&mut self, button_id: ButtonId, new_state: SPIButtonState) {
+        let global = self.config.led_timeouts.as_ref();
+        let own = self.mappings.get(&button_id).and_then(|m| m.led_timeouts.as_ref());
+        match own.or(global).and_then(|t| t.of(new_state, global)) {
+            Some(ms) => {
+                let until = Instant::now() + Duration::from_millis(ms);
+                let shown = self.leds.set_until(button_id, LedLayer::Action, new_state, until);
+                self.write_led(button_id, shown);
             }
+            None => self.set_led(button_id, LedLayer::Action, new_state),
         }
     }
 
-    pub fn set_button_state(&mut self, button_id: u8, new_state: SPIButtonState) {        
-        let mut btn = self.spi.get_button( button_id as usize );
-        btn.set_state(new_state);
+    /// Set a button's LED on `layer`, the panel shows the highest layer
+    /// holding it.
+    pub fn set_led(&mut self, button_id: ButtonId, layer: LedLayer, state: SPIButtonState) {
+        let shown = self.leds.set(button_id, layer, state);
+        self.write_led(button_id, shown);
+    }
+
+    /// Let go of a button's LED on `layer`, showing the layer below.
+    pub fn release_led(&mut self, button_id: ButtonId, layer: LedLayer) {
+        let shown = self.leds.release(button_id, layer);
+        self.write_led(button_id, shown);
+    }
+
+    /// Flash a button's LED briefly over whatever it shows, e.g. to refuse
+    /// a press.
+    fn flash_led(&mut self, button_id: ButtonId, state: SPIButtonState, duration: Duration) {
+        self.patterns.stop(button_id, LedLayer::Feedback);
+        let shown = self.leds.set_until(button_id, LedLayer::Feedback, state, Instant::now() + duration);
+        self.write_led(button_id, shown);
+    }
+
+    /// Flash `circuit_breaker.pattern` over a button whose press was
+    /// refused because the endpoint of its command is down.
+    fn flash_endpoint_down(&mut self, button_id: ButtonId) {
+        let pattern = self
+            .config
+            .circuit_breaker
+            .as_ref()
+            .and_then(|b| b.pattern.as_ref())
+            .and_then(|name| self.config.led_patterns.as_ref()?.get(name))
+            .cloned()
+            .unwrap_or_else(breaker::default_pattern);
+        let now = Instant::now();
+        self.patterns.start(button_id, LedLayer::Feedback, pattern, now);
+        // Held On while the pattern runs, write_led shows the pattern
+        let shown = self.leds.set_until(button_id, LedLayer::Feedback, SPIButtonState::On, now + REFUSAL_FLASH);
+        self.write_led(button_id, shown);
+    }
+
+    fn write_led(&mut self, button_id: ButtonId, state: SPIButtonState) {
+        let pattern = self.leds.owner(button_id).and_then(|layer| self.patterns.lit(button_id, layer));
+        let state = match pattern {
+            Some(true) => SPIButtonState::On,
+            Some(false) => SPIButtonState::Off,
+            None => state,
+        };
+        let mut btn = self.spi.get_button(button_id);
+        btn.set_state(state);
         self.spi.set_button(button_id, btn);
-    } 
+    }
+
+    /// Which layer each LED that something holds shows, for `spibuttonctl`.
+    pub fn led_owners(&self) -> Vec<(ButtonId, LedLayer)> {
+        self.config
+            .buttons
+            .iter()
+            .filter_map(|m| Some((m.button, self.leds.owner(m.button)?)))
+            .collect()
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn stats(&self) -> &DaemonStats {
+        &self.stats
+    }
+
+    /// Whether LED updates are throttled, with the host's latest reading.
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    /// Fill levels of the buffers that grow with use, each bounded by its
+    /// capacity.
+    pub fn buffers(&self) -> Vec<BufferUsage> {
+        let usage = |name, len, capacity| BufferUsage { name, len, capacity };
+        vec![
+            usage("history", self.history.len(), self.history.capacity()),
+            usage("pending_requests", self.requests.len(), self.requests.capacity()),
+            usage("variables", self.variables.len(), self.variables.limit()),
+            // At most one deferred action per button
+            usage("deferred", self.deferred.pending().len(), self.mappings.len()),
+            usage("log_messages", ratelimit::tracked(), ratelimit::MAX_MESSAGES),
+        ]
+    }
+
+    /// Current depth of each watched queue, for `spibuttonctl stats` and
+    /// the `backpressure` alarms.
+    pub fn queue_depths(&self) -> Vec<(Queue, usize)> {
+        let responses = self.response_tx.as_ref().map_or(0, |tx| tx.max_capacity() - tx.capacity());
+        vec![
+            (Queue::Actions, self.in_flight.len()),
+            (Queue::Requests, self.requests.len()),
+            (Queue::Responses, responses),
+        ]
+    }
+
+    /// Whether the queue's `backpressure` alarm is raised.
+    pub fn queue_alarm(&self, queue: Queue) -> bool {
+        self.alarms.is_raised(queue)
+    }
+
+    /// Count a response against the circuit of its endpoint, see
+    /// `circuit_breaker`.
+    pub fn record_response(&mut self, endpoint: Endpoint, status: &ResponseStatus) {
+        if let Some(config) = &self.config.circuit_breaker {
+            let before = self.breakers.state(endpoint);
+            self.breakers.record(endpoint, breaker::is_down(status), Instant::now(), config);
+            if self.breakers.state(endpoint) != before {
+                service::notify(&format!("STATUS={}", self.status()));
+            }
+        }
+    }
+
+    /// The service status shown by `systemctl status`: the `endpoint_down`
+    /// message while a circuit is not closed, `ready` otherwise.
+    pub fn status(&self) -> String {
+        let messages = self.config.messages.as_ref();
+        match self.breakers.iter().find(|(_, state, _)| *state != CircuitState::Closed) {
+            Some((endpoint, _, _)) => {
+                messages::text(messages, "endpoint_down", &[("endpoint", Value::Str(endpoint.to_string()))])
+            }
+            None => messages::text(messages, "ready", &[("buttons", Value::Number(self.mappings.len() as f64))]),
+        }
+    }
+
+    /// The circuit of each endpoint requested so far and how often it
+    /// changed state, for `spibuttonctl stats`.
+    pub fn circuits(&self) -> Vec<(Endpoint, CircuitState, u64)> {
+        self.breakers.iter().collect()
+    }
+
+    /// The endpoint `cmd` sends to, when its circuit refuses requests now.
+    /// `recover:firmware_restart` is never refused, it is how a user brings
+    /// Klipper back, and scripts and snapshots are not either, as they
+    /// need not reach one endpoint only.
+    fn endpoint_down(&mut self, cmd: &str) -> Option<Endpoint> {
+        let config = self.config.circuit_breaker.as_ref()?;
+        let endpoint = if cmd.starts_with("klipper:") {
+            Endpoint::Klipper
+        } else if cmd.starts_with(POWER_ON_PREFIX) || actions::is_action(cmd) {
+            Endpoint::Moonraker
+        } else {
+            return None;
+        };
+        (!self.breakers.allow(endpoint, Instant::now(), config)).then_some(endpoint)
+    }
+
+    /// What keeps the daemon from working normally: a failing panel
+    /// transport or a backed up queue. Empty when it is healthy.
+    pub fn health_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.transport_failing {
+            problems.push("panel transport failing".to_string());
+        }
+        for queue in Queue::ALL.into_iter().filter(|q| self.alarms.is_raised(*q)) {
+            problems.push(format!("{} queue backed up", queue));
+        }
+        problems
+    }
+
+    /// Wait for running actions to finish, up to `deadline`, returning
+    /// how many did not.
+    pub async fn finish_actions(&self, deadline: Instant) -> usize {
+        self.in_flight.drain(deadline).await
+    }
+
+    /// Raise or clear the `backpressure` alarms, warning about queues that
+    /// stay backed up and showing it on the LEDs until they drain.
+    fn check_backpressure(&mut self, now: Instant) {
+        let config = self.config.backpressure.clone().unwrap_or_default();
+        let changes = self.alarms.check(now, &self.queue_depths(), &config);
+        if changes.is_empty() {
+            return;
+        }
+        for change in &changes {
+            if change.raised {
+                let sustain = config.sustain_ms.unwrap_or(backpressure::DEFAULT_SUSTAIN_MS);
+                warn!("The {} queue is backed up: {} waiting for at least {}ms", change.queue, change.depth, sustain);
+                self.emit(ControllerEvent::QueueBackedUp { queue: change.queue, depth: change.depth });
+            } else {
+                info!("The {} queue drained to {}", change.queue, change.depth);
+                self.emit(ControllerEvent::QueueDrained(change.queue));
+            }
+        }
+        let buttons: Vec<ButtonId> = match &config.buttons {
+            Some(buttons) => buttons.clone(),
+            None => self.config.buttons.iter().map(|m| m.button).collect(),
+        };
+        let led = config.led.map_or(SPIButtonState::Flash2, |l| l.state());
+        for button_id in buttons {
+            if self.alarms.any_raised() {
+                self.set_led(button_id, LedLayer::Alarm, led);
+            } else {
+                self.release_led(button_id, LedLayer::Alarm);
+            }
+        }
+    }
+
+    /// Bounces and ghost presses seen since startup.
+    pub fn noise(&self) -> &NoiseStats {
+        &self.noise
+    }
+
+    pub fn variables(&self) -> &Variables {
+        &self.variables
+    }
+
+    pub fn deferred(&self) -> &Deferred {
+        &self.deferred
+    }
+
+    /// The active profile, `None` while the plain mapping is in use.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn profile_names(&self) -> Vec<&String> {
+        self.base_config.profiles.iter().flat_map(|p| p.keys()).collect()
+    }
+
+    /// Switch to another profile's commands without reloading.
+    pub fn set_profile(&mut self, name: &str) -> Result<()> {
+        self.config = self.base_config.with_profile(name)?;
+        self.mappings = mappings_by_id(&self.config);
+        self.profile = Some(name.to_string());
+        info!("Switched to profile {}", name);
+        Ok(())
+    }
+
+    /// The config file `add_mapping` and `remove_mapping` save to.
+    pub fn set_config_file(&mut self, path: &str, format: Option<ConfigFormat>) {
+        self.config_file = Some((path.to_string(), format));
+    }
+
+    /// Map a button, or change its mapping, without reloading. The result
+    /// is validated like a loaded config. With `persist` the mapping is also
+    /// saved to the config file, otherwise the next reload drops it.
+    pub fn add_mapping(&mut self, mapping: ButtonMapping, persist: bool) -> Result<()> {
+        let id = mapping.button;
+        self.change_mapping(id, Some(mapping), persist)
+    }
+
+    /// Unmap a button without reloading, see `add_mapping`.
+    pub fn remove_mapping(&mut self, id: ButtonId, persist: bool) -> Result<()> {
+        if !self.base_config.buttons.iter().any(|m| m.button == id) {
+            return Err(Error::config(DaemonError::UnknownButton(id).to_string()));
+        }
+        self.change_mapping(id, None, persist)
+    }
+
+    fn change_mapping(&mut self, id: ButtonId, mapping: Option<ButtonMapping>, persist: bool) -> Result<()> {
+        let mut config = self.base_config.clone();
+        config.buttons.retain(|m| m.button != id);
+        config.buttons.extend(mapping.clone());
+        config.resolve_press_shorthands();
+        config.resolve_aliases()?;
+        config.expand_vars();
+        let problems = config.validate();
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+            return Err(Error::config(problems.join("; ")));
+        }
+        config.buttons.sort_by_key(|b| b.button);
+        if persist {
+            let (path, format) = self
+                .config_file
+                .as_ref()
+                .ok_or_else(|| Error::config("No config file to save the mapping to"))?;
+            config::persist_mapping(path, *format, id, mapping.as_ref())?;
+        }
+        self.reload_config(config).map(|_| ())
+    }
+
+    /// Look up the mapping configured for a button id.
+    fn mapping(&self, button_id: ButtonId) -> Result<&ButtonMapping, DaemonError> {
+        self.mappings.get(&button_id).ok_or(DaemonError::UnknownButton(button_id))
+    }
+
+    /// Apply the configured policy to an event from an unmapped button.
+    async fn handle_unknown_button(&mut self, button: &mut PanelButton) {
+        self.stats.unknown_button_events += 1;
+        button.set_state(SPIButtonState::Off);
+
+        let (policy, command) = match &self.config.unknown_buttons {
+            Some(cfg) => (cfg.policy, cfg.command.clone()),
+            None => (UnknownButtonPolicy::Log, None),
+        };
+        match policy {
+            UnknownButtonPolicy::Ignore => {}
+            UnknownButtonPolicy::Log => {
+                warn_limited!("Event from unmapped button {}, check wiring or config", button.id());
+            }
+            UnknownButtonPolicy::DefaultCommand => {
+                let command = match command {
+                    Some(command) => command,
+                    None => {
+                        warn_limited!("unknown_buttons policy is default_command but no command is set");
+                        return;
+                    }
+                };
+                let correlation_id = Uuid::new_v4();
+                info!("[{}] Unmapped button {} event, running default command", correlation_id, button.id());
+                let env = vec![
+                    ("SPIBTN_CORRELATION_ID", correlation_id.to_string()),
+                    ("SPIBTN_BUTTON", button.id().to_string()),
+                ];
+                let mut record = ActionRecord::new(correlation_id, button.id(), None, &command);
+                if self.config.observer.unwrap_or(false) {
+                    record.finish(Outcome::Suppressed, "");
+                } else {
+                    match CommandExecutor::execute_blocking(&command, env).await {
+                        Ok(output) => record.finish(Outcome::Succeeded, &output),
+                        Err(e) => record.finish(Outcome::Failed("command failed".to_string()), &e.to_string()),
+                    }
+                }
+                self.record(record);
+            }
+        }
+    }
+
+    /// Track an issued Klipper request until its response arrives. A request
+    /// dropped to stay within `limits.pending_requests` is marked failed.
+    pub fn track_request(&mut self, request_id: u32, button: ButtonId, correlation_id: Uuid) {
+        let request = PendingRequest { button, correlation_id };
+        if let Some((request_id, request)) = self.requests.insert(request_id, request) {
+            self.drop_request(request_id, request);
+        }
+    }
+
+    /// The button and correlation id of a tracked request.
+    pub fn pending_request(&self, request_id: u32) -> Option<&PendingRequest> {
+        self.requests.get(request_id)
+    }
+
+    /// Stop tracking a request, e.g. once its response arrived.
+    pub fn untrack_request(&mut self, request_id: u32) -> Option<PendingRequest> {
+        self.requests.remove(request_id)
+    }
+
+    /// Give up on a request: its response is ignored if it still arrives,
+    /// and its button returns to idle.
+    fn drop_request(&mut self, request_id: u32, request: PendingRequest) {
+        warn_limited!(
+            "More than {} Klipper requests pending, no longer waiting for some",
+            self.requests.capacity()
+        );
+        info!("[{}] Dropped pending request id={}", request.correlation_id, request_id);
+        self.stats.dropped_requests += 1;
+        let outcome = Outcome::Failed("dropped, too many requests pending".to_string());
+        self.history.complete_request(request_id, outcome, "");
+        self.release_led(request.button, LedLayer::Action);
+    }
+
+    /// Cap and overflow policy of the pending requests.
+    fn request_limits(config: &Config) -> (usize, Overflow) {
+        let limits = config.limits.clone().unwrap_or_default();
+        (
+            limits.pending_requests.unwrap_or(DEFAULT_PENDING_REQUESTS),
+            limits.pending_overflow.unwrap_or_default(),
+        )
+    }
+
+    /// Record the outcome of a Klipper request in the action history.
+    pub fn complete_request(&mut self, request_id: u32, success: bool, output: &str) {
+        let outcome = if success {
+            Outcome::Succeeded
+        } else {
+            Outcome::Failed("klipper error".to_string())
+        };
+        if let Some(record) = self.history.complete_request(request_id, outcome, output) {
+            let event = ControllerEvent::finished(record);
+            let (button, outcome) = (record.button, record.outcome.clone());
+            self.emit(event);
+            self.machine_result(button, &outcome);
+        }
+    }
+
+    /// Button presses and releases, action results and panel health as they
+    /// happen, for programs embedding the daemon.
+    pub fn events(&self) -> impl Stream<Item = ControllerEvent> {
+        controller::subscribe(&self.events)
+    }
+
+    fn emit(&self, event: ControllerEvent) {
+        // Nobody subscribed is fine
+        let _ = self.events.send(event);
+    }
+
+    /// Keep an executed action for `spibuttonctl last`, announcing it once
+    /// it has finished.
+    fn record(&mut self, record: ActionRecord) {
+        if record.outcome != Outcome::Pending {
+            self.emit(ControllerEvent::finished(&record));
+            self.machine_result(record.button, &record.outcome);
+        }
+        self.history.push(record);
+    }
+
+    /// Allocate a request id and announce it to the main loop, which then
+    /// correlates the response with the button. `None` without a response queue.
+    fn issue_request(&mut self, button_id: ButtonId, correlation_id: Uuid) -> Option<(u32, Sender<EventMessage>)> {
+        let tx = self.response_tx.clone()?;
+        self.id_next += 1;
+        let request_id = self.id_next;
+        let _ = tx.try_send(EventMessage::Issued { request_id, correlation_id, trigger_button: button_id });
+        Some((request_id, tx))
+    }
+
+    /// Queue the result of a state machine's command as its next event.
+    fn machine_result(&mut self, button_id: ButtonId, outcome: &Outcome) {
+        let event = match outcome {
+            Outcome::Pending => return,
+            Outcome::Failed(_) => MachineEvent::Failure,
+            Outcome::Succeeded | Outcome::Suppressed => MachineEvent::Success,
+        };
+        if self.mapping(button_id).is_ok_and(|m| m.state_machine.is_some()) {
+            self.machine_results.push_back((button_id, event));
+        }
+    }
+
+    /// Move a button's state machine on `event`, showing the new state's
+    /// LED and running the transition's command.
+    async fn machine_event(&mut self, button_id: ButtonId, event: MachineEvent) {
+        let Some(machine) = self.mapping(button_id).ok().and_then(|m| m.state_machine.clone()) else { return };
+        let from = self.machines.state(button_id, &machine).to_string();
+        let Some(transition) = self.machines.fire(button_id, &machine, event) else {
+            debug!("Button {} in state {}: nothing to do on {:?}", button_id, from, event);
+            return;
+        };
+        info!("Button {} state {} -> {} on {:?}", button_id, from, transition.to, event);
+        self.peers.share(PeerUpdate::State { button: button_id, state: transition.to.clone() });
+        // The new state replaces the outcome shown of the previous command
+        self.leds.release(button_id, LedLayer::Action);
+        self.show_machine_state(button_id, &machine);
+        if let Some(command) = &transition.command {
+            let mut button = PanelButton::new(button_id, SPIButtonState::On);
+            self.process_triggers(&mut button, command).await;
+            self.set_button_state(button_id, button.get_state());
+        }
+    }
+
+    fn show_machine_state(&mut self, button_id: ButtonId, machine: &config::StateMachine) {
+        let state = machine.states.get(self.machines.state(button_id, machine));
+        let pattern = state
+            .and_then(|s| s.pattern.as_ref())
+            .and_then(|name| self.config.led_patterns.as_ref()?.get(name));
+        match pattern {
+            Some(pattern) => {
+                // Held On while the pattern runs, write_led shows the pattern
+                self.patterns.start(button_id, LedLayer::State, pattern.clone(), Instant::now());
+                self.set_led(button_id, LedLayer::State, SPIButtonState::On);
+            }
+            None => {
+                self.patterns.stop(button_id, LedLayer::State);
+                let led = state.and_then(|s| s.led).map_or(SPIButtonState::Off, |l| l.state());
+                self.set_led(button_id, LedLayer::State, led);
+            }
+        }
+    }
+
+    /// Show the state of every state machine, e.g. after a reload.
+    fn show_machine_states(&mut self) {
+        let mappings = &self.mappings;
+        self.machines.retain(|id| mappings.get(&id)?.state_machine.as_ref());
+        let buttons: Vec<(ButtonId, Option<config::StateMachine>)> =
+            self.config.buttons.iter().map(|m| (m.button, m.state_machine.clone())).collect();
+        for (button_id, machine) in buttons {
+            match machine {
+                Some(machine) => self.show_machine_state(button_id, &machine),
+                None if self.leds.holds(button_id, LedLayer::State) => {
+                    self.patterns.stop(button_id, LedLayer::State);
+                    self.release_led(button_id, LedLayer::State);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Show the selected button of each radio group, e.g. after a reload.
+    fn show_radio_groups(&mut self) {
+        let groups = self.config.radio_groups.clone().unwrap_or_default();
+        self.radio.retain(&groups);
+        for (name, group) in &groups {
+            let led = group.led.map_or(SPIButtonState::On, |l| l.state());
+            for button_id in &group.buttons {
+                let selected = self.radio.selected(name) == Some(*button_id);
+                self.set_led(*button_id, LedLayer::State, if selected { led } else { SPIButtonState::Off });
+            }
+        }
+    }
+
+    /// Select the button in its radio group, if it is in one, running the
+    /// `deselect_command` of the button selected before.
+    async fn select_radio(&mut self, button_id: ButtonId) {
+        let Some(groups) = self.config.radio_groups.clone() else { return };
+        let Some(selection) = self.radio.select(&groups, button_id) else { return };
+        let led = groups[&selection.group].led.map_or(SPIButtonState::On, |l| l.state());
+        self.set_led(button_id, LedLayer::State, led);
+        let Some(previous) = selection.deselected else { return };
+        info!("Button {} selected in radio group {}, deselecting button {}", button_id, selection.group, previous);
+        self.release_led(previous, LedLayer::State);
+        if let Some(command) = self.mapping(previous).ok().and_then(|m| m.deselect_command.clone()) {
+            let rendered = expr::render(command.trim(), &self.scope());
+            let mut button = self.spi.get_button(previous);
+            self.execute(&mut button, &rendered).await;
+            self.set_button_state(previous, button.get_state());
+        }
+    }
+
+    /// Selected button of each radio group, for `spibuttonctl`.
+    pub fn radio_selections(&self) -> Vec<(String, ButtonId)> {
+        self.radio.iter().map(|(name, button)| (name.clone(), *button)).collect()
+    }
+
+    /// States of the buttons with a state machine, for `spibuttonctl`.
+    pub fn machine_states(&self) -> Vec<(ButtonId, String)> {
+        self.config
+            .buttons
+            .iter()
+            .filter_map(|m| Some((m.button, self.machines.state(m.button, m.state_machine.as_ref()?).to_string())))
+            .collect()
+    }
+
+    /// LED state of an idle button: its indicator or LED rules if it has
+    /// any, else off.
+    pub fn idle_state(&self, button_id: ButtonId) -> SPIButtonState {
+        let Ok(mapping) = self.mapping(button_id) else { return SPIButtonState::Off };
+        match (mapping.indicator, &mapping.led_rules) {
+            (Some(indicator), _) => self.indicators.led(indicator),
+            (None, Some(rules)) => indicator::rule_led(rules, &self.scope()),
+            (None, None) => SPIButtonState::Off,
+        }
+    }
+
+    /// Evaluate the `led_rules` again, e.g. after the printer state or a
+    /// variable changed.
+    fn refresh_led_rules(&mut self) {
+        let ruled: Vec<ButtonId> = self.config.buttons.iter().filter(|m| m.led_rules.is_some()).map(|m| m.button).collect();
+        for button_id in ruled {
+            self.set_led(button_id, LedLayer::Indicator, self.idle_state(button_id));
+        }
+    }
+
+    /// Show the indicators and LED rules again after what they show
+    /// changed: right away, or with the next LED update while throttled.
+    fn update_idle_leds(&mut self) {
+        if self.throttle.is_throttled() {
+            self.idle_leds_stale = true;
+        } else {
+            self.refresh_idle_leds();
+        }
+    }
+
+    fn refresh_idle_leds(&mut self) {
+        let idle: Vec<ButtonId> = self
+            .config
+            .buttons
+            .iter()
+            .filter(|m| m.indicator.is_some() || m.led_rules.is_some())
+            .map(|m| m.button)
+            .collect();
+        for button_id in idle {
+            self.set_led(button_id, LedLayer::Indicator, self.idle_state(button_id));
+        }
+    }
+
+    /// Share state with the daemons of other panels from now on, see
+    /// `sync`. Their updates arrive on `tx` as `EventMessage::Peer`.
+    pub fn connect_peers(&mut self, config: &SyncConfig, tx: Sender<EventMessage>) -> Result<()> {
+        let link = peer::spawn(config, self.peers.node(), tx)?;
+        self.peers.connect(link);
+        Ok(())
+    }
+
+    fn share_led(&mut self, button_id: ButtonId, layer: SharedLayer, state: SPIButtonState) {
+        if let Some(led) = peer::led_of(state) {
+            self.peers.share(PeerUpdate::Led { button: button_id, layer, led });
+        }
+    }
+
+    /// Apply a change made on another panel, unless this daemon has a newer
+    /// one. Nothing runs here, the other daemon ran the commands.
+    pub fn handle_peer(&mut self, envelope: Envelope) {
+        let Some(update) = self.peers.accept(envelope) else { return };
+        debug!("Peer update: {:?}", update);
+        if let Some(button_id) = update.button().filter(|b| self.mapping(*b).is_err()) {
+            warn_limited!("Ignoring peer update: {}", DaemonError::UnknownButton(button_id));
+            return;
+        }
+        match update {
+            PeerUpdate::Hello { .. } => self.peers.resend(),
+            PeerUpdate::Led { button, layer: SharedLayer::Action, led } => self.show_action(button, led.state()),
+            PeerUpdate::Led { button, layer, led } => self.set_led(button, layer.layer(), led.state()),
+            PeerUpdate::Armed { button, window_ms } => {
+                let window = Duration::from_millis(window_ms);
+                info!("Button {} armed on another panel", button);
+                self.arming.arm(button, Instant::now() + window);
+                self.flash_led(button, SPIButtonState::Flash2, window);
+            }
+            PeerUpdate::Disarmed { button } => {
+                self.arming.disarm(button);
+                self.patterns.stop(button, LedLayer::Feedback);
+                self.release_led(button, LedLayer::Feedback);
+            }
+            PeerUpdate::State { button, state } => {
+                let Some(machine) = self.state_machine_of(button).cloned() else {
+                    warn_limited!("Ignoring peer state {} of button {}, it has no state machine", state, button);
+                    return;
+                };
+                if !self.machines.set(button, &machine, &state) {
+                    warn_limited!("Ignoring peer state {} of button {}, its state machine has no such state", state, button);
+                    return;
+                }
+                info!("Button {} state {} from another panel", button, state);
+                self.leds.release(button, LedLayer::Action);
+                self.show_machine_state(button, &machine);
+            }
+        }
+    }
+
+    /// Show Moonraker notifications on the LEDs of the buttons they concern,
+    /// and carry out remote method calls from Klipper macros.
+    pub fn handle_notification(&mut self, notification: &Notification) {
+        match RemoteCall::parse(notification) {
+            Ok(Some(call)) => return self.handle_remote_call(call),
+            Ok(None) => {}
+            Err(e) => {
+                warn_limited!("Ignoring {} call: {}", notification.method, e);
+                return;
+            }
+        }
+        if self.printer.update(notification) {
+            self.update_idle_leds();
+            return;
+        }
+        if self.indicators.update(notification) {
+            info!("Indicators: {:?}", self.indicators);
+            self.update_idle_leds();
+        }
+        if notification.method != "notify_timelapse_event" {
+            return;
+        }
+        let event = match notification.event() {
+            Some(event) if event["action"] == "render" => event,
+            _ => return,
+        };
+        let status = event["status"].as_str().unwrap_or("");
+        let led = match status {
+            "started" | "running" => SPIButtonState::Flash1,
+            "success" => SPIButtonState::Off,
+            "error" => SPIButtonState::Flash2,
+            _ => return,
+        };
+        info!("Timelapse render {}", status);
+        let buttons: Vec<ButtonId> = self
+            .config
+            .buttons
+            .iter()
+            .filter(|m| m.command.trim() == "timelapse:render")
+            .map(|m| m.button)
+            .collect();
+        for button_id in buttons {
+            self.set_button_state(button_id, led);
+        }
+    }
+
+    fn handle_remote_call(&mut self, call: RemoteCall) {
+        info!("Remote call from Klipper: {:?}", call);
+        match call {
+            RemoteCall::SetLed { button, state } => {
+                if self.mapping(button).is_ok() {
+                    self.set_led(button, LedLayer::Remote, state);
+                    self.share_led(button, SharedLayer::Remote, state);
+                } else {
+                    warn_limited!("spibtn_set_led: {}", DaemonError::UnknownButton(button));
+                }
+            }
+            RemoteCall::Disable(buttons) => self.disabled.extend(buttons),
+            RemoteCall::SetProfile(name) => {
+                if let Err(e) = self.set_profile(&name) {
+                    warn_limited!("spibtn_set_profile: {}", e);
+                }
+            }
+            RemoteCall::Enable(buttons) => {
+                for button in buttons {
+                    self.disabled.remove(&button);
+                }
+            }
+            RemoteCall::SetBrightness { buttons, percent } => {
+                if !self.spi.capabilities().dimming {
+                    warn_limited!("spibtn_set_brightness: the panel cannot dim its LEDs");
+                }
+                for button in buttons {
+                    self.spi.set_brightness(button, percent);
+                }
+            }
+            RemoteCall::AddMapping { .. } | RemoteCall::RemoveMapping { .. } if !self.remote_mappings_allowed() => {
+                warn_limited!("Ignoring a mapping change from Klipper, set moonraker.allow_remote_mappings to allow them");
+            }
+            RemoteCall::AddMapping { mapping, persist } => {
+                if let Err(e) = self.add_mapping(*mapping, persist) {
+                    warn_limited!("spibtn_add_mapping: {}", e);
+                }
+            }
+            RemoteCall::RemoveMapping { button, persist } => {
+                if let Err(e) = self.remove_mapping(button, persist) {
+                    warn_limited!("spibtn_remove_mapping: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Anything that can send G-code can call remote methods, and a mapping
+    /// runs its shell command as the daemon's user, so this takes an opt-in.
+    fn remote_mappings_allowed(&self) -> bool {
+        self.config.moonraker.as_ref().and_then(|m| m.allow_remote_mappings).unwrap_or(false)
+    }
+
+    /// Whether a button's `enabled_between` window allows presses right now.
+    fn is_enabled_now(&self, button_id: ButtonId) -> bool {
+        let window = match self.mapping(button_id).map(|m| &m.enabled_between) {
+            Ok(Some(spec)) => spec,
+            _ => return true,
+        };
+        match TimeWindow::parse(window) {
+            Ok(window) => window.contains(chrono::Local::now().time()),
+            Err(e) => {
+                // A broken schedule must not lock the button out
+                warn_limited!("Ignoring schedule for button {}: {}", button_id, e);
+                true
+            }
+        }
+    }
+
+    /// Whether a button's `when` condition allows presses right now.
+    fn condition_holds(&self, button_id: ButtonId) -> bool {
+        match self.mapping(button_id).map(|m| &m.when) {
+            Ok(Some(when)) => expr::condition_holds(when, &self.scope()),
+            _ => true,
+        }
+    }
 
-    fn init(config: &Config, spi: &mut SPIButtonController)
+    fn scope(&self) -> Scope<'_> {
+        Scope {
+            variables: &self.variables,
+            printer: &self.printer,
+            press: None,
+        }
+    }
+
+    /// Show what is beneath LED flashes that have ended, and with `animate`
+    /// the next step of the flash patterns shown.
+    fn reset_expired_leds(&mut self, animate: bool) {
+        for button_id in self.leds.expire(Instant::now()) {
+            if !self.leds.holds(button_id, LedLayer::Feedback) {
+                self.patterns.stop(button_id, LedLayer::Feedback);
+            }
+            self.write_led(button_id, self.leds.shown(button_id));
+        }
+        if !animate {
+            return;
+        }
+        for (button_id, layer) in self.patterns.tick(Instant::now()) {
+            if self.leds.owner(button_id) == Some(layer) {
+                self.write_led(button_id, self.leds.shown(button_id));
+            }
+        }
+    }
+
+    /// Read the host's temperature and load now and then, throttling LED
+    /// updates while either is too high.
+    fn check_throttle(&mut self, now: Instant) {
+        let reading = match self.throttle.check(now, self.config.throttle.as_ref(), throttle::read_host) {
+            Some(_) => self.throttle.reading().unwrap_or_default(),
+            None => return,
+        };
+        if self.throttle.is_throttled() {
+            warn!("Host is hot or busy ({}), slowing LED animations and indicators", reading);
+        } else {
+            info!("Host cooled down ({}), LED animations and indicators at full rate", reading);
+        }
+    }
+
+    fn init(config: &Config, spi: &mut dyn PanelProtocol)
     {
         for register_map in &config.buttons {
-            let btn = SPIButton::new( register_map.config.unwrap_or( SPIButtonState::OnChange as u8 ) );
-            spi.set_button(register_map.button, btn);
+            spi.configure(register_map.button, register_map.config.unwrap_or( SPIButtonState::OnChange as u8 ));
+            spi.set_brightness(register_map.button, register_map.brightness.unwrap_or(100));
             info!(
                 "  - Button {:?}: {:?}",
                 register_map.button, register_map.description
@@ -56,133 +1099,1004 @@ impl Daemon {
         }
     }
 
+    /// The grace period starting now, see `polling.startup_grace_ms`.
+    fn grace(config: &Config) -> StartupGrace {
+        let period = Duration::from_millis(config.polling.startup_grace_ms.unwrap_or(0));
+        StartupGrace::new(Instant::now(), period)
+    }
+
     pub async fn poll(&mut self) -> Result<()> {
-        let events = self.spi.loop_once().expect("Controller poll error.");
+        self.step().await?;
+
+        // Sleep until the next polling group is due
+        sleep(self.poll_timers.until_next(Instant::now())).await;
+
+        Ok(())
+    }
+
+    /// Read the panel once and handle what happened, without waiting for
+    /// the next polling interval.
+    pub async fn step(&mut self) -> Result<()> {
+        if let Some(slept) = self.sleep.check(Instant::now(), clock::boot_time) {
+            self.resume(slept);
+        }
+        let events = match self.spi.loop_once() {
+            Ok(events) => events,
+            Err(e) => {
+                if !std::mem::replace(&mut self.transport_failing, true) {
+                    self.emit(ControllerEvent::TransportFailed(e.to_string()));
+                }
+                return Err(Error::spi("Controller poll error", e));
+            }
+        };
+        if std::mem::replace(&mut self.transport_failing, false) {
+            self.emit(ControllerEvent::TransportRecovered);
+        }
+        self.noise.record(Instant::now(), &events);
+        let mappings = &self.mappings;
+        let group_of = |id: ButtonId| mappings.get(&id).and_then(|m| m.poll_group.clone());
+        let events = self.poll_timers.take_events(Instant::now(), events, group_of);
+        let events = self.grace.filter(Instant::now(), events);
+        let polling = &self.config.polling;
+        let min_press_of = |id: ButtonId| {
+            let ms = mappings.get(&id).and_then(|m| m.min_press_ms).or(polling.min_press_ms).unwrap_or(0);
+            Duration::from_millis(ms)
+        };
+        let events = self.press_filter.filter(Instant::now(), events, min_press_of);
+        let chord_window = Duration::from_millis(polling.chord_window_ms.unwrap_or(DEFAULT_CHORD_WINDOW_MS));
+        let chords = self.config.chords.as_deref().unwrap_or(&[]);
+        let (mut events, chords_fired) = self.chords.filter(Instant::now(), events, chords, chord_window);
+        for event in events.iter().filter_map(ControllerEvent::from_panel) {
+            self.emit(event);
+        }
+        // A modifier pressed together with another button, within the same
+        // read, shifts it whatever their order on the panel
+        events.sort_by_key(|b| !(matches!(b.get_state(), SPIButtonState::On) && self.is_modifier(b.id())));
 
         // The application logic
-        for i in 0..events.len() {
-            let mut b = events[i];
-            println!("Button {}: State {:?}", b.id(), b.get_state());
-            /*
-            if b.is_hold_event() {
-                match b.get_state() {
-                    SPIButtonState::Off => b.set_state(SPIButtonState::On),
-                    SPIButtonState::On => b.set_state(SPIButtonState::Flash1),
-                    SPIButtonState::Flash1 => b.set_state(SPIButtonState::Flash2),
-                    SPIButtonState::Flash2 => b.set_state(SPIButtonState::Off),
-                    _ => {}
-                }
-                b.clear_hold_event();
-                controller.set_button(b.id(), b);
-            }
-            */
+        for mut b in events {
+            debug!("Button {}: State {:?}", b.id(), b.get_state());
+            if self.mapping(b.id()).is_err() {
+                if matches!(b.get_state(), SPIButtonState::On) {
+                    self.handle_unknown_button(&mut b).await;
+                    self.set_button_state(b.id(), b.get_state());
+                }
+                continue;
+            }
+            let debounce = self.debounce_window(b.id());
+            if !self.debouncer.accept(b.id(), Instant::now(), debounce) {
+                debug!("Button {} bounced, ignoring {:?}", b.id(), b.get_state());
+                continue;
+            }
+            if matches!(b.get_state(), SPIButtonState::Off) {
+                self.held.remove(&b.id());
+                self.presses.release(b.id(), Instant::now());
+            } else {
+                self.held.insert(b.id());
+                self.presses.press(b.id(), Instant::now(), SystemTime::now());
+            }
+            if matches!(b.get_state(), SPIButtonState::On) {
+                // The press completing a combo runs it instead of its own command
+                let combos = self.config.combos.as_deref().unwrap_or(&[]);
+                if let Some(index) = self.combos.press(b.id(), Instant::now(), combos) {
+                    self.fire_combo(index, b.id()).await;
+                    continue;
+                }
+            }
             match b.get_state() {
+                SPIButtonState::On if self.cooldowns.refuse_press(b.id(), Instant::now()) => {
+                    info!("Button {} pressed during its cooldown, ignoring", b.id());
+                    if self.mapping(b.id()).is_ok_and(|m| m.cooldown_led.unwrap_or(false)) {
+                        self.flash_led(b.id(), SPIButtonState::Flash1, REFUSAL_FLASH);
+                    }
+                },
+                SPIButtonState::Off if self.cooldowns.refuse_release(b.id()) => {},
+                SPIButtonState::On if self.disabled.contains(&b.id()) => {
+                    info!("Button {} disabled by Klipper, ignoring", b.id());
+                    self.write_led(b.id(), self.leds.shown(b.id()));
+                },
+                SPIButtonState::On if !self.is_enabled_now(b.id()) => {
+                    // Outside the button's schedule: refuse with a brief flash
+                    info!("Button {} pressed outside its enabled window, ignoring", b.id());
+                    self.flash_led(b.id(), SPIButtonState::Flash1, REFUSAL_FLASH);
+                },
+                SPIButtonState::On if !self.condition_holds(b.id()) => {
+                    info!("Button {} pressed while its condition does not hold, ignoring", b.id());
+                    self.flash_led(b.id(), SPIButtonState::Flash1, REFUSAL_FLASH);
+                },
+                SPIButtonState::On if self.is_modifier(b.id()) => {
+                    info!("Modifier button {} held", b.id());
+                    self.modifiers_held.insert(b.id());
+                },
+                SPIButtonState::Off if self.is_modifier(b.id()) => {
+                    self.modifiers_held.remove(&b.id());
+                    self.write_led(b.id(), self.leds.shown(b.id()));
+                },
+                SPIButtonState::On if self.state_machine_of(b.id()).is_some() => {
+                    self.leds.release(b.id(), LedLayer::Feedback);
+                    if self.state_machine_of(b.id()).is_some_and(|m| m.uses_long_press()) {
+                        // Told apart from a long press on release
+                        self.holds.press(b.id(), Instant::now());
+                    } else {
+                        self.machine_event(b.id(), MachineEvent::Press).await;
+                    }
+                },
+                SPIButtonState::Off if self.state_machine_of(b.id()).is_some() => {
+                    if let Some(held) = self.holds.release(b.id(), Instant::now()) {
+                        let long = self.state_machine_of(b.id()).is_some_and(|m| held >= m.long_press());
+                        let event = if long { MachineEvent::LongPress } else { MachineEvent::Press };
+                        self.machine_event(b.id(), event).await;
+                    }
+                },
+                SPIButtonState::On if self.shift_command(b.id()).is_some() => {
+                    self.leds.release(b.id(), LedLayer::Feedback);
+                    let command = self.shift_command(b.id()).unwrap_or_default();
+                    info!("Button {} pressed with modifier held", b.id());
+                    self.process_triggers(&mut b, &command).await;
+                    self.set_button_state(b.id(), b.get_state());
+                },
+                SPIButtonState::On if self.has_hold_tiers(b.id()) => {
+                    // The command is chosen on release by how long it was held
+                    self.leds.release(b.id(), LedLayer::Feedback);
+                    self.holds.press(b.id(), Instant::now());
+                    self.write_led(b.id(), self.leds.shown(b.id()));
+                },
+                SPIButtonState::Off if self.has_hold_tiers(b.id()) => {
+                    self.leds.release(b.id(), LedLayer::Feedback);
+                    self.release_hold(&mut b).await;
+                    self.set_button_state(b.id(), b.get_state());
+                },
                 SPIButtonState::On => {
-                    // Process value triggers
-                    self.process_triggers(&mut b)
-                        .await;
-                    self.spi.set_button(b.id(), b);
+                    self.leds.release(b.id(), LedLayer::Feedback);
+                    let mapping = match self.mapping(b.id()) {
+                        Ok(mapping) => mapping,
+                        Err(e) => {
+                            warn_limited!("Dropping event: {}", e);
+                            continue;
+                        }
+                    };
+                    match &mapping.sequences {
+                        Some(sequences) if !sequences.is_empty() => {
+                            // Count presses, the command is chosen once the sequence ends
+                            let window = Duration::from_millis(
+                                mapping.sequence_window_ms.unwrap_or(DEFAULT_SEQUENCE_WINDOW_MS),
+                            );
+                            let longest = sequences.iter().map(|s| s.presses).max().unwrap_or(1);
+                            let count = self.gestures.press(b.id(), Instant::now(), window);
+                            if count >= longest {
+                                self.gestures.take(b.id());
+                                self.fire_sequence(b.id(), count).await;
+                            }
+                        }
+                        _ => {
+                            // Process value triggers
+                            let command = mapping.command.clone();
+                            if command.trim().is_empty() {
+                                // Only the release runs something
+                                continue;
+                            }
+                            match self.deferral(b.id()) {
+                                Some(when) => self.defer(&mut b, &command, when),
+                                None => self.process_triggers(&mut b, &command).await,
+                            }
+                            self.set_button_state(b.id(), b.get_state());
+                        }
+                    }
+                },
+                SPIButtonState::Off if self.release_command(b.id()).is_some() => {
+                    let command = self.release_command(b.id()).unwrap_or_default();
+                    info!("Button {} released", b.id());
+                    self.process_triggers(&mut b, &command).await;
+                    self.set_button_state(b.id(), b.get_state());
                 },
                 _ => {}
             }
         }
 
+        for index in chords_fired {
+            self.fire_chord(index).await;
+        }
+
+        // State machines moved on by the results of their commands
+        while let Some((button_id, event)) = self.machine_results.pop_front() {
+            self.machine_event(button_id, event).await;
+        }
+
+        // Press sequences whose window closed without another press
+        for (button_id, count) in self.gestures.take_expired(Instant::now()) {
+            self.fire_sequence(button_id, count).await;
+        }
+
+        // Show the hold tier a release would fire
+        let mappings = &self.mappings;
+        let tiers_of = |id: ButtonId| mappings.get(&id).and_then(|m| m.hold_tiers.as_deref()).unwrap_or(&[]);
+        for (button_id, tier) in self.holds.advanced(Instant::now(), tiers_of) {
+            self.patterns.stop(button_id, LedLayer::Feedback);
+            self.set_led(button_id, LedLayer::Feedback, hold::tier_led(tier));
+        }
+
+        // Deferred actions whose time has come, times of day recomputed
+        // first should NTP have stepped the clock
+        if let Some(step) = self.clock.check(chrono::Local::now(), Instant::now()) {
+            let recomputed = self.deferred.reschedule(step);
+            info!(
+                "Wall clock stepped by {}s, recomputed {} scheduled action(s)",
+                step.num_seconds(),
+                recomputed
+            );
+        }
+        for action in self.deferred.take_due(Instant::now()) {
+            info!("Running deferred action of button {}", action.button_id);
+            let mut button = self.spi.get_button(action.button_id);
+            self.process_triggers(&mut button, &action.command).await;
+            self.set_button_state(action.button_id, button.get_state());
+        }
 
+        // LED animations and indicators, spaced out while throttled
+        self.check_throttle(Instant::now());
+        let led_update = self.throttle.led_update_due(Instant::now(), self.config.throttle.as_ref());
+        if led_update && std::mem::take(&mut self.idle_leds_stale) {
+            self.refresh_idle_leds();
+        }
+        self.reset_expired_leds(led_update);
+        self.check_backpressure(Instant::now());
+        self.check_panel(Instant::now());
 
-        // Sleep for the configured polling interval
-        sleep(Duration::from_millis(self.config.polling.interval_ms)).await;
+        // Summarise warnings that stopped repeating
+        ratelimit::flush();
 
         Ok(())
     }
 
+    /// Recover from the host being suspended for `slept`. The panel may
+    /// have lost power and is re-initialized with its LEDs written again,
+    /// the circuits start closed, and whatever was queued before the
+    /// suspend is dropped rather than run in a burst: delayed and overdue
+    /// deferred actions, unfinished press sequences, holds, chords, combos
+    /// and armed buttons. Actions for a later time of day are kept.
+    fn resume(&mut self, slept: Duration) {
+        warn!("Host was suspended for {}s, re-initializing the panel", slept.as_secs());
+        if let Err(e) = self.spi.reinitialize() {
+            warn_limited!("Failed to re-initialize the panel: {}", e);
+        }
+        Daemon::init(&self.config, &mut self.spi);
+        let button_ids: Vec<ButtonId> = self.mappings.keys().copied().collect();
+        for button_id in button_ids {
+            self.write_led(button_id, self.leds.shown(button_id));
+        }
+        self.breakers = Breakers::new();
+
+        // Delays and times of day passed while suspended are dropped, the
+        // later times of day recomputed for the wall clock having moved on
+        let wall = chrono::Local::now();
+        let cancelled = self.deferred.cancel_stale(wall);
+        if cancelled > 0 {
+            info!("Dropped {} deferred action(s) queued before the suspend", cancelled);
+        }
+        if let Some(step) = self.clock.check(wall, Instant::now()) {
+            self.deferred.reschedule(step);
+        }
+        self.gestures = Gestures::new();
+        self.holds = Holds::new();
+        self.chords = Chords::new();
+        self.combos = Combos::new();
+        self.arming = Arming::new();
+        self.press_filter = PressFilter::new();
+        self.grace = Daemon::grace(&self.config);
+        self.emit(ControllerEvent::Resumed(slept));
+    }
+
+    /// Re-initialize the panel when its identity changed, e.g. a different
+    /// panel was plugged in, and check the config still suits it.
+    fn check_panel(&mut self, now: Instant) {
+        let interval = self.config.polling.panel_check_ms.unwrap_or(DEFAULT_PANEL_CHECK_MS);
+        if interval == 0 || self.panel_id.is_none() || now - self.panel_checked < Duration::from_millis(interval) {
+            return;
+        }
+        self.panel_checked = now;
+        let id = match self.spi.identify() {
+            Ok(id) if id == self.panel_id => return,
+            Ok(id) => id,
+            Err(e) => {
+                warn_limited!("Failed to read the panel identity: {}", e);
+                return;
+            }
+        };
+        warn!(
+            "Panel identity changed from {:08x} to {:08x}, re-initializing",
+            self.panel_id.unwrap_or_default(),
+            id.unwrap_or_default()
+        );
+        if let Err(e) = self.spi.reinitialize() {
+            warn_limited!("Failed to re-initialize the panel: {}", e);
+            return;
+        }
+        Daemon::init(&self.config, &mut self.spi);
+        self.emit(ControllerEvent::PanelChanged);
+        // The identity after init is what the panel keeps reporting, and
+        // stays put while nothing answers
+        self.panel_id = self.spi.identify().ok().flatten().or(id);
+        info!("Panel capabilities: {}", self.spi.capabilities());
+        for problem in self.config.check_capabilities(self.spi.capabilities()) {
+            error!("Config does not suit the attached panel: {}", problem);
+        }
+    }
+
+    fn debounce_window(&self, button_id: ButtonId) -> Duration {
+        let ms = self
+            .mapping(button_id)
+            .ok()
+            .and_then(|m| m.debounce_ms)
+            .or(self.config.polling.debounce_ms)
+            .unwrap_or(0);
+        Duration::from_millis(ms)
+    }
+
+    fn is_modifier(&self, button_id: ButtonId) -> bool {
+        self.mapping(button_id)
+            .ok()
+            .and_then(|m| m.modifier)
+            .unwrap_or(false)
+    }
+
+    /// The command a release of the button runs, its `on_release`.
+    fn release_command(&self, button_id: ButtonId) -> Option<String> {
+        self.mapping(button_id).ok()?.on_release.clone()
+    }
+
+    /// The command a press runs instead of the normal one because a
+    /// modifier button is held, if the button has one.
+    fn shift_command(&self, button_id: ButtonId) -> Option<String> {
+        if self.modifiers_held.is_empty() {
+            return None;
+        }
+        self.mapping(button_id).ok()?.shift_command.clone()
+    }
+
+    fn state_machine_of(&self, button_id: ButtonId) -> Option<&config::StateMachine> {
+        self.mapping(button_id).ok()?.state_machine.as_ref()
+    }
+
+    fn has_hold_tiers(&self, button_id: ButtonId) -> bool {
+        self.mapping(button_id)
+            .ok()
+            .and_then(|m| m.hold_tiers.as_ref())
+            .is_some_and(|tiers| !tiers.is_empty())
+    }
+
+    /// Run the command of the longest hold tier reached when a button is
+    /// released, or its normal command after a short press.
+    async fn release_hold(&mut self, button: &mut PanelButton) {
+        let held = match self.holds.release(button.id(), Instant::now()) {
+            Some(held) => held,
+            None => return,
+        };
+        let mapping = match self.mapping(button.id()) {
+            Ok(mapping) => mapping,
+            Err(e) => {
+                warn_limited!("Dropping hold: {}", e);
+                return;
+            }
+        };
+        let tiers = mapping.hold_tiers.as_deref().unwrap_or(&[]);
+        let command = match hold::reached(tiers, held) {
+            Some(tier) => {
+                info!("Button {} released after {}ms, hold tier {}", button.id(), held.as_millis(), tier + 1);
+                tiers[tier].command.clone()
+            }
+            None => mapping.command.clone(),
+        };
+        if command.trim().is_empty() {
+            button.set_state(SPIButtonState::Off);
+            return;
+        }
+        self.process_triggers(button, &command).await;
+    }
+
+    /// How long after a press the button's command runs, `None` when it runs
+    /// right away.
+    fn deferral(&self, button_id: ButtonId) -> Option<Deferral> {
+        let mapping = self.mapping(button_id).ok()?;
+        if let Some(delay_ms) = mapping.delay_ms {
+            return Some(Deferral::After(Duration::from_millis(delay_ms)));
+        }
+        let at = mapping.at.as_ref()?;
+        match deferred::parse_time_of_day(at) {
+            Ok(at) => Some(Deferral::At(at)),
+            Err(e) => {
+                warn_limited!("Running button {} right away: {}", button_id, e);
+                None
+            }
+        }
+    }
+
+    /// Schedule a button's command, or cancel it when already scheduled. The
+    /// LED flashes slowly while the action is pending.
+    fn defer(&mut self, button: &mut PanelButton, command: &str, when: Deferral) {
+        if self.deferred.toggle(button.id(), command, when, Instant::now()) {
+            info!("Button {} scheduled to run {}: {}", button.id(), when, command);
+            button.set_state(SPIButtonState::Flash1);
+        } else {
+            info!("Button {} pressed again, pending action cancelled", button.id());
+            button.set_state(SPIButtonState::Off);
+        }
+    }
+
+    /// Run the command of a chord, on the LED of its first button.
+    async fn fire_chord(&mut self, index: usize) {
+        let Some(chord) = self.config.chords.iter().flatten().nth(index).cloned() else { return };
+        let Some(first) = chord.buttons.first().copied() else { return };
+        let buttons: Vec<String> = chord.buttons.iter().map(|b| b.to_string()).collect();
+        info!("Chord {} pressed", buttons.join("+"));
+        let mut button = self.spi.get_button(first);
+        self.process_triggers(&mut button, &chord.command).await;
+        self.set_button_state(first, button.get_state());
+    }
+
+    /// Run the command of a combo, on the LED of the button whose press
+    /// completed it.
+    async fn fire_combo(&mut self, index: usize, last: ButtonId) {
+        let Some(combo) = self.config.combos.iter().flatten().nth(index).cloned() else { return };
+        let presses: Vec<String> = combo.presses.iter().map(|b| b.to_string()).collect();
+        info!("Combo {} pressed", presses.join(","));
+        let mut button = self.spi.get_button(last);
+        self.process_triggers(&mut button, &combo.command).await;
+        self.set_button_state(last, button.get_state());
+    }
+
+    /// Run the command matching a completed press sequence. A single press
+    /// without a sequence of its own runs the button's normal command.
+    async fn fire_sequence(&mut self, button_id: ButtonId, count: u32) {
+        let mapping = match self.mapping(button_id) {
+            Ok(mapping) => mapping,
+            Err(e) => {
+                warn_limited!("Dropping press sequence: {}", e);
+                self.set_button_state(button_id, SPIButtonState::Off);
+                return;
+            }
+        };
+        let command = mapping
+            .sequences
+            .iter()
+            .flatten()
+            .find(|s| s.presses == count)
+            .map(|s| s.command.clone())
+            .or_else(|| (count == 1 && !mapping.command.trim().is_empty()).then(|| mapping.command.clone()));
+
+        let mut button = self.spi.get_button(button_id);
+        match command {
+            Some(command) => {
+                info!("Button {} pressed {} time(s)", button_id, count);
+                self.presses.set_taps(button_id, count);
+                self.process_triggers(&mut button, &command).await;
+            }
+            None => {
+                info!("Button {} pressed {} time(s), no matching sequence", button_id, count);
+                button.set_state(SPIButtonState::Off);
+            }
+        }
+        self.set_button_state(button_id, button.get_state());
+    }
+
     async fn process_triggers(
         &mut self,
-        button: &mut SPIButton,
+        button: &mut PanelButton,
+        command: &str,
     ) {        
+        let scope = Scope {
+            press: Some(self.presses.latest(button.id(), SystemTime::now())),
+            ..self.scope()
+        };
+        let rendered = expr::render(command.trim(), &scope);
+        let cmd = rendered.as_str();
+
+        // Commands that cannot be undone, and buttons set to `confirm`, need
+        // a confirming second press
+        let (confirm, window_ms) = self
+            .mapping(button.id())
+            .map_or((false, None), |m| (m.confirm.unwrap_or(false), m.confirm_window_ms));
+        if confirm || actions::requires_confirmation(cmd) {
+            let window = Duration::from_millis(window_ms.unwrap_or(DEFAULT_CONFIRM_WINDOW_MS));
+            let now = Instant::now();
+            if !self.arming.confirm(button.id(), now, window) {
+                // Fast flash until confirmed or the window closes
+                info!("Button {} armed, press again within {}ms to run: {}", button.id(), window.as_millis(), cmd);
+                button.set_state(SPIButtonState::Off);
+                self.flash_led(button.id(), SPIButtonState::Flash2, window);
+                self.peers.share(PeerUpdate::Armed { button: button.id(), window_ms: window.as_millis() as u64 });
+                return;
+            }
+            self.peers.share(PeerUpdate::Disarmed { button: button.id() });
+        }
+
+        if let Some(cooldown_ms) = self.mapping(button.id()).ok().and_then(|m| m.cooldown_ms) {
+            self.cooldowns.start(button.id(), Instant::now(), Duration::from_millis(cooldown_ms));
+        }
+        self.select_radio(button.id()).await;
+        self.execute(button, cmd).await;
+    }
+
+    /// Run a rendered command for `button`, recording it in the history.
+    async fn execute(&mut self, button: &mut PanelButton, cmd: &str) {
+        let group = self.mapping(button.id()).ok().and_then(|m| m.mutex.clone());
+        let group_lock = self.mutex_groups.get(group.as_deref());
+        let observer = self.config.observer.unwrap_or(false);
+        let down = if observer { None } else { self.endpoint_down(cmd) };
+
         // Execute the associated command
-        let cfg_button: &ButtonMapping = &self.config.buttons[button.id() as usize];
-        let cmd = cfg_button.command.trim();
-
-        if cmd.starts_with("klipper:") {
-            // Klipper API command syntax: klipper:METHOD|<JSON_PARAMS>
-            if let Some(klipper_cfg) = &self.config.klipper {
-                if let Some(tx) = &self.response_tx {
-                    let mut cmd_clone = cmd.to_string();
-                    let klipper_clone = klipper_cfg.clone();
-                    let tx_clone = tx.clone();
-
-                    // Generate request id and notify main loop that a request was issued
-                    self.id_next += 1;
-                    let request_id = self.id_next;
-                    let trigger_button = format!("{}", button.id());
+        let cfg_button: &ButtonMapping = match self.mapping(button.id()) {
+            Ok(mapping) => mapping,
+            Err(e) => {
+                warn_limited!("Not executing command: {}", e);
+                button.set_state(SPIButtonState::Off);
+                return;
+            }
+        };
+
+        // One correlation id per button event, carried through every log line,
+        // request and response that the event causes
+        let correlation_id = Uuid::new_v4();
+        info!(
+            "[{}] Button {} event: {:?}",
+            correlation_id, button.id(), cfg_button.description
+        );
+        let mut record = ActionRecord::new(correlation_id, button.id(), cfg_button.description.clone(), cmd);
+
+        if observer {
+            info!("[{}] Observer mode, suppressed command: {}", correlation_id, cmd);
+            button.set_state(SPIButtonState::Off);
+            record.finish(Outcome::Suppressed, "");
+        } else if let Some(endpoint) = down {
+            // Fail at once rather than wait on an endpoint that stopped answering
+            warn_limited!("[{}] Not sending to {}, its circuit is open: {}", correlation_id, endpoint, cmd);
+            button.set_state(SPIButtonState::Off);
+            self.flash_endpoint_down(button.id());
+            record.finish(Outcome::Failed(format!("{} down", endpoint)), "");
+        } else if cmd.starts_with(SET_VAR_PREFIX) {
+            // Variable assignment: set_var:NAME=VALUE
+            match vars::parse_assignment(cmd).and_then(|(name, value)| {
+                self.variables.set(&name, &value)?;
+                Ok((name, value))
+            }) {
+                Ok((name, value)) => {
+                    info!("[{}] Variable {} = {:?}", correlation_id, name, value);
+                    self.refresh_led_rules();
+                    button.set_state(SPIButtonState::Off);
+                    record.finish(Outcome::Succeeded, "");
+                }
+                Err(e) => {
+                    warn_limited!("Failed to set variable for register {:?}: {}", record.description, e);
+                    button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("set_var failed".to_string()), &e.to_string());
+                }
+            }
+        } else if cmd.starts_with("klipper:") || cmd == RECOVER_COMMAND {
+            // Klipper API command syntax: klipper:METHOD|<JSON_PARAMS>, or the
+            // built-in recovery flow
+            if let Some(klipper_clone) = self.config.klipper.clone() {
+                if let Some((request_id, tx)) = self.issue_request(button.id(), correlation_id) {
                     let value = match button.get_state() {
                         SPIButtonState::Off => "0",
-                        _ => "1", 
+                        _ => "1",
                     };
-                    cmd_clone = cmd_clone.replace("{{val}}", value );
+                    let cmd_clone = cmd.replace("{{val}}", value);
 
-                    // send Issued event so main can persist metadata
-                    let _ = tx.clone().try_send(EventMessage::Issued { request_id: request_id.clone(), trigger_button: trigger_button.clone() });
+                    // spawn the async request using the issued request_id
+                    let recover = cmd == RECOVER_COMMAND;
+                    tokio::spawn(self.in_flight.track(concurrency::exclusive(group_lock, correlation_id, async move {
+                        if recover {
+                            recovery::firmware_restart(&klipper_clone, request_id, correlation_id, tx).await;
+                        } else {
+                            CommandExecutor::send_klipper_command(&cmd_clone, &klipper_clone, request_id, correlation_id, tx).await;
+                        }
+                    })));
+                    // The recovery flow animates the LED until it finishes
+                    button.set_state(if recover { SPIButtonState::On } else { SPIButtonState::Off });
 
-                    // spawn the async request using the supplied request_id
-                    tokio::spawn(async move {
-                        CommandExecutor::send_klipper_command(&cmd_clone, &klipper_clone, request_id, tx_clone).await;
-                    });
-                    button.set_state(SPIButtonState::Off);
+                    // Completed when the response reaches the main loop
+                    record.request_id = Some(request_id);
                 } else {
-                    warn!("Klipper command requested but no response queue configured");
+                    warn_limited!("Klipper command requested but no response queue configured");
                     button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("no response queue".to_string()), "");
                 }
             } else {
-                warn!("Klipper command requested but no klipper config provided");
+                warn_limited!("Klipper command requested but no klipper config provided");
                 button.set_state(SPIButtonState::Flash2);
+                record.finish(Outcome::Failed("no klipper config".to_string()), "");
+            }
+        } else if cmd.starts_with(SNAPSHOT_PREFIX) {
+            // Webcam snapshot through Moonraker, syntax: snapshot:[WEBCAM]
+            match self.config.snapshot.clone() {
+                Some(snapshot_cfg) => match self.issue_request(button.id(), correlation_id) {
+                    Some((request_id, tx)) => {
+                        let cmd_clone = cmd.to_string();
+                        let moonraker_clone = self.config.moonraker.clone();
+                        tokio::spawn(self.in_flight.track(concurrency::exclusive(group_lock, correlation_id, async move {
+                            snapshot::take(&cmd_clone, moonraker_clone.as_ref(), &snapshot_cfg, request_id, correlation_id, tx).await;
+                        })));
+                        button.set_state(SPIButtonState::Off);
+                        record.request_id = Some(request_id);
+                    }
+                    None => {
+                        warn_limited!("Snapshot requested but no response queue configured");
+                        button.set_state(SPIButtonState::Flash2);
+                        record.finish(Outcome::Failed("no response queue".to_string()), "");
+                    }
+                },
+                None => {
+                    warn_limited!("Snapshot requested but no snapshot config provided");
+                    button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("no snapshot config".to_string()), "");
+                }
+            }
+        } else if cmd.starts_with(POWER_ON_PREFIX) {
+            // Power on, wait for Klipper, run a macro: power_on:DEVICE[|MACRO]
+            match PowerOn::parse(cmd) {
+                Ok(action) => match self.issue_request(button.id(), correlation_id) {
+                    Some((request_id, tx)) => {
+                        let moonraker_clone = self.config.moonraker.clone();
+                        tokio::spawn(self.in_flight.track(concurrency::exclusive(group_lock, correlation_id, async move {
+                            power::power_on(action, moonraker_clone.as_ref(), request_id, correlation_id, tx).await;
+                        })));
+                        // Progress messages animate the LED until it finishes
+                        button.set_state(SPIButtonState::On);
+                        record.request_id = Some(request_id);
+                    }
+                    None => {
+                        warn_limited!("Power on requested but no response queue configured");
+                        button.set_state(SPIButtonState::Flash2);
+                        record.finish(Outcome::Failed("no response queue".to_string()), "");
+                    }
+                },
+                Err(e) => {
+                    warn_limited!("Not executing command for register {:?}: {}", cfg_button.description, e);
+                    button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("invalid command".to_string()), &e);
+                }
+            }
+        } else if actions::is_action(cmd) {
+            // Built-in Moonraker actions, e.g. timelapse:render
+            match actions::parse(cmd) {
+                Ok(action) => match self.issue_request(button.id(), correlation_id) {
+                    Some((request_id, tx)) => {
+                        let moonraker_clone = self.config.moonraker.clone();
+                        tokio::spawn(self.in_flight.track(concurrency::exclusive(group_lock, correlation_id, async move {
+                            moonraker::call(moonraker_clone.as_ref(), &action, request_id, correlation_id, tx).await;
+                        })));
+                        button.set_state(SPIButtonState::Off);
+                        record.request_id = Some(request_id);
+                    }
+                    None => {
+                        warn_limited!("Moonraker action requested but no response queue configured");
+                        button.set_state(SPIButtonState::Flash2);
+                        record.finish(Outcome::Failed("no response queue".to_string()), "");
+                    }
+                },
+                Err(e) => {
+                    warn_limited!("Not executing command for register {:?}: {}", cfg_button.description, e);
+                    button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("unknown action".to_string()), &e);
+                }
+            }
+        } else if let Some(body) = cmd.strip_prefix(SCRIPT_PREFIX) {
+            // rhai script, its Klipper requests are sent once it finished
+            let press = self.presses.latest(button.id(), SystemTime::now());
+            let input = ScriptInput {
+                button: button.id(),
+                pressed: !matches!(button.get_state(), SPIButtonState::Off),
+                held_ms: press.held.as_millis() as u64,
+                taps: press.taps,
+                held: self.held.clone(),
+                leds: self.config.buttons.iter().map(|m| (m.button, self.spi.get_button(m.button).get_state())).collect(),
+                variables: self.variables.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            };
+            let description = cfg_button.description.clone();
+            let klipper_cfg = self.config.klipper.clone();
+            match script::run(body, &input) {
+                Ok(effects) if !effects.klipper.is_empty() && klipper_cfg.is_none() => {
+                    warn_limited!("Script of register {:?} calls Klipper but no klipper config provided", description);
+                    button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("no klipper config".to_string()), &effects.output.join("\n"));
+                }
+                Ok(effects) => {
+                    button.set_state(SPIButtonState::Off);
+                    for (led_button, state) in effects.leds {
+                        if led_button == button.id() {
+                            button.set_state(state);
+                        } else {
+                            self.set_button_state(led_button, state);
+                        }
+                    }
+                    let mut requests = Vec::new();
+                    for command in effects.klipper {
+                        match self.issue_request(button.id(), correlation_id) {
+                            Some((request_id, tx)) => requests.push((command, request_id, tx)),
+                            None => break,
+                        }
+                    }
+                    if let (Some(klipper_cfg), Some((_, last, _))) = (klipper_cfg, requests.last()) {
+                        // Completed when the response to the last request reaches the main loop
+                        record.request_id = Some(*last);
+                        tokio::spawn(self.in_flight.track(concurrency::exclusive(group_lock, correlation_id, async move {
+                            for (command, request_id, tx) in requests {
+                                CommandExecutor::send_klipper_command(&command, &klipper_cfg, request_id, correlation_id, tx).await;
+                            }
+                        })));
+                    } else {
+                        record.finish(Outcome::Succeeded, &effects.output.join("\n"));
+                    }
+                    info!("[{}] Script of register {:?} finished", correlation_id, description);
+                }
+                Err(e) => {
+                    warn_limited!("Script of register {:?} failed: {}", description, e);
+                    button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("script failed".to_string()), &e.to_string());
+                }
             }
         } else {
-            match CommandExecutor::execute(&cfg_button.command) {
-                Ok(_) => {
+            let mut env = vec![("SPIBTN_CORRELATION_ID", correlation_id.to_string())];
+            env.extend(self.presses.latest(button.id(), SystemTime::now()).env(button.id()));
+            match CommandExecutor::execute_blocking(cmd, env).await {
+                Ok(output) => {
                     info!(
-                        "Successfully executed command for trigger on register {:?}",
-                        cfg_button.description
+                        "[{}] Successfully executed command for trigger on register {:?}",
+                        correlation_id, cfg_button.description
                     );
                     button.set_state(SPIButtonState::Off);
+                    record.finish(Outcome::Succeeded, &output);
                 }
                 Err(e) => {
-                    warn!(
+                    warn_limited!(
                         "Failed to execute command for register {:?}: {}",
                         cfg_button.description, e
                     );
                     button.set_state(SPIButtonState::Flash2);
+                    record.finish(Outcome::Failed("command failed".to_string()), &e.to_string());
                 }
             }
         }
+        self.record(record);
     }
 
-    pub fn reload_config(&mut self, new_config: Config) -> Result<()> {
-        self.config = new_config;
-        Daemon::init(&self.config, &mut self.spi);
+    /// Load the config file again, see `reload_config`.
+    pub fn reload(&mut self) -> Result<ConfigDiff> {
+        let (path, format) = self
+            .config_file
+            .clone()
+            .ok_or_else(|| Error::config("No config file to reload"))?;
+        self.reload_config(Config::load_complete(&path, format)?)
+    }
+
+    /// Switch to a reloaded config, reconfiguring only the buttons whose
+    /// mapping changed so the others keep their LED and toggle state. A
+    /// changed `spi` section reopens the panel, refused while Klipper
+    /// requests or deferred actions are pending. Returns and logs what
+    /// changed.
+    pub fn reload_config(&mut self, new_config: Config) -> Result<ConfigDiff> {
+        // Stay in the active profile unless the new config dropped it
+        let keep = self
+            .profile
+            .as_ref()
+            .filter(|name| new_config.profiles.as_ref().is_some_and(|p| p.contains_key(*name)));
+        let profile = keep.cloned().or_else(|| new_config.default_profile.clone());
+        let config = match &profile {
+            Some(name) => new_config.with_profile(name)?,
+            None => new_config.clone(),
+        };
+
+        // The panel reads as many buttons as it was opened for
+        if config.spi != self.config.spi || config.panel_size() > self.panel_size {
+            let pending = self.history.pending() + self.deferred.pending().len();
+            if pending > 0 {
+                return Err(Error::config(format!(
+                    "The panel must be reopened while {} transfer(s) are pending, reload rejected",
+                    pending
+                )));
+            }
+            // A lock on the new device, the current one is kept otherwise
+            let lock = match &self.device_lock {
+                Some(lock) if lock.path() == config.spi.device => None,
+                _ => Some(DeviceLock::acquire(&config.spi.device)?),
+            };
+            let spi = panel::open(&config.spi, config.panel_size())
+                .map_err(|e| Error::spi(format!("Failed to reopen {}", config.spi.device), e))?;
+            self.spi = FrameBuffer::new(spi);
+            self.panel_size = config.panel_size();
+            if lock.is_some() {
+                self.device_lock = lock;
+            }
+            info!("SPI device reopened: {}", config.spi.device);
+            info!("Panel capabilities: {}", self.spi.capabilities());
+            Daemon::init(&config, &mut self.spi);
+            self.panel_id = self.spi.identify().ok().flatten();
+        } else {
+            let changes = self.config.diff_buttons(&config);
+            for mapping in config.buttons.iter().filter(|m| !changes.unchanged.contains(&m.button)) {
+                self.spi
+                    .configure(mapping.button, mapping.config.unwrap_or(SPIButtonState::OnChange as u8));
+                self.spi.set_brightness(mapping.button, mapping.brightness.unwrap_or(100));
+                info!("  - Button {:?}: {:?}", mapping.button, mapping.description);
+            }
+            for button_id in &changes.removed {
+                self.leds.remove(*button_id);
+                self.patterns.remove(*button_id);
+                self.write_led(*button_id, SPIButtonState::Off);
+            }
+            info!("Buttons: {}", changes);
+        }
+        if config.polling != self.config.polling {
+            self.poll_timers = PollTimers::new(&config.polling, Instant::now());
+        }
+        self.grace = Daemon::grace(&config);
+        self.press_filter = PressFilter::new();
+        self.chords = Chords::new();
+        let (capacity, overflow) = Daemon::request_limits(&config);
+        for (request_id, request) in self.requests.set_limits(capacity, overflow) {
+            self.drop_request(request_id, request);
+        }
+        self.variables.set_limit(config.limits.as_ref().and_then(|l| l.variables));
+        let diff = self.base_config.diff(&new_config);
+        for change in &diff.changes {
+            info!("  {}", change);
+        }
+        if profile != self.profile {
+            info!("  profile: {} -> {}", self.profile.as_deref().unwrap_or("none"), profile.as_deref().unwrap_or("none"));
+        }
+        self.mappings = mappings_by_id(&config);
+        self.config = config;
+        self.base_config = new_config;
+        self.profile = profile;
+        self.show_machine_states();
+        self.show_radio_groups();
+        self.refresh_led_rules();
         info!("Configuration reloaded successfully");
-        Ok(())
+        Ok(diff)
     }
 }
 
+/// Mappings of `config` by button id.
+fn mappings_by_id(config: &Config) -> HashMap<ButtonId, ButtonMapping> {
+    config.buttons.iter().map(|m| (m.button, m.clone())).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::builder::{ButtonBuilder, ConfigBuilder};
+    use crate::config::MoonrakerConfig;
+    use crate::panel::{Capabilities, PanelButton};
+
+    /// Replays scripted reads, then reads nothing.
+    struct ScriptedPanel {
+        reads: VecDeque<Vec<PanelButton>>,
+        capabilities: Capabilities,
+    }
+
+    impl PanelProtocol for ScriptedPanel {
+        fn loop_once(&mut self) -> std::io::Result<Vec<PanelButton>> {
+            Ok(self.reads.pop_front().unwrap_or_default())
+        }
+        fn get_button(&self, id: ButtonId) -> PanelButton {
+            PanelButton::new(id, SPIButtonState::Off)
+        }
+        fn set_button(&mut self, _id: ButtonId, _button: PanelButton) {}
+        fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+        fn capabilities(&self) -> &Capabilities {
+            &self.capabilities
+        }
+    }
+
+    #[tokio::test]
+    async fn test_modifier_shifts_a_press_in_the_same_read() {
+        let button = |id, state| PanelButton::new(ButtonId(id), state);
+        // Whichever of the two comes first on the panel
+        for order in [[0, 1], [1, 0]] {
+            let config = ConfigBuilder::new()
+                .observer(true)
+                .button(ButtonBuilder::new(ButtonId(0), "").modifier())
+                .button(ButtonBuilder::new(ButtonId(1), "echo plain").shift_command("echo shifted"))
+                .build()
+                .unwrap();
+            let panel = ScriptedPanel {
+                reads: VecDeque::from([
+                    order.iter().map(|id| button(*id, SPIButtonState::On)).collect(),
+                    vec![button(0, SPIButtonState::Off), button(1, SPIButtonState::Off)],
+                    vec![button(1, SPIButtonState::On)],
+                ]),
+                capabilities: panel::capabilities(&config.spi),
+            };
+            let mut daemon = Daemon::with_panel(config, Box::new(panel), None).unwrap();
+            for _ in 0..3 {
+                daemon.poll().await.unwrap();
+            }
+            let commands: Vec<&str> = daemon.history().last(10).map(|r| r.command.as_str()).collect();
+            assert_eq!(commands, ["echo shifted", "echo plain"], "panel order {:?}", order);
+        }
+    }
 
     #[test]
-    fn test_mask_matching() {
-        let trigger = ValueTrigger {
-            value: 0x01,
-            mask: Some(0x01),
-            command: "test".to_string(),
-            description: None,
+    fn test_remote_mappings_need_opt_in_and_fit_the_panel() {
+        let mut config = ConfigBuilder::new()
+            .device("/nonexistent/spidev")
+            .observer(true)
+            .button(ButtonBuilder::new(ButtonId(0), "echo a"))
+            .button(ButtonBuilder::new(ButtonId(2), "echo c"))
+            .build()
+            .unwrap();
+        let add = |button: u64| Notification {
+            method: "spibtn_add_mapping".to_string(),
+            params: serde_json::json!({"button": button, "command": "echo added"}),
         };
+        let panel = ScriptedPanel { reads: VecDeque::new(), capabilities: panel::capabilities(&config.spi) };
+        let mut daemon = Daemon::with_panel(config.clone(), Box::new(panel), None).unwrap();
+        daemon.handle_notification(&add(1));
+        assert!(!daemon.mappings.contains_key(&ButtonId(1)));
+
+        config.moonraker = Some(MoonrakerConfig {
+            url: "http://localhost:7125".to_string(),
+            timeout_ms: None,
+            allow_remote_mappings: Some(true),
+        });
+        let panel = ScriptedPanel { reads: VecDeque::new(), capabilities: panel::capabilities(&config.spi) };
+        let mut daemon = Daemon::with_panel(config, Box::new(panel), None).unwrap();
+        daemon.handle_notification(&add(1));
+        assert!(daemon.mappings.contains_key(&ButtonId(1)));
+        // Past the 3 buttons read the panel must be reopened, which fails here
+        assert!(daemon.add_mapping(ButtonMapping { button: ButtonId(5), ..daemon.mappings[&ButtonId(1)].clone() }, false).is_err());
+        assert!(!daemon.mappings.contains_key(&ButtonId(5)));
+    }
 
-        let daemon_config = Config::default();
-        let daemon_spi = SpiDevice::new("/dev/null").ok();
+    async fn next(rx: &mut tokio::sync::mpsc::Receiver<EventMessage>) -> Envelope {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await {
+            Ok(Some(EventMessage::Peer(envelope))) => envelope,
+            _ => panic!("no peer update"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_toggle_is_mirrored_to_a_peer() {
+        let config = ConfigBuilder::new()
+            .observer(true)
+            .button(ButtonBuilder::new(ButtonId(0), "echo lights on").toggle_command("echo lights off"))
+            .build()
+            .unwrap();
+        let daemon = |reads: Vec<Vec<PanelButton>>| {
+            let panel = ScriptedPanel { reads: reads.into(), capabilities: panel::capabilities(&config.spi) };
+            Daemon::with_panel(config.clone(), Box::new(panel), None).unwrap()
+        };
+        let mut a = daemon(vec![vec![PanelButton::new(ButtonId(0), SPIButtonState::On)]]);
+        let mut b = daemon(vec![]);
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let (a_tx, mut a_rx) = tokio::sync::mpsc::channel(8);
+        let (b_tx, mut b_rx) = tokio::sync::mpsc::channel(8);
+        let sync = |listen: Option<&str>, peers: Option<&str>, secret: &str| SyncConfig {
+            listen: listen.map(str::to_string),
+            peers: peers.map(|p| vec![p.to_string()]),
+            secret: Some(secret.to_string()),
+            ..SyncConfig::default()
+        };
+        a.connect_peers(&sync(Some(&address), None, "s3cret"), a_tx).unwrap();
+        b.connect_peers(&sync(None, Some(&address), "s3cret"), b_tx).unwrap();
+        // Connected once both greeted each other
+        assert_eq!(next(&mut a_rx).await.update, PeerUpdate::Hello { secret: None });
+        assert_eq!(next(&mut b_rx).await.update, PeerUpdate::Hello { secret: None });
+        // A daemon with another secret is refused both ways
+        let mut c = daemon(vec![]);
+        let (c_tx, mut c_rx) = tokio::sync::mpsc::channel(8);
+        c.connect_peers(&sync(None, Some(&address), "guess"), c_tx).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(500), c_rx.recv()).await.is_err());
+        assert!(a_rx.try_recv().is_err());
 
-        // Test matching with mask
-        assert!(0x01 & trigger.mask.unwrap() == trigger.value);
-        assert!(0x03 & trigger.mask.unwrap() == trigger.value);
+        a.poll().await.unwrap();
+        assert_eq!(a.machine_states(), [(ButtonId(0), "on".to_string())]);
+        let envelope = next(&mut b_rx).await;
+        b.handle_peer(envelope);
+        assert_eq!(b.machine_states(), [(ButtonId(0), "on".to_string())]);
+        // The command ran on the panel pressed only
+        assert_eq!(a.history().last(10).count(), 1);
+        assert_eq!(b.history().last(10).count(), 0);
     }
 }
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::units::ButtonId;
+
+/// Drops button state changes arriving within the debounce window of the
+/// last accepted change, so a bouncing membrane switch fires only once.
+#[derive(Debug, Default)]
+pub struct Debouncer {
+    last_change: HashMap<ButtonId, Instant>,
+}
+
+impl Debouncer {
+    pub fn new() -> Self {
+        Debouncer::default()
+    }
+
+    /// Whether a state change of the button should be handled.
+    pub fn accept(&mut self, button_id: ButtonId, now: Instant, window: Duration) -> bool {
+        match self.last_change.get(&button_id) {
+            Some(last) if now.duration_since(*last) < window => false,
+            _ => {
+                self.last_change.insert(button_id, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounces_are_dropped() {
+        let mut debouncer = Debouncer::new();
+        let window = Duration::from_millis(30);
+        let t0 = Instant::now();
+
+        assert!(debouncer.accept(ButtonId(1), t0, window));
+        assert!(!debouncer.accept(ButtonId(1), t0 + Duration::from_millis(5), window));
+        assert!(!debouncer.accept(ButtonId(1), t0 + Duration::from_millis(29), window));
+        // Other buttons are independent
+        assert!(debouncer.accept(ButtonId(2), t0 + Duration::from_millis(5), window));
+        assert!(debouncer.accept(ButtonId(1), t0 + Duration::from_millis(200), window));
+        // Dropped bounces do not extend the window
+        assert!(debouncer.accept(ButtonId(1), t0 + Duration::from_millis(230), window));
+    }
+
+    #[test]
+    fn test_zero_window_accepts_everything() {
+        let mut debouncer = Debouncer::new();
+        let t0 = Instant::now();
+        assert!(debouncer.accept(ButtonId(1), t0, Duration::ZERO));
+        assert!(debouncer.accept(ButtonId(1), t0, Duration::ZERO));
+    }
+}
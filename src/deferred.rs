@@ -0,0 +1,251 @@
+use chrono::{DateTime, Local, NaiveTime};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::units::ButtonId;
+
+/// An action scheduled by a press of a button with `delay_ms` or `at`.
+#[derive(Debug, Clone)]
+pub struct PendingAction {
+    pub button_id: ButtonId,
+    pub command: String,
+    due: Instant,
+    /// Wall clock time of `due`, for display
+    due_at: DateTime<Local>,
+    /// Time of day the action was scheduled for, `None` for a delay
+    at: Option<NaiveTime>,
+    /// When the button was pressed, by both clocks
+    scheduled: Instant,
+    scheduled_at: DateTime<Local>,
+}
+
+/// When a deferred action runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deferral {
+    /// After a delay, `delay_ms`
+    After(Duration),
+    /// At the next time of day, `at`
+    At(NaiveTime),
+}
+
+impl fmt::Display for Deferral {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Deferral::After(delay) => write!(f, "in {}s", delay.as_secs()),
+            Deferral::At(at) => write!(f, "at {}", at.format("%H:%M")),
+        }
+    }
+}
+
+impl fmt::Display for PendingAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let left = self.due.saturating_duration_since(Instant::now()).as_secs();
+        write!(
+            f,
+            "button {} at {} (in {}m{:02}s): {}",
+            self.button_id,
+            self.due_at.format("%H:%M:%S"),
+            left / 60,
+            left % 60,
+            self.command
+        )
+    }
+}
+
+/// Actions waiting for their time, at most one per button. Pressing the
+/// button again cancels its pending action.
+#[derive(Debug, Default)]
+pub struct Deferred {
+    pending: HashMap<ButtonId, PendingAction>,
+}
+
+impl Deferred {
+    pub fn new() -> Self {
+        Deferred::default()
+    }
+
+    /// Schedule `command` to run at `when`, or cancel the button's pending
+    /// action if it has one. Returns `true` when scheduled.
+    pub fn toggle(&mut self, button_id: ButtonId, command: &str, when: Deferral, now: Instant) -> bool {
+        if self.pending.remove(&button_id).is_some() {
+            return false;
+        }
+        self.schedule(button_id, command, when, now, Local::now());
+        true
+    }
+
+    fn schedule(&mut self, button_id: ButtonId, command: &str, when: Deferral, now: Instant, wall: DateTime<Local>) {
+        let (delay, at) = match when {
+            Deferral::After(delay) => (delay, None),
+            Deferral::At(at) => (until(at, wall.time()), Some(at)),
+        };
+        self.pending.insert(
+            button_id,
+            PendingAction {
+                button_id,
+                command: command.to_string(),
+                due: now + delay,
+                due_at: wall + chrono::Duration::from_std(delay).unwrap_or_default(),
+                at,
+                scheduled: now,
+                scheduled_at: wall,
+            },
+        );
+    }
+
+    /// Follow a step of the wall clock. Actions for a time of day are
+    /// recomputed as if the clock had been right when the button was
+    /// pressed, those whose time has now passed run right away. Delays keep
+    /// their monotonic due time. Returns the number of actions recomputed.
+    pub fn reschedule(&mut self, step: chrono::Duration) -> usize {
+        let mut recomputed = 0;
+        for action in self.pending.values_mut() {
+            action.scheduled_at += step;
+            match action.at {
+                Some(at) => {
+                    let delay = until(at, action.scheduled_at.time());
+                    action.due = action.scheduled + delay;
+                    action.due_at = action.scheduled_at + chrono::Duration::from_std(delay).unwrap_or_default();
+                    recomputed += 1;
+                }
+                None => action.due_at += step,
+            }
+        }
+        recomputed
+    }
+
+    /// Remove and return every action whose time has come.
+    pub fn take_due(&mut self, now: Instant) -> Vec<PendingAction> {
+        let mut due: Vec<PendingAction> = self
+            .pending
+            .values()
+            .filter(|a| a.due <= now)
+            .cloned()
+            .collect();
+        due.sort_by_key(|a| a.button_id);
+        for action in &due {
+            self.pending.remove(&action.button_id);
+        }
+        due
+    }
+
+    /// Drop the actions a suspend of the host made stale: every delay, and
+    /// actions for a time of day the wall clock passed by `wall`. Returns
+    /// how many were dropped, the others need a `reschedule` for the time
+    /// suspended.
+    pub fn cancel_stale(&mut self, wall: DateTime<Local>) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|_, action| action.at.is_some() && action.due_at > wall);
+        before - self.pending.len()
+    }
+
+    /// Pending actions, soonest first.
+    pub fn pending(&self) -> Vec<&PendingAction> {
+        let mut pending: Vec<&PendingAction> = self.pending.values().collect();
+        pending.sort_by_key(|a| a.due);
+        pending
+    }
+}
+
+/// Time from `now` until the next `at` time of day, e.g. `23:30`. A time
+/// that has already passed today means tomorrow.
+pub fn delay_until(at: &str, now: NaiveTime) -> Result<Duration> {
+    Ok(until(parse_time_of_day(at)?, now))
+}
+
+/// Parse an `at` time of day such as `23:30`.
+pub fn parse_time_of_day(at: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(at.trim(), "%H:%M")
+        .map_err(|e| Error::config(format!("Invalid time of day: {}", at)).caused_by(e))
+}
+
+fn until(at: NaiveTime, now: NaiveTime) -> Duration {
+    let mut delay = at - now;
+    if delay <= chrono::Duration::zero() {
+        delay += chrono::Duration::days(1);
+    }
+    delay.to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_press_again_cancels() {
+        let mut deferred = Deferred::new();
+        let t0 = Instant::now();
+        let delay = Deferral::After(Duration::from_secs(600));
+
+        assert!(deferred.toggle(ButtonId(3), "lights_off", delay, t0));
+        assert_eq!(deferred.pending().len(), 1);
+        assert!(!deferred.toggle(ButtonId(3), "lights_off", delay, t0 + Duration::from_secs(5)));
+        assert!(deferred.pending().is_empty());
+        assert!(deferred.take_due(t0 + Duration::from_secs(600)).is_empty());
+    }
+
+    #[test]
+    fn test_take_due() {
+        let mut deferred = Deferred::new();
+        let t0 = Instant::now();
+        deferred.toggle(ButtonId(1), "a", Deferral::After(Duration::from_secs(10)), t0);
+        deferred.toggle(ButtonId(2), "b", Deferral::After(Duration::from_secs(60)), t0);
+
+        assert!(deferred.take_due(t0 + Duration::from_secs(9)).is_empty());
+        let due = deferred.take_due(t0 + Duration::from_secs(10));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].command, "a");
+        assert_eq!(deferred.pending()[0].button_id, ButtonId(2));
+    }
+
+    #[test]
+    fn test_delay_until_wraps_to_tomorrow() {
+        let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert_eq!(delay_until("23:30", t(23, 0)).unwrap(), Duration::from_secs(1800));
+        assert_eq!(delay_until("06:00", t(23, 0)).unwrap(), Duration::from_secs(7 * 3600));
+        assert_eq!(delay_until("23:00", t(23, 0)).unwrap(), Duration::from_secs(24 * 3600));
+        assert!(delay_until("late", t(23, 0)).is_err());
+    }
+
+    #[test]
+    fn test_clock_step_recomputes_times_of_day() {
+        let mut deferred = Deferred::new();
+        let t0 = Instant::now();
+        let wall = |s: &str| s.parse::<chrono::NaiveDateTime>().unwrap().and_local_timezone(Local).unwrap();
+        let at = NaiveTime::from_hms_opt(23, 30, 0).unwrap();
+
+        // Pressed at 23:28 while the clock still read a day after the epoch
+        let booted = wall("1970-01-02T00:05:00");
+        deferred.schedule(ButtonId(1), "lights_off", Deferral::At(at), t0, booted);
+        deferred.schedule(ButtonId(2), "fan_off", Deferral::After(Duration::from_secs(600)), t0, booted);
+        assert_eq!(deferred.pending.get(&ButtonId(1)).unwrap().due, t0 + Duration::from_secs(23 * 3600 + 25 * 60));
+
+        assert_eq!(deferred.reschedule(wall("2026-10-17T23:28:00") - booted), 1);
+        assert_eq!(deferred.pending.get(&ButtonId(1)).unwrap().due, t0 + Duration::from_secs(120));
+        assert_eq!(deferred.pending.get(&ButtonId(2)).unwrap().due, t0 + Duration::from_secs(600));
+        assert_eq!(deferred.pending()[0].due_at, wall("2026-10-17T23:30:00"));
+    }
+
+    #[test]
+    fn test_suspend_keeps_later_times_of_day() {
+        let mut deferred = Deferred::new();
+        let t0 = Instant::now();
+        let wall = |s: &str| s.parse::<chrono::NaiveDateTime>().unwrap().and_local_timezone(Local).unwrap();
+        let at = |h, m| Deferral::At(NaiveTime::from_hms_opt(h, m, 0).unwrap());
+        let pressed = wall("2026-10-17T22:00:00");
+        deferred.schedule(ButtonId(1), "lights_off", at(23, 30), t0, pressed);
+        deferred.schedule(ButtonId(2), "preheat", at(8, 0), t0, pressed);
+        deferred.schedule(ButtonId(3), "fan_off", Deferral::After(Duration::from_secs(600)), t0, pressed);
+
+        // Suspended at 22:10 for two hours, the monotonic clock stood still
+        let resumed = wall("2026-10-18T00:10:00");
+        assert_eq!(deferred.cancel_stale(resumed), 2);
+        deferred.reschedule(resumed - (pressed + chrono::Duration::minutes(10)));
+        let pending = deferred.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].due, t0 + Duration::from_secs(8 * 3600));
+        assert_eq!(pending[0].due_at, wall("2026-10-18T08:00:00"));
+    }
+}
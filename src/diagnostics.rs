@@ -0,0 +1,272 @@
+use log::debug;
+use spibuttonlib::{SPIButton, SPIButtonController, SPIButtonState};
+use std::fs;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::error::Result;
+use crate::units::Hertz;
+
+/// Number of button positions probed on each device. Covers three chained
+/// 8-bit shift registers, which is the largest panel in common use.
+const PROBE_BUTTONS: usize = 24;
+
+/// Clock speeds tried by the scanner, slowest first.
+const PROBE_SPEEDS_HZ: [Hertz; 4] = [Hertz(100_000), Hertz(500_000), Hertz(1_000_000), Hertz(4_000_000)];
+
+/// Number of reads taken per device and how far apart they are.
+const PROBE_POLLS: usize = 20;
+const PROBE_INTERVAL_MS: u64 = 100;
+
+/// Clock speeds tried by the signal quality sweep, slowest first.
+const SWEEP_SPEEDS_HZ: [Hertz; 8] = [
+    Hertz(100_000),
+    Hertz(250_000),
+    Hertz(500_000),
+    Hertz(800_000),
+    Hertz(1_000_000),
+    Hertz(2_000_000),
+    Hertz(4_000_000),
+    Hertz(8_000_000),
+];
+
+/// Transfers made at each speed during the sweep.
+const SWEEP_TRANSFERS: usize = 200;
+const SWEEP_INTERVAL_MS: u64 = 5;
+
+/// What a probe concluded about a single device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeResult {
+    /// Device could not be opened or a transfer failed.
+    Unavailable(String),
+    /// Every input read as pressed on the first poll: MISO is floating high.
+    FloatingBus,
+    /// Reads were stable and some buttons changed state during the scan.
+    PanelDetected { active_buttons: Vec<u8> },
+    /// Reads were stable but nothing changed, either an idle panel or nothing attached.
+    Quiet,
+}
+
+/// List the spidev device nodes present on the system, sorted by name.
+pub fn spidev_devices() -> Vec<String> {
+    let mut devices: Vec<String> = match fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("spidev"))
+            .map(|name| format!("/dev/{}", name))
+            .collect(),
+        Err(_) => vec![],
+    };
+    devices.sort();
+    devices
+}
+
+/// Probe a single device at a given speed with a benign read pattern: buttons
+/// are configured as plain change reporters and their LEDs are left off.
+pub async fn probe(device: &str, speed_hz: Hertz) -> ProbeResult {
+    let mut spi = match SPIButtonController::new(PROBE_BUTTONS, device, speed_hz.0, 0) {
+        Ok(spi) => spi,
+        Err(e) => return ProbeResult::Unavailable(format!("{}", e)),
+    };
+    for id in 0..PROBE_BUTTONS {
+        spi.set_button(id as u8, SPIButton::new(SPIButtonState::OnChange as u8));
+    }
+
+    let mut active_buttons: Vec<u8> = vec![];
+    for poll in 0..PROBE_POLLS {
+        let events = match spi.loop_once() {
+            Ok(events) => events,
+            Err(e) => return ProbeResult::Unavailable(format!("{:?}", e)),
+        };
+        debug!("{} @ {}Hz poll {}: {} event(s)", device, speed_hz, poll, events.len());
+
+        if poll == 0 && events.len() >= PROBE_BUTTONS {
+            return ProbeResult::FloatingBus;
+        }
+        for b in events {
+            if !active_buttons.contains(&b.id()) {
+                active_buttons.push(b.id());
+            }
+        }
+        sleep(Duration::from_millis(PROBE_INTERVAL_MS)).await;
+    }
+
+    if active_buttons.is_empty() {
+        ProbeResult::Quiet
+    } else {
+        active_buttons.sort();
+        ProbeResult::PanelDetected { active_buttons }
+    }
+}
+
+/// `scan` mode: probe the given devices (or every spidev node when none are
+/// given) and print a report with suggested `spi:` settings.
+pub async fn scan(devices: &[String]) -> Result<()> {
+    let devices = if devices.is_empty() {
+        spidev_devices()
+    } else {
+        devices.to_vec()
+    };
+
+    if devices.is_empty() {
+        println!("No spidev devices found under /dev. Is the SPI overlay enabled?");
+        return Ok(());
+    }
+
+    println!("Scanning {} device(s), press any panel button to confirm detection...", devices.len());
+
+    for device in &devices {
+        println!();
+        println!("{}", device);
+
+        let mut best: Option<(Hertz, ProbeResult)> = None;
+        for speed_hz in PROBE_SPEEDS_HZ {
+            let result = probe(device, speed_hz).await;
+            println!("  {:>9} Hz: {}", speed_hz, describe(&result));
+            match result {
+                ProbeResult::Unavailable(_) | ProbeResult::FloatingBus => {}
+                // Prefer a speed where activity was seen over a merely quiet one
+                ProbeResult::PanelDetected { .. } => best = Some((speed_hz, result)),
+                ProbeResult::Quiet => {
+                    if !matches!(best, Some((_, ProbeResult::PanelDetected { .. }))) {
+                        best = Some((speed_hz, result));
+                    }
+                }
+            }
+        }
+
+        match best {
+            Some((speed_hz, result)) => {
+                if matches!(result, ProbeResult::Quiet) {
+                    println!("  No button activity seen; the panel may be idle or absent.");
+                }
+                println!("  Suggested configuration:");
+                println!("    spi:");
+                println!("      device: {}", device);
+                println!("      speed_hz: {}", speed_hz);
+                println!("      mode: 0");
+            }
+            None => println!("  No usable panel found on this device."),
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(result: &ProbeResult) -> String {
+    match result {
+        ProbeResult::Unavailable(e) => format!("unavailable ({})", e),
+        ProbeResult::FloatingBus => "all inputs high, bus is floating (no panel)".to_string(),
+        ProbeResult::PanelDetected { active_buttons } => {
+            format!("panel detected, activity on button(s) {:?}", active_buttons)
+        }
+        ProbeResult::Quiet => "responding, no activity".to_string(),
+    }
+}
+
+/// Transfer statistics gathered at a single clock speed.
+#[derive(Debug, Clone)]
+pub struct SweepResult {
+    pub speed_hz: Hertz,
+    pub transfers: usize,
+    pub errors: usize,
+}
+
+impl SweepResult {
+    pub fn error_rate(&self) -> f64 {
+        if self.transfers == 0 {
+            return 1.0;
+        }
+        self.errors as f64 / self.transfers as f64
+    }
+}
+
+/// Run known-answer transfers at one speed. With no button held the panel
+/// must report no changes, so any event or failed transfer counts as an error.
+pub async fn sweep_speed(device: &str, mode: u8, speed_hz: Hertz) -> SweepResult {
+    let mut result = SweepResult {
+        speed_hz,
+        transfers: 0,
+        errors: 0,
+    };
+
+    let mut spi = match SPIButtonController::new(PROBE_BUTTONS, device, speed_hz.0, mode) {
+        Ok(spi) => spi,
+        Err(e) => {
+            debug!("{} @ {}Hz: open failed: {}", device, speed_hz, e);
+            result.errors = SWEEP_TRANSFERS;
+            result.transfers = SWEEP_TRANSFERS;
+            return result;
+        }
+    };
+    for id in 0..PROBE_BUTTONS {
+        spi.set_button(id as u8, SPIButton::new(SPIButtonState::OnChange as u8));
+    }
+
+    // The first read latches the idle state and is not counted
+    let _ = spi.loop_once();
+
+    for _ in 0..SWEEP_TRANSFERS {
+        result.transfers += 1;
+        match spi.loop_once() {
+            Ok(events) if events.is_empty() => {}
+            Ok(events) => {
+                debug!("{} @ {}Hz: {} unexpected event(s)", device, speed_hz, events.len());
+                result.errors += 1;
+            }
+            Err(e) => {
+                debug!("{} @ {}Hz: transfer failed: {:?}", device, speed_hz, e);
+                result.errors += 1;
+            }
+        }
+        sleep(Duration::from_millis(SWEEP_INTERVAL_MS)).await;
+    }
+
+    result
+}
+
+/// `sweep` mode: measure error rates across clock speeds on one device and
+/// report the highest speed that produced no errors.
+pub async fn sweep(device: &str, mode: u8) -> Result<()> {
+    println!(
+        "Sweeping {} (mode {}), {} transfers per speed. Do not touch the panel...",
+        device, mode, SWEEP_TRANSFERS
+    );
+
+    let mut results = vec![];
+    for speed_hz in SWEEP_SPEEDS_HZ {
+        let result = sweep_speed(device, mode, speed_hz).await;
+        println!(
+            "  {:>9} Hz: {:>4}/{} errors ({:.1}%)",
+            result.speed_hz,
+            result.errors,
+            result.transfers,
+            result.error_rate() * 100.0
+        );
+        results.push(result);
+    }
+
+    // Only trust a speed if every slower speed was also clean, so a lucky
+    // run above a failing speed is not recommended.
+    let reliable = results
+        .iter()
+        .take_while(|r| r.errors == 0)
+        .last();
+
+    match reliable {
+        Some(r) => {
+            println!();
+            println!("Highest reliable speed: {} Hz", r.speed_hz);
+            println!("Suggested configuration:");
+            println!("  spi:");
+            println!("    speed_hz: {}", r.speed_hz);
+        }
+        None => {
+            println!();
+            println!("No speed was reliable. Check wiring, grounding and the device path.");
+        }
+    }
+
+    Ok(())
+}
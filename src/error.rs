@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io;
+use thiserror::Error;
+
+use crate::units::ButtonId;
+
+/// The underlying cause of an error, kept so callers can inspect it through
+/// `std::error::Error::source`.
+pub type Source = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Errors returned by the library, by where the failure happened so callers
+/// can tell a bad config from a broken panel. Each keeps what caused it, if
+/// anything, as its source. The binaries report them through anyhow.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The configuration could not be read, parsed, migrated or applied.
+    #[error("{}", Caused(.context, .source.as_deref()))]
+    Config { context: String, source: Option<Source> },
+    /// The panel could not be opened or a transfer with it failed.
+    #[error("{context}: {source}")]
+    Spi { context: String, source: io::Error },
+    /// A Klipper or Moonraker API request failed, e.g. with a
+    /// `ResponseStatus`.
+    #[error("{context}: {source}")]
+    Rpc { context: String, source: Source },
+    /// A button's action, e.g. a shell command or `set_var:`, failed.
+    #[error("{}", Caused(.context, .source.as_deref()))]
+    Action { context: String, source: Option<Source> },
+    /// The daemon's own plumbing failed, e.g. the control socket.
+    #[error("{}", Caused(.context, .source.as_deref()))]
+    Internal { context: String, source: Option<Source> },
+}
+
+impl Error {
+    pub fn config(context: impl Into<String>) -> Self {
+        Error::Config { context: context.into(), source: None }
+    }
+
+    pub fn spi(context: impl Into<String>, source: io::Error) -> Self {
+        Error::Spi { context: context.into(), source }
+    }
+
+    pub fn rpc(context: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Rpc { context: context.into(), source: source.into() }
+    }
+
+    pub fn action(context: impl Into<String>) -> Self {
+        Error::Action { context: context.into(), source: None }
+    }
+
+    pub fn internal(context: impl Into<String>) -> Self {
+        Error::Internal { context: context.into(), source: None }
+    }
+
+    /// Keep `cause` as the source of a `Config`, `Action` or `Internal`
+    /// error, e.g. `Error::config("Failed to read x.yaml").caused_by(e)`.
+    /// `Spi` and `Rpc` errors are given theirs when created.
+    pub fn caused_by(mut self, cause: impl Into<Source>) -> Self {
+        if let Error::Config { source, .. } | Error::Action { source, .. } | Error::Internal { source, .. } = &mut self {
+            *source = Some(cause.into());
+        }
+        self
+    }
+}
+
+/// A context followed by its cause, if any, e.g. `Failed to read x.yaml: No
+/// such file or directory (os error 2)`.
+struct Caused<'a>(&'a str, Option<&'a (dyn std::error::Error + Send + Sync + 'static)>);
+
+impl fmt::Display for Caused<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            Some(source) => write!(f, "{}: {}", self.0, source),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors raised while handling button events. These are reported and the
+/// event dropped; they never stop the daemon.
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    /// The controller reported a button id that has no mapping, e.g. after a
+    /// reload removed it while a press sequence was still pending.
+    #[error("button {0} has no mapping in the configuration")]
+    UnknownButton(ButtonId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source_is_kept() {
+        let e = Error::spi("Failed to open /dev/spidev1.0", io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(e.to_string(), format!("Failed to open /dev/spidev1.0: {}", io::Error::from(io::ErrorKind::NotFound)));
+        let kind = e.source().and_then(|s| s.downcast_ref::<io::Error>()).map(io::Error::kind);
+        assert_eq!(kind, Some(io::ErrorKind::NotFound));
+
+        let e = Error::config("Unknown profile: night");
+        assert_eq!(e.to_string(), "Unknown profile: night");
+        assert!(e.source().is_none());
+        let e = Error::internal("Failed to write x.yaml").caused_by(io::Error::other("disk full"));
+        assert_eq!(e.to_string(), "Failed to write x.yaml: disk full");
+        assert!(e.source().is_some());
+    }
+}
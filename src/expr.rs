@@ -0,0 +1,391 @@
+use log::warn;
+use regex::{Captures, Regex};
+use serde_json::Value as JsonValue;
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+/// A value in a condition or template expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Unset variable or unknown printer field
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+}
+
+impl Value {
+    pub fn from_json(json: &JsonValue) -> Self {
+        match json {
+            JsonValue::Null => Value::Null,
+            JsonValue::Bool(b) => Value::Bool(*b),
+            JsonValue::Number(n) => n.as_f64().map(Value::Number).unwrap_or(Value::Null),
+            JsonValue::String(s) => Value::Str(s.clone()),
+            other => Value::Str(other.to_string()),
+        }
+    }
+
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => *n != 0.0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    /// Numbers, and strings holding a number since variables are text.
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Str(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Resolves names used in expressions, e.g. `var.material` or
+/// `extruder.temperature`.
+pub trait Context {
+    fn lookup(&self, name: &str) -> Value;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Name(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Str(String),
+    Name(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPERATORS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "*", "/", "%",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = text.parse().map_err(|_| Error::config(format!("Invalid number: {}", text)))?;
+            tokens.push(Token::Number(n));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '"' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|&q| q == c)
+                .ok_or_else(|| Error::config(format!("Unterminated string in: {}", src)))?;
+            tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            i += 1;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| Error::config(format!("Unexpected character {:?} in: {}", c, src)))?;
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+/// Binary operators by precedence level, loosest first.
+const LEVELS: &[&[(&str, Op)]] = &[
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("==", Op::Eq), ("!=", Op::Ne)],
+    &[("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+    &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+];
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Op(symbol)) = self.peek() {
+            let op = match LEVELS[level].iter().find(|(s, _)| s == symbol) {
+                Some((_, op)) => *op,
+                None => break,
+            };
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Op("!")) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Op("-")) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Number(n)) => Ok(Expr::Literal(Value::Number(n))),
+            Some(Token::Str(s)) => Ok(Expr::Literal(Value::Str(s))),
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                _ => Expr::Name(name),
+            }),
+            Some(Token::Open) => {
+                let inner = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(Error::config("Missing closing parenthesis")),
+                }
+            }
+            Some(token) => Err(Error::config(format!("Unexpected {:?}", token))),
+            None => Err(Error::config("Unexpected end of expression")),
+        }
+    }
+}
+
+/// Parse an expression such as `extruder.temperature > 180 && layer == 2`.
+pub fn parse(src: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let expr = parser.binary(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(Error::config(format!("Unexpected {:?} in: {}", token, src))),
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, ctx: &dyn Context) -> Result<Value> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Name(name) => Ok(ctx.lookup(name)),
+            Expr::Not(inner) => Ok(Value::Bool(!inner.eval(ctx)?.truthy())),
+            Expr::Neg(inner) => match inner.eval(ctx)?.as_number() {
+                Some(n) => Ok(Value::Number(-n)),
+                None => Err(Error::action("Cannot negate a non-number")),
+            },
+            Expr::Binary(Op::And, left, right) => {
+                Ok(Value::Bool(left.eval(ctx)?.truthy() && right.eval(ctx)?.truthy()))
+            }
+            Expr::Binary(Op::Or, left, right) => {
+                Ok(Value::Bool(left.eval(ctx)?.truthy() || right.eval(ctx)?.truthy()))
+            }
+            Expr::Binary(op, left, right) => binary(*op, left.eval(ctx)?, right.eval(ctx)?),
+        }
+    }
+
+    /// Every name the expression reads.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Expr::Literal(_) => vec![],
+            Expr::Name(name) => vec![name.as_str()],
+            Expr::Not(inner) | Expr::Neg(inner) => inner.names(),
+            Expr::Binary(_, left, right) => {
+                let mut names = left.names();
+                names.extend(right.names());
+                names
+            }
+        }
+    }
+}
+
+fn binary(op: Op, left: Value, right: Value) -> Result<Value> {
+    let numbers = match (&left, &right) {
+        (Value::Null, _) | (_, Value::Null) => None,
+        _ => left.as_number().zip(right.as_number()),
+    };
+    let value = match (op, numbers) {
+        (Op::Eq, Some((a, b))) => Value::Bool(a == b),
+        (Op::Ne, Some((a, b))) => Value::Bool(a != b),
+        (Op::Eq, None) => Value::Bool(left.to_string() == right.to_string()),
+        (Op::Ne, None) => Value::Bool(left.to_string() != right.to_string()),
+        (Op::Lt, Some((a, b))) => Value::Bool(a < b),
+        (Op::Le, Some((a, b))) => Value::Bool(a <= b),
+        (Op::Gt, Some((a, b))) => Value::Bool(a > b),
+        (Op::Ge, Some((a, b))) => Value::Bool(a >= b),
+        (Op::Add, Some((a, b))) => Value::Number(a + b),
+        (Op::Add, None) => Value::Str(format!("{}{}", left, right)),
+        (Op::Sub, Some((a, b))) => Value::Number(a - b),
+        (Op::Mul, Some((a, b))) => Value::Number(a * b),
+        (Op::Div, Some((_, b))) | (Op::Rem, Some((_, b))) if b == 0.0 => {
+            return Err(Error::action("Division by zero"))
+        }
+        (Op::Div, Some((a, b))) => Value::Number(a / b),
+        (Op::Rem, Some((a, b))) => Value::Number(a % b),
+        (op, None) => return Err(Error::action(format!("{:?} needs numbers, got {:?} and {:?}", op, left, right))),
+        (Op::And, _) | (Op::Or, _) => unreachable!("short-circuited in eval"),
+    };
+    Ok(value)
+}
+
+/// Evaluate a condition, treating evaluation errors as false.
+pub fn condition_holds(src: &str, ctx: &dyn Context) -> bool {
+    match parse(src).and_then(|expr| expr.eval(ctx)) {
+        Ok(value) => value.truthy(),
+        Err(e) => {
+            warn!("Condition {:?} failed: {}", src, e);
+            false
+        }
+    }
+}
+
+/// Substitute `{{ expression }}` placeholders, e.g. `{{var.material}}` or
+/// `{{extruder.target + 10}}`. `{{val}}` is left for Klipper commands to
+/// fill in with the button state.
+pub fn render(template: &str, ctx: &dyn Context) -> String {
+    if !template.contains("{{") {
+        return template.to_string();
+    }
+    let re = Regex::new(r"\{\{(.*?)\}\}").unwrap();
+    re.replace_all(template, |caps: &Captures| {
+        let src = caps[1].trim();
+        if src == "val" {
+            return caps[0].to_string();
+        }
+        match parse(src).and_then(|expr| expr.eval(ctx)) {
+            Ok(value) => value.to_string(),
+            Err(e) => {
+                warn!("Template {{{{{}}}}} failed: {}", src, e);
+                String::new()
+            }
+        }
+    })
+    .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Vars(HashMap<&'static str, Value>);
+
+    impl Context for Vars {
+        fn lookup(&self, name: &str) -> Value {
+            self.0.get(name).cloned().unwrap_or(Value::Null)
+        }
+    }
+
+    fn ctx() -> Vars {
+        Vars(HashMap::from([
+            ("extruder.temperature", Value::Number(205.3)),
+            ("layer", Value::Str("2".to_string())),
+            ("var.material", Value::Str("PETG".to_string())),
+        ]))
+    }
+
+    fn eval(src: &str) -> Value {
+        parse(src).unwrap().eval(&ctx()).unwrap()
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert_eq!(eval("extruder.temperature > 180 && layer == 2"), Value::Bool(true));
+        assert_eq!(eval("extruder.temperature > 180 && layer == 3"), Value::Bool(false));
+        assert_eq!(eval("var.material == 'PETG' || false"), Value::Bool(true));
+        assert_eq!(eval("!(layer >= 2)"), Value::Bool(false));
+        assert_eq!(eval("unknown == ''"), Value::Bool(true));
+        assert!(!condition_holds("unknown > 3", &ctx()));
+    }
+
+    #[test]
+    fn test_arithmetic_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Value::Number(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Value::Number(9.0));
+        assert_eq!(eval("-layer + 10 % 4"), Value::Number(0.0));
+        assert!(parse("1 / 0").unwrap().eval(&ctx()).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("layer ==").is_err());
+        assert!(parse("(layer == 2").is_err());
+        assert!(parse("layer = 2").is_err());
+        assert!(parse("'open").is_err());
+        assert_eq!(parse("a.b > c").unwrap().names(), vec!["a.b", "c"]);
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render("LOAD_{{var.material}} T={{ extruder.temperature + 0.7 }}", &ctx()), "LOAD_PETG T=206");
+        assert_eq!(render("x={{val}}", &ctx()), "x={{val}}");
+    }
+}
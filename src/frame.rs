@@ -0,0 +1,129 @@
+use spibuttonlib::SPIButtonState;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::panel::{Capabilities, PanelButton, PanelProtocol};
+use crate::units::ButtonId;
+
+/// Double buffering for a panel's LEDs. Writes during a poll compose an
+/// off-screen frame, which is flushed to the panel as a whole right before
+/// the next exchange, so a layer switch or a burst of updates never shows
+/// half done and states overwritten within a poll never reach the panel.
+pub struct FrameBuffer {
+    panel: Box<dyn PanelProtocol>,
+    /// LED states written since the last flush
+    back: BTreeMap<ButtonId, SPIButtonState>,
+    brightness: BTreeMap<ButtonId, u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(panel: Box<dyn PanelProtocol>) -> Self {
+        FrameBuffer {
+            panel,
+            back: BTreeMap::new(),
+            brightness: BTreeMap::new(),
+        }
+    }
+
+    /// Hand the composed frame to the panel.
+    pub fn flush(&mut self) {
+        for (id, state) in std::mem::take(&mut self.back) {
+            self.panel.set_button(id, PanelButton::new(id, state));
+        }
+        for (id, percent) in std::mem::take(&mut self.brightness) {
+            self.panel.set_brightness(id, percent);
+        }
+    }
+}
+
+impl PanelProtocol for FrameBuffer {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        self.flush();
+        self.panel.loop_once()
+    }
+
+    /// The composed state, what the panel will show after the next flush.
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        match self.back.get(&id) {
+            Some(state) => PanelButton::new(id, *state),
+            None => self.panel.get_button(id),
+        }
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        self.back.insert(id, button.get_state());
+    }
+
+    fn configure(&mut self, id: ButtonId, flags: u8) {
+        self.panel.configure(id, flags);
+    }
+
+    fn set_brightness(&mut self, id: ButtonId, percent: u8) {
+        self.brightness.insert(id, percent);
+    }
+
+    fn capabilities(&self) -> &Capabilities {
+        self.panel.capabilities()
+    }
+
+    fn identify(&mut self) -> io::Result<Option<u32>> {
+        self.panel.identify()
+    }
+
+    fn reinitialize(&mut self) -> io::Result<()> {
+        self.panel.reinitialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SpiConfig;
+    use crate::panel;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Records the LED writes reaching the panel.
+    struct RecordingPanel {
+        writes: Rc<RefCell<Vec<(ButtonId, bool)>>>,
+        capabilities: Capabilities,
+    }
+
+    impl PanelProtocol for RecordingPanel {
+        fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+            Ok(vec![])
+        }
+        fn get_button(&self, id: ButtonId) -> PanelButton {
+            PanelButton::new(id, SPIButtonState::Off)
+        }
+        fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+            let lit = !matches!(button.get_state(), SPIButtonState::Off);
+            self.writes.borrow_mut().push((id, lit));
+        }
+        fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+        fn capabilities(&self) -> &Capabilities {
+            &self.capabilities
+        }
+    }
+
+    #[test]
+    fn test_writes_reach_the_panel_together_on_the_next_frame() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let mut frame = FrameBuffer::new(Box::new(RecordingPanel {
+            writes: writes.clone(),
+            capabilities: panel::capabilities(&SpiConfig::default()),
+        }));
+        let led = |id, state| PanelButton::new(ButtonId(id), state);
+
+        frame.set_button(ButtonId(4), led(4, SPIButtonState::On));
+        frame.set_button(ButtonId(1), led(1, SPIButtonState::Flash2));
+        frame.set_button(ButtonId(4), led(4, SPIButtonState::Off));
+        assert!(writes.borrow().is_empty());
+        assert!(matches!(frame.get_button(ButtonId(1)).get_state(), SPIButtonState::Flash2));
+
+        frame.loop_once().unwrap();
+        assert_eq!(*writes.borrow(), vec![(ButtonId(1), true), (ButtonId(4), false)]);
+        frame.loop_once().unwrap();
+        assert_eq!(writes.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,281 @@
+use serde_json::json;
+use serde_yaml::Value;
+
+use crate::config::{Config, ControlConfig, KlipperConfig};
+use crate::error::{Error, Result};
+use crate::migrate::CONFIG_VERSION;
+
+/// Comment for each field of the example, by its path. Every field the
+/// config structs serialize needs one, see `test_every_field_is_commented`.
+const COMMENTS: &[(&str, &str)] = &[
+    ("version", "Config format version, older configs are upgraded on load"),
+    ("spi", "SPI bus the button panel is attached to"),
+    ("spi.device", "SPI device path, /dev/spidev<bus>.<cs>"),
+    ("spi.speed_hz", "SPI clock speed in Hz, see `spi-button-controller sweep`"),
+    ("spi.mode", "SPI mode (0-3)"),
+    (
+        "spi.protocol",
+        "Panel wire protocol: spibutton (default), shift_in, shift_register, mcp23s17 or led_frame",
+    ),
+    ("spi.shift_register", "Chain length, bit order and LED outputs of a shift_register panel"),
+    ("spi.mcp23s17", "Address, pull-ups and button/LED pins of an mcp23s17 expander"),
+    ("spi.led_frame", "Delta frames and full frame interval of an led_frame panel"),
+    ("spi.trace", "Hexdump every transfer at debug level, at most max_per_sec (default 10) a second"),
+    ("polling", "How buttons are read"),
+    ("polling.interval_ms", "Polling interval in milliseconds"),
+    ("polling.groups", "Polling groups with their own interval_ms, e.g. estop: {interval_ms: 10}"),
+    ("polling.panel_check_ms", "How often a panel with an ID register is checked for a swap, 0 never"),
+    ("polling.debounce_ms", "Ignore state changes within this many ms of the previous one"),
+    ("polling.startup_grace_ms", "Ignore buttons held or bouncing this many ms after startup and reloads"),
+    ("polling.min_press_ms", "Ignore presses released within this many ms, e.g. noise from stepper drivers"),
+    ("polling.chord_window_ms", "Longest time between the presses of a chord (default 80)"),
+    ("buttons", "Button mappings, one per button id counting from 0"),
+    ("buttons.button", "Button id, its position in the shift register"),
+    (
+        "buttons.config",
+        "LED and event features: OnChange (0x20) | OnHold (0x40) | lamp Toggle (0x08), e.g. 0x68",
+    ),
+    ("buttons.description", "Shown in logs and by spibuttonctl"),
+    ("buttons.command", "Shell command, or klipper:METHOD|PARAMS for a Klipper API call"),
+    ("buttons.on_release", "Command run when the button is released, e.g. to stop a jog"),
+    ("buttons.enabled_between", "Daily window in which presses are accepted, e.g. 07:00-22:00"),
+    ("buttons.when", "Condition for accepting presses, e.g. extruder.temperature > 180"),
+    ("buttons.sequences", "Commands fired by repeated presses, e.g. a triple press"),
+    ("buttons.sequence_window_ms", "Maximum gap between presses of a sequence"),
+    ("buttons.indicator", "Printer state shown on the LED while idle: job_queue"),
+    ("buttons.brightness", "LED brightness in percent, on panels that can dim"),
+    ("buttons.mutex", "Concurrency group, actions of buttons sharing it run one at a time"),
+    ("buttons.delay_ms", "Run the command this long after the press"),
+    ("buttons.at", "Run the command at the next occurrence of this time of day, e.g. 23:30"),
+    ("buttons.debounce_ms", "Debounce window of this button, overriding polling.debounce_ms"),
+    ("buttons.min_press_ms", "Minimum press duration of this button, overriding polling.min_press_ms"),
+    ("buttons.poll_group", "Polling group from polling.groups handling this button's events"),
+    ("buttons.modifier", "While held, other buttons run their shift_command"),
+    ("buttons.shift_command", "Command run instead while a modifier button is held"),
+    ("buttons.hold_tiers", "Commands fired by releasing after holding for hold_ms"),
+    ("buttons.long_press_ms", "Hold at least this long to run long_press_command instead of command"),
+    ("buttons.long_press_command", "Command run by a long press, e.g. cancel on a pause button"),
+    ("buttons.double_press_command", "Command run by two presses within sequence_window_ms"),
+    ("buttons.tap_commands", "Commands by number of taps within sequence_window_ms, e.g. {2: PARK, 3: HOME}"),
+    ("buttons.cooldown_ms", "Ignore the button this long after it ran a command, e.g. 5s"),
+    ("buttons.cooldown_led", "Flash the LED on presses ignored during the cooldown"),
+    ("buttons.confirm", "Run the command only on a second press within confirm_window_ms"),
+    ("buttons.confirm_window_ms", "Time allowed for the confirming press, default 3s"),
+    ("buttons.state_machine", "States with their LED and transitions on press, long_press, success or failure"),
+    ("buttons.toggle_command", "Command of every second press: command latches on, this latches off"),
+    ("buttons.led_timeouts", "LED state timeouts of this button, overriding the top-level led_timeouts"),
+    ("buttons.deselect_command", "Command run when another button of its radio group is selected"),
+    ("buttons.led_rules", "LED while idle from the first rule that holds, e.g. [{when: 'print_stats.state == \"printing\"', led: on}]"),
+    ("klipper", "Klipper API socket for klipper: commands"),
+    ("klipper.socket_path", "Path to the Klipper API Unix domain socket"),
+    ("klipper.timeout_ms", "Give up waiting for a response after this long"),
+    ("klipper.retries", "How often a request failing with a retryable error is sent again"),
+    ("klipper.retry_delay_ms", "Delay before each retry"),
+    ("klipper.error_categories", "Overrides mapping RPC errors onto categories"),
+    ("klipper.api_key_file", "Root-only file holding the API key sent with every request"),
+    ("klipper.simulate", "Answer requests from a simulated Klipper instead of a printer, for testing"),
+    ("klipper.required", "Wait at startup until Klipper answers before reading the panel"),
+    ("control", "Control socket for spibuttonctl"),
+    ("control.socket_path", "Path of the Unix socket"),
+    ("control.history_size", "Number of executed actions kept for `spibuttonctl last`"),
+    ("control.allowed_uids", "Users besides root and the daemon's own allowed on an @abstract socket"),
+    ("control.allowed_gids", "Groups allowed on an @abstract socket"),
+    ("observer", "Read buttons and log events but run no commands"),
+    ("unknown_buttons", "What to do with events from unmapped buttons: ignore, log or default_command"),
+    ("moonraker", "Moonraker API used for notifications and webcam snapshots"),
+    ("snapshot", "Where snapshot: commands get and put the webcam image"),
+    ("variables", "Initial values and persistence of set_var: variables"),
+    ("profiles", "Named sets of command overrides, switched with spibuttonctl profile"),
+    ("default_profile", "Profile active at startup"),
+    ("aliases", "Commands by name, used in buttons as command: \"!NAME\""),
+    ("groups", "Buttons sharing a command template with {{param.NAME}} placeholders"),
+    ("vars", "Values replacing {{NAME}} in commands and descriptions on load"),
+    ("limits", "Caps on buffers kept in memory: pending_requests, pending_overflow, variables"),
+    ("backpressure", "Alarm when a queue stays this deep: actions, requests, responses, sustain_ms, led, buttons"),
+    ("circuit_breaker", "Fail fast while Klipper or Moonraker is down: failures, open_ms, pattern"),
+    ("chords", "Commands run by pressing buttons together, e.g. {buttons: [0, 3], command: ...}"),
+    ("led_timeouts", "How long an LED state left by a command shows before it clears, e.g. {flash2: 30s}"),
+    ("radio_groups", "Buttons of which one at a time is selected, by name, e.g. {tools: {buttons: [0, 1, 2], initial: 0}}"),
+    ("combos", "Commands run by pressing buttons in order within timeout_ms, e.g. {presses: [1, 1, 3], command: ...}"),
+    ("led_patterns", "Named LED flash patterns, e.g. sos: {durations_ms: [150, 150, ...], repeat: 3}"),
+    ("messages", "Texts shown outside the logs, e.g. the systemd status, by id: {ready: \"Bereit, {{buttons}} Tasten\"}"),
+    ("throttle", "Slow LED animations and indicators while the host is hot or busy: temperature_c (75), load (3.0), led_interval_ms (500ms)"),
+    ("sync", "Mirror LEDs, armed buttons and toggles with other panels' daemons: {listen: 0.0.0.0:7130, peers: [host:7130], secret_file: /etc/...}"),
+    ("runtime", "Tokio runtime, read at startup only: {flavor: current_thread or multi_thread, max_blocking_threads: 2, shutdown_timeout_ms: 5s}"),
+];
+
+/// JSON Schema of the config format for editors, derived from the config
+/// structs so it lists the same fields and doc comments.
+pub fn schema() -> Result<String> {
+    let schema = schemars::schema_for!(Config);
+    serde_json::to_string_pretty(&schema).map_err(|e| Error::internal("Failed to serialize the config schema").caused_by(e))
+}
+
+/// A commented example config for `buttons` buttons. The fields come from
+/// serializing the config structs, so every option is listed; unset ones
+/// are commented out. Only the first button lists every option.
+pub fn example_config(buttons: usize) -> Result<String> {
+    let mut out = String::from("# SPI Button Controller configuration\n");
+    render_object(&mut out, &example_value(buttons)?, "", 0, true);
+    Ok(out)
+}
+
+fn example_value(buttons: usize) -> Result<Value> {
+    let buttons = (0..buttons)
+        .map(|i| {
+            serde_json::from_value(json!({
+                "button": i,
+                "config": 0x68,
+                "description": format!("Button {}", i),
+                "command": format!("echo button {} pressed", i),
+            }))
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| Error::internal("Failed to build the example buttons").caused_by(e))?;
+    let config = Config {
+        version: Some(CONFIG_VERSION),
+        buttons,
+        klipper: Some(KlipperConfig {
+            socket_path: "/run/klipper_uds".to_string(),
+            timeout_ms: None,
+            retries: None,
+            retry_delay_ms: None,
+            error_categories: None,
+            api_key_file: None,
+            simulate: None,
+            required: None,
+            api_key: None,
+        }),
+        control: Some(ControlConfig::default()),
+        ..Config::default()
+    };
+    // YAML values keep the fields in struct order
+    serde_yaml::to_value(config).map_err(|e| Error::internal("Failed to serialize the config").caused_by(e))
+}
+
+fn comment(path: &str) -> Option<&'static str> {
+    COMMENTS.iter().find(|(p, _)| *p == path).map(|(_, c)| *c)
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn scalar(path: &str, value: &Value) -> String {
+    match value.as_u64() {
+        // Feature flags read best in hex
+        Some(flags) if path == "buttons.config" => format!("{:#04x}", flags),
+        _ => serde_yaml::to_string(value).unwrap_or_default().trim_end().to_string(),
+    }
+}
+
+/// Write the fields of `value` at `indent`, with comments and unset fields
+/// when `full`.
+fn render_object(out: &mut String, value: &Value, path: &str, indent: usize, full: bool) {
+    let pad = " ".repeat(indent);
+    let Some(map) = value.as_mapping() else { return };
+    for (key, field) in map {
+        let key = key.as_str().unwrap_or_default();
+        let field_path = join(path, key);
+        if field.is_null() && !full {
+            continue;
+        }
+        if full {
+            if indent == 0 {
+                out.push('\n');
+            }
+            if let Some(text) = comment(&field_path) {
+                out.push_str(&format!("{}# {}\n", pad, text));
+            }
+        }
+        match field {
+            Value::Null => out.push_str(&format!("{}# {}:\n", pad, key)),
+            Value::Mapping(_) => {
+                out.push_str(&format!("{}{}:\n", pad, key));
+                render_object(out, field, &field_path, indent + 2, full);
+            }
+            Value::Sequence(items) if items.iter().all(Value::is_mapping) && !items.is_empty() => {
+                out.push_str(&format!("{}{}:\n", pad, key));
+                for (i, item) in items.iter().enumerate() {
+                    let mut entry = String::new();
+                    render_object(&mut entry, item, &field_path, indent + 4, full && i == 0);
+                    // Turn the first field's indentation into the list dash
+                    let first = entry.lines().position(|l| !l.trim_start().starts_with('#')).unwrap_or(0);
+                    for (n, line) in entry.lines().enumerate() {
+                        if n == first {
+                            out.push_str(&format!("{}  - {}\n", pad, line.trim_start()));
+                        } else {
+                            out.push_str(&format!("{}\n", line));
+                        }
+                    }
+                    if full && i + 1 < items.len() {
+                        out.push('\n');
+                    }
+                }
+            }
+            _ => out.push_str(&format!("{}{}: {}\n", pad, key, scalar(&field_path, field))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(value: &Value, path: &str, found: &mut Vec<String>) {
+        match value {
+            Value::Mapping(map) => {
+                for (key, field) in map {
+                    let field_path = join(path, key.as_str().unwrap_or_default());
+                    found.push(field_path.clone());
+                    paths(field, &field_path, found);
+                }
+            }
+            Value::Sequence(items) => items.iter().for_each(|item| paths(item, path, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_every_field_is_commented() {
+        let mut found = Vec::new();
+        paths(&example_value(1).unwrap(), "", &mut found);
+        let missing: Vec<&String> = found.iter().filter(|p| comment(p).is_none()).collect();
+        assert!(missing.is_empty(), "fields without a comment: {:?}", missing);
+        let stale: Vec<&str> = COMMENTS
+            .iter()
+            .map(|(p, _)| *p)
+            .filter(|p| !found.iter().any(|f| f == p))
+            .collect();
+        assert!(stale.is_empty(), "comments for unknown fields: {:?}", stale);
+    }
+
+    #[test]
+    fn test_schema_covers_the_example() {
+        let schema: serde_json::Value = serde_json::from_str(&schema().unwrap()).unwrap();
+        let Value::Mapping(example) = example_value(1).unwrap() else { panic!("example is not a mapping") };
+        for key in example.keys().filter_map(Value::as_str) {
+            assert!(schema["properties"].get(key).is_some(), "{} is missing in the schema", key);
+        }
+        let defs = &schema["$defs"];
+        assert_eq!(defs["PollingConfig"]["properties"]["interval_ms"]["$ref"], "#/$defs/Milliseconds");
+        assert_eq!(defs["Milliseconds"]["anyOf"][1]["type"], "string");
+        assert_eq!(defs["ButtonMapping"]["required"], json!(["button", "command"]));
+    }
+
+    #[test]
+    fn test_example_loads_and_validates() {
+        let example = example_config(3).unwrap();
+        assert!(example.contains("# Path to the Klipper API Unix domain socket\n  socket_path: /run/klipper_uds\n"));
+        assert!(example.contains("    # mutex:\n"));
+
+        let config: Config = serde_yaml::from_str(&example).unwrap();
+        assert_eq!(config.buttons.len(), 3);
+        assert_eq!(config.buttons[2].command, "echo button 2 pressed");
+        assert!(config.validate().is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::units::ButtonId;
+
+/// Time allowed between presses of a sequence when not configured.
+pub const DEFAULT_SEQUENCE_WINDOW_MS: u64 = 400;
+
+#[derive(Debug)]
+struct TapCounter {
+    count: u32,
+    deadline: Instant,
+}
+
+/// Counts consecutive presses per button. A sequence ends when no further
+/// press arrives within the window, and its press count is then reported.
+#[derive(Debug, Default)]
+pub struct Gestures {
+    taps: HashMap<ButtonId, TapCounter>,
+}
+
+impl Gestures {
+    pub fn new() -> Self {
+        Gestures::default()
+    }
+
+    /// Register a press and return the number of presses in the current sequence.
+    pub fn press(&mut self, button_id: ButtonId, now: Instant, window: Duration) -> u32 {
+        let counter = self.taps.entry(button_id).or_insert(TapCounter {
+            count: 0,
+            deadline: now,
+        });
+        counter.count += 1;
+        counter.deadline = now + window;
+        counter.count
+    }
+
+    /// End a button's sequence early, e.g. when the longest configured
+    /// sequence has been reached and no more presses can change the outcome.
+    pub fn take(&mut self, button_id: ButtonId) -> Option<u32> {
+        self.taps.remove(&button_id).map(|c| c.count)
+    }
+
+    /// Remove and return every sequence whose window has closed.
+    pub fn take_expired(&mut self, now: Instant) -> Vec<(ButtonId, u32)> {
+        let mut expired: Vec<(ButtonId, u32)> = self
+            .taps
+            .iter()
+            .filter(|(_, c)| c.deadline <= now)
+            .map(|(id, c)| (*id, c.count))
+            .collect();
+        expired.sort();
+        for (id, _) in &expired {
+            self.taps.remove(id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presses_within_window_accumulate() {
+        let mut gestures = Gestures::new();
+        let window = Duration::from_millis(400);
+        let t0 = Instant::now();
+
+        assert_eq!(gestures.press(ButtonId(1), t0, window), 1);
+        assert_eq!(gestures.press(ButtonId(1), t0 + Duration::from_millis(300), window), 2);
+        assert_eq!(gestures.press(ButtonId(2), t0 + Duration::from_millis(300), window), 1);
+
+        // Window is measured from the latest press
+        assert!(gestures.take_expired(t0 + Duration::from_millis(600)).is_empty());
+        assert_eq!(
+            gestures.take_expired(t0 + Duration::from_millis(700)),
+            vec![(ButtonId(1), 2), (ButtonId(2), 1)]
+        );
+        assert!(gestures.take_expired(t0 + Duration::from_secs(5)).is_empty());
+    }
+
+    #[test]
+    fn test_take_ends_sequence() {
+        let mut gestures = Gestures::new();
+        let t0 = Instant::now();
+        gestures.press(ButtonId(3), t0, Duration::from_millis(400));
+        gestures.press(ButtonId(3), t0, Duration::from_millis(400));
+        assert_eq!(gestures.take(ButtonId(3)), Some(2));
+        assert_eq!(gestures.take(ButtonId(3)), None);
+    }
+}
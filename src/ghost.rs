@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use spibuttonlib::SPIButtonState;
+
+use crate::panel::PanelButton;
+use crate::units::ButtonId;
+
+/// Holds back presses until a later read still finds the button down and
+/// it has been down for its minimum press duration. A press released
+/// before that is a ghost, e.g. a single noisy sample picked up near a
+/// stepper driver, and neither it nor its release is handled.
+#[derive(Debug, Default)]
+pub struct PressFilter {
+    pending: HashMap<ButtonId, (PanelButton, Instant)>,
+}
+
+impl PressFilter {
+    pub fn new() -> Self {
+        PressFilter::default()
+    }
+
+    /// The events to handle after a read at `now`: confirmed presses of
+    /// earlier reads, then this read's events that are not held back.
+    /// Buttons with a zero `min_press` pass straight through.
+    pub fn filter<F>(&mut self, now: Instant, events: Vec<PanelButton>, min_press: F) -> Vec<PanelButton>
+    where
+        F: Fn(ButtonId) -> Duration,
+    {
+        let earlier: Vec<ButtonId> = self
+            .pending
+            .iter()
+            .filter(|(id, (_, since))| now.duration_since(*since) >= min_press(**id))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut passed = Vec::new();
+        for b in events {
+            match b.get_state() {
+                SPIButtonState::Off if self.pending.contains_key(&b.id()) => {
+                    self.pending.remove(&b.id());
+                    debug!("Button {} released before its minimum press duration, ignoring", b.id());
+                }
+                SPIButtonState::Off => passed.push(b),
+                _ if min_press(b.id()).is_zero() => passed.push(b),
+                _ => {
+                    self.pending.entry(b.id()).or_insert((b, now));
+                }
+            }
+        }
+
+        // Pending since an earlier read and not released in this one
+        let mut confirmed: Vec<(PanelButton, Instant)> =
+            earlier.iter().filter_map(|id| self.pending.remove(id)).collect();
+        confirmed.sort_by_key(|(_, since)| *since);
+        confirmed.into_iter().map(|(b, _)| b).chain(passed).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presses_need_a_confirming_read() {
+        let button = |id, state| PanelButton::new(ButtonId(id), state);
+        let ids = |events: Vec<PanelButton>| events.iter().map(|b| b.id().0).collect::<Vec<u8>>();
+        let ms = Duration::from_millis;
+        let min_press = |id: ButtonId| if id.0 == 3 { Duration::ZERO } else { ms(20) };
+        let t0 = Instant::now();
+        let mut filter = PressFilter::new();
+
+        // A single-sample ghost on button 1, a real press on button 2
+        let events = vec![button(1, SPIButtonState::On), button(2, SPIButtonState::On)];
+        assert!(filter.filter(t0, events, min_press).is_empty());
+        assert!(filter.filter(t0 + ms(10), vec![button(1, SPIButtonState::Off)], min_press).is_empty());
+        // Button 2 is still down and has been for long enough
+        assert_eq!(ids(filter.filter(t0 + ms(20), vec![], min_press)), vec![2]);
+        assert_eq!(ids(filter.filter(t0 + ms(30), vec![button(2, SPIButtonState::Off)], min_press)), vec![2]);
+
+        // Long enough but not yet confirmed by a later read, or not filtered at all
+        let events = vec![button(1, SPIButtonState::On), button(3, SPIButtonState::On)];
+        assert_eq!(ids(filter.filter(t0 + ms(40), events, min_press)), vec![3]);
+        assert_eq!(ids(filter.filter(t0 + ms(45), vec![], min_press)), Vec::<u8>::new());
+        assert_eq!(ids(filter.filter(t0 + ms(60), vec![], min_press)), vec![1]);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use spibuttonlib::SPIButtonState;
+
+use crate::panel::PanelButton;
+use crate::units::ButtonId;
+
+/// Latches button states for a grace period after startup or a reload, so
+/// a button held down or bouncing while the panel is set up fires nothing.
+/// A button still held when the period ends stays latched until released;
+/// only changes after that are handled.
+#[derive(Debug)]
+pub struct StartupGrace {
+    until: Instant,
+    held: HashSet<ButtonId>,
+}
+
+impl StartupGrace {
+    pub fn new(now: Instant, period: Duration) -> Self {
+        StartupGrace {
+            until: now + period,
+            held: HashSet::new(),
+        }
+    }
+
+    /// The events to handle at `now`, dropping those of the grace period
+    /// and of buttons held through it.
+    pub fn filter(&mut self, now: Instant, events: Vec<PanelButton>) -> Vec<PanelButton> {
+        let in_grace = now < self.until;
+        events
+            .into_iter()
+            .filter(|b| {
+                let released = matches!(b.get_state(), SPIButtonState::Off);
+                if in_grace {
+                    if released {
+                        self.held.remove(&b.id());
+                    } else {
+                        self.held.insert(b.id());
+                    }
+                    false
+                } else if self.held.contains(&b.id()) {
+                    if released {
+                        self.held.remove(&b.id());
+                    }
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buttons_held_through_grace_stay_latched() {
+        let button = |id, state| PanelButton::new(ButtonId(id), state);
+        let ids = |events: Vec<PanelButton>| events.iter().map(|b| b.id().0).collect::<Vec<u8>>();
+        let ms = Duration::from_millis;
+        let t0 = Instant::now();
+        let mut grace = StartupGrace::new(t0, ms(500));
+
+        // Button 1 is held at startup, button 2 bounces
+        let events = vec![
+            button(1, SPIButtonState::On),
+            button(2, SPIButtonState::On),
+            button(2, SPIButtonState::Off),
+        ];
+        assert!(grace.filter(t0 + ms(10), events).is_empty());
+
+        // After the grace period, button 1 fires only once released and pressed again
+        assert!(grace.filter(t0 + ms(600), vec![button(1, SPIButtonState::On)]).is_empty());
+        let events = vec![button(1, SPIButtonState::Off), button(2, SPIButtonState::On)];
+        assert_eq!(ids(grace.filter(t0 + ms(700), events)), vec![2]);
+        assert_eq!(ids(grace.filter(t0 + ms(800), vec![button(1, SPIButtonState::On)])), vec![1]);
+    }
+}
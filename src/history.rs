@@ -0,0 +1,198 @@
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::units::ButtonId;
+
+/// Number of executed actions kept in memory when not configured.
+pub const DEFAULT_HISTORY_SIZE: usize = 50;
+
+/// Longest output snippet kept per record.
+const OUTPUT_SNIPPET_LEN: usize = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Klipper request sent, response not yet received
+    Pending,
+    Succeeded,
+    Failed(String),
+    /// Not executed because the daemon runs in observer mode
+    Suppressed,
+}
+
+/// One executed action, as shown by `spibuttonctl last`.
+#[derive(Debug, Clone)]
+pub struct ActionRecord {
+    pub at: DateTime<Local>,
+    pub started: Instant,
+    pub correlation_id: Uuid,
+    pub button: ButtonId,
+    pub description: Option<String>,
+    pub command: String,
+    pub request_id: Option<u32>,
+    pub duration: Option<Duration>,
+    pub outcome: Outcome,
+    pub output: String,
+}
+
+impl ActionRecord {
+    pub fn new(correlation_id: Uuid, button: ButtonId, description: Option<String>, command: &str) -> Self {
+        ActionRecord {
+            at: Local::now(),
+            started: Instant::now(),
+            correlation_id,
+            button,
+            description,
+            command: command.to_string(),
+            request_id: None,
+            duration: None,
+            outcome: Outcome::Pending,
+            output: String::new(),
+        }
+    }
+
+    /// Mark the action finished now, keeping a one-line snippet of its output.
+    pub fn finish(&mut self, outcome: Outcome, output: &str) {
+        self.duration = Some(self.started.elapsed());
+        self.outcome = outcome;
+        self.output = snippet(output);
+    }
+}
+
+impl fmt::Display for ActionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duration = match self.duration {
+            Some(d) => format!("{}ms", d.as_millis()),
+            None => "-".to_string(),
+        };
+        let outcome = match &self.outcome {
+            Outcome::Pending => "pending".to_string(),
+            Outcome::Succeeded => "ok".to_string(),
+            Outcome::Failed(reason) => format!("failed ({})", reason),
+            Outcome::Suppressed => "suppressed".to_string(),
+        };
+        write!(
+            f,
+            "{} button={} {:?} {} {} [{}] {}",
+            self.at.format("%Y-%m-%d %H:%M:%S"),
+            self.button,
+            self.description.as_deref().unwrap_or(""),
+            outcome,
+            duration,
+            self.correlation_id,
+            self.command
+        )?;
+        if !self.output.is_empty() {
+            write!(f, " => {}", self.output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ring buffer of the most recently executed actions.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    records: VecDeque<ActionRecord>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, record: ActionRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Complete the pending record for a Klipper request once its response
+    /// arrives, returning it.
+    pub fn complete_request(&mut self, request_id: u32, outcome: Outcome, output: &str) -> Option<&ActionRecord> {
+        let record = self
+            .records
+            .iter_mut()
+            .rev()
+            .find(|r| r.request_id == Some(request_id))?;
+        record.finish(outcome, output);
+        Some(record)
+    }
+
+    /// Klipper requests still waiting for their response.
+    pub fn pending(&self) -> usize {
+        self.records.iter().filter(|r| r.outcome == Outcome::Pending).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The last `n` records, oldest first.
+    pub fn last(&self, n: usize) -> impl Iterator<Item = &ActionRecord> {
+        self.records.iter().skip(self.records.len().saturating_sub(n))
+    }
+}
+
+fn snippet(output: &str) -> String {
+    let line = output.trim().lines().next().unwrap_or("");
+    if line.chars().count() > OUTPUT_SNIPPET_LEN {
+        let cut: String = line.chars().take(OUTPUT_SNIPPET_LEN).collect();
+        format!("{}...", cut)
+    } else {
+        line.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(button: ButtonId) -> ActionRecord {
+        ActionRecord::new(Uuid::new_v4(), button, None, "echo test")
+    }
+
+    #[test]
+    fn test_history_keeps_last_entries() {
+        let mut history = History::new(3);
+        for button in 0..5 {
+            history.push(record(ButtonId(button)));
+        }
+
+        let buttons: Vec<ButtonId> = history.last(10).map(|r| r.button).collect();
+        assert_eq!(buttons, [2, 3, 4].map(ButtonId));
+        let buttons: Vec<ButtonId> = history.last(2).map(|r| r.button).collect();
+        assert_eq!(buttons, [3, 4].map(ButtonId));
+    }
+
+    #[test]
+    fn test_complete_request() {
+        let mut history = History::new(3);
+        let mut pending = record(ButtonId(1));
+        pending.request_id = Some(7);
+        history.push(pending);
+
+        history.complete_request(7, Outcome::Succeeded, "{\"result\": {}}\nmore");
+        let r = history.last(1).next().unwrap();
+        assert_eq!(r.outcome, Outcome::Succeeded);
+        assert_eq!(r.output, "{\"result\": {}}");
+        assert!(r.duration.is_some());
+    }
+}
@@ -0,0 +1,111 @@
+use spibuttonlib::SPIButtonState;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::HoldTier;
+use crate::units::ButtonId;
+
+/// Buttons with hold tiers that are currently held down.
+#[derive(Debug, Default)]
+pub struct Holds {
+    pressed: HashMap<ButtonId, Held>,
+}
+
+#[derive(Debug)]
+struct Held {
+    since: Instant,
+    /// Tier last shown on the LED
+    shown: Option<usize>,
+}
+
+impl Holds {
+    pub fn new() -> Self {
+        Holds::default()
+    }
+
+    pub fn press(&mut self, button_id: ButtonId, now: Instant) {
+        self.pressed.insert(button_id, Held { since: now, shown: None });
+    }
+
+    /// End a hold, returning how long the button was held. `None` if the
+    /// press was never seen, e.g. after a reload.
+    pub fn release(&mut self, button_id: ButtonId, now: Instant) -> Option<Duration> {
+        self.pressed.remove(&button_id).map(|held| now - held.since)
+    }
+
+    /// Buttons whose reached tier changed since the last call, with the new
+    /// tier, so the LED only needs updating then.
+    pub fn advanced<'a>(
+        &mut self,
+        now: Instant,
+        tiers_of: impl Fn(ButtonId) -> &'a [HoldTier],
+    ) -> Vec<(ButtonId, usize)> {
+        let mut changed = Vec::new();
+        for (button_id, held) in self.pressed.iter_mut() {
+            let tier = reached(tiers_of(*button_id), now - held.since);
+            if let Some(tier) = tier.filter(|t| held.shown != Some(*t)) {
+                held.shown = Some(tier);
+                changed.push((*button_id, tier));
+            }
+        }
+        changed.sort();
+        changed
+    }
+}
+
+/// Index of the longest tier `held` has reached, tiers sorted by `hold_ms`.
+pub fn reached(tiers: &[HoldTier], held: Duration) -> Option<usize> {
+    tiers.iter().rposition(|t| held >= Duration::from_millis(t.hold_ms))
+}
+
+/// LED showing which tier a release would fire: on, then slow and fast
+/// flashing for the longer tiers.
+pub fn tier_led(tier: usize) -> SPIButtonState {
+    match tier {
+        0 => SPIButtonState::On,
+        1 => SPIButtonState::Flash1,
+        _ => SPIButtonState::Flash2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers() -> Vec<HoldTier> {
+        [1000, 3000, 6000]
+            .iter()
+            .map(|ms| HoldTier {
+                hold_ms: *ms,
+                description: None,
+                command: format!("echo {}", ms),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reached_tier() {
+        let tiers = tiers();
+        assert_eq!(reached(&tiers, Duration::from_millis(999)), None);
+        assert_eq!(reached(&tiers, Duration::from_millis(1000)), Some(0));
+        assert_eq!(reached(&tiers, Duration::from_millis(5999)), Some(1));
+        assert_eq!(reached(&tiers, Duration::from_secs(60)), Some(2));
+    }
+
+    #[test]
+    fn test_led_follows_tiers_while_held() {
+        let tiers = tiers();
+        let mut holds = Holds::new();
+        let t0 = Instant::now();
+        holds.press(ButtonId(4), t0);
+
+        assert!(holds.advanced(t0 + Duration::from_millis(500), |_| &tiers).is_empty());
+        assert_eq!(holds.advanced(t0 + Duration::from_millis(1200), |_| &tiers), vec![(ButtonId(4), 0)]);
+        // Unchanged tier, nothing to update
+        assert!(holds.advanced(t0 + Duration::from_millis(2000), |_| &tiers).is_empty());
+        assert_eq!(holds.advanced(t0 + Duration::from_millis(3100), |_| &tiers), vec![(ButtonId(4), 1)]);
+
+        assert_eq!(holds.release(ButtonId(4), t0 + Duration::from_secs(4)), Some(Duration::from_secs(4)));
+        assert_eq!(holds.release(ButtonId(4), t0 + Duration::from_secs(5)), None);
+    }
+}
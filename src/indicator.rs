@@ -0,0 +1,117 @@
+use spibuttonlib::SPIButtonState;
+
+use crate::config::{Indicator, LedRule};
+use crate::expr::{self, Context};
+use crate::notifications::Notification;
+
+/// Printer state shown on indicator LEDs, kept up to date from Moonraker
+/// notifications.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IndicatorState {
+    pub queued_jobs: usize,
+    pub queue_paused: bool,
+}
+
+impl IndicatorState {
+    /// Apply a notification. Returns whether the state changed.
+    pub fn update(&mut self, notification: &Notification) -> bool {
+        let before = self.clone();
+        if notification.method == "notify_job_queue_changed" {
+            if let Some(event) = notification.event() {
+                // updated_queue is null when only the queue state changed
+                if let Some(queue) = event["updated_queue"].as_array() {
+                    self.queued_jobs = queue.len();
+                }
+                if let Some(state) = event["queue_state"].as_str() {
+                    self.queue_paused = state == "paused";
+                }
+            }
+        }
+        *self != before
+    }
+
+    /// LED state an idle button shows for its indicator.
+    pub fn led(&self, indicator: Indicator) -> SPIButtonState {
+        match indicator {
+            Indicator::JobQueue if self.queued_jobs == 0 => SPIButtonState::Off,
+            Indicator::JobQueue if self.queue_paused => SPIButtonState::Flash1,
+            Indicator::JobQueue => SPIButtonState::On,
+        }
+    }
+}
+
+/// LED state of the first of `rules` whose condition holds, off when none
+/// does.
+pub fn rule_led(rules: &[LedRule], ctx: &dyn Context) -> SPIButtonState {
+    rules
+        .iter()
+        .find(|rule| expr::condition_holds(&rule.when, ctx))
+        .map_or(SPIButtonState::Off, |rule| rule.led.state())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Led;
+    use crate::printer::{PrinterState, Scope};
+    use crate::vars::Variables;
+
+    fn job_queue_changed(event: &str) -> Notification {
+        Notification::parse(&format!(
+            r#"{{"jsonrpc": "2.0", "method": "notify_job_queue_changed", "params": [{}]}}"#,
+            event
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_job_queue_updates() {
+        let mut state = IndicatorState::default();
+        assert!(matches!(state.led(Indicator::JobQueue), SPIButtonState::Off));
+
+        assert!(state.update(&job_queue_changed(
+            r#"{"action": "jobs_added", "updated_queue": [{"job_id": "a"}, {"job_id": "b"}], "queue_state": "ready"}"#
+        )));
+        assert_eq!(state.queued_jobs, 2);
+        assert!(matches!(state.led(Indicator::JobQueue), SPIButtonState::On));
+
+        // A state change alone keeps the known queue length
+        assert!(state.update(&job_queue_changed(
+            r#"{"action": "state_changed", "updated_queue": null, "queue_state": "paused"}"#
+        )));
+        assert_eq!(state.queued_jobs, 2);
+        assert!(matches!(state.led(Indicator::JobQueue), SPIButtonState::Flash1));
+
+        assert!(!state.update(&job_queue_changed(
+            r#"{"action": "state_changed", "updated_queue": null, "queue_state": "paused"}"#
+        )));
+    }
+
+    #[test]
+    fn test_first_matching_rule_sets_the_led() {
+        let rules: Vec<LedRule> = serde_yaml::from_str(
+            r#"
+- {when: 'print_stats.state == "printing"', led: on}
+- {when: 'print_stats.state == "paused"', led: flash1}
+"#,
+        )
+        .unwrap();
+        assert_eq!(rules[1].led, Led::Flash1);
+        let variables = Variables::new(None);
+        let mut printer = PrinterState::default();
+        let status = |state: &str| {
+            Notification::parse(&format!(
+                r#"{{"jsonrpc": "2.0", "method": "notify_status_update", "params": [{{"print_stats": {{"state": "{}"}}}}, 1.0]}}"#,
+                state
+            ))
+            .unwrap()
+        };
+        let led = |printer: &PrinterState| rule_led(&rules, &Scope { variables: &variables, printer, press: None }) as u8;
+
+        assert_eq!(led(&printer), SPIButtonState::Off as u8);
+        printer.update(&status("paused"));
+        assert_eq!(led(&printer), SPIButtonState::Flash1 as u8);
+        printer.update(&status("printing"));
+        assert_eq!(led(&printer), SPIButtonState::On as u8);
+    }
+}
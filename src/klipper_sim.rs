@@ -0,0 +1,274 @@
+use log::{debug, info, warn};
+use serde_json::{json, Value as JsonValue};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::time::sleep;
+
+use crate::command::{EtxFramer, ETX};
+use crate::config::{KlipperSimulation, SimulatedOutcome};
+use crate::error::{Error, Result};
+use crate::socket;
+
+/// Error answered by failing requests unless `error_message` says otherwise.
+/// Matches the built-in retryable category.
+const DEFAULT_ERROR_MESSAGE: &str = "Klippy not ready";
+
+/// Seed used when `seed` is unset, so runs repeat by default.
+const DEFAULT_SEED: u64 = 1;
+
+/// Bind `socket_path` and answer Klipper API requests there as
+/// `simulation` describes. Refuses a path something is listening on,
+/// e.g. a real Klipper's socket.
+pub fn spawn(socket_path: &str, simulation: KlipperSimulation) -> Result<()> {
+    if socket::file_exists(socket_path) {
+        if socket::connect_blocking(socket_path).is_ok() {
+            return Err(Error::config(format!(
+                "Not simulating Klipper on {}, something is listening there",
+                socket_path
+            )));
+        }
+        std::fs::remove_file(socket_path)
+            .map_err(|e| Error::internal(format!("Failed to remove stale socket: {}", socket_path)).caused_by(e))?;
+    }
+    let listener = socket::bind(socket_path)
+        .map_err(|e| Error::internal(format!("Failed to bind simulated Klipper socket: {}", socket_path)).caused_by(e))?;
+    warn!("Simulating Klipper on {}, no printer is used", socket_path);
+
+    tokio::spawn(async move {
+        let mut simulator = Simulator::new(simulation);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let (outcome, delay) = simulator.next();
+                    let error_message = simulator.error_message();
+                    tokio::spawn(async move {
+                        if let Err(e) = answer(stream, outcome, delay, &error_message).await {
+                            debug!("Simulated Klipper client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Simulated Klipper accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Draws the outcome and delay of each request in turn.
+struct Simulator {
+    simulation: KlipperSimulation,
+    requests: usize,
+    rng: XorShift,
+}
+
+impl Simulator {
+    fn new(simulation: KlipperSimulation) -> Self {
+        let rng = XorShift::new(simulation.seed.unwrap_or(DEFAULT_SEED));
+        Simulator { simulation, requests: 0, rng }
+    }
+
+    fn next(&mut self) -> (SimulatedOutcome, Duration) {
+        let sim = &self.simulation;
+        let scripted = sim.script.as_ref().and_then(|script| script.get(self.requests)).copied();
+        self.requests += 1;
+        let draw = self.rng.unit();
+        let outcome = scripted.unwrap_or_else(|| {
+            let error_rate = sim.error_rate.unwrap_or(0.0);
+            if draw < error_rate {
+                SimulatedOutcome::Error
+            } else if draw < error_rate + sim.disconnect_rate.unwrap_or(0.0) {
+                SimulatedOutcome::Disconnect
+            } else {
+                SimulatedOutcome::Ok
+            }
+        });
+        let jitter = match sim.jitter_ms.unwrap_or(0) {
+            0 => 0,
+            jitter => self.rng.next() % (jitter + 1),
+        };
+        (outcome, Duration::from_millis(sim.latency_ms.unwrap_or(0) + jitter))
+    }
+
+    fn error_message(&self) -> String {
+        self.simulation.error_message.clone().unwrap_or_else(|| DEFAULT_ERROR_MESSAGE.to_string())
+    }
+}
+
+async fn answer(
+    mut stream: UnixStream,
+    outcome: SimulatedOutcome,
+    delay: Duration,
+    error_message: &str,
+) -> io::Result<()> {
+    let mut framer = EtxFramer::new();
+    let mut buffer = [0; 4096];
+    let request = loop {
+        if let Some(frame) = framer.next_frame() {
+            break frame;
+        }
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        framer.push(&buffer[..n])?;
+    };
+    let request: JsonValue = serde_json::from_slice(&request).unwrap_or_default();
+    let id = request["id"].clone();
+    let method = request["method"].as_str().unwrap_or("");
+    info!("Simulated Klipper request id={} {}: {:?} after {}ms", id, method, outcome, delay.as_millis());
+
+    sleep(delay).await;
+    let response = match outcome {
+        SimulatedOutcome::Ok if method == "info" => {
+            json!({"id": id, "result": {"state": "ready", "state_message": "Printer is ready"}})
+        }
+        SimulatedOutcome::Ok => json!({"id": id, "result": {}}),
+        SimulatedOutcome::Error => json!({"id": id, "error": {"error": "WebRequestError", "message": error_message}}),
+        SimulatedOutcome::Disconnect => return Ok(()),
+        SimulatedOutcome::Hang => {
+            // Until the client gives up
+            while stream.read(&mut buffer).await? > 0 {}
+            return Ok(());
+        }
+    };
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(ETX);
+    stream.write_all(&response).await
+}
+
+/// xorshift64*, enough for repeatable outcomes without a dependency.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // A zero state would stay zero
+        XorShift(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number from 0 up to, not including, 1.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandExecutor, EventMessage, ResponseStatus};
+    use crate::config::KlipperConfig;
+    use std::io::ErrorKind;
+    use tokio::sync::mpsc;
+
+    fn start(name: &str, simulation: KlipperSimulation) -> KlipperConfig {
+        let path = std::env::temp_dir().join(format!("spibtn-sim-{}-{}", name, std::process::id()));
+        let socket_path = path.to_string_lossy().to_string();
+        spawn(&socket_path, simulation).unwrap();
+        KlipperConfig {
+            socket_path,
+            timeout_ms: Some(200),
+            ..KlipperConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scripted_outcomes_then_rates() {
+        use SimulatedOutcome::*;
+        let klipper = start(
+            "script",
+            KlipperSimulation {
+                script: Some(vec![Error, Disconnect, Hang, Ok]),
+                disconnect_rate: Some(1.0),
+                ..KlipperSimulation::default()
+            },
+        );
+        let mut statuses = Vec::new();
+        for id in 1..=5 {
+            statuses.push(CommandExecutor::klipper_request("klipper:info|{}", &klipper, id).await.0);
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                ResponseStatus::RpcError { code: None, message: "Klippy not ready".to_string() },
+                ResponseStatus::EmptyResponse,
+                ResponseStatus::Timeout,
+                ResponseStatus::Ok,
+                ResponseStatus::EmptyResponse,
+            ]
+        );
+
+        // A path in use is refused
+        assert!(spawn(&klipper.socket_path, KlipperSimulation::default()).is_err());
+        let _ = std::fs::remove_file(&klipper.socket_path);
+        assert!(matches!(
+            CommandExecutor::klipper_request("info", &klipper, 6).await.0,
+            ResponseStatus::ConnectionError(ErrorKind::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retries_ride_out_injected_errors() {
+        let simulation = KlipperSimulation {
+            latency_ms: Some(1),
+            jitter_ms: Some(5),
+            error_rate: Some(0.5),
+            seed: Some(7),
+            ..KlipperSimulation::default()
+        };
+        // The same seed gives the same outcomes
+        let draws = |simulation: &KlipperSimulation| {
+            let mut simulator = Simulator::new(simulation.clone());
+            (0..20).map(|_| simulator.next()).collect::<Vec<_>>()
+        };
+        let outcomes = draws(&simulation);
+        assert_eq!(outcomes, draws(&simulation));
+        assert!(outcomes.iter().any(|(o, _)| *o == SimulatedOutcome::Error));
+        assert!(outcomes.iter().all(|(_, delay)| (1..=6).contains(&delay.as_millis())));
+
+        let mut klipper = start("retry", simulation);
+        klipper.retries = Some(20);
+        klipper.retry_delay_ms = Some(1);
+        let (tx, mut rx) = mpsc::channel(4);
+        let command = r#"klipper:gcode/script|{"script":"G28"}"#;
+        CommandExecutor::send_klipper_command(command, &klipper, 1, Default::default(), tx).await;
+        match rx.recv().await {
+            Some(EventMessage::Response(response)) => assert_eq!(response.status, ResponseStatus::Ok),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let _ = std::fs::remove_file(&klipper.socket_path);
+    }
+
+    #[tokio::test]
+    async fn test_late_answer_is_not_sent_again() {
+        use SimulatedOutcome::*;
+        // Answered after the timeout, a second request would hang
+        let mut klipper = start(
+            "late",
+            KlipperSimulation {
+                latency_ms: Some(300),
+                script: Some(vec![Ok, Hang, Hang]),
+                ..KlipperSimulation::default()
+            },
+        );
+        klipper.timeout_ms = Some(100);
+        klipper.retries = Some(2);
+        klipper.retry_delay_ms = Some(500);
+        let (tx, mut rx) = mpsc::channel(4);
+        CommandExecutor::send_klipper_command("klipper:gcode/script|{}", &klipper, 1, Default::default(), tx).await;
+        match rx.recv().await {
+            Some(EventMessage::Response(response)) => assert_eq!(response.status, ResponseStatus::Ok),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let _ = std::fs::remove_file(&klipper.socket_path);
+    }
+}
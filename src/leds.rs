@@ -0,0 +1,140 @@
+use spibuttonlib::SPIButtonState;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use crate::units::ButtonId;
+
+/// Who sets a button's LED, lowest priority first. The LED shows the
+/// highest layer holding it, and when that layer lets go the one below
+/// shows again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedLayer {
+    /// Printer state shown while the button is idle, see `indicator`
+    Indicator,
+    /// LED of the button's current state, see `state_machine`
+    State,
+    /// Progress and outcome of the button's action
+    Action,
+    /// Set from Klipper with `spibtn_set_led`, e.g. an alarm
+    Remote,
+    /// The daemon's own alarms, e.g. a queue backing up
+    Alarm,
+    /// Brief feedback on a press: refusals, arming and hold tiers
+    Feedback,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    state: SPIButtonState,
+    /// When a temporary claim lets go by itself
+    until: Option<Instant>,
+}
+
+/// The layers holding each button's LED.
+#[derive(Debug, Default)]
+pub struct LedStack {
+    claims: HashMap<ButtonId, BTreeMap<LedLayer, Claim>>,
+}
+
+impl LedStack {
+    pub fn new() -> Self {
+        LedStack::default()
+    }
+
+    /// Set the button's LED on `layer`, returning what it shows now. `Off`
+    /// lets go of the LED, handing it back to the layers below.
+    pub fn set(&mut self, button: ButtonId, layer: LedLayer, state: SPIButtonState) -> SPIButtonState {
+        self.claim(button, layer, state, None)
+    }
+
+    /// Like `set`, letting go by itself at `until`, see `expire`.
+    pub fn set_until(&mut self, button: ButtonId, layer: LedLayer, state: SPIButtonState, until: Instant) -> SPIButtonState {
+        self.claim(button, layer, state, Some(until))
+    }
+
+    fn claim(&mut self, button: ButtonId, layer: LedLayer, state: SPIButtonState, until: Option<Instant>) -> SPIButtonState {
+        if matches!(state, SPIButtonState::Off) {
+            return self.release(button, layer);
+        }
+        self.claims.entry(button).or_default().insert(layer, Claim { state, until });
+        self.shown(button)
+    }
+
+    /// Let go of the button's LED on `layer`, returning what it shows now.
+    pub fn release(&mut self, button: ButtonId, layer: LedLayer) -> SPIButtonState {
+        if let Some(claims) = self.claims.get_mut(&button) {
+            claims.remove(&layer);
+            if claims.is_empty() {
+                self.claims.remove(&button);
+            }
+        }
+        self.shown(button)
+    }
+
+    /// Forget every layer of a button, e.g. one removed from the config.
+    pub fn remove(&mut self, button: ButtonId) {
+        self.claims.remove(&button);
+    }
+
+    /// What the button's LED shows, off when no layer holds it.
+    pub fn shown(&self, button: ButtonId) -> SPIButtonState {
+        self.owner(button)
+            .and_then(|layer| self.claims.get(&button)?.get(&layer))
+            .map_or(SPIButtonState::Off, |c| c.state)
+    }
+
+    /// Whether `layer` holds the button's LED, shown or not.
+    pub fn holds(&self, button: ButtonId, layer: LedLayer) -> bool {
+        self.claims.get(&button).is_some_and(|claims| claims.contains_key(&layer))
+    }
+
+    /// The layer the button's LED shows.
+    pub fn owner(&self, button: ButtonId) -> Option<LedLayer> {
+        self.claims.get(&button)?.keys().next_back().copied()
+    }
+
+    /// Let go of the temporary claims ended by `now`, returning the buttons
+    /// whose LED changed.
+    pub fn expire(&mut self, now: Instant) -> Vec<ButtonId> {
+        let mut changed = Vec::new();
+        for (button, claims) in &mut self.claims {
+            let top = claims.keys().next_back().copied();
+            claims.retain(|_, c| c.until.is_none_or(|until| until > now));
+            if claims.keys().next_back().copied() != top {
+                changed.push(*button);
+            }
+        }
+        self.claims.retain(|_, claims| !claims.is_empty());
+        changed.sort();
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_higher_layers_win_and_restore_on_release() {
+        let mut leds = LedStack::new();
+        let button = ButtonId(1);
+        let t0 = Instant::now();
+        let shows = |state: SPIButtonState| state as u8;
+
+        assert_eq!(shows(leds.set(button, LedLayer::Indicator, SPIButtonState::Flash1)), 2);
+        // A refusal flash covers the indicator, a result beneath it waits
+        leds.set_until(button, LedLayer::Feedback, SPIButtonState::Flash2, t0 + Duration::from_millis(600));
+        assert_eq!(shows(leds.set(button, LedLayer::Action, SPIButtonState::On)), 3);
+        assert_eq!(leds.owner(button), Some(LedLayer::Feedback));
+
+        assert!(leds.expire(t0).is_empty());
+        assert_eq!(leds.expire(t0 + Duration::from_secs(1)), vec![button]);
+        assert_eq!(shows(leds.shown(button)), 1);
+
+        // The action finishing hands the LED back to the indicator
+        assert_eq!(shows(leds.set(button, LedLayer::Action, SPIButtonState::Off)), 2);
+        assert_eq!(shows(leds.release(button, LedLayer::Indicator)), 0);
+        assert_eq!(leds.owner(button), None);
+    }
+}
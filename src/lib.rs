@@ -0,0 +1,68 @@
+//! Daemon reading a button panel over SPI and running the commands mapped
+//! to its buttons. The `spi-button-controller` binary wraps `Daemon` with
+//! signal handling and the control socket; everything it uses is public
+//! here so other programs can drive a panel the same way.
+
+pub mod actions;
+pub mod arming;
+pub mod backpressure;
+pub mod breaker;
+pub mod builder;
+pub mod chord;
+pub mod combo;
+pub mod clock;
+pub mod concurrency;
+pub mod config;
+pub mod command;
+pub mod control;
+pub mod controller;
+pub mod cooldown;
+pub mod daemon;
+pub mod debounce;
+pub mod deferred;
+pub mod diagnostics;
+pub mod error;
+pub mod expr;
+pub mod frame;
+pub mod generate;
+pub mod gesture;
+pub mod ghost;
+pub mod grace;
+pub mod history;
+pub mod hold;
+pub mod indicator;
+pub mod klipper_sim;
+pub mod leds;
+pub mod lock;
+pub mod logs;
+pub mod machine;
+pub mod messages;
+pub mod migrate;
+pub mod noise;
+pub mod moonraker;
+pub mod notifications;
+pub mod panel;
+pub mod pattern;
+pub mod peer;
+pub mod polling;
+pub mod power;
+pub mod press;
+pub mod printer;
+pub mod qa;
+pub mod radio;
+pub mod ratelimit;
+pub mod recovery;
+pub mod remote;
+pub mod requests;
+pub mod rpc_errors;
+pub mod schedule;
+pub mod script;
+pub mod service;
+pub mod snapshot;
+pub mod socket;
+pub mod spitrace;
+pub mod throttle;
+pub mod units;
+pub mod vars;
+
+pub use error::{Error, Result};
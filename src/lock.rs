@@ -0,0 +1,104 @@
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+use crate::error::{Error, Result};
+
+/// An exclusive advisory lock on the SPI device, so two instances never
+/// interleave their transfers on one panel. The kernel drops it when the
+/// holder exits, however it exits, so a crashed instance leaves nothing
+/// stale behind.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+    path: String,
+}
+
+impl DeviceLock {
+    /// Lock `device`, failing with the process holding it if another
+    /// instance does.
+    pub fn acquire(device: &str) -> Result<DeviceLock> {
+        let file = File::open(device).map_err(|e| Error::spi(format!("Failed to open {}", device), e))?;
+        match file.try_lock() {
+            Ok(()) => Ok(DeviceLock {
+                _file: file,
+                path: device.to_string(),
+            }),
+            Err(TryLockError::WouldBlock) => {
+                let holder = file.metadata().ok().and_then(|m| holder(&m)).map_or_else(
+                    || "another process".to_string(),
+                    |pid| match process_name(pid) {
+                        Some(name) => format!("{} (pid {})", name, pid),
+                        None => format!("pid {}", pid),
+                    },
+                );
+                Err(Error::spi(
+                    format!("{} is in use by {}, another instance driving the same panel", device, holder),
+                    io::Error::from(io::ErrorKind::WouldBlock),
+                ))
+            }
+            Err(TryLockError::Error(e)) => Err(Error::spi(format!("Failed to lock {}", device), e)),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// The pid holding a lock on the file of `metadata`, from /proc/locks.
+fn holder(metadata: &fs::Metadata) -> Option<u32> {
+    let locks = fs::read_to_string("/proc/locks").ok()?;
+    let dev = metadata.dev();
+    // The kernel's encoding of device numbers, see <sys/sysmacros.h>
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    find_holder(&locks, &format!("{:02x}:{:02x}:{}", major, minor, metadata.ino()))
+}
+
+/// The pid of the `/proc/locks` line for the file `id` (MAJOR:MINOR:INODE),
+/// e.g. `1: FLOCK  ADVISORY  WRITE 1234 00:05:17 0 EOF`. Lines of waiters
+/// start with `->` and are skipped.
+fn find_holder(locks: &str, id: &str) -> Option<u32> {
+    locks.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, kind, _, _, pid, file, ..] if *kind != "->" && *file == id => pid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// The command line of process `pid`, if it can be read.
+fn process_name(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<String> = cmdline
+        .split(|b| *b == 0)
+        .filter(|a| !a.is_empty())
+        .map(|a| String::from_utf8_lossy(a).into_owned())
+        .collect();
+    (!args.is_empty()).then(|| args.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_names_the_holder() {
+        let locks = "1: FLOCK  ADVISORY  WRITE 4321 00:05:17 0 EOF\n1: -> FLOCK  ADVISORY  WRITE 99 00:05:17 0 EOF\n2: POSIX  ADVISORY  WRITE 77 08:01:17 0 EOF\n";
+        assert_eq!(find_holder(locks, "00:05:17"), Some(4321));
+        assert_eq!(find_holder(locks, "08:01:17"), Some(77));
+        assert_eq!(find_holder(locks, "00:05:18"), None);
+
+        let path = std::env::temp_dir().join(format!("spibtn-lock-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let path = path.to_str().unwrap();
+        let lock = DeviceLock::acquire(path).unwrap();
+        let e = DeviceLock::acquire(path).unwrap_err().to_string();
+        assert!(e.contains(&format!("(pid {})", std::process::id())), "{}", e);
+        drop(lock);
+        DeviceLock::acquire(path).unwrap();
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,129 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+
+/// Log lines kept for `spibuttonctl logs`, the oldest dropped first.
+pub const RECENT_LOG_LINES: usize = 200;
+
+/// Lines a `logs follow` client may fall behind by before it skips some.
+const FOLLOW_BUFFER: usize = 256;
+
+/// A formatted log record.
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub level: Level,
+    pub text: String,
+}
+
+/// The recent log lines, and the live ones for followers.
+#[derive(Debug)]
+pub struct Tap {
+    recent: Mutex<VecDeque<LogLine>>,
+    live: broadcast::Sender<LogLine>,
+}
+
+impl Tap {
+    pub fn new() -> Self {
+        Tap {
+            recent: Mutex::new(VecDeque::new()),
+            live: broadcast::channel(FOLLOW_BUFFER).0,
+        }
+    }
+
+    pub fn push(&self, line: LogLine) {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() >= RECENT_LOG_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+        // Nobody following is fine
+        let _ = self.live.send(line);
+    }
+
+    /// The kept lines at `level` or more severe, oldest first.
+    pub fn recent(&self, level: LevelFilter) -> Vec<LogLine> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.iter().filter(|l| l.level <= level).cloned().collect()
+    }
+
+    /// Lines logged from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<LogLine> {
+        self.live.subscribe()
+    }
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        Tap::new()
+    }
+}
+
+/// The daemon's tap, empty unless `init` installed the logger.
+pub fn tap() -> &'static Tap {
+    static TAP: OnceLock<Tap> = OnceLock::new();
+    TAP.get_or_init(Tap::new)
+}
+
+/// Logs through env_logger, keeping a copy of every line it lets through.
+struct TapLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for TapLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        tap().push(LogLine {
+            level: record.level(),
+            text: format!(
+                "[{} {:<5} {}] {}",
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install env_logger, configured by `RUST_LOG`, feeding the tap.
+pub fn init() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter();
+    if log::set_boxed_logger(Box::new(TapLogger { inner })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_lines_are_capped_and_filtered() {
+        let tap = Tap::new();
+        let mut live = tap.subscribe();
+        for i in 0..RECENT_LOG_LINES + 5 {
+            let level = if i % 2 == 0 { Level::Info } else { Level::Debug };
+            tap.push(LogLine { level, text: format!("line {}", i) });
+        }
+
+        let all = tap.recent(LevelFilter::Trace);
+        assert_eq!(all.len(), RECENT_LOG_LINES);
+        assert_eq!(all[0].text, "line 5");
+        assert!(tap.recent(LevelFilter::Info).iter().all(|l| l.level == Level::Info));
+        assert!(tap.recent(LevelFilter::Warn).is_empty());
+        assert_eq!(live.try_recv().unwrap().text, "line 0");
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::{MachineEvent, StateMachine, Transition};
+use crate::units::ButtonId;
+
+/// How long a press must last to be a `long_press` when the state machine
+/// does not set `long_press_ms`.
+pub const DEFAULT_MACHINE_LONG_PRESS_MS: u64 = 1000;
+
+impl StateMachine {
+    /// The transition `event` takes from `state`. Transitions leaving that
+    /// state come before those leaving any state.
+    pub fn transition(&self, state: &str, event: MachineEvent) -> Option<&Transition> {
+        let on = |t: &&Transition| t.on == event;
+        let from_state = self.transitions.iter().filter(on).find(|t| t.from.as_deref() == Some(state));
+        from_state.or_else(|| self.transitions.iter().filter(on).find(|t| t.from.is_none()))
+    }
+
+    /// Whether some transition needs a press told apart from a long press,
+    /// else presses are handled right away instead of on release.
+    pub fn uses_long_press(&self) -> bool {
+        self.transitions.iter().any(|t| t.on == MachineEvent::LongPress)
+    }
+
+    pub fn long_press(&self) -> Duration {
+        Duration::from_millis(self.long_press_ms.unwrap_or(DEFAULT_MACHINE_LONG_PRESS_MS))
+    }
+}
+
+/// The current state of every button with a `state_machine`.
+#[derive(Debug, Default)]
+pub struct Machines {
+    current: HashMap<ButtonId, String>,
+}
+
+impl Machines {
+    pub fn new() -> Self {
+        Machines::default()
+    }
+
+    /// The button's state, its machine's `initial` one until it moved.
+    pub fn state<'a>(&'a self, button: ButtonId, machine: &'a StateMachine) -> &'a str {
+        self.current.get(&button).map_or(machine.initial.as_str(), |s| s.as_str())
+    }
+
+    /// Handle `event`, moving the button to the next state. Returns the
+    /// transition taken, `None` when the current state has none for it.
+    pub fn fire(&mut self, button: ButtonId, machine: &StateMachine, event: MachineEvent) -> Option<Transition> {
+        let transition = machine.transition(self.state(button, machine), event)?.clone();
+        self.current.insert(button, transition.to.clone());
+        Some(transition)
+    }
+
+    /// Move the button to `state` without a transition, e.g. one taken on
+    /// another panel. Returns `false` for a state its machine lacks.
+    pub fn set(&mut self, button: ButtonId, machine: &StateMachine, state: &str) -> bool {
+        if !machine.states.contains_key(state) {
+            return false;
+        }
+        self.current.insert(button, state.to_string());
+        true
+    }
+
+    /// Keep only the states `machine_of` still knows, e.g. after a reload.
+    /// The others start over in their initial state.
+    pub fn retain<'a>(&mut self, machine_of: impl Fn(ButtonId) -> Option<&'a StateMachine>) {
+        self.current
+            .retain(|button, state| machine_of(*button).is_some_and(|m| m.states.contains_key(state)));
+    }
+
+    /// Buttons and their state, for `spibuttonctl states`.
+    pub fn iter(&self) -> impl Iterator<Item = (&ButtonId, &String)> {
+        self.current.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions_follow_presses_and_results() {
+        let machine: StateMachine = serde_yaml::from_str(
+            r#"
+initial: idle
+states: {idle: {}, armed: {led: flash1}, heating: {led: flash2}, ready: {led: on}}
+transitions:
+  - {from: idle, on: press, to: armed}
+  - {from: armed, on: press, to: heating, command: PREHEAT}
+  - {from: heating, on: success, to: ready}
+  - {from: heating, on: failure, to: idle}
+  - {on: long_press, to: idle, command: COOLDOWN}
+"#,
+        )
+        .unwrap();
+        let mut machines = Machines::new();
+        let button = ButtonId(2);
+        let mut fire = |event| machines.fire(button, &machine, event).map(|t| (t.to, t.command));
+
+        assert_eq!(fire(MachineEvent::Success), None);
+        assert_eq!(fire(MachineEvent::Press), Some(("armed".into(), None)));
+        assert_eq!(fire(MachineEvent::Press), Some(("heating".into(), Some("PREHEAT".into()))));
+        assert_eq!(fire(MachineEvent::Success), Some(("ready".into(), None)));
+        // Nothing leaves ready on a press, any state on a long press
+        assert_eq!(fire(MachineEvent::Press), None);
+        assert_eq!(fire(MachineEvent::LongPress), Some(("idle".into(), Some("COOLDOWN".into()))));
+        assert!(machine.uses_long_press());
+    }
+}
@@ -1,43 +1,104 @@
-mod config;
-mod command;
-mod daemon;
-
 use anyhow::{Context, Result};
-use log::{info, error};
-use std::fs;
+use log::{debug, info, error};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::runtime::{self, Runtime};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
-use crate::command::EventMessage;
-use std::collections::HashMap;
+use spi_button_controller::command::{EventMessage, ProgressStage};
+use spi_button_controller::rpc_errors::ErrorCategory;
+use spi_button_controller::config::{Config, ConfigFormat, RuntimeConfig, RuntimeFlavor};
+use spi_button_controller::control::{self, ControlRequest};
+use spi_button_controller::requests::PendingRequest;
+use spi_button_controller::{
+    config, daemon, diagnostics, generate, klipper_sim, logs, notifications, printer, qa, service,
+};
 use spibuttonlib::SPIButtonState;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Blocking threads when `runtime.max_blocking_threads` is not configured.
+/// The poll loop awaits shell commands one at a time, the second thread
+/// serves e.g. host name lookups meanwhile.
+const DEFAULT_BLOCKING_THREADS: usize = 2;
+
+/// Time running actions get to finish on shutdown when
+/// `runtime.shutdown_timeout_ms` is not configured.
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/spi-button-controller/config.yaml";
+
+/// Environment variable naming the config file when none is given on the
+/// command line, e.g. in a container image.
+const CONFIG_PATH_VAR: &str = "SPIBTN_CONFIG";
+
+fn main() -> Result<()> {
     // Initialize logging
     init_logger();
 
     // Parse command line arguments
-    let config_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "/etc/spi-button-controller/config.yaml".to_string());
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_format = take_format_flag(&mut args)?;
+    let check_config = take_flag(&mut args, "--check-config");
+    let multi_thread = take_flag(&mut args, "--multi-thread");
+    match args.first().map(String::as_str) {
+        Some("generate-config") => {
+            // Print a commented example config and exit
+            let buttons = match args.get(1) {
+                Some(n) => n.parse::<usize>().context(format!("Invalid number of buttons: {}", n))?,
+                None => 4,
+            };
+            print!("{}", generate::example_config(buttons)?);
+            return Ok(());
+        }
+        Some("schema") => {
+            // Print the JSON Schema of the config format and exit
+            println!("{}", generate::schema()?);
+            return Ok(());
+        }
+        Some("scan") => {
+            // Diagnostic mode: probe spidev buses and exit
+            return Ok(runtime(&RuntimeConfig::default())?.block_on(diagnostics::scan(&args[1..]))?);
+        }
+        Some("sweep") => {
+            // Diagnostic mode: find the highest reliable clock speed and exit
+            let device = args
+                .get(1)
+                .ok_or_else(|| anyhow::anyhow!("Usage: spi-button-controller sweep DEVICE [MODE]"))?;
+            let mode = match args.get(2) {
+                Some(m) => m.parse::<u8>().context(format!("Invalid SPI mode: {}", m))?,
+                None => 0,
+            };
+            return Ok(runtime(&RuntimeConfig::default())?.block_on(diagnostics::sweep(device, mode))?);
+        }
+        Some("qa-report") => {
+            // Manufacturing check: test every button and LED, write a report and exit
+            let output = take_value_flag(&mut args, "--output")?.map(PathBuf::from);
+            let config_path = args.get(1).cloned().unwrap_or_else(default_config_path);
+            let config = config::Config::load_complete(&config_path, config_format)?;
+            let rt = runtime(&RuntimeConfig::default())?;
+            let report = rt.block_on(qa::run(&config, output.as_deref()))?;
+            // Stdin is read on a blocking thread that may still wait for a line
+            rt.shutdown_background();
+            if !report.passed {
+                return Err(anyhow::anyhow!("Panel failed the QA check"));
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    let config_path = args.first().cloned().unwrap_or_else(default_config_path);
+
+    if check_config {
+        // Dry run: print the config as the daemon would see it and exit
+        let config = config::Config::load_complete(&config_path, config_format)?;
+        print!("{}", config.normalized()?);
+        return Ok(());
+    }
 
     info!("SPI Button Controller starting...");
     info!("Loading configuration from: {}", config_path);
 
     // Load configuration
-    let config_content = fs::read_to_string(&config_path)
-        .context(format!("Failed to read config file: {}", config_path))?;
-    let mut config: config::Config = serde_yaml::from_str(&config_content)
-        .context("Failed to parse configuration file")?;
-
-    // Sort by button number & sanity check unique button IDs as ordinal vector number === button ID
-    config.buttons.sort_by(|a,b| {a.button.cmp(&b.button)});
-    let bcnt: usize = config.buttons.len();
-    if bcnt != config.buttons[ bcnt - 1 ].button as usize + 1
-    {
-        return Err(anyhow::anyhow!("Configuration error for button IDs, they must be consective starting from zero."));
-    }
+    let config = config::Config::load_complete(&config_path, config_format)?;
 
     info!("Configuration loaded successfully");
 
@@ -48,20 +109,110 @@ async fn main() -> Result<()> {
         return Err(anyhow::anyhow!("SPI device not found: {}", spi_device_path));
     }
 
+    let mut runtime_config = config.runtime.clone().unwrap_or_default();
+    if multi_thread {
+        runtime_config.flavor = Some(RuntimeFlavor::MultiThread);
+    }
+    let shutdown_timeout = Duration::from_millis(runtime_config.shutdown_timeout_ms.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS));
+    let rt = runtime(&runtime_config)?;
+    let result = rt.block_on(run(config, &config_path, config_format, shutdown_timeout));
+    // Shell commands run on blocking threads, which dropping the runtime
+    // would wait for without limit
+    let remaining = match &result {
+        Ok(deadline) => deadline.saturating_duration_since(Instant::now()),
+        Err(_) => Duration::ZERO,
+    };
+    rt.shutdown_timeout(remaining);
+    result.map(|_| ())
+}
+
+/// The config file to use when none is given: `SPIBTN_CONFIG`, or the
+/// default path.
+fn default_config_path() -> String {
+    std::env::var(CONFIG_PATH_VAR).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string())
+}
+
+/// Build the tokio runtime, only once a command needs one so the commands
+/// printing a config start without any threads. Blocking work such as
+/// shell commands runs on at most `max_blocking_threads` extra threads,
+/// started on demand.
+fn runtime(config: &RuntimeConfig) -> Result<Runtime> {
+    let flavor = config.flavor.unwrap_or_default();
+    let mut builder = match flavor {
+        RuntimeFlavor::CurrentThread => runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => runtime::Builder::new_multi_thread(),
+    };
+    let blocking_threads = config.max_blocking_threads.unwrap_or(DEFAULT_BLOCKING_THREADS);
+    debug!("Starting a {:?} runtime with up to {} blocking threads", flavor, blocking_threads);
+    builder
+        .max_blocking_threads(blocking_threads)
+        .enable_all()
+        .build()
+        .context("Failed to start the tokio runtime")
+}
+
+/// Run the daemon until SIGTERM or SIGINT, then give running actions
+/// until `shutdown_timeout` to finish. Returns that deadline, which also
+/// bounds the wait for shell commands.
+async fn run(
+    config: Config,
+    config_path: &str,
+    config_format: Option<ConfigFormat>,
+    shutdown_timeout: Duration,
+) -> Result<Instant> {
+
     // Create response queue for Klipper command replies
     let (resp_tx, mut resp_rx) = mpsc::channel::<EventMessage>(32);
 
-    // map request_id -> trigger_info for correlation
-    let mut pending: HashMap<u32, String> = HashMap::new();
+    // Create daemon and provide response sender. It locks the SPI device
+    // first, so a second instance fails before touching the sockets below.
+    let mut daemon = daemon::Daemon::new(config.clone(), Some(resp_tx.clone()))?;
+    daemon.set_config_file(config_path, config_format);
 
-    // Create daemon and provide response sender
-    let mut daemon = daemon::Daemon::new(config, Some(resp_tx))?;
+    // A simulated Klipper answers requests instead of a printer
+    if let Some(klipper_cfg) = &config.klipper {
+        if let Some(simulation) = &klipper_cfg.simulate {
+            klipper_sim::spawn(&klipper_cfg.socket_path, simulation.clone())?;
+        }
+    }
 
     // Setup signal handling via tokio
     let mut sigterm = signal(SignalKind::terminate()).context("Failed to setup SIGTERM handler")?;
     let mut sigint = signal(SignalKind::interrupt()).context("Failed to setup SIGINT handler")?;
     let mut sighup = signal(SignalKind::hangup()).context("Failed to setup SIGHUP handler")?;
 
+    // With klipper.required a panel without a printer behind it never starts
+    if let Some(klipper_cfg) = config.klipper.as_ref().filter(|k| k.required.unwrap_or(false)) {
+        tokio::select! {
+            _ = service::wait_for_klipper(klipper_cfg, config.messages.as_ref()) => {}
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM while waiting for Klipper, exiting");
+                return Ok(Instant::now());
+            }
+            _ = sigint.recv() => {
+                info!("Received SIGINT while waiting for Klipper, exiting");
+                return Ok(Instant::now());
+            }
+        }
+    }
+
+    // Control socket requests (spibuttonctl) are answered by the main loop
+    let (control_tx, mut control_rx) = mpsc::channel::<ControlRequest>(8);
+    if let Some(control_cfg) = &config.control {
+        control::spawn_server(control_cfg, control_tx.clone())?;
+    }
+
+    // Other panels' daemons mirror LEDs, armed buttons and toggles
+    if let Some(sync_cfg) = &config.sync {
+        daemon.connect_peers(sync_cfg, resp_tx.clone())?;
+    }
+
+    // Moonraker notifications drive LED feedback, e.g. timelapse rendering
+    if let Some(moonraker_cfg) = &config.moonraker {
+        notifications::spawn(moonraker_cfg.clone(), printer::subscriptions(&config), resp_tx)?;
+    }
+
+    service::notify(&format!("READY=1\nSTATUS={}", daemon.status()));
     info!("Daemon started successfully");
 
     loop {
@@ -69,7 +220,7 @@ async fn main() -> Result<()> {
             result = daemon.poll() => {
                 if let Err(e) = result {
                     error!("Daemon poll error: {}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
             _ = sigterm.recv() => {
@@ -82,67 +233,135 @@ async fn main() -> Result<()> {
             }
             _ = sighup.recv() => {
                 info!("Received SIGHUP, reloading configuration");
-                let config_content = fs::read_to_string(&config_path)?;
-                let new_config: config::Config = serde_yaml::from_str(&config_content)?;
-                daemon.reload_config(new_config)?;
-                info!("Configuration reloaded successfully");
+                if let Err(e) = daemon.reload() {
+                    error!("Keeping the current configuration: {}", e);
+                }
             }
             // Klipper command messages (issued & responses)
             maybe_msg = resp_rx.recv() => {
                 if let Some(msg) = maybe_msg {
                     match msg {
-                        EventMessage::Issued { request_id, trigger_button } => {
+                        EventMessage::Issued { request_id, correlation_id, trigger_button } => {
                             // persist mapping for later correlation
-                            pending.insert(request_id.clone(), trigger_button.clone());
-                            info!("Tracked issued request id={} triger_button={}", request_id, trigger_button);
+                            daemon.track_request(request_id, trigger_button, correlation_id);
+                            info!("[{}] Tracked issued request id={} triger_button={}", correlation_id, request_id, trigger_button);
+                        }
+                        EventMessage::Notification(notification) => {
+                            daemon.handle_notification(&notification);
+                        }
+                        EventMessage::Peer(envelope) => {
+                            daemon.handle_peer(envelope);
+                        }
+                        EventMessage::Progress { request_id, stage } => {
+                            if let Some(request) = daemon.pending_request(request_id) {
+                                let button = request.button;
+                                info!("[{}] Progress {:?}", request.correlation_id, stage);
+                                let led = match stage {
+                                    ProgressStage::Checking | ProgressStage::RunningMacro => SPIButtonState::On,
+                                    ProgressStage::Restarting | ProgressStage::PoweringOn => SPIButtonState::Flash2,
+                                    ProgressStage::WaitingReady => SPIButtonState::Flash1,
+                                };
+                                daemon.set_button_state(button, led);
+                            }
                         }
                         EventMessage::Response(resp) => {
+                            if let Some(endpoint) = resp.endpoint {
+                                daemon.record_response(endpoint, &resp.status);
+                            }
                             // correlate with original trigger
-                            if let Some(button) = pending.remove(&resp.request_id) {
-                                let mut final_button_status = SPIButtonState::Off;
-                                let button_u8 = button.parse::<u8>().unwrap();
-                                info!("Klipper response id={} correlated_to={} success={} status={:?} body={:?}"
-                                    , resp.request_id, button, resp.success, resp.status, resp.body);
-                                if resp.success {
-                                } else {
-                                    match resp.status {
-                                        Some(msg) => {
-                                            match msg.as_ref() {
-                                                "empty_response" => {
-                                                    // OK case: restart
-                                                },
-                                                _ => {
-                                                    // Presumed error case
-                                                    final_button_status = SPIButtonState::Flash2;
-                                                },
-                                            }
-                                        }
-                                        _ => {
-                                            // error case, no status
-                                            final_button_status = SPIButtonState::Flash2;
-                                        }
+                            if let Some(PendingRequest { button: button_id, correlation_id }) = daemon.untrack_request(resp.request_id) {
+                                info!("[{}] Klipper response id={} correlated_to={} status={} body={:?}"
+                                    , correlation_id, resp.request_id, button_id, resp.status, resp.body);
+                                // Klipper dropping the connection while restarting
+                                // (EmptyResponse) counts as success, see rpc_errors
+                                let succeeded = resp.category.is_none();
+                                let final_button_status = match resp.category {
+                                    // Hands the LED back, e.g. to the button's indicator
+                                    None => SPIButtonState::Off,
+                                    // Still failing after retries, likely transient
+                                    Some(ErrorCategory::Retryable) => SPIButtonState::Flash1,
+                                    Some(ErrorCategory::NeedsRestart) | Some(ErrorCategory::UserError) => {
+                                        SPIButtonState::Flash2
                                     }
+                                };
+                                if let Some(category) = resp.category {
+                                    error!("[{}] Klipper request id={} failed ({:?}): {}",
+                                        correlation_id, resp.request_id, category, resp.status);
                                 }
-                                daemon.set_button_state(button_u8, final_button_status);
+                                daemon.set_button_state(button_id, final_button_status);
+
+                                // Record the outcome for `spibuttonctl last`
+                                let output = match &resp.body {
+                                    Some(body) => body.to_string(),
+                                    None => resp.status.to_string(),
+                                };
+                                daemon.complete_request(resp.request_id, succeeded, &output);
                             } else {
-                                info!("Klipper response id={} (no matching issue found) success={} status={:?} body={:?}", resp.request_id, resp.success, resp.status, resp.body);
+                                info!("[{}] Klipper response id={} (no matching issue found) status={} body={:?}", resp.correlation_id, resp.request_id, resp.status, resp.body);
                             }
                             // TODO: Set value on button
                         }
                     }
                 }
-            }            
+            }
+            // Control socket requests
+            maybe_req = control_rx.recv() => {
+                if let Some(req) = maybe_req {
+                    let response = control::handle(&mut daemon, &req.line);
+                    let _ = req.reply.send(response);
+                }
+            }
         }
     }
 
+    let deadline = Instant::now() + shutdown_timeout;
+    let unfinished = daemon.finish_actions(deadline).await;
+    if unfinished > 0 {
+        info!("Abandoning {} action(s) still running after {:?}", unfinished, shutdown_timeout);
+    }
     info!("SPI Button Controller shutdown complete");
-    Ok(())
+    Ok(deadline)
 }
 
 fn init_logger() {
-    // Use `env_logger` for logging. Systemd/journald will capture stdout/stderr.
+    // Use `env_logger` for logging to stderr, captured by journald or the
+    // container runtime. `spibuttonctl logs` reads the copy the tap keeps.
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
-    env_logger::init();
+    logs::init();
+}
+
+/// Remove a boolean `flag` from the arguments, returning whether it was given.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|a| a != flag);
+    args.len() != before
+}
+
+/// Remove `FLAG VALUE` (or `FLAG=VALUE`) from the arguments, returning the
+/// value.
+fn take_value_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>> {
+    let prefix = format!("{}=", flag);
+    let pos = match args.iter().position(|a| a == flag || a.starts_with(&prefix)) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let arg = args.remove(pos);
+    match arg.strip_prefix(&prefix) {
+        Some(value) => Ok(Some(value.to_string())),
+        None if pos < args.len() => Ok(Some(args.remove(pos))),
+        None => Err(anyhow::anyhow!("{} requires a value", flag)),
+    }
+}
+
+/// Remove `--format FORMAT` (or `--format=FORMAT`) from the arguments,
+/// returning the config format it forces.
+fn take_format_flag(args: &mut Vec<String>) -> Result<Option<config::ConfigFormat>> {
+    let value = take_value_flag(args, "--format")
+        .map_err(|_| anyhow::anyhow!("Usage: spi-button-controller [--format yaml|toml|json] [CONFIG]"))?;
+    match value {
+        Some(value) => Ok(Some(value.parse()?)),
+        None => Ok(None),
+    }
 }
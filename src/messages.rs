@@ -0,0 +1,110 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+
+use crate::expr::{self, Context, Value};
+
+/// A text shown to people outside the logs, e.g. the service status in
+/// `systemctl status`, with its English default and the placeholders it
+/// offers.
+#[derive(Debug)]
+pub struct Message {
+    pub id: &'static str,
+    pub default: &'static str,
+    pub placeholders: &'static [&'static str],
+}
+
+/// Every message the `messages` section can replace.
+pub const CATALOG: &[Message] = &[
+    Message {
+        id: "waiting_for_klipper",
+        default: "Waiting for Klipper at {{socket}}",
+        placeholders: &["socket"],
+    },
+    Message {
+        id: "ready",
+        default: "Ready, {{buttons}} button(s) mapped",
+        placeholders: &["buttons"],
+    },
+    Message {
+        id: "endpoint_down",
+        default: "{{endpoint}} is unreachable, its buttons fail fast",
+        placeholders: &["endpoint"],
+    },
+];
+
+pub fn find(id: &str) -> Option<&'static Message> {
+    CATALOG.iter().find(|m| m.id == id)
+}
+
+/// Values of a message's placeholders.
+struct Args<'a>(&'a [(&'a str, Value)]);
+
+impl Context for Args<'_> {
+    fn lookup(&self, name: &str) -> Value {
+        self.0
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map_or(Value::Null, |(_, value)| value.clone())
+    }
+}
+
+/// The text of message `id` with its placeholders filled in, from the
+/// configured `messages` or else the default. Line breaks a placeholder
+/// value brings in become spaces, keeping the text one line.
+pub fn text(messages: Option<&BTreeMap<String, String>>, id: &str, args: &[(&str, Value)]) -> String {
+    let template = messages
+        .and_then(|m| m.get(id))
+        .map(String::as_str)
+        .or_else(|| find(id).map(|m| m.default))
+        .unwrap_or(id);
+    expr::render(template, &Args(args)).replace(['\n', '\r'], " ")
+}
+
+/// Check a configured template for message `id`: the message must exist,
+/// fit on one line, as the service status is a single `STATUS=` line, and
+/// its placeholders parse and use only what the message offers.
+pub fn check(id: &str, template: &str) -> Result<(), String> {
+    let message = find(id).ok_or_else(|| {
+        let ids: Vec<&str> = CATALOG.iter().map(|m| m.id).collect();
+        format!("unknown message, expected one of {}", ids.join(", "))
+    })?;
+    if template.contains(['\n', '\r']) {
+        return Err("must be a single line".to_string());
+    }
+    let placeholder = Regex::new(r"\{\{(.*?)\}\}").unwrap();
+    for caps in placeholder.captures_iter(template) {
+        let parsed = expr::parse(caps[1].trim()).map_err(|e| format!("{{{{{}}}}}: {}", caps[1].trim(), e))?;
+        if let Some(name) = parsed.names().into_iter().find(|n| !message.placeholders.contains(n)) {
+            return Err(format!(
+                "unknown placeholder {}, expected one of {}",
+                name,
+                message.placeholders.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_messages_replace_defaults() {
+        let args = [("buttons", Value::Number(4.0))];
+        assert_eq!(text(None, "ready", &args), "Ready, 4 button(s) mapped");
+
+        let messages = BTreeMap::from([("ready".to_string(), "Bereit, {{buttons}} Tasten".to_string())]);
+        assert_eq!(text(Some(&messages), "ready", &args), "Bereit, 4 Tasten");
+        assert_eq!(
+            text(Some(&messages), "waiting_for_klipper", &[("socket", Value::Str("/tmp/klippy_uds".into()))]),
+            "Waiting for Klipper at /tmp/klippy_uds"
+        );
+
+        assert!(check("ready", "Bereit, {{buttons}} Tasten").is_ok());
+        assert!(check("ready", "{{socket}}").unwrap_err().contains("unknown placeholder socket"));
+        assert!(check("greeting", "Hello").unwrap_err().contains("unknown message"));
+        assert!(check("ready", "{{buttons +}}").is_err());
+        assert!(check("ready", "Ready\nREADY=1").unwrap_err().contains("single line"));
+    }
+}
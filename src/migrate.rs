@@ -0,0 +1,144 @@
+use serde_json::{json, Map, Value};
+
+use crate::error::{Error, Result};
+
+/// Config `version` this daemon reads. Configs without one are version 1
+/// when they use the `registers:` format, otherwise current.
+pub const CONFIG_VERSION: u64 = 2;
+
+/// Upgrade a parsed config to `CONFIG_VERSION`, returning what was changed
+/// for the log. A current config is left untouched.
+pub fn migrate(config: &mut Value) -> Result<Vec<String>> {
+    let Some(map) = config.as_object_mut() else {
+        return Ok(vec![]);
+    };
+    let version = match map.get("version") {
+        Some(v) => v.as_u64().ok_or_else(|| Error::config(format!("version must be a number, not {}", v)))?,
+        None if map.contains_key("registers") => 1,
+        None => CONFIG_VERSION,
+    };
+    if version > CONFIG_VERSION {
+        return Err(Error::config(format!(
+            "Config version {} is newer than this daemon supports ({})",
+            version,
+            CONFIG_VERSION
+        )));
+    }
+
+    let mut notes = Vec::new();
+    if version < 2 {
+        registers_to_buttons(map, &mut notes)?;
+    }
+    if version < CONFIG_VERSION {
+        map.insert("version".into(), json!(CONFIG_VERSION));
+        notes.push(format!("version {} to {}", version, CONFIG_VERSION));
+    }
+    Ok(notes)
+}
+
+/// Version 1, the duplex crate's format: `registers` with `value_triggers`
+/// firing a command when `value & mask == value`. Only presses (bit 0 set)
+/// exist on a button panel, so the first trigger matching a press becomes
+/// the button's command.
+fn registers_to_buttons(map: &mut Map<String, Value>, notes: &mut Vec<String>) -> Result<()> {
+    let registers = match map.remove("registers") {
+        Some(Value::Array(registers)) => registers,
+        Some(other) => return Err(Error::config(format!("registers must be a list, not {}", other))),
+        None => return Ok(()),
+    };
+    let mut buttons = match map.remove("buttons") {
+        Some(Value::Array(buttons)) => buttons,
+        _ => Vec::new(),
+    };
+
+    for (i, register) in registers.iter().enumerate() {
+        let id = register
+            .get("register")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| Error::config(format!("registers[{}].register must be a number", i)))?;
+        let triggers = register.get("value_triggers").and_then(Value::as_array).cloned().unwrap_or_default();
+        let fires_on_press = |t: &Value| {
+            let value = t.get("value").and_then(Value::as_u64).unwrap_or(0);
+            let mask = t.get("mask").and_then(Value::as_u64).unwrap_or(0xff);
+            value & mask & 0x01 != 0
+        };
+        let Some(trigger) = triggers.iter().find(|t| fires_on_press(t)) else {
+            notes.push(format!("registers[{}] dropped, no trigger fires on a press", i));
+            continue;
+        };
+        let dropped = triggers.len() - 1;
+        if dropped > 0 {
+            notes.push(format!("registers[{}] keeps one trigger, {} dropped", i, dropped));
+        }
+
+        let mut button = Map::new();
+        button.insert("button".into(), json!(id));
+        if let Some(config) = register.get("config") {
+            button.insert("config".into(), config.clone());
+        }
+        let description = trigger.get("description").or_else(|| register.get("description"));
+        if let Some(description) = description {
+            button.insert("description".into(), description.clone());
+        }
+        button.insert("command".into(), trigger.get("command").cloned().unwrap_or(json!("")));
+        buttons.push(Value::Object(button));
+        notes.push(format!("registers[{}] to button {}", i, id));
+    }
+    map.insert("buttons".into(), Value::Array(buttons));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::units::ButtonId;
+
+    #[test]
+    fn test_registers_become_buttons() {
+        let mut config: Value = serde_yaml::from_str(
+            r#"
+spi: {device: /dev/spidev1.0, speed_hz: 800000, mode: 0}
+polling: {interval_ms: 100}
+registers:
+  - register: 19
+    description: Lamp
+    value_triggers:
+      - {value: 0x00, mask: 0x01, command: "echo released"}
+      - {value: 0x01, mask: 0x01, command: "echo lamp", description: "Lamp toggle"}
+  - register: 18
+    value_triggers:
+      - {value: 0x00, mask: 0x01, command: "echo released"}
+"#,
+        )
+        .unwrap();
+
+        let notes = migrate(&mut config).unwrap();
+        assert_eq!(
+            notes,
+            vec![
+                "registers[0] keeps one trigger, 1 dropped",
+                "registers[0] to button 19",
+                "registers[1] dropped, no trigger fires on a press",
+                "version 1 to 2",
+            ]
+        );
+        let config: Config = serde_json::from_value(config).unwrap();
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+        assert_eq!(config.buttons.len(), 1);
+        assert_eq!(config.buttons[0].button, ButtonId(19));
+        assert_eq!(config.buttons[0].command, "echo lamp");
+        assert_eq!(config.buttons[0].description.as_deref(), Some("Lamp toggle"));
+    }
+
+    #[test]
+    fn test_current_config_is_untouched() {
+        let mut config = json!({"buttons": [{"button": 0, "command": "echo a"}]});
+        let before = config.clone();
+        assert!(migrate(&mut config).unwrap().is_empty());
+        assert_eq!(config, before);
+
+        let mut newer = json!({"version": CONFIG_VERSION + 1});
+        assert!(migrate(&mut newer).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use log::info;
+use reqwest::{Client, Url};
+use serde_json::Value as JsonValue;
+use std::io;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+use crate::actions::MoonrakerCall;
+use crate::breaker::Endpoint;
+use crate::command::{EventMessage, EventResponse, ResponseStatus};
+use crate::config::MoonrakerConfig;
+
+/// Moonraker URL used when no `moonraker` section is configured.
+pub const DEFAULT_MOONRAKER_URL: &str = "http://localhost:7125";
+
+/// Header carrying the idempotency key of a POST, for a proxy or webhook
+/// that drops a request it already carried out.
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+/// Idempotency key of the requests made for request `request_id` of a
+/// button event, the same however often they are sent.
+pub fn idempotency_key(correlation_id: Uuid, request_id: u32) -> String {
+    format!("{}-{}", correlation_id, request_id)
+}
+
+/// Minimal Moonraker HTTP API client. Failures are reported as the same
+/// `ResponseStatus` used for Klipper requests, so they share error
+/// categories and LED feedback.
+pub struct Moonraker {
+    client: Client,
+    base: Url,
+    /// Sent with every POST, see `with_idempotency_key`
+    idempotency_key: Option<String>,
+}
+
+impl Moonraker {
+    pub fn new(config: Option<&MoonrakerConfig>) -> Result<Self, ResponseStatus> {
+        let url = config.map(|c| c.url.as_str()).unwrap_or(DEFAULT_MOONRAKER_URL);
+        let base = Url::parse(url)
+            .map_err(|e| ResponseStatus::InvalidParams(format!("moonraker url {}: {}", url, e)))?;
+        let mut builder = Client::builder();
+        if let Some(ms) = config.and_then(|c| c.timeout_ms) {
+            builder = builder.timeout(Duration::from_millis(ms));
+        }
+        let client = builder
+            .build()
+            .map_err(|e| ResponseStatus::InvalidParams(e.to_string()))?;
+        Ok(Moonraker {
+            client,
+            base,
+            idempotency_key: None,
+        })
+    }
+
+    /// Send `key` in the `Idempotency-Key` header of every POST, followed
+    /// by the path so the steps of one action keep apart, e.g.
+    /// `KEY/machine/device_power/device`.
+    pub fn with_idempotency_key(mut self, key: String) -> Self {
+        self.idempotency_key = Some(key);
+        self
+    }
+
+    fn post_to(&self, url: Url) -> reqwest::RequestBuilder {
+        let request = self.client.post(url.clone());
+        match &self.idempotency_key {
+            Some(key) => request.header(IDEMPOTENCY_HEADER, format!("{}{}", key, url.path())),
+            None => request,
+        }
+    }
+
+    /// GET an API endpoint such as `/server/webcams/list`, returning the
+    /// `result` of the answer.
+    pub async fn get(&self, path: &str) -> Result<JsonValue, ResponseStatus> {
+        let url = self.endpoint(path)?;
+        let response = self.client.get(url).send().await.map_err(status_of)?;
+        Self::result(response).await
+    }
+
+    /// POST to an API endpoint with JSON params, returning the `result`.
+    pub async fn post(&self, path: &str, params: &JsonValue) -> Result<JsonValue, ResponseStatus> {
+        let url = self.endpoint(path)?;
+        let body = serde_json::to_vec(params).map_err(|e| ResponseStatus::InvalidParams(e.to_string()))?;
+        let response = self
+            .post_to(url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(status_of)?;
+        Self::result(response).await
+    }
+
+    /// Download a URL, e.g. a webcam snapshot.
+    pub async fn fetch(&self, url: Url) -> Result<Vec<u8>, ResponseStatus> {
+        let response = self.client.get(url).send().await.map_err(status_of)?;
+        let code = response.status();
+        if !code.is_success() {
+            return Err(http_error(code.as_u16(), code.to_string()));
+        }
+        let bytes = response.bytes().await.map_err(status_of)?;
+        Ok(bytes.to_vec())
+    }
+
+    /// POST raw bytes with the given content type to any URL.
+    pub async fn upload(&self, url: &str, content_type: &str, data: Vec<u8>) -> Result<(), ResponseStatus> {
+        let url = Url::parse(url).map_err(|e| ResponseStatus::InvalidParams(format!("{}: {}", url, e)))?;
+        let response = self
+            .post_to(url)
+            .header("Content-Type", content_type)
+            .body(data)
+            .send()
+            .await
+            .map_err(status_of)?;
+        let code = response.status();
+        if !code.is_success() {
+            return Err(http_error(code.as_u16(), code.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Resolve a URL reported by Moonraker. Webcam URLs are usually relative
+    /// to the web frontend, which is served from the same host on the
+    /// default port rather than Moonraker's.
+    pub fn resolve(&self, url: &str) -> Result<Url, ResponseStatus> {
+        if let Ok(absolute) = Url::parse(url) {
+            return Ok(absolute);
+        }
+        let mut resolved = self
+            .base
+            .join(url)
+            .map_err(|e| ResponseStatus::InvalidParams(format!("{}: {}", url, e)))?;
+        let _ = resolved.set_port(None);
+        Ok(resolved)
+    }
+
+    /// The websocket Moonraker pushes notifications on, e.g.
+    /// ws://localhost:7125/websocket.
+    pub fn websocket_url(&self) -> Url {
+        let mut url = self.base.clone();
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        let _ = url.set_scheme(scheme);
+        url.set_path("/websocket");
+        url.set_query(None);
+        url
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url, ResponseStatus> {
+        self.base
+            .join(path)
+            .map_err(|e| ResponseStatus::InvalidParams(format!("{}: {}", path, e)))
+    }
+
+    async fn result(response: reqwest::Response) -> Result<JsonValue, ResponseStatus> {
+        let code = response.status();
+        let text = response.text().await.map_err(status_of)?;
+        let json: Option<JsonValue> = serde_json::from_str(&text).ok();
+        if !code.is_success() {
+            // Moonraker errors carry {"error": {"code": ..., "message": ...}}
+            return Err(match json.as_ref().map(ResponseStatus::from_response) {
+                Some(status @ ResponseStatus::RpcError { .. }) => status,
+                _ => http_error(code.as_u16(), code.to_string()),
+            });
+        }
+        match json {
+            Some(mut json) => Ok(json.get_mut("result").map(JsonValue::take).unwrap_or(json)),
+            None => Err(ResponseStatus::ParseError(format!("not JSON: {}", text))),
+        }
+    }
+}
+
+/// POST a built-in action to Moonraker and report the outcome as a regular
+/// response.
+pub async fn call(
+    config: Option<&MoonrakerConfig>,
+    action: &MoonrakerCall,
+    request_id: u32,
+    correlation_id: Uuid,
+    response_tx: Sender<EventMessage>,
+) {
+    info!("[{}] Moonraker call id={}: {} {}", correlation_id, request_id, action.path, action.params);
+    let result = match Moonraker::new(config) {
+        Ok(client) => client.with_idempotency_key(idempotency_key(correlation_id, request_id)).post(&action.path, &action.params).await,
+        Err(status) => Err(status),
+    };
+    let (status, body) = match result {
+        Ok(body) => (ResponseStatus::Ok, Some(body)),
+        Err(status) => (status, None),
+    };
+    EventResponse::send(&response_tx, request_id, correlation_id, Some(Endpoint::Moonraker), status, body, &[]).await;
+}
+
+fn http_error(code: u16, message: String) -> ResponseStatus {
+    ResponseStatus::RpcError {
+        code: Some(code as i64),
+        message,
+    }
+}
+
+fn status_of(e: reqwest::Error) -> ResponseStatus {
+    if e.is_timeout() {
+        ResponseStatus::Timeout
+    } else if e.is_connect() {
+        ResponseStatus::ConnectionError(io::ErrorKind::ConnectionRefused)
+    } else {
+        ResponseStatus::ConnectionError(io::ErrorKind::Other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_relative_to_web_host() {
+        let moonraker = Moonraker::new(Some(&MoonrakerConfig {
+            url: "http://printer.local:7125".to_string(),
+            timeout_ms: None,
+            allow_remote_mappings: None,
+        }))
+        .unwrap();
+        assert_eq!(
+            moonraker.resolve("/webcam/?action=snapshot").unwrap().as_str(),
+            "http://printer.local/webcam/?action=snapshot"
+        );
+        assert_eq!(
+            moonraker.resolve("http://cam.local:8080/snap.jpg").unwrap().as_str(),
+            "http://cam.local:8080/snap.jpg"
+        );
+        assert_eq!(moonraker.websocket_url().as_str(), "ws://printer.local:7125/websocket");
+
+        // Every POST of an action carries its key and path
+        let moonraker = moonraker.with_idempotency_key("3f2a-7".to_string());
+        let request = moonraker.post_to(moonraker.endpoint("/machine/reboot").unwrap()).build().unwrap();
+        assert_eq!(request.headers()[IDEMPOTENCY_HEADER], "3f2a-7/machine/reboot");
+
+        // IPv6 literals keep their brackets
+        let moonraker = Moonraker::new(Some(&MoonrakerConfig {
+            url: "http://[fd00::12]:7125".to_string(),
+            timeout_ms: None,
+            allow_remote_mappings: None,
+        }))
+        .unwrap();
+        assert_eq!(moonraker.resolve("/webcam/").unwrap().as_str(), "http://[fd00::12]/webcam/");
+        assert_eq!(moonraker.websocket_url().as_str(), "ws://[fd00::12]:7125/websocket");
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use spibuttonlib::SPIButtonState;
+
+use crate::panel::PanelButton;
+use crate::units::ButtonId;
+
+/// State changes of a button closer together than this form one burst,
+/// taken as noise rather than separate presses.
+pub const NOISE_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// Margin added to the measured noise in suggestions, and their step.
+const SUGGESTION_STEP_MS: u64 = 5;
+
+/// Counts bounces and ghost presses per button from the raw panel events,
+/// before debouncing or press filtering drop them, for `report`.
+#[derive(Debug, Default)]
+pub struct NoiseStats {
+    buttons: BTreeMap<ButtonId, ButtonNoise>,
+}
+
+/// Noise seen on one button.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ButtonNoise {
+    pub changes: u64,
+    /// Changes following another within `NOISE_THRESHOLD`, except those of
+    /// ghosts
+    pub bounces: u64,
+    /// Longest time between two changes of a bounce
+    pub longest_bounce: Duration,
+    /// Presses released within `NOISE_THRESHOLD`
+    pub ghosts: u64,
+    /// Longest time a ghost press was down
+    pub longest_ghost: Duration,
+    burst: Option<Burst>,
+}
+
+/// Changes of a button each within `NOISE_THRESHOLD` of the one before.
+#[derive(Debug, Clone, PartialEq)]
+struct Burst {
+    start: Instant,
+    last: Instant,
+    starts_pressed: bool,
+    pressed: bool,
+    changes: u64,
+    longest_gap: Duration,
+}
+
+impl NoiseStats {
+    pub fn new() -> Self {
+        NoiseStats::default()
+    }
+
+    /// Count the events of a read at `now`.
+    pub fn record(&mut self, now: Instant, events: &[PanelButton]) {
+        for b in events {
+            let pressed = !matches!(b.get_state(), SPIButtonState::Off);
+            self.buttons.entry(b.id()).or_default().change(now, pressed);
+        }
+    }
+
+    /// The statistics so far, with suggested settings.
+    pub fn report(&self, now: Instant) -> TuningReport {
+        let buttons: BTreeMap<ButtonId, ButtonNoise> = self
+            .buttons
+            .iter()
+            .map(|(id, noise)| {
+                let mut noise = noise.clone();
+                if noise.burst.as_ref().is_some_and(|burst| now.duration_since(burst.last) >= NOISE_THRESHOLD) {
+                    noise.close_burst();
+                }
+                (*id, noise)
+            })
+            .collect();
+        let debounce_ms = buttons.values().filter(|n| n.bounces > 0).map(|n| suggestion(n.longest_bounce)).max();
+        let min_press_ms = buttons.values().filter(|n| n.ghosts > 0).map(|n| suggestion(n.longest_ghost)).max();
+        TuningReport { buttons, debounce_ms, min_press_ms }
+    }
+}
+
+impl ButtonNoise {
+    fn change(&mut self, now: Instant, pressed: bool) {
+        self.changes += 1;
+        match self.burst.as_mut() {
+            Some(burst) if now.duration_since(burst.last) < NOISE_THRESHOLD => {
+                burst.longest_gap = burst.longest_gap.max(now.duration_since(burst.last));
+                burst.last = now;
+                burst.pressed = pressed;
+                burst.changes += 1;
+                return;
+            }
+            Some(_) => self.close_burst(),
+            None => {}
+        }
+        self.burst = Some(Burst {
+            start: now,
+            last: now,
+            starts_pressed: pressed,
+            pressed,
+            changes: 1,
+            longest_gap: Duration::ZERO,
+        });
+    }
+
+    /// Count the burst as a ghost press if it starts with a press and ends
+    /// released, else as bounces.
+    fn close_burst(&mut self) {
+        let Some(burst) = self.burst.take() else { return };
+        if burst.starts_pressed && !burst.pressed {
+            self.ghosts += 1;
+            self.longest_ghost = self.longest_ghost.max(burst.last.duration_since(burst.start));
+        } else if burst.changes > 1 {
+            self.bounces += burst.changes - 1;
+            self.longest_bounce = self.longest_bounce.max(burst.longest_gap);
+        }
+    }
+}
+
+/// A setting just above the noise measured, in steps of 5ms.
+fn suggestion(noise: Duration) -> u64 {
+    let ms = noise.as_millis() as u64 + SUGGESTION_STEP_MS;
+    ms.div_ceil(SUGGESTION_STEP_MS) * SUGGESTION_STEP_MS
+}
+
+/// Noise per button and the `polling` settings that would filter all of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningReport {
+    pub buttons: BTreeMap<ButtonId, ButtonNoise>,
+    /// Suggested `polling.debounce_ms`, `None` without bounces
+    pub debounce_ms: Option<u64>,
+    /// Suggested `polling.min_press_ms`, `None` without ghosts
+    pub min_press_ms: Option<u64>,
+}
+
+impl fmt::Display for TuningReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.buttons.is_empty() {
+            return write!(f, "no button events yet");
+        }
+        for (id, noise) in &self.buttons {
+            writeln!(
+                f,
+                "button {}: {} changes, {} bounces (longest {}ms), {} ghosts (longest {}ms)",
+                id,
+                noise.changes,
+                noise.bounces,
+                noise.longest_bounce.as_millis(),
+                noise.ghosts,
+                noise.longest_ghost.as_millis()
+            )?;
+        }
+        match self.debounce_ms {
+            Some(ms) => writeln!(f, "suggested polling.debounce_ms: {}", ms)?,
+            None => writeln!(f, "no bounces seen, debounce_ms is not needed")?,
+        }
+        match self.min_press_ms {
+            Some(ms) => write!(f, "suggested polling.min_press_ms: {}", ms),
+            None => write!(f, "no ghost presses seen, min_press_ms is not needed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounces_and_ghosts_are_told_apart() {
+        let ms = Duration::from_millis;
+        let t0 = Instant::now();
+        let mut stats = NoiseStats::new();
+        let mut read = |at: u64, id: u8, state| stats.record(t0 + ms(at), &[PanelButton::new(ButtonId(id), state)]);
+
+        // Button 1 bounces on press and release of a 200ms press
+        read(0, 1, SPIButtonState::On);
+        read(3, 1, SPIButtonState::Off);
+        read(12, 1, SPIButtonState::On);
+        read(200, 1, SPIButtonState::Off);
+        read(205, 1, SPIButtonState::On);
+        read(210, 1, SPIButtonState::Off);
+        // Button 2 sees a single-sample ghost
+        read(300, 2, SPIButtonState::On);
+        read(308, 2, SPIButtonState::Off);
+
+        let report = stats.report(t0 + ms(1000));
+        let button1 = &report.buttons[&ButtonId(1)];
+        assert_eq!((button1.changes, button1.bounces, button1.ghosts), (6, 4, 0));
+        assert_eq!(button1.longest_bounce, ms(9));
+        let button2 = &report.buttons[&ButtonId(2)];
+        assert_eq!((button2.bounces, button2.ghosts, button2.longest_ghost), (0, 1, ms(8)));
+        assert_eq!(report.debounce_ms, Some(15));
+        assert_eq!(report.min_press_ms, Some(15));
+        assert!(report.to_string().ends_with("suggested polling.min_press_ms: 15"));
+    }
+}
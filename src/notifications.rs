@@ -0,0 +1,163 @@
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info};
+use serde_json::{json, Value as JsonValue};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::command::EventMessage;
+use crate::config::MoonrakerConfig;
+use crate::error::{Error, Result};
+use crate::moonraker::Moonraker;
+use crate::ratelimit::warn_limited;
+use crate::remote::REMOTE_METHODS;
+
+/// Delay before reconnecting after the websocket closed or failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Request id of the printer object subscription, after the remote methods.
+const SUBSCRIBE_ID: usize = REMOTE_METHODS.len();
+
+/// A Moonraker notification such as `notify_timelapse_event`.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub method: String,
+    pub params: JsonValue,
+}
+
+impl Notification {
+    /// Parse a websocket message, ignoring anything that is not a `notify_*`
+    /// notification or a call of one of our remote methods.
+    pub fn parse(text: &str) -> Option<Self> {
+        let message: JsonValue = serde_json::from_str(text).ok()?;
+        let method = message.get("method")?.as_str()?;
+        if !method.starts_with("notify_") && !REMOTE_METHODS.contains(&method) {
+            return None;
+        }
+        Some(Notification {
+            method: method.to_string(),
+            params: message.get("params").cloned().unwrap_or(JsonValue::Null),
+        })
+    }
+
+    /// The first parameter, which carries the event for most notifications.
+    pub fn event(&self) -> Option<&JsonValue> {
+        self.params.get(0)
+    }
+}
+
+/// Listen to Moonraker's websocket and forward its notifications and calls
+/// of our remote methods to the main loop, reconnecting whenever the
+/// connection drops. Status updates of the given Klipper `objects` are
+/// subscribed to.
+pub fn spawn(config: MoonrakerConfig, objects: Vec<String>, tx: Sender<EventMessage>) -> Result<()> {
+    let client = Moonraker::new(Some(&config))
+        .map_err(|status| Error::config("Invalid moonraker config").caused_by(status))?;
+    tokio::spawn(async move {
+        let url = client.websocket_url();
+        loop {
+            match listen(&client, url.as_str(), &objects, &tx).await {
+                Ok(()) => info!("Moonraker websocket closed"),
+                Err(e) => warn_limited!("Listening to Moonraker at {} failed: {}", url, e),
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    });
+    Ok(())
+}
+
+async fn listen(client: &Moonraker, url: &str, objects: &[String], tx: &Sender<EventMessage>) -> Result<()> {
+    let (mut ws, _) = connect_async(url).await.map_err(rpc_error)?;
+    info!("Listening for Moonraker notifications on {}", url);
+    for (id, method) in REMOTE_METHODS.iter().enumerate() {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "method": "connection.register_remote_method",
+            "params": { "method_name": method },
+            "id": id,
+        });
+        ws.send(Message::Text(request.to_string())).await.map_err(rpc_error)?;
+    }
+    if !objects.is_empty() {
+        let subscribed: serde_json::Map<String, JsonValue> =
+            objects.iter().map(|o| (o.clone(), JsonValue::Null)).collect();
+        let request = json!({
+            "jsonrpc": "2.0",
+            "method": "printer.objects.subscribe",
+            "params": { "objects": subscribed },
+            "id": SUBSCRIBE_ID,
+        });
+        ws.send(Message::Text(request.to_string())).await.map_err(rpc_error)?;
+        debug!("Subscribed to printer objects: {}", objects.join(", "));
+    }
+    sync_job_queue(client, tx).await;
+    while let Some(message) = ws.next().await {
+        if let Message::Text(text) = message.map_err(rpc_error)? {
+            let notification = Notification::parse(&text).or_else(|| initial_status(&text));
+            if let Some(notification) = notification {
+                tx.send(EventMessage::Notification(notification))
+                    .await
+                    .map_err(|_| Error::internal("main loop stopped"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn rpc_error(e: tokio_tungstenite::tungstenite::Error) -> Error {
+    Error::rpc("Moonraker websocket", e)
+}
+
+/// The subscription response carries the full current state of the
+/// objects, pass it on as a status update so the cache starts complete.
+fn initial_status(text: &str) -> Option<Notification> {
+    let message: JsonValue = serde_json::from_str(text).ok()?;
+    if message.get("id")?.as_u64()? != SUBSCRIBE_ID as u64 {
+        return None;
+    }
+    Some(Notification {
+        method: "notify_status_update".to_string(),
+        params: json!([message["result"]["status"]]),
+    })
+}
+
+/// Notifications only report changes, so seed the job queue indicator with
+/// the current queue whenever the websocket (re)connects.
+async fn sync_job_queue(client: &Moonraker, tx: &Sender<EventMessage>) {
+    match client.get("/server/job_queue/status").await {
+        Ok(status) => {
+            let notification = Notification {
+                method: "notify_job_queue_changed".to_string(),
+                params: json!([{
+                    "action": "state_changed",
+                    "updated_queue": status["queued_jobs"],
+                    "queue_state": status["queue_state"],
+                }]),
+            };
+            let _ = tx.send(EventMessage::Notification(notification)).await;
+        }
+        // The job_queue component is optional in Moonraker
+        Err(status) => debug!("Job queue status unavailable: {}", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notification() {
+        let n = Notification::parse(
+            r#"{"jsonrpc": "2.0", "method": "notify_timelapse_event", "params": [{"action": "render", "status": "running"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(n.method, "notify_timelapse_event");
+        assert_eq!(n.event().unwrap()["status"], "running");
+
+        // Responses to requests are not notifications
+        assert!(Notification::parse(r#"{"jsonrpc": "2.0", "result": "ok", "id": 1}"#).is_none());
+        assert!(Notification::parse("not json").is_none());
+    }
+}
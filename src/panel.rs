@@ -0,0 +1,880 @@
+use spibuttonlib::{SPIButton, SPIButtonController, SPIButtonState};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::{BitOrder, LedFrameConfig, Mcp23s17Config, PanelProtocolKind, SpiConfig};
+use crate::spitrace::TracedSpi;
+use crate::units::{ButtonId, RegisterAddr};
+
+/// Half periods of the slow and fast LED flashing done in software by
+/// panels without flashing firmware.
+const FLASH1_HALF_PERIOD: Duration = Duration::from_millis(500);
+const FLASH2_HALF_PERIOD: Duration = Duration::from_millis(125);
+
+/// Frames of all LEDs sent between full frames of an `led_frame` panel
+/// with `delta` on, when `full_frame_interval` is not configured.
+pub const DEFAULT_FULL_FRAME_INTERVAL: u32 = 100;
+
+/// First byte of an `led_frame` frame carrying every LED, and of one
+/// carrying runs of changed LEDs.
+const FRAME_FULL: u8 = 0xf0;
+const FRAME_DELTA: u8 = 0xd0;
+/// Offset and length preceding each run of a delta frame
+const RUN_HEADER: usize = 3;
+
+/// A button as seen through a panel protocol. Its state is what the panel
+/// reported in an event, and the LED state when written back.
+#[derive(Debug, Clone, Copy)]
+pub struct PanelButton {
+    id: ButtonId,
+    state: SPIButtonState,
+}
+
+impl PanelButton {
+    pub fn new(id: ButtonId, state: SPIButtonState) -> Self {
+        PanelButton { id, state }
+    }
+
+    pub fn id(&self) -> ButtonId {
+        self.id
+    }
+
+    pub fn get_state(&self) -> SPIButtonState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: SPIButtonState) {
+        self.state = state;
+    }
+}
+
+/// Wire protocol of a button board: how button states are read and LED
+/// states written. Selected by `spi.protocol` in the config.
+pub trait PanelProtocol {
+    /// Exchange one frame with the panel, returning the buttons whose state
+    /// changed.
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>>;
+
+    /// Current LED state of a button.
+    fn get_button(&self, id: ButtonId) -> PanelButton;
+
+    /// Set a button's LED state, sent with the next frame.
+    fn set_button(&mut self, id: ButtonId, button: PanelButton);
+
+    /// Apply a button's feature flags from its `config`. Protocols without
+    /// per-button features ignore them.
+    fn configure(&mut self, id: ButtonId, flags: u8);
+
+    /// Dim a button's LED to `percent` of full brightness. Ignored unless
+    /// the capabilities include `dimming`.
+    fn set_brightness(&mut self, _id: ButtonId, _percent: u8) {}
+
+    /// What the hardware behind the protocol can do.
+    fn capabilities(&self) -> &Capabilities;
+
+    /// Identity read back from the panel, checked periodically to notice a
+    /// different panel being plugged in. `None` for protocols without an
+    /// ID register.
+    fn identify(&mut self) -> io::Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// Detect the capabilities again and re-apply the init registers, e.g.
+    /// after `identify` changed.
+    fn reinitialize(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a panel can do, checked by `Config::validate` so mappings the
+/// hardware cannot satisfy are rejected before it is opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Most buttons the panel can read, `None` when only the mapped
+    /// buttons are scanned
+    pub max_buttons: Option<usize>,
+    pub leds: LedSupport,
+    /// LEDs can show colours
+    pub rgb: bool,
+    /// LEDs can be dimmed with a `brightness`
+    pub dimming: bool,
+    pub analog_channels: usize,
+    /// The panel signals changes on an interrupt line
+    pub interrupt_line: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedSupport {
+    None,
+    All,
+    /// Only these buttons have an LED
+    Buttons(BTreeSet<ButtonId>),
+}
+
+impl Capabilities {
+    pub fn has_led(&self, button: ButtonId) -> bool {
+        match &self.leds {
+            LedSupport::None => false,
+            LedSupport::All => true,
+            LedSupport::Buttons(buttons) => buttons.contains(&button),
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_buttons {
+            Some(max) => write!(f, "up to {} buttons", max)?,
+            None => write!(f, "mapped buttons only")?,
+        }
+        match &self.leds {
+            LedSupport::None => write!(f, ", no LEDs")?,
+            LedSupport::All => write!(f, ", LEDs")?,
+            LedSupport::Buttons(buttons) => write!(f, ", LEDs on {} button(s)", buttons.len())?,
+        }
+        if self.rgb {
+            write!(f, ", RGB")?;
+        }
+        if self.dimming {
+            write!(f, ", dimming")?;
+        }
+        if self.analog_channels > 0 {
+            write!(f, ", {} analog channel(s)", self.analog_channels)?;
+        }
+        if self.interrupt_line {
+            write!(f, ", interrupt line")?;
+        }
+        Ok(())
+    }
+}
+
+/// Capabilities of the panel the `spi` section describes.
+pub fn capabilities(spi: &SpiConfig) -> Capabilities {
+    let plain = Capabilities {
+        max_buttons: None,
+        leds: LedSupport::All,
+        rgb: false,
+        dimming: false,
+        analog_channels: 0,
+        interrupt_line: false,
+    };
+    match spi.protocol.unwrap_or_default() {
+        PanelProtocolKind::SpiButton => plain,
+        PanelProtocolKind::ShiftIn => Capabilities {
+            leds: LedSupport::None,
+            ..plain
+        },
+        PanelProtocolKind::ShiftRegister => Capabilities {
+            max_buttons: spi.shift_register.as_ref().and_then(|s| s.chain_length).map(|n| n * 8),
+            dimming: true,
+            ..plain
+        },
+        PanelProtocolKind::LedFrame => Capabilities {
+            dimming: true,
+            ..plain
+        },
+        PanelProtocolKind::Mcp23s17 => {
+            let mcp = spi.mcp23s17.clone().unwrap_or_default();
+            Capabilities {
+                max_buttons: Some(16),
+                leds: LedSupport::Buttons(mcp.leds.unwrap_or_default().into_keys().collect()),
+                interrupt_line: true,
+                ..plain
+            }
+        }
+    }
+}
+
+/// Open the panel described by the `spi` section.
+pub fn open(spi: &SpiConfig, buttons: usize) -> io::Result<Box<dyn PanelProtocol>> {
+    match spi.protocol.unwrap_or_default() {
+        PanelProtocolKind::SpiButton => {
+            let controller = SPIButtonController::new(buttons, &spi.device, spi.speed_hz.0, spi.mode)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            Ok(Box::new(SpiButtonPanel {
+                controller,
+                capabilities: capabilities(spi),
+            }))
+        }
+        PanelProtocolKind::ShiftIn => Ok(Box::new(ShiftInPanel::open(spi, buttons)?)),
+        PanelProtocolKind::ShiftRegister => Ok(Box::new(ShiftRegisterPanel::open(spi, buttons)?)),
+        PanelProtocolKind::Mcp23s17 => Ok(Box::new(Mcp23s17Panel::open(spi, buttons)?)),
+        PanelProtocolKind::LedFrame => Ok(Box::new(LedFramePanel::open(spi, buttons)?)),
+    }
+}
+
+/// The spibuttonlib register protocol of the panel firmware this daemon
+/// was written for.
+struct SpiButtonPanel {
+    controller: SPIButtonController,
+    capabilities: Capabilities,
+}
+
+impl PanelProtocol for SpiButtonPanel {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        let events = self
+            .controller
+            .loop_once()
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        Ok(events.iter().map(|b| PanelButton::new(ButtonId(b.id()), b.get_state())).collect())
+    }
+
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        PanelButton::new(id, self.controller.get_button(id.index()).get_state())
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        // Keep the firmware's feature flags, only the LED state changes
+        let mut current = self.controller.get_button(id.index());
+        current.set_state(button.get_state());
+        self.controller.set_button(id.0, current);
+    }
+
+    fn configure(&mut self, id: ButtonId, flags: u8) {
+        self.controller.set_button(id.0, SPIButton::new(flags));
+    }
+
+    fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+}
+
+/// Plain input shift registers (e.g. chained CD4021 or 74HC165 with the
+/// load line on chip select): one bit per button, set while pressed, first
+/// button in the most significant bit of the first byte. There are no LEDs,
+/// their states are only remembered.
+struct ShiftInPanel {
+    spi: TracedSpi,
+    capabilities: Capabilities,
+    pressed: Vec<bool>,
+    leds: Vec<SPIButtonState>,
+}
+
+impl ShiftInPanel {
+    fn open(config: &SpiConfig, buttons: usize) -> io::Result<Self> {
+        Ok(ShiftInPanel {
+            spi: open_spidev(config)?,
+            capabilities: capabilities(config),
+            pressed: vec![false; buttons],
+            leds: vec![SPIButtonState::Off; buttons],
+        })
+    }
+}
+
+fn open_spidev(config: &SpiConfig) -> io::Result<TracedSpi> {
+    let mut spi = Spidev::open(&config.device)?;
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(config.speed_hz.0)
+        .mode(SpiModeFlags::from_bits_truncate(config.mode as u32))
+        .build();
+    spi.configure(&options)?;
+    Ok(TracedSpi::new(spi, config.trace.as_ref()))
+}
+
+/// Byte and mask of bit `index` in a frame.
+fn bit_mask(index: usize, order: BitOrder) -> (usize, u8) {
+    let mask = match order {
+        BitOrder::MsbFirst => 0x80 >> (index % 8),
+        BitOrder::LsbFirst => 0x01 << (index % 8),
+    };
+    (index / 8, mask)
+}
+
+/// Buttons whose bit differs from `pressed`, updating it. With
+/// `active_low` a cleared bit means pressed.
+fn changed_bits(frame: &[u8], pressed: &mut [bool], order: BitOrder, active_low: bool) -> Vec<PanelButton> {
+    let mut events = Vec::new();
+    for (id, was) in pressed.iter_mut().enumerate() {
+        let (byte, mask) = bit_mask(id, order);
+        let now = (frame[byte] & mask != 0) != active_low;
+        if now != *was {
+            *was = now;
+            let state = if now { SPIButtonState::On } else { SPIButtonState::Off };
+            events.push(PanelButton::new(ButtonId(id as u8), state));
+        }
+    }
+    events
+}
+
+impl PanelProtocol for ShiftInPanel {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        let tx = vec![0u8; self.pressed.len().div_ceil(8)];
+        let mut rx = vec![0u8; tx.len()];
+        self.spi.read_write(&tx, &mut rx)?;
+        Ok(changed_bits(&rx, &mut self.pressed, BitOrder::MsbFirst, false))
+    }
+
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        PanelButton::new(id, self.leds.get(id.index()).copied().unwrap_or(SPIButtonState::Off))
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        if let Some(led) = self.leds.get_mut(id.index()) {
+            *led = button.get_state();
+        }
+    }
+
+    fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+
+    fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+}
+
+/// Chained 74HC165 inputs and 74HC595 outputs on one bus: each frame
+/// shifts the LED outputs out on MOSI while the inputs come back on MISO.
+/// Input and output 0 are in the first byte on the wire. Flashing LEDs are
+/// toggled by the daemon, at the pace of the polling interval, and dimmed
+/// ones lit in only part of the frames.
+struct ShiftRegisterPanel {
+    spi: TracedSpi,
+    capabilities: Capabilities,
+    order: BitOrder,
+    active_low: bool,
+    /// Output bit of each button's LED, if not the button's own number
+    outputs: BTreeMap<ButtonId, usize>,
+    pressed: Vec<bool>,
+    leds: HashMap<ButtonId, SPIButtonState>,
+    /// Brightness in percent of dimmed LEDs
+    brightness: HashMap<ButtonId, u8>,
+    frames: u64,
+    started: Instant,
+}
+
+impl ShiftRegisterPanel {
+    fn open(config: &SpiConfig, buttons: usize) -> io::Result<Self> {
+        let shift = config.shift_register.clone().unwrap_or_default();
+        let chain_length = shift.chain_length.unwrap_or(buttons.div_ceil(8)).max(1);
+        Ok(ShiftRegisterPanel {
+            spi: open_spidev(config)?,
+            capabilities: capabilities(config),
+            order: shift.bit_order.unwrap_or_default(),
+            active_low: shift.active_low.unwrap_or(false),
+            outputs: shift.leds.unwrap_or_default(),
+            pressed: vec![false; chain_length * 8],
+            leds: HashMap::new(),
+            brightness: HashMap::new(),
+            frames: 0,
+            started: Instant::now(),
+        })
+    }
+}
+
+/// Whether an LED in `state` is lit at `elapsed`, flashing ones in the
+/// first half of their period.
+fn led_lit(state: SPIButtonState, elapsed: Duration) -> bool {
+    let flash = |half: Duration| (elapsed.as_millis() / half.as_millis()).is_multiple_of(2);
+    match state {
+        SPIButtonState::On => true,
+        SPIButtonState::Flash1 => flash(FLASH1_HALF_PERIOD),
+        SPIButtonState::Flash2 => flash(FLASH2_HALF_PERIOD),
+        _ => false,
+    }
+}
+
+/// Whether an LED at `percent` brightness is lit in frame number `frame`,
+/// spreading the lit frames evenly.
+fn pwm_on(percent: u8, frame: u64) -> bool {
+    let percent = u64::from(percent.min(100));
+    (frame + 1) * percent / 100 != frame * percent / 100
+}
+
+/// Output frame number `frame` of `bytes` bytes lighting the LEDs in
+/// `leds`, those in `brightness` only in part of the frames.
+fn led_frame(
+    leds: &HashMap<ButtonId, SPIButtonState>,
+    brightness: &HashMap<ButtonId, u8>,
+    outputs: &BTreeMap<ButtonId, usize>,
+    bytes: usize,
+    order: BitOrder,
+    elapsed: Duration,
+    frame_number: u64,
+) -> Vec<u8> {
+    let mut frame = vec![0u8; bytes];
+    for (id, state) in leds {
+        let dimmed_off = brightness.get(id).is_some_and(|p| !pwm_on(*p, frame_number));
+        let lit = led_lit(*state, elapsed) && !dimmed_off;
+        let output = outputs.get(id).copied().unwrap_or(id.index());
+        let (byte, mask) = bit_mask(output, order);
+        if lit && byte < bytes {
+            frame[byte] |= mask;
+        }
+    }
+    frame
+}
+
+impl PanelProtocol for ShiftRegisterPanel {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        let tx = led_frame(
+            &self.leds,
+            &self.brightness,
+            &self.outputs,
+            self.pressed.len() / 8,
+            self.order,
+            self.started.elapsed(),
+            self.frames,
+        );
+        self.frames += 1;
+        let mut rx = vec![0u8; tx.len()];
+        self.spi.read_write(&tx, &mut rx)?;
+        Ok(changed_bits(&rx, &mut self.pressed, self.order, self.active_low))
+    }
+
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        PanelButton::new(id, self.leds.get(&id).copied().unwrap_or(SPIButtonState::Off))
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        self.leds.insert(id, button.get_state());
+    }
+
+    fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+
+    fn set_brightness(&mut self, id: ButtonId, percent: u8) {
+        if percent >= 100 {
+            self.brightness.remove(&id);
+        } else {
+            self.brightness.insert(id, percent);
+        }
+    }
+
+    fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+}
+
+/// MCP23S17 registers by their IOCON.BANK = 0 address of port A.
+#[derive(Debug, Clone, Copy)]
+enum Mcp23s17Register {
+    Iodir = 0x00,
+    Gpinten = 0x04,
+    Iocon = 0x0a,
+    Gppu = 0x0c,
+    Gpio = 0x12,
+    Olat = 0x14,
+}
+
+const IOCON_BANK: u8 = 0x80;
+const IOCON_MIRROR: u8 = 0x40;
+const IOCON_HAEN: u8 = 0x08;
+
+/// Register address of `port` (0 = A, 1 = B). With BANK = 0 the ports'
+/// registers are paired, with BANK = 1 each port has its own bank.
+fn mcp23s17_address(register: Mcp23s17Register, port: u8, bank: bool) -> RegisterAddr {
+    let base = register as u8;
+    if bank {
+        RegisterAddr(base / 2 + port * 0x10)
+    } else {
+        RegisterAddr(base + port)
+    }
+}
+
+/// An MCP23S17 expander: buttons and LEDs on any of its 16 pins. Inputs are
+/// read from GPIO and the LEDs written to OLAT every poll, flashing done in
+/// software.
+struct Mcp23s17Panel {
+    spi: TracedSpi,
+    capabilities: Capabilities,
+    spi_config: SpiConfig,
+    opcode: u8,
+    bank: bool,
+    iocon: u8,
+    active_low: bool,
+    inputs: BTreeMap<ButtonId, u8>,
+    led_pins: BTreeMap<ButtonId, u8>,
+    pressed: HashMap<ButtonId, bool>,
+    leds: HashMap<ButtonId, SPIButtonState>,
+    started: Instant,
+}
+
+impl Mcp23s17Panel {
+    fn open(config: &SpiConfig, buttons: usize) -> io::Result<Self> {
+        let mcp: Mcp23s17Config = config.mcp23s17.clone().unwrap_or_default();
+        let address = mcp.address.unwrap_or(0);
+        let pull_ups = mcp.pull_ups.unwrap_or(true);
+        let inputs = mcp
+            .inputs
+            .unwrap_or_else(|| (0..buttons.min(16) as u8).map(|b| (ButtonId(b), b)).collect());
+        let bank = mcp.bank.unwrap_or(false);
+        let mut iocon = 0;
+        if bank {
+            iocon |= IOCON_BANK;
+        }
+        if mcp.mirror_interrupts.unwrap_or(false) {
+            iocon |= IOCON_MIRROR;
+        }
+        if address != 0 {
+            iocon |= IOCON_HAEN;
+        }
+        let mut panel = Mcp23s17Panel {
+            spi: open_spidev(config)?,
+            capabilities: capabilities(config),
+            spi_config: config.clone(),
+            opcode: 0x40 | (address << 1),
+            bank,
+            iocon,
+            active_low: pull_ups,
+            pressed: inputs.keys().map(|b| (*b, false)).collect(),
+            inputs,
+            led_pins: mcp.leds.unwrap_or_default(),
+            leds: HashMap::new(),
+            started: Instant::now(),
+        };
+        panel.init_registers()?;
+        Ok(panel)
+    }
+
+    fn init_registers(&mut self) -> io::Result<()> {
+        // A chip left in BANK = 1 by an earlier run has IOCON at 0x05, which
+        // is GPINTENB with BANK = 0 and rewritten below anyway. Clearing it
+        // returns the chip to the power-on layout either way.
+        self.write(RegisterAddr(0x05), 0)?;
+        self.write(mcp23s17_address(Mcp23s17Register::Iocon, 0, false), self.iocon)?;
+
+        let input_mask = self.input_mask();
+        self.write_pair(Mcp23s17Register::Iodir, !self.output_mask())?;
+        self.write_pair(Mcp23s17Register::Gppu, if self.active_low { input_mask } else { 0 })?;
+        // Interrupt on change of any button, for boards wiring INT to a GPIO
+        self.write_pair(Mcp23s17Register::Gpinten, input_mask)
+    }
+
+    fn input_mask(&self) -> u16 {
+        self.inputs.values().fold(0u16, |mask, pin| mask | 1 << pin)
+    }
+
+    /// LED pins are outputs, everything else stays an input
+    fn output_mask(&self) -> u16 {
+        self.led_pins.values().fold(0u16, |mask, pin| mask | 1 << pin)
+    }
+
+    fn write(&mut self, register: RegisterAddr, value: u8) -> io::Result<()> {
+        self.spi.write(&[self.opcode, register.0, value])
+    }
+
+    fn read(&mut self, register: RegisterAddr) -> io::Result<u8> {
+        let tx = [self.opcode | 1, register.0, 0];
+        let mut rx = [0u8; 3];
+        self.spi.read_write(&tx, &mut rx)?;
+        Ok(rx[2])
+    }
+
+    /// Write a register of both ports, port A from the low byte.
+    fn write_pair(&mut self, register: Mcp23s17Register, value: u16) -> io::Result<()> {
+        let [a, b] = value.to_le_bytes();
+        self.write(mcp23s17_address(register, 0, self.bank), a)?;
+        self.write(mcp23s17_address(register, 1, self.bank), b)
+    }
+
+    fn read_pair(&mut self, register: Mcp23s17Register) -> io::Result<u16> {
+        let a = self.read(mcp23s17_address(register, 0, self.bank))?;
+        let b = self.read(mcp23s17_address(register, 1, self.bank))?;
+        Ok(u16::from_le_bytes([a, b]))
+    }
+}
+
+/// Buttons whose input pin in `gpio` differs from `pressed`, updating it.
+fn changed_pins(
+    gpio: u16,
+    inputs: &BTreeMap<ButtonId, u8>,
+    pressed: &mut HashMap<ButtonId, bool>,
+    active_low: bool,
+) -> Vec<PanelButton> {
+    let mut events = Vec::new();
+    for (button, pin) in inputs {
+        let now = (gpio & 1 << pin != 0) != active_low;
+        let was = pressed.entry(*button).or_insert(false);
+        if now != *was {
+            *was = now;
+            let state = if now { SPIButtonState::On } else { SPIButtonState::Off };
+            events.push(PanelButton::new(*button, state));
+        }
+    }
+    events
+}
+
+impl PanelProtocol for Mcp23s17Panel {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        let elapsed = self.started.elapsed();
+        let latch = self
+            .led_pins
+            .iter()
+            .filter(|(button, _)| self.leds.get(button).is_some_and(|s| led_lit(*s, elapsed)))
+            .fold(0u16, |latch, (_, pin)| latch | 1 << pin);
+        self.write_pair(Mcp23s17Register::Olat, latch)?;
+        let gpio = self.read_pair(Mcp23s17Register::Gpio)?;
+        Ok(changed_pins(gpio, &self.inputs, &mut self.pressed, self.active_low))
+    }
+
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        PanelButton::new(id, self.leds.get(&id).copied().unwrap_or(SPIButtonState::Off))
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        self.leds.insert(id, button.get_state());
+    }
+
+    fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+
+    fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// The chip has no ID register, IOCON and IODIR read back instead: a
+    /// swapped or power cycled expander is back at its reset values.
+    fn identify(&mut self) -> io::Result<Option<u32>> {
+        let iocon = self.read(mcp23s17_address(Mcp23s17Register::Iocon, 0, self.bank))?;
+        let iodir = self.read_pair(Mcp23s17Register::Iodir)?;
+        Ok(Some(u32::from(iocon) << 16 | u32::from(iodir)))
+    }
+
+    fn reinitialize(&mut self) -> io::Result<()> {
+        self.capabilities = capabilities(&self.spi_config);
+        self.init_registers()
+    }
+}
+
+/// A panel firmware taking one byte per LED, its state in the top two bits
+/// and brightness in the low six, so it flashes and dims the LEDs itself.
+/// The frame starts with `FRAME_FULL` and the LED count, followed by every
+/// LED, or with `FRAME_DELTA` and a number of runs, each an offset, a
+/// length and that many LEDs. Buttons come back as in `shift_in`, in the
+/// first bytes the panel sends.
+struct LedFramePanel {
+    spi: TracedSpi,
+    capabilities: Capabilities,
+    delta: bool,
+    full_frame_interval: u32,
+    pressed: Vec<bool>,
+    leds: Vec<SPIButtonState>,
+    /// Brightness in percent of each LED
+    brightness: Vec<u8>,
+    /// LED bytes of the last frame the panel received, `None` when unknown
+    sent: Option<Vec<u8>>,
+    frames: u64,
+}
+
+impl LedFramePanel {
+    fn open(config: &SpiConfig, buttons: usize) -> io::Result<Self> {
+        let frame: LedFrameConfig = config.led_frame.clone().unwrap_or_default();
+        Ok(LedFramePanel {
+            spi: open_spidev(config)?,
+            capabilities: capabilities(config),
+            delta: frame.delta.unwrap_or(false),
+            full_frame_interval: frame.full_frame_interval.unwrap_or(DEFAULT_FULL_FRAME_INTERVAL).max(1),
+            pressed: vec![false; buttons],
+            leds: vec![SPIButtonState::Off; buttons],
+            brightness: vec![100; buttons],
+            sent: None,
+            frames: 0,
+        })
+    }
+}
+
+/// The byte of an LED in `state` at `percent` brightness.
+fn led_byte(state: SPIButtonState, percent: u8) -> u8 {
+    let code = match state {
+        SPIButtonState::On => 1,
+        SPIButtonState::Flash1 => 2,
+        SPIButtonState::Flash2 => 3,
+        _ => 0,
+    };
+    code << 6 | (u16::from(percent.min(100)) * 0x3f / 100) as u8
+}
+
+/// Offset and length of the runs of `next` that differ from `prev`. Runs
+/// closer than a run header are merged, resending the unchanged LEDs
+/// between them is cheaper.
+fn delta_runs(prev: &[u8], next: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in prev.iter().zip(next).enumerate().filter(|(_, (a, b))| a != b) {
+        match runs.last_mut() {
+            Some((start, len)) if i - (*start + *len) <= RUN_HEADER && i - *start < 0xff => *len = i - *start + 1,
+            _ => runs.push((i, 1)),
+        }
+    }
+    runs
+}
+
+/// The frame updating a panel showing `prev`, or every LED when `prev` is
+/// unknown. A delta frame is only sent while it is the shorter one.
+fn encode_led_frame(prev: Option<&[u8]>, next: &[u8]) -> Vec<u8> {
+    let mut full = vec![FRAME_FULL];
+    full.extend_from_slice(&(next.len() as u16).to_be_bytes());
+    full.extend_from_slice(next);
+    let Some(prev) = prev.filter(|p| p.len() == next.len()) else { return full };
+    let runs = delta_runs(prev, next);
+    if runs.len() > 0xff {
+        return full;
+    }
+    let mut delta = vec![FRAME_DELTA, runs.len() as u8];
+    for (offset, len) in runs {
+        delta.extend_from_slice(&(offset as u16).to_be_bytes());
+        delta.push(len as u8);
+        delta.extend_from_slice(&next[offset..offset + len]);
+    }
+    if delta.len() < full.len() {
+        delta
+    } else {
+        full
+    }
+}
+
+impl PanelProtocol for LedFramePanel {
+    fn loop_once(&mut self) -> io::Result<Vec<PanelButton>> {
+        let next: Vec<u8> = self.leds.iter().zip(&self.brightness).map(|(s, p)| led_byte(*s, *p)).collect();
+        let keyframe = !self.delta || self.frames.is_multiple_of(u64::from(self.full_frame_interval));
+        self.frames += 1;
+        let mut tx = encode_led_frame(if keyframe { None } else { self.sent.as_deref() }, &next);
+        tx.resize(tx.len().max(self.pressed.len().div_ceil(8)), 0);
+        let mut rx = vec![0u8; tx.len()];
+        if let Err(e) = self.spi.read_write(&tx, &mut rx) {
+            self.sent = None;
+            return Err(e);
+        }
+        self.sent = Some(next);
+        Ok(changed_bits(&rx, &mut self.pressed, BitOrder::MsbFirst, false))
+    }
+
+    fn get_button(&self, id: ButtonId) -> PanelButton {
+        PanelButton::new(id, self.leds.get(id.index()).copied().unwrap_or(SPIButtonState::Off))
+    }
+
+    fn set_button(&mut self, id: ButtonId, button: PanelButton) {
+        if let Some(led) = self.leds.get_mut(id.index()) {
+            *led = button.get_state();
+        }
+    }
+
+    fn configure(&mut self, _id: ButtonId, _flags: u8) {}
+
+    fn set_brightness(&mut self, id: ButtonId, percent: u8) {
+        if let Some(brightness) = self.brightness.get_mut(id.index()) {
+            *brightness = percent.min(100);
+        }
+    }
+
+    fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    fn reinitialize(&mut self) -> io::Result<()> {
+        self.sent = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_in_reports_changes() {
+        let mut pressed = vec![false; 10];
+
+        let events = changed_bits(&[0b1000_0100, 0b0100_0000], &mut pressed, BitOrder::MsbFirst, false);
+        let ids: Vec<(ButtonId, bool)> = events
+            .iter()
+            .map(|b| (b.id(), matches!(b.get_state(), SPIButtonState::On)))
+            .collect();
+        assert_eq!(ids, vec![(ButtonId(0), true), (ButtonId(5), true), (ButtonId(9), true)]);
+
+        // Only changes are reported
+        let events = changed_bits(&[0b1000_0000, 0b0100_0000], &mut pressed, BitOrder::MsbFirst, false);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id(), ButtonId(5));
+        assert!(matches!(events[0].get_state(), SPIButtonState::Off));
+    }
+
+    #[test]
+    fn test_shift_register_active_low_lsb_first() {
+        let mut pressed = vec![false; 16];
+        // Idle inputs read high
+        assert!(changed_bits(&[0xff, 0xff], &mut pressed, BitOrder::LsbFirst, true).is_empty());
+
+        let events = changed_bits(&[0xff, 0b1111_1101], &mut pressed, BitOrder::LsbFirst, true);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id(), ButtonId(9));
+        assert!(matches!(events[0].get_state(), SPIButtonState::On));
+    }
+
+    #[test]
+    fn test_led_frame_maps_and_flashes() {
+        let mut leds = HashMap::new();
+        leds.insert(ButtonId(0), SPIButtonState::On);
+        leds.insert(ButtonId(1), SPIButtonState::Flash1);
+        leds.insert(ButtonId(2), SPIButtonState::Off);
+        leds.insert(ButtonId(3), SPIButtonState::On);
+        // Button 3's LED is wired to output 12, button 20 is beyond the chain
+        leds.insert(ButtonId(20), SPIButtonState::On);
+        let outputs = BTreeMap::from([(ButtonId(3), 12)]);
+
+        let full = HashMap::new();
+        let ms = Duration::from_millis;
+
+        let frame = led_frame(&leds, &full, &outputs, 2, BitOrder::MsbFirst, ms(100), 0);
+        assert_eq!(frame, vec![0b1100_0000, 0b0000_1000]);
+        // Second half of the slow flash period
+        let frame = led_frame(&leds, &full, &outputs, 2, BitOrder::MsbFirst, ms(600), 0);
+        assert_eq!(frame, vec![0b1000_0000, 0b0000_1000]);
+
+        let frame = led_frame(&leds, &full, &outputs, 2, BitOrder::LsbFirst, ms(100), 0);
+        assert_eq!(frame, vec![0b0000_0011, 0b0001_0000]);
+
+        // Button 0 at a quarter brightness is lit in one frame of four
+        let dimmed = HashMap::from([(ButtonId(0), 25)]);
+        let lit: Vec<bool> = (0..8)
+            .map(|n| led_frame(&leds, &dimmed, &outputs, 2, BitOrder::MsbFirst, ms(100), n)[0] & 0x80 != 0)
+            .collect();
+        assert_eq!(lit, vec![false, false, false, true, false, false, false, true]);
+    }
+
+    #[test]
+    fn test_mcp23s17_register_banks() {
+        assert_eq!(mcp23s17_address(Mcp23s17Register::Gpio, 0, false), RegisterAddr(0x12));
+        assert_eq!(mcp23s17_address(Mcp23s17Register::Gpio, 1, false), RegisterAddr(0x13));
+        assert_eq!(mcp23s17_address(Mcp23s17Register::Gpio, 0, true), RegisterAddr(0x09));
+        assert_eq!(mcp23s17_address(Mcp23s17Register::Olat, 1, true), RegisterAddr(0x1a));
+        assert_eq!(mcp23s17_address(Mcp23s17Register::Iocon, 0, true), RegisterAddr(0x05));
+    }
+
+    #[test]
+    fn test_mcp23s17_pins_to_buttons() {
+        // Button 0 on GPA0, button 1 on GPB7, pulled up
+        let inputs = BTreeMap::from([(ButtonId(0), 0), (ButtonId(1), 15)]);
+        let mut pressed = HashMap::new();
+        assert!(changed_pins(0xffff, &inputs, &mut pressed, true).is_empty());
+
+        let events = changed_pins(0x7fff, &inputs, &mut pressed, true);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id(), ButtonId(1));
+        assert!(matches!(events[0].get_state(), SPIButtonState::On));
+    }
+
+    #[test]
+    fn test_led_frame_sends_changed_runs() {
+        let on = led_byte(SPIButtonState::On, 100);
+        assert_eq!(on, 0x7f);
+        assert_eq!(led_byte(SPIButtonState::Flash2, 50), 0xdf);
+
+        let prev = vec![0u8; 40];
+        let mut next = prev.clone();
+        // A progress bar growing by one LED, and two LEDs close together
+        next[10] = on;
+        next[30] = on;
+        next[33] = on;
+        assert_eq!(delta_runs(&prev, &next), vec![(10, 1), (30, 4)]);
+        let frame = encode_led_frame(Some(&prev), &next);
+        assert_eq!(frame[..6], [FRAME_DELTA, 2, 0, 10, 1, on]);
+        assert_eq!(frame.len(), 2 + 4 + 7);
+        assert_eq!(encode_led_frame(Some(&next), &next), vec![FRAME_DELTA, 0]);
+
+        // Unknown panel contents, or most LEDs changed, take a full frame
+        assert_eq!(encode_led_frame(None, &next)[..3], [FRAME_FULL, 0, 40]);
+        let all = vec![on; 40];
+        assert_eq!(encode_led_frame(Some(&prev), &all).len(), 3 + 40);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::LedPattern;
+use crate::leds::LedLayer;
+use crate::units::ButtonId;
+
+impl LedPattern {
+    /// Whether the LED is lit `elapsed` after the pattern started. Once
+    /// every repeat ran it stays off.
+    pub fn lit(&self, elapsed: Duration) -> bool {
+        let cycle: u64 = self.durations_ms.iter().sum();
+        if cycle == 0 {
+            return false;
+        }
+        let elapsed = elapsed.as_millis() as u64;
+        if self.repeat.is_some_and(|n| elapsed / cycle >= u64::from(n)) {
+            return false;
+        }
+        let mut at = elapsed % cycle;
+        for (i, ms) in self.durations_ms.iter().enumerate() {
+            if at < *ms {
+                return i % 2 == 0;
+            }
+            at -= ms;
+        }
+        false
+    }
+}
+
+#[derive(Debug)]
+struct Running {
+    pattern: LedPattern,
+    started: Instant,
+    lit: bool,
+}
+
+/// The LED patterns running on buttons, by the layer they belong to. The
+/// daemon shows one in place of the plain state while its layer owns the
+/// LED, see `Daemon::write_led`.
+#[derive(Debug, Default)]
+pub struct Patterns {
+    running: HashMap<(ButtonId, LedLayer), Running>,
+}
+
+impl Patterns {
+    pub fn new() -> Self {
+        Patterns::default()
+    }
+
+    /// Run `pattern` on the button's `layer` from its start, replacing any
+    /// other there.
+    pub fn start(&mut self, button: ButtonId, layer: LedLayer, pattern: LedPattern, now: Instant) {
+        let lit = pattern.lit(Duration::ZERO);
+        self.running.insert((button, layer), Running { pattern, started: now, lit });
+    }
+
+    pub fn stop(&mut self, button: ButtonId, layer: LedLayer) {
+        self.running.remove(&(button, layer));
+    }
+
+    /// Stop every pattern of a button, e.g. one removed from the config.
+    pub fn remove(&mut self, button: ButtonId) {
+        self.running.retain(|(b, _), _| *b != button);
+    }
+
+    /// Whether the pattern on the button's `layer` is lit, `None` when it
+    /// runs none.
+    pub fn lit(&self, button: ButtonId, layer: LedLayer) -> Option<bool> {
+        self.running.get(&(button, layer)).map(|r| r.lit)
+    }
+
+    /// Advance every pattern to `now`, returning the buttons and layers
+    /// whose LED turned on or off.
+    pub fn tick(&mut self, now: Instant) -> Vec<(ButtonId, LedLayer)> {
+        let mut changed = Vec::new();
+        for (key, running) in &mut self.running {
+            let lit = running.pattern.lit(now.duration_since(running.started));
+            if lit != running.lit {
+                running.lit = lit;
+                changed.push(*key);
+            }
+        }
+        changed.sort();
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triple_blink_then_off() {
+        let ms = Duration::from_millis;
+        let triple_blink = LedPattern {
+            durations_ms: vec![100, 100, 100, 100, 100, 700],
+            repeat: Some(2),
+        };
+        let lit: Vec<bool> = [0, 150, 250, 450, 600, 1250, 1450, 2400].iter().map(|t| triple_blink.lit(ms(*t))).collect();
+        assert_eq!(lit, vec![true, false, true, true, false, true, true, false]);
+
+        let mut patterns = Patterns::new();
+        let button = ButtonId(2);
+        let layer = LedLayer::State;
+        let t0 = Instant::now();
+        patterns.start(button, layer, triple_blink, t0);
+        assert_eq!(patterns.lit(button, layer), Some(true));
+        assert_eq!(patterns.lit(button, LedLayer::Feedback), None);
+        assert!(patterns.tick(t0 + ms(50)).is_empty());
+        assert_eq!(patterns.tick(t0 + ms(120)), vec![(button, layer)]);
+        assert_eq!(patterns.lit(button, layer), Some(false));
+
+        patterns.stop(button, layer);
+        assert_eq!(patterns.lit(button, layer), None);
+    }
+}
@@ -0,0 +1,388 @@
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use spibuttonlib::SPIButtonState;
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener as StdListener;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::Sender;
+use tokio::time::{sleep, timeout};
+
+use crate::command::EventMessage;
+use crate::config::{Led, SyncConfig};
+use crate::error::{Error, Result};
+use crate::leds::LedLayer;
+use crate::ratelimit::warn_limited;
+use crate::units::ButtonId;
+
+/// Delay before dialing a peer again after the connection closed or failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Time a peer has to greet after connecting.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Updates buffered per connection before a slow peer misses some.
+const PEER_BUFFER: usize = 64;
+
+/// A change to the state the daemons of one printer share.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerUpdate {
+    /// Sent on connecting, asking the other side for the state it has.
+    /// Carries `sync.secret`, the peer closes the connection unless it
+    /// matches its own.
+    Hello {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret: Option<String>,
+    },
+    /// A button's LED on a shared layer, `off` letting go of it
+    Led { button: ButtonId, layer: SharedLayer, led: Led },
+    /// A button was armed and waits `window_ms` for its confirming press
+    Armed { button: ButtonId, window_ms: u64 },
+    /// A button's arming was used up by a confirming press
+    Disarmed { button: ButtonId },
+    /// A state machine button, e.g. one with a `toggle_command`, moved to
+    /// `state`
+    State { button: ButtonId, state: String },
+}
+
+/// The LED layers mirrored between daemons. The others follow from shared
+/// state, e.g. the State layer, or are the panel's own, e.g. refusals on
+/// the Feedback layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedLayer {
+    Action,
+    Remote,
+}
+
+impl SharedLayer {
+    pub fn layer(self) -> LedLayer {
+        match self {
+            SharedLayer::Action => LedLayer::Action,
+            SharedLayer::Remote => LedLayer::Remote,
+        }
+    }
+}
+
+/// The `Led` a plain LED state stands for, `None` for the `config` flags.
+pub fn led_of(state: SPIButtonState) -> Option<Led> {
+    match state {
+        SPIButtonState::Off => Some(Led::Off),
+        SPIButtonState::On => Some(Led::On),
+        SPIButtonState::Flash1 => Some(Led::Flash1),
+        SPIButtonState::Flash2 => Some(Led::Flash2),
+        _ => None,
+    }
+}
+
+/// One line on the wire: an update with the daemon that made it and its
+/// version, e.g. `{"node":"…","version":7,"update":{"disarmed":{"button":3}}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub node: String,
+    pub version: u64,
+    pub update: PeerUpdate,
+}
+
+/// What an update changes. Of two updates to the same key the one with the
+/// higher version wins, ties going to the higher node, so daemons changing
+/// a button at once still end up alike.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Led(ButtonId, SharedLayer),
+    Armed(ButtonId),
+    State(ButtonId),
+}
+
+impl PeerUpdate {
+    /// The button the update is about, `None` for `Hello`.
+    pub fn button(&self) -> Option<ButtonId> {
+        match self {
+            PeerUpdate::Hello { .. } => None,
+            PeerUpdate::Led { button, .. }
+            | PeerUpdate::Armed { button, .. }
+            | PeerUpdate::Disarmed { button }
+            | PeerUpdate::State { button, .. } => Some(*button),
+        }
+    }
+
+    fn key(&self) -> Option<Key> {
+        match self {
+            PeerUpdate::Hello { .. } => None,
+            PeerUpdate::Led { button, layer, .. } => Some(Key::Led(*button, *layer)),
+            PeerUpdate::Armed { button, .. } | PeerUpdate::Disarmed { button } => Some(Key::Armed(*button)),
+            PeerUpdate::State { button, .. } => Some(Key::State(*button)),
+        }
+    }
+}
+
+impl Envelope {
+    fn newer_than(&self, other: &Envelope) -> bool {
+        (self.version, &self.node) > (other.version, &other.node)
+    }
+}
+
+/// The latest update of each piece of shared state, deciding which updates
+/// from peers are newer than what this daemon has.
+#[derive(Debug)]
+pub struct PeerState {
+    node: String,
+    clock: u64,
+    latest: HashMap<Key, Envelope>,
+    link: Option<PeerLink>,
+}
+
+impl PeerState {
+    pub fn new(node: String) -> Self {
+        PeerState { node, clock: 0, latest: HashMap::new(), link: None }
+    }
+
+    /// The name this daemon's updates go by.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Send updates to the peers behind `link` from now on.
+    pub fn connect(&mut self, link: PeerLink) {
+        self.link = Some(link);
+    }
+
+    /// Version a change made here and send it to the peers, if any.
+    pub fn share(&mut self, update: PeerUpdate) -> Option<Envelope> {
+        let link = self.link.as_ref()?;
+        self.clock += 1;
+        let envelope = Envelope { node: self.node.clone(), version: self.clock, update };
+        if let Some(key) = envelope.update.key() {
+            self.latest.insert(key, envelope.clone());
+        }
+        link.send(&envelope);
+        Some(envelope)
+    }
+
+    /// Send the latest update of everything changed since startup again,
+    /// with its version, for a peer that just connected. A daemon that has
+    /// changed nothing sends nothing and takes on its peers' state.
+    /// Arming is left out, its window has likely passed.
+    pub fn resend(&self) {
+        let Some(link) = &self.link else { return };
+        let mut latest: Vec<&Envelope> = self
+            .latest
+            .values()
+            .filter(|e| !matches!(e.update, PeerUpdate::Armed { .. } | PeerUpdate::Disarmed { .. }))
+            .collect();
+        latest.sort_by_key(|e| e.version);
+        for envelope in latest {
+            link.send(envelope);
+        }
+    }
+
+    /// The update of a peer to apply here, `None` when this daemon sent it
+    /// or already has a newer one.
+    pub fn accept(&mut self, envelope: Envelope) -> Option<PeerUpdate> {
+        if envelope.node == self.node {
+            return None;
+        }
+        self.clock = self.clock.max(envelope.version);
+        let Some(key) = envelope.update.key() else { return Some(envelope.update) };
+        if self.latest.get(&key).is_some_and(|known| !envelope.newer_than(known)) {
+            return None;
+        }
+        self.latest.insert(key, envelope.clone());
+        Some(envelope.update)
+    }
+}
+
+/// The connections to the other daemons, fed from the main loop.
+#[derive(Debug, Clone)]
+pub struct PeerLink {
+    outgoing: broadcast::Sender<String>,
+}
+
+impl PeerLink {
+    fn send(&self, envelope: &Envelope) {
+        match serde_json::to_string(envelope) {
+            // No peer connected is fine
+            Ok(line) => drop(self.outgoing.send(line)),
+            Err(e) => warn_limited!("Failed to encode peer update: {}", e),
+        }
+    }
+}
+
+/// Listen on `sync.listen` and dial every `sync.peers` address, redialing
+/// whenever a connection drops. Updates from peers go to the main loop as
+/// `EventMessage::Peer`.
+pub fn spawn(config: &SyncConfig, node: &str, tx: Sender<EventMessage>) -> Result<PeerLink> {
+    let (outgoing, _) = broadcast::channel(PEER_BUFFER);
+    let link = PeerLink { outgoing };
+    let greeting = Envelope { node: node.to_string(), version: 0, update: PeerUpdate::Hello { secret: config.secret.clone() } };
+    let hello = Hello {
+        line: serde_json::to_string(&greeting)
+            .map_err(|e| Error::internal("Failed to encode the peer greeting").caused_by(e))?,
+        secret: config.secret.clone(),
+    };
+    if let Some(address) = &config.listen {
+        let listener = StdListener::bind(address)
+            .and_then(|l| l.set_nonblocking(true).map(|_| l))
+            .and_then(TcpListener::from_std)
+            .map_err(|e| Error::internal(format!("Failed to listen for peers on {}", address)).caused_by(e))?;
+        info!("Listening for peers on {}", address);
+        let (link, tx, hello) = (link.clone(), tx.clone(), hello.clone());
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, from)) => {
+                        info!("Peer {} connected", from);
+                        let (link, tx, hello) = (link.clone(), tx.clone(), hello.clone());
+                        tokio::spawn(async move {
+                            match serve(stream, &link, &tx, &hello).await {
+                                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                                    warn_limited!("Refused peer {}: {}", from, e)
+                                }
+                                Err(e) => debug!("Peer {} disconnected: {}", from, e),
+                                Ok(()) => {}
+                            }
+                        });
+                    }
+                    Err(e) => warn_limited!("Accepting a peer failed: {}", e),
+                }
+            }
+        });
+    }
+    for address in config.peers.iter().flatten() {
+        let (address, link, tx, hello) = (address.clone(), link.clone(), tx.clone(), hello.clone());
+        tokio::spawn(async move {
+            loop {
+                match TcpStream::connect(&address).await {
+                    Ok(stream) => {
+                        info!("Connected to peer {}", address);
+                        match serve(stream, &link, &tx, &hello).await {
+                            Ok(()) => info!("Peer {} closed the connection", address),
+                            Err(e) => warn_limited!("Connection to peer {} failed: {}", address, e),
+                        }
+                    }
+                    Err(e) => warn_limited!("Connecting to peer {} failed: {}", address, e),
+                }
+                sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+    Ok(link)
+}
+
+/// The greeting sent on every connection, and the secret expected back.
+#[derive(Clone)]
+struct Hello {
+    line: String,
+    secret: Option<String>,
+}
+
+/// Exchange updates with one peer until either side closes: greet it, wait
+/// for its greeting with the shared secret, then pass the lines it sends to
+/// the main loop and send it ours.
+async fn serve(stream: TcpStream, link: &PeerLink, tx: &Sender<EventMessage>, hello: &Hello) -> io::Result<()> {
+    let mut outgoing = link.outgoing.subscribe();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(format!("{}\n", hello.line).as_bytes()).await?;
+    let greeting = timeout(HELLO_TIMEOUT, lines.next_line())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "no greeting"))??
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed before greeting"))?;
+    match serde_json::from_str::<Envelope>(&greeting) {
+        Ok(Envelope { node, version, update: PeerUpdate::Hello { secret } }) if secret == hello.secret => {
+            let envelope = Envelope { node, version, update: PeerUpdate::Hello { secret: None } };
+            if tx.send(EventMessage::Peer(envelope)).await.is_err() {
+                return Ok(());
+            }
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "it did not greet with the sync secret")),
+    }
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else { return Ok(()) };
+                match serde_json::from_str::<Envelope>(&line) {
+                    Ok(envelope) => {
+                        if tx.send(EventMessage::Peer(envelope)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => warn_limited!("Ignoring peer update {:?}: {}", line, e),
+                }
+            }
+            update = outgoing.recv() => match update {
+                Ok(line) => writer.write_all(format!("{}\n", line).as_bytes()).await?,
+                Err(RecvError::Lagged(missed)) => warn_limited!("A peer missed {} update(s), it was too slow", missed),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(node: &str, version: u64, update: PeerUpdate) -> Envelope {
+        Envelope { node: node.to_string(), version, update }
+    }
+
+    #[test]
+    fn test_newer_updates_win_on_every_daemon() {
+        let on = PeerUpdate::State { button: ButtonId(2), state: "on".into() };
+        let off = PeerUpdate::State { button: ButtonId(2), state: "off".into() };
+        let mut a = PeerState::new("a".into());
+        let mut b = PeerState::new("b".into());
+        let (link, mut sent) = broadcast::channel(PEER_BUFFER);
+        a.connect(PeerLink { outgoing: link.clone() });
+        b.connect(PeerLink { outgoing: link });
+
+        // Both toggled at once: the tie goes to b on both daemons
+        let from_a = a.share(on.clone()).unwrap();
+        let from_b = b.share(off.clone()).unwrap();
+        assert_eq!(a.accept(from_b), Some(off.clone()));
+        assert_eq!(b.accept(from_a.clone()), None);
+        // Its own updates and stale ones change nothing
+        assert_eq!(a.accept(from_a), None);
+        assert_eq!(b.accept(envelope("a", 0, on.clone())), None);
+        // A later change wins again
+        let later = a.share(on.clone()).unwrap();
+        assert_eq!(later.version, 2);
+        assert_eq!(b.accept(later.clone()), Some(on));
+        let hello = PeerUpdate::Hello { secret: None };
+        assert_eq!(b.accept(envelope("a", 0, hello.clone())), Some(hello));
+
+        // A peer connecting gets the latest state with its version, not
+        // the arming
+        b.share(PeerUpdate::Armed { button: ButtonId(4), window_ms: 3000 });
+        while sent.try_recv().is_ok() {}
+        b.resend();
+        let resent: Vec<Envelope> = std::iter::from_fn(|| sent.try_recv().ok())
+            .map(|line| serde_json::from_str(&line).unwrap())
+            .collect();
+        assert_eq!(resent, vec![later]);
+        assert!(PeerState::new("c".into()).latest.is_empty());
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let update = envelope("a", 7, PeerUpdate::Led { button: ButtonId(3), layer: SharedLayer::Remote, led: Led::Flash2 });
+        let line = serde_json::to_string(&update).unwrap();
+        assert_eq!(line, r#"{"node":"a","version":7,"update":{"led":{"button":3,"layer":"remote","led":"flash2"}}}"#);
+        assert_eq!(serde_json::from_str::<Envelope>(&line).unwrap(), update);
+        let hello = envelope("b", 0, PeerUpdate::Hello { secret: Some("s3cret".into()) });
+        let line = serde_json::to_string(&hello).unwrap();
+        assert_eq!(line, r#"{"node":"b","version":0,"update":{"hello":{"secret":"s3cret"}}}"#);
+        assert_eq!(serde_json::from_str::<Envelope>(&line).unwrap(), hello);
+    }
+
+    #[test]
+    fn test_nothing_is_shared_without_peers() {
+        let mut state = PeerState::new("a".into());
+        assert_eq!(state.share(PeerUpdate::Disarmed { button: ButtonId(1) }), None);
+    }
+}
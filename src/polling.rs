@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::PollingConfig;
+use crate::panel::PanelButton;
+use crate::units::ButtonId;
+
+/// One timer per polling group, `None` being the group of buttons without
+/// a `poll_group`. The panel is read whenever any group is due, since one
+/// transfer reads every button; events of buttons whose group is not due
+/// yet are held until it is.
+#[derive(Debug)]
+pub struct PollTimers {
+    timers: HashMap<Option<String>, Timer>,
+    held: Vec<PanelButton>,
+}
+
+#[derive(Debug)]
+struct Timer {
+    interval: Duration,
+    due: Instant,
+}
+
+impl PollTimers {
+    /// Timers for the groups of `polling`, all due at `now`.
+    pub fn new(polling: &PollingConfig, now: Instant) -> Self {
+        let groups = polling
+            .groups
+            .iter()
+            .flatten()
+            .map(|(name, group)| (Some(name.clone()), group.interval_ms));
+        let timers = std::iter::once((None, polling.interval_ms))
+            .chain(groups)
+            .map(|(name, ms)| {
+                let interval = Duration::from_millis(ms);
+                (name, Timer { interval, due: now })
+            })
+            .collect();
+        PollTimers { timers, held: Vec::new() }
+    }
+
+    /// The events to handle at `now`: held and new ones of the groups that
+    /// are due, in the order they were read. The others are held. Due
+    /// groups are rescheduled one interval on.
+    pub fn take_events(
+        &mut self,
+        now: Instant,
+        events: Vec<PanelButton>,
+        group_of: impl Fn(ButtonId) -> Option<String>,
+    ) -> Vec<PanelButton> {
+        let mut due: Vec<Option<String>> = Vec::new();
+        for (name, timer) in self.timers.iter_mut() {
+            if timer.due <= now {
+                due.push(name.clone());
+                // Skip intervals missed while e.g. a command ran
+                timer.due = (timer.due + timer.interval).max(now);
+            }
+        }
+        // Unknown groups fall back to the default interval
+        let group = |id: ButtonId| group_of(id).filter(|g| self.timers.contains_key(&Some(g.clone())));
+
+        let (ready, held): (Vec<PanelButton>, Vec<PanelButton>) = self
+            .held
+            .drain(..)
+            .chain(events)
+            .partition(|b| due.contains(&group(b.id())));
+        self.held = held;
+        ready
+    }
+
+    /// Time from `now` until the next group is due.
+    pub fn until_next(&self, now: Instant) -> Duration {
+        self.timers
+            .values()
+            .map(|t| t.due.saturating_duration_since(now))
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PollGroup;
+    use spibuttonlib::SPIButtonState;
+    use std::collections::BTreeMap;
+
+    fn press(id: u8) -> PanelButton {
+        PanelButton::new(ButtonId(id), SPIButtonState::On)
+    }
+
+    #[test]
+    fn test_groups_handle_events_at_their_interval() {
+        let polling = PollingConfig {
+            interval_ms: 100,
+            groups: Some(BTreeMap::from([
+                ("estop".to_string(), PollGroup { interval_ms: 10 }),
+                ("menu".to_string(), PollGroup { interval_ms: 200 }),
+            ])),
+            ..PollingConfig::default()
+        };
+        // Button 0 is the e-stop, 1 a menu button, 2 has no group
+        let group_of = |id: ButtonId| match id.0 {
+            0 => Some("estop".to_string()),
+            1 => Some("menu".to_string()),
+            _ => None,
+        };
+        let ms = Duration::from_millis;
+        let t0 = Instant::now();
+        let mut timers = PollTimers::new(&polling, t0);
+        let ids = |events: Vec<PanelButton>| events.iter().map(|b| b.id().0).collect::<Vec<u8>>();
+
+        assert!(timers.take_events(t0, vec![], group_of).is_empty());
+        assert_eq!(timers.until_next(t0), ms(10));
+
+        let events = timers.take_events(t0 + ms(10), vec![press(1), press(0), press(2)], group_of);
+        assert_eq!(ids(events), vec![0]);
+        assert_eq!(ids(timers.take_events(t0 + ms(100), vec![], group_of)), vec![2]);
+        assert!(timers.take_events(t0 + ms(110), vec![], group_of).is_empty());
+        assert_eq!(ids(timers.take_events(t0 + ms(200), vec![], group_of)), vec![1]);
+    }
+}
@@ -0,0 +1,149 @@
+use log::{info, warn};
+use serde_json::{json, Value as JsonValue};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::breaker::Endpoint;
+use crate::command::{EventMessage, EventResponse, ProgressStage, ResponseStatus};
+use crate::config::MoonrakerConfig;
+use crate::moonraker::{idempotency_key, Moonraker};
+
+/// Button command prefix of the power-on sequence:
+/// `power_on:DEVICE` or `power_on:DEVICE|MACRO`.
+pub const POWER_ON_PREFIX: &str = "power_on:";
+
+/// How long to wait for Klipper to connect to the MCU after power on.
+const READY_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Interval between state queries while waiting for Klipper.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A parsed `power_on:` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerOn {
+    /// Moonraker power device, e.g. `printer`
+    pub device: String,
+    /// G-code run once Klipper is ready
+    pub macro_script: Option<String>,
+}
+
+impl PowerOn {
+    pub fn parse(command: &str) -> Result<Self, String> {
+        let spec = command
+            .trim()
+            .strip_prefix(POWER_ON_PREFIX)
+            .ok_or_else(|| format!("Not a power_on command: {}", command))?;
+        let (device, macro_script) = match spec.split_once('|') {
+            Some((device, script)) => (device.trim(), Some(script.trim())),
+            None => (spec.trim(), None),
+        };
+        if device.is_empty() {
+            return Err(format!("power_on needs a device name: {}", command));
+        }
+        Ok(PowerOn {
+            device: device.to_string(),
+            macro_script: macro_script.filter(|s| !s.is_empty()).map(|s| s.to_string()),
+        })
+    }
+}
+
+/// Switch on a Moonraker power device, wait until Klipper is connected to
+/// its MCU, then run the follow-up macro if any. Each step is reported as
+/// `Progress`, the outcome as a regular response.
+pub async fn power_on(
+    action: PowerOn,
+    moonraker: Option<&MoonrakerConfig>,
+    request_id: u32,
+    correlation_id: Uuid,
+    response_tx: Sender<EventMessage>,
+) {
+    let progress = |stage: ProgressStage| {
+        let response_tx = response_tx.clone();
+        async move {
+            let _ = response_tx.send(EventMessage::Progress { request_id, stage }).await;
+        }
+    };
+
+    let result = async {
+        let client = Moonraker::new(moonraker)?.with_idempotency_key(idempotency_key(correlation_id, request_id));
+
+        progress(ProgressStage::PoweringOn).await;
+        info!("[{}] Powering on {}", correlation_id, action.device);
+        client
+            .post(
+                "/machine/device_power/device",
+                &json!({ "device": action.device, "action": "on" }),
+            )
+            .await?;
+
+        progress(ProgressStage::WaitingReady).await;
+        let info = wait_ready(&client, correlation_id).await?;
+
+        if let Some(script) = &action.macro_script {
+            progress(ProgressStage::RunningMacro).await;
+            info!("[{}] Running post power macro: {}", correlation_id, script);
+            client.post("/printer/gcode/script", &json!({ "script": script })).await?;
+        }
+        Ok(info)
+    };
+    let (status, body) = match result.await {
+        Ok(info) => (ResponseStatus::Ok, Some(info)),
+        Err(status) => (status, None),
+    };
+    EventResponse::send(&response_tx, request_id, correlation_id, Some(Endpoint::Moonraker), status, body, &[]).await;
+}
+
+/// Poll Moonraker until Klipper reports `ready`, returning the server info.
+async fn wait_ready(client: &Moonraker, correlation_id: Uuid) -> Result<JsonValue, ResponseStatus> {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    let mut last_state = String::new();
+    while Instant::now() < deadline {
+        sleep(POLL_INTERVAL).await;
+        // Moonraker itself may be busy reconnecting to Klipper, keep polling
+        if let Ok(info) = client.get("/server/info").await {
+            let state = info["klippy_state"].as_str().unwrap_or("").to_string();
+            if state == "ready" {
+                info!("[{}] Klipper is ready", correlation_id);
+                return Ok(info);
+            }
+            if state != last_state {
+                info!("[{}] Klipper state: {}", correlation_id, state);
+                last_state = state;
+            }
+        }
+    }
+    warn!(
+        "[{}] Klipper not ready {}s after power on, last state: {}",
+        correlation_id,
+        READY_TIMEOUT.as_secs(),
+        last_state
+    );
+    Err(ResponseStatus::Timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_power_on() {
+        assert_eq!(
+            PowerOn::parse("power_on:printer").unwrap(),
+            PowerOn {
+                device: "printer".to_string(),
+                macro_script: None
+            }
+        );
+        assert_eq!(
+            PowerOn::parse("power_on:printer|HOME_AND_HEAT").unwrap(),
+            PowerOn {
+                device: "printer".to_string(),
+                macro_script: Some("HOME_AND_HEAT".to_string())
+            }
+        );
+        assert!(PowerOn::parse("power_on:").is_err());
+        assert!(PowerOn::parse("power_on:|G28").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::expr::Value;
+use crate::units::ButtonId;
+
+/// How a button was used to run a command, passed to it as `SPIBTN_*`
+/// environment variables and `{{press.NAME}}` placeholders.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Press {
+    /// Wall clock time of the press
+    pub time: SystemTime,
+    /// How long the button was held, zero until it is released
+    pub held: Duration,
+    /// Presses in a row, more than 1 for a press sequence
+    pub taps: u32,
+}
+
+impl Press {
+    pub fn new(time: SystemTime) -> Self {
+        Press {
+            time,
+            held: Duration::ZERO,
+            taps: 1,
+        }
+    }
+
+    fn timestamp_ms(&self) -> u128 {
+        self.time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis())
+    }
+
+    /// The value of `press.NAME` in templates, null for unknown names.
+    pub fn lookup(&self, name: &str) -> Value {
+        match name {
+            "held_ms" => Value::Number(self.held.as_millis() as f64),
+            "taps" => Value::Number(f64::from(self.taps)),
+            "timestamp_ms" => Value::Number(self.timestamp_ms() as f64),
+            _ => Value::Null,
+        }
+    }
+
+    /// Environment variables describing the press of `button` for shell
+    /// commands.
+    pub fn env(&self, button: ButtonId) -> Vec<(&'static str, String)> {
+        vec![
+            ("SPIBTN_BUTTON", button.to_string()),
+            ("SPIBTN_HELD_MS", self.held.as_millis().to_string()),
+            ("SPIBTN_TAPS", self.taps.to_string()),
+            ("SPIBTN_TIMESTAMP_MS", self.timestamp_ms().to_string()),
+        ]
+    }
+}
+
+/// The latest press of each button, which the commands it runs are told
+/// about.
+#[derive(Debug, Default)]
+pub struct Presses {
+    latest: HashMap<ButtonId, Press>,
+    /// When buttons held down now were pressed
+    down: HashMap<ButtonId, Instant>,
+}
+
+impl Presses {
+    pub fn new() -> Self {
+        Presses::default()
+    }
+
+    pub fn press(&mut self, button: ButtonId, now: Instant, time: SystemTime) {
+        self.down.insert(button, now);
+        self.latest.insert(button, Press::new(time));
+    }
+
+    /// Record how long the button was held when it is released at `now`.
+    pub fn release(&mut self, button: ButtonId, now: Instant) {
+        let Some(pressed) = self.down.remove(&button) else { return };
+        if let Some(press) = self.latest.get_mut(&button) {
+            press.held = now.duration_since(pressed);
+        }
+    }
+
+    /// Count the latest press of the button as the last of `taps` presses
+    /// in a row.
+    pub fn set_taps(&mut self, button: ButtonId, taps: u32) {
+        if let Some(press) = self.latest.get_mut(&button) {
+            press.taps = taps;
+        }
+    }
+
+    /// The latest press of the button, one at `now` for a button never
+    /// pressed, e.g. one running a scheduled action after a restart.
+    pub fn latest(&self, button: ButtonId, now: SystemTime) -> Press {
+        self.latest.get(&button).copied().unwrap_or_else(|| Press::new(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_records_the_hold() {
+        let t0 = Instant::now();
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let button = ButtonId(4);
+        let mut presses = Presses::new();
+        presses.press(button, t0, time);
+        assert_eq!(presses.latest(button, SystemTime::now()).held, Duration::ZERO);
+
+        presses.release(button, t0 + Duration::from_millis(1500));
+        presses.set_taps(button, 2);
+        let press = presses.latest(button, SystemTime::now());
+        assert_eq!(press.lookup("held_ms"), Value::Number(1500.0));
+        assert_eq!(press.lookup("taps"), Value::Number(2.0));
+        assert_eq!(press.lookup("timestamp_ms"), Value::Number(1_700_000_000_123.0));
+        assert_eq!(press.lookup("speed"), Value::Null);
+        assert!(press.env(button).contains(&("SPIBTN_HELD_MS", "1500".to_string())));
+
+        // A new press starts over
+        presses.press(button, t0 + Duration::from_secs(3), time);
+        assert_eq!(presses.latest(button, SystemTime::now()).taps, 1);
+    }
+}
@@ -0,0 +1,140 @@
+use regex::Regex;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::config::Config;
+use crate::expr::{self, Context, Value};
+use crate::notifications::Notification;
+use crate::press::Press;
+use crate::vars::Variables;
+
+/// Names of the press running a command, e.g. `press.held_ms`.
+const PRESS_PREFIX: &str = "press.";
+
+/// Klipper object fields last reported by Moonraker, e.g.
+/// `extruder.temperature`. Kept current by `notify_status_update`.
+#[derive(Debug, Default)]
+pub struct PrinterState {
+    objects: Map<String, JsonValue>,
+}
+
+impl PrinterState {
+    /// Merge a status update into the cache. Returns `true` if the
+    /// notification was one.
+    pub fn update(&mut self, notification: &Notification) -> bool {
+        if notification.method != "notify_status_update" {
+            return false;
+        }
+        let status = match notification.event().and_then(|e| e.as_object()) {
+            Some(status) => status,
+            None => return false,
+        };
+        for (object, fields) in status {
+            let cached = self
+                .objects
+                .entry(object.clone())
+                .or_insert_with(|| JsonValue::Object(Map::new()));
+            match (cached.as_object_mut(), fields.as_object()) {
+                // Updates only carry the fields that changed
+                (Some(cached), Some(fields)) => {
+                    cached.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())))
+                }
+                _ => *cached = fields.clone(),
+            }
+        }
+        true
+    }
+
+    /// Look up `object.field`, following further dots into nested values.
+    pub fn lookup(&self, path: &str) -> Option<&JsonValue> {
+        let mut parts = path.split('.');
+        let mut value = self.objects.get(parts.next()?)?;
+        for part in parts {
+            value = match value {
+                JsonValue::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+                other => other.get(part)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+/// Names resolve to variables (`var.NAME` or a bare `NAME`), to the press
+/// running a command (`press.NAME`) or to cached printer fields
+/// (`object.field`).
+pub struct Scope<'a> {
+    pub variables: &'a Variables,
+    pub printer: &'a PrinterState,
+    /// The press whose command is rendered, `None` elsewhere
+    pub press: Option<Press>,
+}
+
+impl Context for Scope<'_> {
+    fn lookup(&self, name: &str) -> Value {
+        if let Some(field) = name.strip_prefix(PRESS_PREFIX) {
+            return self.press.map_or(Value::Null, |p| p.lookup(field));
+        }
+        if name.starts_with("var.") || !name.contains('.') {
+            return self.variables.lookup(name);
+        }
+        self.printer.lookup(name).map(Value::from_json).unwrap_or(Value::Null)
+    }
+}
+
+/// Klipper objects read by the configured conditions, LED rules and
+/// templates, which the Moonraker listener subscribes to.
+pub fn subscriptions(config: &Config) -> Vec<String> {
+    let template = Regex::new(r"\{\{(.*?)\}\}").unwrap();
+    let mut sources: Vec<&str> = Vec::new();
+    for mapping in &config.buttons {
+        sources.extend(mapping.when.as_deref());
+        sources.extend(mapping.led_rules.iter().flatten().map(|r| r.when.as_str()));
+        let commands = std::iter::once(&mapping.command)
+            .chain(mapping.sequences.iter().flatten().map(|s| &s.command));
+        for command in commands {
+            sources.extend(template.captures_iter(command).map(|c| c.get(1).unwrap().as_str()));
+        }
+    }
+    let mut objects: Vec<String> = sources
+        .into_iter()
+        .filter_map(|src| expr::parse(src).ok())
+        .flat_map(|parsed| {
+            parsed
+                .names()
+                .into_iter()
+                .filter(|name| name.contains('.') && !name.starts_with("var.") && !name.starts_with(PRESS_PREFIX))
+                .filter_map(|name| name.split('.').next().map(|o| o.to_string()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    objects.sort();
+    objects.dedup();
+    objects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_updates_merge() {
+        let mut printer = PrinterState::default();
+        let update = |json: &str| {
+            Notification::parse(&format!(
+                r#"{{"jsonrpc": "2.0", "method": "notify_status_update", "params": [{}, 1.5]}}"#,
+                json
+            ))
+            .unwrap()
+        };
+        assert!(printer.update(&update(r#"{"extruder": {"temperature": 25.0, "target": 0.0}}"#)));
+        assert!(printer.update(&update(r#"{"extruder": {"temperature": 190.5}, "print_stats": {"info": {"current_layer": 2}}}"#)));
+
+        assert_eq!(printer.lookup("extruder.temperature"), Some(&JsonValue::from(190.5)));
+        assert_eq!(printer.lookup("extruder.target"), Some(&JsonValue::from(0.0)));
+        assert_eq!(printer.lookup("print_stats.info.current_layer"), Some(&JsonValue::from(2)));
+        assert_eq!(printer.lookup("heater_bed.temperature"), None);
+
+        let variables = Variables::new(None);
+        let scope = Scope { variables: &variables, printer: &printer, press: None };
+        assert!(expr::condition_holds("extruder.temperature > 180 && print_stats.info.current_layer == 2", &scope));
+    }
+}
@@ -0,0 +1,359 @@
+use log::debug;
+use serde::Serialize;
+use spibuttonlib::SPIButtonState;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::config::Config;
+use crate::control::{self, ControlRequest};
+use crate::error::{Error, Result};
+use crate::lock::DeviceLock;
+use crate::panel::{self, PanelButton, PanelProtocol};
+use crate::units::ButtonId;
+
+/// Reads taken with the panel untouched before the buttons are tested, any
+/// event or failed transfer among them counts as an error.
+const IDLE_TRANSFERS: usize = 100;
+/// How far apart reads are taken throughout the test.
+const READ_INTERVAL_MS: u64 = 5;
+/// How long the operator has to press a button before it counts as dead.
+const PRESS_TIMEOUT_SECS: u64 = 30;
+
+/// Whether the operator saw an LED light up.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedCheck {
+    Confirmed,
+    Rejected,
+    /// No answer was given, e.g. stdin closed without a control socket
+    Skipped,
+    /// The panel has no LED on this button
+    NoLed,
+}
+
+/// Results of one button.
+#[derive(Debug, Clone, Serialize)]
+pub struct ButtonReport {
+    pub button: ButtonId,
+    pub description: Option<String>,
+    /// Time from the prompt until the press was read, `None` when it never was
+    pub response_ms: Option<u64>,
+    /// How long the button was held down
+    pub held_ms: Option<u64>,
+    /// Other buttons that reported while this one was expected, e.g. from
+    /// crossed wires
+    pub other_buttons: Vec<ButtonId>,
+    pub led: LedCheck,
+}
+
+impl ButtonReport {
+    pub fn passed(&self) -> bool {
+        self.response_ms.is_some()
+            && self.held_ms.is_some()
+            && self.other_buttons.is_empty()
+            && matches!(self.led, LedCheck::Confirmed | LedCheck::NoLed)
+    }
+}
+
+/// The report `qa-report` emits, as JSON or HTML.
+#[derive(Debug, Clone, Serialize)]
+pub struct QaReport {
+    pub generated_at: String,
+    pub device: String,
+    pub capabilities: String,
+    pub idle_transfers: usize,
+    pub idle_errors: usize,
+    pub buttons: Vec<ButtonReport>,
+    pub passed: bool,
+}
+
+impl QaReport {
+    fn finish(mut self) -> Self {
+        self.passed = self.idle_errors == 0 && self.buttons.iter().all(ButtonReport::passed);
+        self
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::internal("Failed to serialize the QA report").caused_by(e))
+    }
+
+    /// A self-contained page to print or ship with the panel.
+    pub fn to_html(&self) -> String {
+        let mut rows = String::new();
+        for b in &self.buttons {
+            let ms = |ms: Option<u64>| ms.map_or("-".to_string(), |ms| ms.to_string());
+            let others: Vec<String> = b.other_buttons.iter().map(|id| id.to_string()).collect();
+            rows.push_str(&format!(
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td></tr>\n",
+                if b.passed() { "pass" } else { "fail" },
+                b.button,
+                escape(b.description.as_deref().unwrap_or("")),
+                ms(b.response_ms),
+                ms(b.held_ms),
+                others.join(", "),
+                b.led,
+                if b.passed() { "PASS" } else { "FAIL" },
+            ));
+        }
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Panel QA report</title>\n\
+             <style>body{{font-family:sans-serif}}table{{border-collapse:collapse}}td,th{{border:1px solid #999;padding:4px 8px}}\
+             .pass{{background:#dfd}}.fail{{background:#fdd}}</style></head><body>\n\
+             <h1>Panel QA report: {}</h1>\n\
+             <p>Generated {} on {} ({})</p>\n\
+             <p>Idle transfers: {} errors in {}</p>\n\
+             <table><tr><th>Button</th><th>Description</th><th>Response ms</th><th>Held ms</th>\
+             <th>Other buttons</th><th>LED</th><th>Result</th></tr>\n{}</table>\n</body></html>\n",
+            if self.passed { "PASS" } else { "FAIL" },
+            escape(&self.generated_at),
+            escape(&self.device),
+            escape(&self.capabilities),
+            self.idle_errors,
+            self.idle_transfers,
+            rows,
+        )
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// An operator's answer, typed on stdin or sent with `spibuttonctl`.
+fn parse_answer(line: &str) -> Option<LedCheck> {
+    match line.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Some(LedCheck::Confirmed),
+        "n" | "no" => Some(LedCheck::Rejected),
+        "skip" => Some(LedCheck::Skipped),
+        _ => None,
+    }
+}
+
+/// Where answers come from: stdin until it closes, and the control socket
+/// when the config has one.
+struct Operator {
+    stdin: Option<Lines<BufReader<Stdin>>>,
+    control: Option<mpsc::Receiver<ControlRequest>>,
+}
+
+impl Operator {
+    fn new(config: &Config) -> Result<Self> {
+        let control = match &config.control {
+            Some(control_cfg) => {
+                let (tx, rx) = mpsc::channel(8);
+                control::spawn_server(control_cfg, tx)?;
+                Some(rx)
+            }
+            None => None,
+        };
+        Ok(Operator {
+            stdin: Some(BufReader::new(tokio::io::stdin()).lines()),
+            control,
+        })
+    }
+
+    /// Ask a yes/no question, `Skipped` once no source of answers is left.
+    async fn ask(&mut self, question: &str) -> LedCheck {
+        eprintln!("{} [y/n/skip]", question);
+        let Operator { stdin, control } = self;
+        loop {
+            tokio::select! {
+                line = async { stdin.as_mut().unwrap().next_line().await }, if stdin.is_some() => {
+                    match line {
+                        Ok(Some(line)) => match parse_answer(&line) {
+                            Some(answer) => return answer,
+                            None => eprintln!("Please answer y, n or skip"),
+                        },
+                        _ => *stdin = None,
+                    }
+                }
+                request = async { control.as_mut().unwrap().recv().await }, if control.is_some() => {
+                    let request = match request {
+                        Some(request) => request,
+                        None => {
+                            *control = None;
+                            continue;
+                        }
+                    };
+                    let answer = parse_answer(&request.line);
+                    let reply = match answer {
+                        Some(_) => "ok".to_string(),
+                        None => format!("error: qa-report is waiting for yes, no or skip: {}", question),
+                    };
+                    let _ = request.reply.send(reply);
+                    if let Some(answer) = answer {
+                        return answer;
+                    }
+                }
+                else => return LedCheck::Skipped,
+            }
+        }
+    }
+}
+
+/// Read the panel until `button` is pressed and released, noting other
+/// buttons that report meanwhile.
+async fn time_press(spi: &mut dyn PanelProtocol, button: ButtonId, report: &mut ButtonReport) -> Result<()> {
+    let prompted = Instant::now();
+    let deadline = Duration::from_secs(PRESS_TIMEOUT_SECS);
+    let mut pressed: Option<Instant> = None;
+    while prompted.elapsed() < deadline {
+        let events = spi.loop_once().map_err(|e| Error::spi("Panel read failed", e))?;
+        let now = Instant::now();
+        for b in events {
+            if b.id() != button {
+                if !report.other_buttons.contains(&b.id()) {
+                    report.other_buttons.push(b.id());
+                }
+                continue;
+            }
+            match (b.get_state(), pressed) {
+                (SPIButtonState::Off, Some(at)) => {
+                    report.held_ms = Some(now.duration_since(at).as_millis() as u64);
+                    return Ok(());
+                }
+                (SPIButtonState::Off, None) => {}
+                (_, None) => {
+                    pressed = Some(now);
+                    report.response_ms = Some(now.duration_since(prompted).as_millis() as u64);
+                }
+                (_, Some(_)) => {}
+            }
+        }
+        sleep(Duration::from_millis(READ_INTERVAL_MS)).await;
+    }
+    debug!("Button {} not released within {}s", button, PRESS_TIMEOUT_SECS);
+    Ok(())
+}
+
+/// Write an LED state and read once so it is sent.
+fn show(spi: &mut dyn PanelProtocol, button: ButtonId, state: SPIButtonState) -> Result<()> {
+    spi.set_button(button, PanelButton::new(button, state));
+    spi.loop_once().map_err(|e| Error::spi("Panel write failed", e))?;
+    Ok(())
+}
+
+/// `qa-report` mode: check the idle panel reads clean, time a press of
+/// every mapped button, have the operator confirm each LED and write the
+/// report to `output`, HTML when its extension is `.html`, else JSON.
+/// Without `output` the JSON goes to stdout, prompts always go to stderr.
+pub async fn run(config: &Config, output: Option<&Path>) -> Result<QaReport> {
+    let _lock = DeviceLock::acquire(&config.spi.device)?;
+    let mut spi = panel::open(&config.spi, config.panel_size())
+        .map_err(|e| Error::spi(format!("Failed to open {}", config.spi.device), e))?;
+    let capabilities = panel::capabilities(&config.spi);
+    let buttons: BTreeMap<ButtonId, Option<String>> =
+        config.buttons.iter().map(|m| (m.button, m.description.clone())).collect();
+    for id in buttons.keys() {
+        // Plain change reporting, whatever the config asks of the button
+        spi.configure(*id, SPIButtonState::OnChange as u8);
+    }
+    let mut operator = Operator::new(config)?;
+
+    eprintln!("Checking {} idle transfers, do not touch the panel...", IDLE_TRANSFERS);
+    // The first read latches the idle state and is not counted
+    let _ = spi.loop_once();
+    let mut idle_errors = 0;
+    for _ in 0..IDLE_TRANSFERS {
+        match spi.loop_once() {
+            Ok(events) if events.is_empty() => {}
+            _ => idle_errors += 1,
+        }
+        sleep(Duration::from_millis(READ_INTERVAL_MS)).await;
+    }
+
+    let mut reports = Vec::new();
+    for (id, description) in buttons {
+        let mut report = ButtonReport {
+            button: id,
+            description: description.clone(),
+            response_ms: None,
+            held_ms: None,
+            other_buttons: Vec::new(),
+            led: LedCheck::NoLed,
+        };
+        eprintln!("Press and release button {} ({})", id, description.as_deref().unwrap_or("no description"));
+        time_press(spi.as_mut(), id, &mut report).await?;
+        if report.response_ms.is_none() {
+            eprintln!("  No press within {}s", PRESS_TIMEOUT_SECS);
+        }
+
+        if capabilities.has_led(id) {
+            show(spi.as_mut(), id, SPIButtonState::On)?;
+            report.led = operator.ask(&format!("Is the LED of button {} lit?", id)).await;
+            show(spi.as_mut(), id, SPIButtonState::Off)?;
+        }
+        eprintln!("  {}", if report.passed() { "PASS" } else { "FAIL" });
+        reports.push(report);
+    }
+
+    let report = QaReport {
+        generated_at: chrono::Local::now().to_rfc3339(),
+        device: config.spi.device.clone(),
+        capabilities: capabilities.to_string(),
+        idle_transfers: IDLE_TRANSFERS,
+        idle_errors,
+        buttons: reports,
+        passed: false,
+    }
+    .finish();
+
+    match output {
+        Some(path) => {
+            let html = path.extension().is_some_and(|ext| ext == "html");
+            let content = if html { report.to_html() } else { report.to_json()? };
+            fs::write(path, content)
+                .map_err(|e| Error::internal(format!("Failed to write {}", path.display())).caused_by(e))?;
+            eprintln!("Report written to {}", path.display());
+        }
+        None => println!("{}", report.to_json()?),
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_passes_only_when_every_check_did() {
+        let button = |id, led| ButtonReport {
+            button: ButtonId(id),
+            description: Some("<Home> & park".to_string()),
+            response_ms: Some(850),
+            held_ms: Some(120),
+            other_buttons: vec![],
+            led,
+        };
+        let report = QaReport {
+            generated_at: "2026-01-01T00:00:00+00:00".to_string(),
+            device: "/dev/spidev1.0".to_string(),
+            capabilities: "mapped buttons only, LEDs".to_string(),
+            idle_transfers: IDLE_TRANSFERS,
+            idle_errors: 0,
+            buttons: vec![button(0, LedCheck::Confirmed), button(1, LedCheck::NoLed)],
+            passed: false,
+        };
+        assert!(report.clone().finish().passed);
+
+        let mut failing = report.clone();
+        failing.buttons[1].led = LedCheck::Rejected;
+        let failing = failing.finish();
+        assert!(!failing.passed);
+        let html = failing.to_html();
+        assert!(html.contains("&lt;Home&gt; &amp; park"));
+        assert!(html.contains("<h1>Panel QA report: FAIL</h1>"));
+
+        let mut noisy = report;
+        noisy.idle_errors = 1;
+        assert!(!noisy.finish().passed);
+
+        assert_eq!(parse_answer(" Yes\n"), Some(LedCheck::Confirmed));
+        assert_eq!(parse_answer("maybe"), None);
+    }
+}
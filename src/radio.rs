@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+use crate::config::RadioGroup;
+use crate::units::ButtonId;
+
+/// A selection made by `Radio::select`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    pub group: String,
+    /// The button selected before, if it was another
+    pub deselected: Option<ButtonId>,
+}
+
+/// The selected button of each radio group. Pressing a button of a group
+/// selects it and deselects the one selected before.
+#[derive(Debug, Default)]
+pub struct Radio {
+    selected: BTreeMap<String, ButtonId>,
+}
+
+impl Radio {
+    pub fn new() -> Self {
+        Radio::default()
+    }
+
+    /// Select `button` in its group, `None` when it is in none.
+    pub fn select(&mut self, groups: &BTreeMap<String, RadioGroup>, button: ButtonId) -> Option<Selection> {
+        let (name, _) = groups.iter().find(|(_, g)| g.buttons.contains(&button))?;
+        let previous = self.selected.insert(name.clone(), button);
+        Some(Selection {
+            group: name.clone(),
+            deselected: previous.filter(|p| *p != button),
+        })
+    }
+
+    /// The selected button of `group`.
+    pub fn selected(&self, group: &str) -> Option<ButtonId> {
+        self.selected.get(group).copied()
+    }
+
+    /// Keep the selections still possible, e.g. after a reload, and select
+    /// the `initial` button of groups without one.
+    pub fn retain(&mut self, groups: &BTreeMap<String, RadioGroup>) {
+        self.selected
+            .retain(|name, button| groups.get(name).is_some_and(|g| g.buttons.contains(button)));
+        for (name, group) in groups {
+            if let Some(initial) = group.initial.filter(|b| group.buttons.contains(b)) {
+                self.selected.entry(name.clone()).or_insert(initial);
+            }
+        }
+    }
+
+    /// Groups and their selected button, for `spibuttonctl states`.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ButtonId)> {
+        self.selected.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selecting_deselects_the_previous_button() {
+        let group = |buttons: &[u8], initial: Option<u8>| RadioGroup {
+            buttons: buttons.iter().map(|b| ButtonId(*b)).collect(),
+            led: None,
+            initial: initial.map(ButtonId),
+        };
+        let mut groups = BTreeMap::from([("tools".to_string(), group(&[0, 1, 2], Some(0)))]);
+        let mut radio = Radio::new();
+        radio.retain(&groups);
+        assert_eq!(radio.selected("tools"), Some(ButtonId(0)));
+
+        let selection = radio.select(&groups, ButtonId(2)).unwrap();
+        assert_eq!(selection.deselected, Some(ButtonId(0)));
+        assert_eq!(radio.select(&groups, ButtonId(2)).unwrap().deselected, None);
+        assert_eq!(radio.select(&groups, ButtonId(5)), None);
+
+        // A reload dropping the selected button falls back to `initial`
+        groups.insert("tools".to_string(), group(&[0, 1], Some(1)));
+        radio.retain(&groups);
+        assert_eq!(radio.selected("tools"), Some(ButtonId(1)));
+    }
+}
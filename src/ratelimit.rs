@@ -0,0 +1,235 @@
+use log::warn;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Identical warnings within this window are counted instead of logged.
+const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Distinct messages tracked at once. Messages carrying ids or values are
+/// all distinct, so a burst of them must not grow the limiter unbounded.
+pub const MAX_MESSAGES: usize = 256;
+
+/// Log a warning through the shared deduplicator. The first occurrence of a
+/// message is logged immediately, identical repeats inside the window are
+/// counted and summarised later as "last message repeated N times".
+macro_rules! warn_limited {
+    ($($arg:tt)+) => {{
+        let msg = format!($($arg)+);
+        if $crate::ratelimit::admit(&msg) {
+            log::warn!("{}", msg);
+        }
+    }};
+}
+pub(crate) use warn_limited;
+
+#[derive(Debug)]
+struct Entry {
+    window_start: Instant,
+    repeats: u64,
+}
+
+/// Tracks recently logged messages and how often they repeated.
+#[derive(Debug)]
+pub struct LogLimiter {
+    window: Duration,
+    entries: HashMap<String, Entry>,
+    /// Repeats suppressed since startup, including ones already summarised
+    suppressed: u64,
+}
+
+/// A message that stopped repeating and needs its summary logged.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub message: String,
+    pub repeats: u64,
+}
+
+impl LogLimiter {
+    pub fn new(window: Duration) -> Self {
+        LogLimiter {
+            window,
+            entries: HashMap::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Returns true when the message should be logged now. A pending summary
+    /// for an expired window is returned alongside so it is logged first,
+    /// as is the summary of the oldest message when it is forgotten to stay
+    /// within `MAX_MESSAGES`.
+    pub fn admit_at(&mut self, message: &str, now: Instant) -> (bool, Option<Summary>) {
+        match self.entries.get_mut(message) {
+            Some(entry) if now.duration_since(entry.window_start) < self.window => {
+                entry.repeats += 1;
+                self.suppressed += 1;
+                (false, None)
+            }
+            Some(entry) => {
+                let summary = (entry.repeats > 0).then(|| Summary {
+                    message: message.to_string(),
+                    repeats: entry.repeats,
+                });
+                entry.window_start = now;
+                entry.repeats = 0;
+                (true, summary)
+            }
+            None => {
+                let mut summary = None;
+                if self.entries.len() >= MAX_MESSAGES {
+                    let oldest = self.entries.iter().min_by_key(|(_, e)| e.window_start).map(|(m, _)| m.clone());
+                    if let Some((message, entry)) = oldest.and_then(|m| self.entries.remove_entry(&m)) {
+                        summary = (entry.repeats > 0).then_some(Summary { message, repeats: entry.repeats });
+                    }
+                }
+                self.entries.insert(
+                    message.to_string(),
+                    Entry {
+                        window_start: now,
+                        repeats: 0,
+                    },
+                );
+                (true, summary)
+            }
+        }
+    }
+
+    /// Messages currently tracked.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Repeats suppressed so far, counted whether or not their summary was
+    /// logged yet.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// Drop messages whose window has expired, returning summaries for the
+    /// ones that repeated so the counts are never lost.
+    pub fn flush_at(&mut self, now: Instant) -> Vec<Summary> {
+        let window = self.window;
+        let mut summaries = vec![];
+        self.entries.retain(|message, entry| {
+            if now.duration_since(entry.window_start) < window {
+                return true;
+            }
+            if entry.repeats > 0 {
+                summaries.push(Summary {
+                    message: message.clone(),
+                    repeats: entry.repeats,
+                });
+            }
+            false
+        });
+        summaries
+    }
+}
+
+fn limiter() -> &'static Mutex<LogLimiter> {
+    static LIMITER: OnceLock<Mutex<LogLimiter>> = OnceLock::new();
+    LIMITER.get_or_init(|| Mutex::new(LogLimiter::new(REPEAT_WINDOW)))
+}
+
+fn log_summary(summary: &Summary) {
+    warn!(
+        "Last message repeated {} times: {}",
+        summary.repeats, summary.message
+    );
+}
+
+/// Shared-limiter entry point used by `warn_limited!`.
+pub fn admit(message: &str) -> bool {
+    let mut limiter = limiter().lock().unwrap_or_else(|e| e.into_inner());
+    let (log_now, summary) = limiter.admit_at(message, Instant::now());
+    if let Some(summary) = summary {
+        log_summary(&summary);
+    }
+    log_now
+}
+
+/// Messages the shared limiter currently tracks, for `spibuttonctl stats`.
+pub fn tracked() -> usize {
+    limiter().lock().unwrap_or_else(|e| e.into_inner()).len()
+}
+
+/// Warnings the shared limiter suppressed since startup, for
+/// `spibuttonctl stats`.
+pub fn suppressed() -> u64 {
+    limiter().lock().unwrap_or_else(|e| e.into_inner()).suppressed()
+}
+
+/// Emit summaries for warnings that have stopped repeating. Called from the
+/// poll loop so counts surface even when the failure clears.
+pub fn flush() {
+    let mut limiter = limiter().lock().unwrap_or_else(|e| e.into_inner());
+    for summary in limiter.flush_at(Instant::now()) {
+        log_summary(&summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_suppressed_within_window() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(60));
+        let t0 = Instant::now();
+
+        assert_eq!(limiter.admit_at("klipper down", t0), (true, None));
+        assert_eq!(limiter.admit_at("klipper down", t0 + Duration::from_secs(1)), (false, None));
+        assert_eq!(limiter.admit_at("klipper down", t0 + Duration::from_secs(2)), (false, None));
+        // A different message is not affected
+        assert_eq!(limiter.admit_at("spi error", t0 + Duration::from_secs(2)), (true, None));
+
+        let (log_now, summary) = limiter.admit_at("klipper down", t0 + Duration::from_secs(61));
+        assert!(log_now);
+        assert_eq!(
+            summary,
+            Some(Summary {
+                message: "klipper down".to_string(),
+                repeats: 2
+            })
+        );
+        assert_eq!(limiter.suppressed(), 2);
+    }
+
+    #[test]
+    fn test_flush_reports_and_forgets_expired() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(60));
+        let t0 = Instant::now();
+
+        limiter.admit_at("klipper down", t0);
+        limiter.admit_at("klipper down", t0 + Duration::from_secs(5));
+        limiter.admit_at("spi error", t0);
+
+        assert!(limiter.flush_at(t0 + Duration::from_secs(30)).is_empty());
+
+        let summaries = limiter.flush_at(t0 + Duration::from_secs(61));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].repeats, 1);
+
+        // Both entries expired, so the next occurrence logs again
+        assert_eq!(limiter.admit_at("spi error", t0 + Duration::from_secs(62)), (true, None));
+    }
+
+    #[test]
+    fn test_oldest_message_is_forgotten_at_the_cap() {
+        let mut limiter = LogLimiter::new(Duration::from_secs(60));
+        let t0 = Instant::now();
+        limiter.admit_at("message 0", t0);
+        limiter.admit_at("message 0", t0);
+        for i in 1..MAX_MESSAGES {
+            limiter.admit_at(&format!("message {}", i), t0 + Duration::from_millis(i as u64));
+        }
+        let (log_now, summary) = limiter.admit_at("one more", t0 + Duration::from_secs(1));
+        assert!(log_now);
+        assert_eq!(summary.map(|s| (s.message, s.repeats)), Some(("message 0".to_string(), 1)));
+        assert_eq!(limiter.len(), MAX_MESSAGES);
+    }
+}
@@ -0,0 +1,112 @@
+use log::{info, warn};
+use serde_json::Value as JsonValue;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::breaker::Endpoint;
+use crate::command::{CommandExecutor, EventMessage, EventResponse, ProgressStage, ResponseStatus};
+use crate::config::KlipperConfig;
+
+/// Button command running the built-in recovery flow.
+pub const RECOVER_COMMAND: &str = "recover:firmware_restart";
+
+/// How long to wait for Klipper to report `ready` after the restart.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval between `info` queries while waiting for Klipper.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Klipper's state from an `info` response, e.g. `ready` or `shutdown`.
+pub fn klippy_state(response: &JsonValue) -> Option<&str> {
+    response.get("result")?.get("state")?.as_str()
+}
+
+/// Check Klipper's state and, if it is shut down or in error, issue a
+/// FIRMWARE_RESTART and wait until Klipper is ready again. Progress is
+/// reported as `Progress` messages, the outcome as a regular response.
+pub async fn firmware_restart(
+    klipper: &KlipperConfig,
+    request_id: u32,
+    correlation_id: Uuid,
+    response_tx: Sender<EventMessage>,
+) {
+    let progress = |stage: ProgressStage| {
+        let response_tx = response_tx.clone();
+        async move {
+            let _ = response_tx.send(EventMessage::Progress { request_id, stage }).await;
+        }
+    };
+    let rules = klipper.error_categories.as_deref().unwrap_or(&[]);
+    let respond = |status: ResponseStatus, body: Option<JsonValue>| {
+        EventResponse::send(&response_tx, request_id, correlation_id, Some(Endpoint::Klipper), status, body, rules)
+    };
+
+    progress(ProgressStage::Checking).await;
+    let (status, body) = CommandExecutor::klipper_request("info", klipper, request_id).await;
+    let Some(response) = body.filter(|_| status == ResponseStatus::Ok) else {
+        respond(status, None).await;
+        return;
+    };
+    match klippy_state(&response) {
+        Some("shutdown") | Some("error") => {
+            info!("[{}] Klipper is shut down, sending FIRMWARE_RESTART", correlation_id);
+            progress(ProgressStage::Restarting).await;
+            // Klipper usually drops the connection instead of answering
+            let (status, _) =
+                CommandExecutor::klipper_request("gcode/firmware_restart", klipper, request_id).await;
+            if !matches!(status, ResponseStatus::Ok | ResponseStatus::EmptyResponse) {
+                warn!("[{}] FIRMWARE_RESTART returned {}, waiting for ready anyway", correlation_id, status);
+            }
+        }
+        Some("ready") => {
+            info!("[{}] Klipper is ready, nothing to recover", correlation_id);
+            respond(ResponseStatus::Ok, Some(response)).await;
+            return;
+        }
+        state => {
+            // Already starting up, just wait for it
+            info!("[{}] Klipper state {:?}, waiting for ready", correlation_id, state);
+        }
+    }
+
+    progress(ProgressStage::WaitingReady).await;
+    let deadline = Instant::now() + READY_TIMEOUT;
+    while Instant::now() < deadline {
+        sleep(POLL_INTERVAL).await;
+        // Connection errors are expected while Klipper restarts
+        if let (ResponseStatus::Ok, Some(response)) =
+            CommandExecutor::klipper_request("info", klipper, request_id).await
+        {
+            if klippy_state(&response) == Some("ready") {
+                info!("[{}] Klipper is ready again", correlation_id);
+                respond(ResponseStatus::Ok, Some(response)).await;
+                return;
+            }
+        }
+    }
+    warn!(
+        "[{}] Klipper not ready {}s after FIRMWARE_RESTART",
+        correlation_id,
+        READY_TIMEOUT.as_secs()
+    );
+    respond(ResponseStatus::Timeout, None).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_klippy_state() {
+        let response: JsonValue = serde_json::from_str(
+            r#"{"id": 1, "result": {"state": "shutdown", "state_message": "MCU 'mcu' shutdown"}}"#,
+        )
+        .unwrap();
+        assert_eq!(klippy_state(&response), Some("shutdown"));
+
+        let error: JsonValue = serde_json::from_str(r#"{"id": 1, "error": {"message": "x"}}"#).unwrap();
+        assert_eq!(klippy_state(&error), None);
+    }
+}
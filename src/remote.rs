@@ -0,0 +1,149 @@
+use serde_json::Value as JsonValue;
+use spibuttonlib::SPIButtonState;
+
+use crate::config::ButtonMapping;
+use crate::notifications::Notification;
+use crate::units::ButtonId;
+
+/// Remote methods registered with Moonraker. Klipper macros call them with
+/// e.g. `{action_call_remote_method("spibtn_set_led", button=3, state="flash1")}`.
+pub const REMOTE_METHODS: &[&str] = &[
+    "spibtn_set_led",
+    "spibtn_disable",
+    "spibtn_enable",
+    "spibtn_set_profile",
+    "spibtn_set_brightness",
+    "spibtn_add_mapping",
+    "spibtn_remove_mapping",
+];
+
+/// A call from Klipper into the daemon.
+#[derive(Debug)]
+pub enum RemoteCall {
+    SetLed { button: ButtonId, state: SPIButtonState },
+    /// Ignore presses of these buttons until enabled again
+    Disable(Vec<ButtonId>),
+    Enable(Vec<ButtonId>),
+    /// Switch the button commands to a configured profile
+    SetProfile(String),
+    /// Dim LEDs at runtime, e.g. at night
+    SetBrightness { buttons: Vec<ButtonId>, percent: u8 },
+    /// Map a button, given with the fields of a `buttons` entry, and save
+    /// it to the config file with `persist=True`
+    AddMapping { mapping: Box<ButtonMapping>, persist: bool },
+    RemoveMapping { button: ButtonId, persist: bool },
+}
+
+impl RemoteCall {
+    /// Parse a remote method call. `Ok(None)` when the notification is not
+    /// one of ours.
+    pub fn parse(notification: &Notification) -> Result<Option<Self>, String> {
+        let params = &notification.params;
+        let call = match notification.method.as_str() {
+            "spibtn_set_led" => {
+                let button = button_id(&params["button"])?;
+                let state = params["state"].as_str().unwrap_or("");
+                let state = led_state(state).ok_or_else(|| format!("Unknown LED state: {:?}", state))?;
+                RemoteCall::SetLed { button, state }
+            }
+            "spibtn_disable" => RemoteCall::Disable(button_ids(params)?),
+            "spibtn_enable" => RemoteCall::Enable(button_ids(params)?),
+            "spibtn_set_profile" => match params["profile"].as_str() {
+                Some(name) => RemoteCall::SetProfile(name.to_string()),
+                None => return Err(format!("Missing profile name: {}", params)),
+            },
+            "spibtn_set_brightness" => RemoteCall::SetBrightness {
+                buttons: button_ids(params)?,
+                percent: params["brightness"]
+                    .as_u64()
+                    .filter(|b| *b <= 100)
+                    .map(|b| b as u8)
+                    .ok_or_else(|| format!("Invalid brightness: {}", params["brightness"]))?,
+            },
+            "spibtn_add_mapping" => RemoteCall::AddMapping {
+                mapping: serde_json::from_value(params.clone()).map_err(|e| format!("Invalid mapping: {}", e))?,
+                persist: params["persist"].as_bool().unwrap_or(false),
+            },
+            "spibtn_remove_mapping" => RemoteCall::RemoveMapping {
+                button: button_id(&params["button"])?,
+                persist: params["persist"].as_bool().unwrap_or(false),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(call))
+    }
+}
+
+pub fn led_state(name: &str) -> Option<SPIButtonState> {
+    match name.to_ascii_lowercase().as_str() {
+        "off" => Some(SPIButtonState::Off),
+        "on" => Some(SPIButtonState::On),
+        "flash1" => Some(SPIButtonState::Flash1),
+        "flash2" => Some(SPIButtonState::Flash2),
+        _ => None,
+    }
+}
+
+fn button_id(value: &JsonValue) -> Result<ButtonId, String> {
+    value
+        .as_u64()
+        .and_then(|id| u8::try_from(id).ok())
+        .map(ButtonId)
+        .ok_or_else(|| format!("Invalid button id: {}", value))
+}
+
+/// Buttons given as `button=3` or `buttons=[1, 2]`.
+fn button_ids(params: &JsonValue) -> Result<Vec<ButtonId>, String> {
+    match params["buttons"].as_array() {
+        Some(ids) => ids.iter().map(button_id).collect(),
+        None => Ok(vec![button_id(&params["button"])?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(method: &str, params: &str) -> Result<Option<RemoteCall>, String> {
+        let notification = Notification::parse(&format!(
+            r#"{{"jsonrpc": "2.0", "method": "{}", "params": {}}}"#,
+            method, params
+        ))
+        .unwrap();
+        RemoteCall::parse(&notification)
+    }
+
+    #[test]
+    fn test_parse_remote_calls() {
+        assert!(matches!(
+            call("spibtn_set_led", r#"{"button": 3, "state": "Flash1"}"#),
+            Ok(Some(RemoteCall::SetLed { button: ButtonId(3), state: SPIButtonState::Flash1 }))
+        ));
+        assert!(matches!(
+            call("spibtn_disable", r#"{"buttons": [1, 2]}"#),
+            Ok(Some(RemoteCall::Disable(ids))) if ids == [ButtonId(1), ButtonId(2)]
+        ));
+        assert!(matches!(
+            call("spibtn_enable", r#"{"button": 4}"#),
+            Ok(Some(RemoteCall::Enable(ids))) if ids == [ButtonId(4)]
+        ));
+        assert!(matches!(
+            call("spibtn_set_profile", r#"{"profile": "printing"}"#),
+            Ok(Some(RemoteCall::SetProfile(name))) if name == "printing"
+        ));
+        assert!(matches!(
+            call("spibtn_add_mapping", r#"{"button": 7, "command": "echo hi", "persist": true}"#),
+            Ok(Some(RemoteCall::AddMapping { mapping, persist: true })) if mapping.command == "echo hi"
+        ));
+        assert!(call("spibtn_add_mapping", r#"{"button": 7}"#).is_err());
+        assert!(matches!(
+            call("spibtn_set_brightness", r#"{"buttons": [0, 1], "brightness": 20}"#),
+            Ok(Some(RemoteCall::SetBrightness { percent: 20, .. }))
+        ));
+        assert!(call("spibtn_set_brightness", r#"{"button": 0, "brightness": 150}"#).is_err());
+        assert!(call("spibtn_set_profile", "{}").is_err());
+        assert!(call("spibtn_set_led", r#"{"button": 3, "state": "blink"}"#).is_err());
+        assert!(call("spibtn_disable", r#"{"button": 300}"#).is_err());
+        assert!(matches!(call("notify_klippy_ready", "[]"), Ok(None)));
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::config::Overflow;
+use crate::units::ButtonId;
+
+/// Klipper requests tracked at once when `limits.pending_requests` is not
+/// configured.
+pub const DEFAULT_PENDING_REQUESTS: usize = 256;
+
+/// The button and correlation id of a Klipper request, for handling its
+/// progress and response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRequest {
+    pub button: ButtonId,
+    pub correlation_id: Uuid,
+}
+
+/// Klipper requests awaiting their response, by request id. A response
+/// that never arrives would otherwise keep its request forever, so past
+/// `capacity` one is dropped according to `overflow`.
+#[derive(Debug)]
+pub struct PendingRequests {
+    capacity: usize,
+    overflow: Overflow,
+    /// Request ids increase, so the first entry is the oldest
+    requests: BTreeMap<u32, PendingRequest>,
+}
+
+impl PendingRequests {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        PendingRequests {
+            capacity,
+            overflow,
+            requests: BTreeMap::new(),
+        }
+    }
+
+    /// Track a request, returning the one dropped to make room, which is
+    /// the new one itself with `Overflow::DropNewest`.
+    pub fn insert(&mut self, request_id: u32, request: PendingRequest) -> Option<(u32, PendingRequest)> {
+        if self.requests.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropNewest => return Some((request_id, request)),
+                Overflow::DropOldest => {
+                    let oldest = self.requests.pop_first();
+                    self.requests.insert(request_id, request);
+                    return oldest;
+                }
+            }
+        }
+        self.requests.insert(request_id, request);
+        None
+    }
+
+    pub fn get(&self, request_id: u32) -> Option<&PendingRequest> {
+        self.requests.get(&request_id)
+    }
+
+    pub fn remove(&mut self, request_id: u32) -> Option<PendingRequest> {
+        self.requests.remove(&request_id)
+    }
+
+    /// Apply new limits, e.g. after a reload, returning the oldest requests
+    /// dropped when the capacity shrank.
+    pub fn set_limits(&mut self, capacity: usize, overflow: Overflow) -> Vec<(u32, PendingRequest)> {
+        self.capacity = capacity;
+        self.overflow = overflow;
+        let mut dropped = Vec::new();
+        while self.requests.len() > capacity {
+            dropped.extend(self.requests.pop_first());
+        }
+        dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(button: u8) -> PendingRequest {
+        PendingRequest {
+            button: ButtonId(button),
+            correlation_id: Uuid::new_v4(),
+        }
+    }
+
+    #[test]
+    fn test_overflow_drops_by_policy() {
+        let mut oldest = PendingRequests::new(2, Overflow::DropOldest);
+        assert!(oldest.insert(1, request(1)).is_none());
+        assert!(oldest.insert(2, request(2)).is_none());
+        assert_eq!(oldest.insert(3, request(3)).map(|(id, r)| (id, r.button)), Some((1, ButtonId(1))));
+        assert!(oldest.get(1).is_none() && oldest.get(3).is_some());
+
+        let mut newest = PendingRequests::new(2, Overflow::DropNewest);
+        newest.insert(1, request(1));
+        newest.insert(2, request(2));
+        assert_eq!(newest.insert(3, request(3)).map(|(id, _)| id), Some(3));
+        assert!(newest.get(1).is_some() && newest.get(3).is_none());
+
+        assert_eq!(newest.set_limits(1, Overflow::DropOldest).len(), 1);
+        assert_eq!(newest.len(), 1);
+        assert!(newest.get(2).is_some());
+    }
+}
@@ -0,0 +1,135 @@
+use log::warn;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::command::ResponseStatus;
+
+/// What a failed Klipper request means for the user, deciding LED feedback
+/// and whether the request is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// Transient, e.g. Klipper busy or the socket not up yet. Retried.
+    Retryable,
+    /// Printer is shut down and needs a FIRMWARE_RESTART
+    NeedsRestart,
+    /// The command itself is wrong, e.g. unknown G-code or out of range move
+    UserError,
+}
+
+/// A config override mapping an RPC error onto a category. `match` is a
+/// regular expression tested against the error message; `code`, when set,
+/// must equal the JSON-RPC error code.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ErrorRule {
+    #[serde(rename = "match")]
+    pub pattern: Option<String>,
+    pub code: Option<i64>,
+    pub category: ErrorCategory,
+}
+
+/// Built-in rules applied after the configured ones.
+const DEFAULT_RULES: &[(&str, ErrorCategory)] = &[
+    (r"(?i)shutdown|firmware_restart|lost communication", ErrorCategory::NeedsRestart),
+    (r"(?i)not ready|busy|disconnected|timeout", ErrorCategory::Retryable),
+];
+
+impl ErrorRule {
+    fn matches(&self, code: Option<i64>, message: &str) -> bool {
+        if self.code.is_some() && self.code != code {
+            return false;
+        }
+        match &self.pattern {
+            Some(pattern) => match Regex::new(pattern) {
+                Ok(re) => re.is_match(message),
+                Err(e) => {
+                    warn!("Ignoring invalid error_categories pattern {:?}: {}", pattern, e);
+                    false
+                }
+            },
+            None => true,
+        }
+    }
+}
+
+/// Categorize a failed response. Returns `None` for statuses that are not
+/// failures.
+pub fn categorize(status: &ResponseStatus, rules: &[ErrorRule]) -> Option<ErrorCategory> {
+    match status {
+        ResponseStatus::Ok | ResponseStatus::EmptyResponse => None,
+        ResponseStatus::ConnectionError(_) | ResponseStatus::Timeout => Some(ErrorCategory::Retryable),
+        ResponseStatus::InvalidParams(_) | ResponseStatus::ParseError(_) => Some(ErrorCategory::UserError),
+        ResponseStatus::RpcError { code, message } => {
+            if let Some(rule) = rules.iter().find(|r| r.matches(*code, message)) {
+                return Some(rule.category);
+            }
+            let category = DEFAULT_RULES
+                .iter()
+                .find(|(pattern, _)| Regex::new(pattern).map(|re| re.is_match(message)).unwrap_or(false))
+                .map(|(_, category)| *category)
+                .unwrap_or(ErrorCategory::UserError);
+            Some(category)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(code: Option<i64>, message: &str) -> ResponseStatus {
+        ResponseStatus::RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_default_categories() {
+        assert_eq!(categorize(&ResponseStatus::Ok, &[]), None);
+        assert_eq!(categorize(&ResponseStatus::EmptyResponse, &[]), None);
+        assert_eq!(categorize(&ResponseStatus::Timeout, &[]), Some(ErrorCategory::Retryable));
+        assert_eq!(
+            categorize(&rpc(None, "Printer is shutdown"), &[]),
+            Some(ErrorCategory::NeedsRestart)
+        );
+        assert_eq!(
+            categorize(&rpc(None, "Klippy Disconnected"), &[]),
+            Some(ErrorCategory::Retryable)
+        );
+        assert_eq!(
+            categorize(&rpc(None, "Unknown command:\"G99\""), &[]),
+            Some(ErrorCategory::UserError)
+        );
+    }
+
+    #[test]
+    fn test_configured_rules_take_precedence() {
+        let rules = vec![
+            ErrorRule {
+                pattern: Some("Must home".to_string()),
+                code: None,
+                category: ErrorCategory::Retryable,
+            },
+            ErrorRule {
+                pattern: None,
+                code: Some(503),
+                category: ErrorCategory::NeedsRestart,
+            },
+        ];
+        assert_eq!(
+            categorize(&rpc(None, "Must home axis first"), &rules),
+            Some(ErrorCategory::Retryable)
+        );
+        assert_eq!(
+            categorize(&rpc(Some(503), "Service Unavailable"), &rules),
+            Some(ErrorCategory::NeedsRestart)
+        );
+        // Code mismatch falls through to the defaults
+        assert_eq!(
+            categorize(&rpc(Some(400), "Service Unavailable"), &rules),
+            Some(ErrorCategory::UserError)
+        );
+    }
+}
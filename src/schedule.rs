@@ -0,0 +1,67 @@
+use chrono::NaiveTime;
+
+use crate::error::{Error, Result};
+
+/// A daily time window such as `07:00-22:00`. A window whose end is before
+/// its start wraps past midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (start, end) = spec
+            .split_once('-')
+            .ok_or_else(|| Error::config(format!("Time window must look like HH:MM-HH:MM: {}", spec)))?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M")
+            .map_err(|e| Error::config(format!("Invalid window start time: {}", start)).caused_by(e))?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M")
+            .map_err(|e| Error::config(format!("Invalid window end time: {}", end)).caused_by(e))?;
+        Ok(TimeWindow { start, end })
+    }
+
+    /// Whether `time` falls inside the window. The start is inclusive and the
+    /// end exclusive, so `07:00-22:00` allows 21:59 but not 22:00.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_daytime_window() {
+        let window = TimeWindow::parse("07:00-22:00").unwrap();
+        assert!(window.contains(t(7, 0)));
+        assert!(window.contains(t(21, 59)));
+        assert!(!window.contains(t(22, 0)));
+        assert!(!window.contains(t(3, 0)));
+    }
+
+    #[test]
+    fn test_window_wrapping_midnight() {
+        let window = TimeWindow::parse("22:00 - 06:00").unwrap();
+        assert!(window.contains(t(23, 30)));
+        assert!(window.contains(t(5, 59)));
+        assert!(!window.contains(t(6, 0)));
+        assert!(!window.contains(t(12, 0)));
+    }
+
+    #[test]
+    fn test_invalid_window() {
+        assert!(TimeWindow::parse("07:00").is_err());
+        assert!(TimeWindow::parse("7am-10pm").is_err());
+    }
+}
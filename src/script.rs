@@ -0,0 +1,154 @@
+use rhai::{Dynamic, Engine, Map, Scope};
+use spibuttonlib::SPIButtonState;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+
+use crate::error::{Error, Result};
+use crate::remote;
+use crate::units::ButtonId;
+
+/// Button command prefix running a rhai script: `script:BODY`.
+pub const SCRIPT_PREFIX: &str = "script:";
+
+/// Script steps before it is stopped, so a runaway loop cannot stall
+/// polling.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// What a script sees of the panel when it starts.
+#[derive(Debug, Default)]
+pub struct ScriptInput {
+    pub button: ButtonId,
+    /// Whether the event is a press rather than a release
+    pub pressed: bool,
+    /// How long the button was held, 0 for a command run on the press
+    pub held_ms: u64,
+    /// Presses in a row, more than 1 for a press sequence
+    pub taps: u32,
+    /// Buttons currently held down
+    pub held: HashSet<ButtonId>,
+    /// LED state of every button
+    pub leds: BTreeMap<ButtonId, SPIButtonState>,
+    pub variables: BTreeMap<String, String>,
+}
+
+/// What a script asked for, carried out by the daemon once it finished.
+#[derive(Debug, Default, PartialEq)]
+pub struct ScriptEffects {
+    pub leds: Vec<(ButtonId, SPIButtonState)>,
+    /// Klipper requests as `klipper:METHOD|PARAMS` commands, sent one
+    /// after the other in this order
+    pub klipper: Vec<String>,
+    /// Lines printed with `print`
+    pub output: Vec<String>,
+}
+
+/// Run the script `body` for a button event. The script sees `button`,
+/// `pressed`, `held_ms`, `taps` and these functions:
+///
+/// - `is_held(id)`, `led(id)` and `variable(name)` read the panel and variables
+/// - `set_led(id, state)` with `off`, `on`, `flash1` or `flash2`
+/// - `klipper(method)`, `klipper(method, params)` and `gcode(script)` queue
+///   Klipper requests
+pub fn run(body: &str, input: &ScriptInput) -> Result<ScriptEffects> {
+    let effects = Rc::new(RefCell::new(ScriptEffects::default()));
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    let out = effects.clone();
+    engine.on_print(move |line| out.borrow_mut().output.push(line.to_string()));
+
+    let held = input.held.clone();
+    engine.register_fn("is_held", move |id: i64| held.contains(&ButtonId(id as u8)));
+    let leds = input.leds.clone();
+    engine.register_fn("led", move |id: i64| {
+        led_name(leds.get(&ButtonId(id as u8)).copied().unwrap_or(SPIButtonState::Off))
+    });
+    let variables = input.variables.clone();
+    engine.register_fn("variable", move |name: &str| variables.get(name).cloned().unwrap_or_default());
+
+    let out = effects.clone();
+    engine.register_fn("set_led", move |id: i64, state: &str| -> std::result::Result<(), Box<rhai::EvalAltResult>> {
+        let button = u8::try_from(id).map_err(|_| format!("Invalid button id: {}", id))?;
+        let state = remote::led_state(state).ok_or_else(|| format!("Unknown LED state: {:?}", state))?;
+        out.borrow_mut().leds.push((ButtonId(button), state));
+        Ok(())
+    });
+    let out = effects.clone();
+    engine.register_fn("klipper", move |method: &str, params: Map| {
+        let command = format!("klipper:{}|{}", method, rhai::format_map_as_json(&params));
+        out.borrow_mut().klipper.push(command);
+    });
+    let out = effects.clone();
+    engine.register_fn("klipper", move |method: &str| out.borrow_mut().klipper.push(format!("klipper:{}|{{}}", method)));
+    let out = effects.clone();
+    engine.register_fn("gcode", move |script: &str| {
+        let params = serde_json::json!({ "script": script });
+        out.borrow_mut().klipper.push(format!("klipper:gcode/script|{}", params));
+    });
+
+    let mut scope = Scope::new();
+    scope.push_constant("button", i64::from(input.button.0));
+    scope.push_constant("pressed", input.pressed);
+    scope.push_constant("held_ms", input.held_ms as i64);
+    scope.push_constant("taps", i64::from(input.taps));
+    engine
+        .run_with_scope(&mut scope, body)
+        .map_err(|e| Error::action(format!("Script failed: {}", e)))?;
+    drop(engine);
+
+    let effects = Rc::try_unwrap(effects).map(RefCell::into_inner).unwrap_or_default();
+    Ok(effects)
+}
+
+fn led_name(state: SPIButtonState) -> Dynamic {
+    let name = match state {
+        SPIButtonState::On => "on",
+        SPIButtonState::Flash1 => "flash1",
+        SPIButtonState::Flash2 => "flash2",
+        _ => "off",
+    };
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_queues_effects_in_order() {
+        let input = ScriptInput {
+            button: ButtonId(2),
+            pressed: true,
+            held_ms: 0,
+            taps: 2,
+            held: HashSet::from([ButtonId(5)]),
+            leds: BTreeMap::from([(ButtonId(1), SPIButtonState::Flash1)]),
+            variables: BTreeMap::from([("material".to_string(), "PETG".to_string())]),
+        };
+        let effects = run(
+            r#"
+            if is_held(5) && led(1) == "flash1" {
+                gcode("LOAD_" + variable("material"));
+                klipper("printer/objects/query", #{ objects: #{ extruder: () } });
+            }
+            set_led(button, "on");
+            print(`button ${button} done after ${taps} taps`);
+            "#,
+            &input,
+        )
+        .unwrap();
+        assert_eq!(
+            effects.klipper,
+            vec![
+                r#"klipper:gcode/script|{"script":"LOAD_PETG"}"#,
+                r#"klipper:printer/objects/query|{"objects":{"extruder":null}}"#,
+            ]
+        );
+        assert_eq!(effects.leds, vec![(ButtonId(2), SPIButtonState::On)]);
+        assert_eq!(effects.output, vec!["button 2 done after 2 taps"]);
+
+        assert!(run("set_led(1, \"blink\")", &input).is_err());
+        assert!(matches!(run("loop {}", &input), Err(Error::Action { .. })));
+    }
+}
@@ -0,0 +1,92 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+use crate::command::{CommandExecutor, ResponseStatus};
+use crate::config::KlipperConfig;
+use crate::expr::Value;
+use crate::messages;
+use crate::socket;
+
+/// Time between `info` requests while waiting for Klipper at startup.
+pub const KLIPPER_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time one `info` request may take before it counts as unanswered.
+const KLIPPER_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How far each attempt pushes back systemd's start timeout, more than one
+/// attempt and the pause after it take.
+const START_TIMEOUT_EXTENSION: Duration = Duration::from_secs(30);
+
+/// Failed attempts between warnings while waiting for Klipper.
+const WARN_EVERY_ATTEMPTS: u32 = 15;
+
+/// Environment variable systemd sets to the socket of `sd_notify`.
+const NOTIFY_SOCKET_VAR: &str = "NOTIFY_SOCKET";
+
+/// Wait until the Klipper socket answers an `info` request, for
+/// `klipper.required`. Klipper need not be `ready`, only answering. The
+/// service status shows the `waiting_for_klipper` message meanwhile, and
+/// every attempt extends the start timeout so systemd does not kill the
+/// daemon for waiting longer than `TimeoutStartSec`.
+pub async fn wait_for_klipper(klipper: &KlipperConfig, messages: Option<&BTreeMap<String, String>>) {
+    info!("Waiting for Klipper at {} before starting", klipper.socket_path);
+    let socket = [("socket", Value::Str(klipper.socket_path.clone()))];
+    notify(&format!("STATUS={}", messages::text(messages, "waiting_for_klipper", &socket)));
+    let mut attempts: u32 = 0;
+    loop {
+        attempts += 1;
+        notify(&format!("EXTEND_TIMEOUT_USEC={}", START_TIMEOUT_EXTENSION.as_micros()));
+        let answer = timeout(KLIPPER_ATTEMPT_TIMEOUT, CommandExecutor::klipper_request("info", klipper, 0)).await;
+        let problem = match answer {
+            Ok((ResponseStatus::Ok, _)) => {
+                info!("Klipper answered after {} attempt(s)", attempts);
+                return;
+            }
+            Ok((status, _)) => status.to_string(),
+            Err(_) => format!("no answer within {}s", KLIPPER_ATTEMPT_TIMEOUT.as_secs()),
+        };
+        if attempts % WARN_EVERY_ATTEMPTS == 1 {
+            warn!("Klipper is not answering yet ({}), still waiting", problem);
+        }
+        sleep(KLIPPER_RETRY_INTERVAL).await;
+    }
+}
+
+/// Tell the service manager about the daemon's state, e.g. `READY=1`,
+/// when it runs under systemd with `Type=notify`. Elsewhere it does
+/// nothing.
+pub fn notify(state: &str) {
+    let Ok(address) = std::env::var(NOTIFY_SOCKET_VAR) else { return };
+    if let Err(e) = notify_to(&address, state) {
+        warn!("Failed to notify the service manager at {}: {}", address, e);
+    }
+}
+
+/// Send `state` to the notification socket at `address`, a path or an
+/// abstract name after `@`.
+fn notify_to(address: &str, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    datagram.send_to_addr(state.as_bytes(), &socket::socket_addr(address)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    #[test]
+    fn test_notify_sends_the_state() {
+        let name = format!("spibtn-notify-{}", std::process::id());
+        let listener = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        notify_to(&format!("@{}", name), "READY=1").unwrap();
+        let mut buf = [0u8; 16];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
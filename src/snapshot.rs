@@ -0,0 +1,111 @@
+use log::info;
+use serde_json::{json, Value as JsonValue};
+use std::path::PathBuf;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+use crate::command::{EventMessage, EventResponse, ResponseStatus};
+use crate::config::{MoonrakerConfig, SnapshotConfig};
+use crate::moonraker::{idempotency_key, Moonraker};
+
+/// Button command prefix for webcam snapshots. An optional webcam name may
+/// follow, e.g. `snapshot:nozzle`.
+pub const SNAPSHOT_PREFIX: &str = "snapshot:";
+
+/// The `snapshot_url` of the named webcam, or of the first one, in a
+/// Moonraker `/server/webcams/list` result.
+pub fn snapshot_url<'a>(webcams: &'a JsonValue, name: Option<&str>) -> Option<&'a str> {
+    webcams
+        .get("webcams")?
+        .as_array()?
+        .iter()
+        .find(|cam| name.is_none() || cam.get("name").and_then(|n| n.as_str()) == name)?
+        .get("snapshot_url")?
+        .as_str()
+        .filter(|url| !url.is_empty())
+}
+
+/// Take a webcam snapshot, save it and/or post it to the webhook, and report
+/// the outcome as a regular response.
+pub async fn take(
+    command: &str,
+    moonraker: Option<&MoonrakerConfig>,
+    snapshot: &SnapshotConfig,
+    request_id: u32,
+    correlation_id: Uuid,
+    response_tx: Sender<EventMessage>,
+) {
+    let (status, body) = match capture(command, moonraker, snapshot, request_id, correlation_id).await {
+        Ok(body) => (ResponseStatus::Ok, Some(body)),
+        Err(status) => (status, None),
+    };
+    EventResponse::send(&response_tx, request_id, correlation_id, None, status, body, &[]).await;
+}
+
+async fn capture(
+    command: &str,
+    moonraker: Option<&MoonrakerConfig>,
+    snapshot: &SnapshotConfig,
+    request_id: u32,
+    correlation_id: Uuid,
+) -> Result<JsonValue, ResponseStatus> {
+    if snapshot.directory.is_none() && snapshot.webhook.is_none() {
+        return Err(ResponseStatus::InvalidParams(
+            "snapshot needs a directory or a webhook".to_string(),
+        ));
+    }
+    let client = Moonraker::new(moonraker)?.with_idempotency_key(idempotency_key(correlation_id, request_id));
+
+    let webcam = command
+        .strip_prefix(SNAPSHOT_PREFIX)
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .or(snapshot.webcam.as_deref());
+    let url = match &snapshot.url {
+        Some(url) => client.resolve(url)?,
+        None => {
+            let webcams = client.get("/server/webcams/list").await?;
+            let url = snapshot_url(&webcams, webcam).ok_or_else(|| {
+                ResponseStatus::InvalidParams(format!("no webcam {}", webcam.unwrap_or("configured")))
+            })?;
+            client.resolve(url)?
+        }
+    };
+    info!("[{}] Fetching snapshot {}", correlation_id, url);
+    let image = client.fetch(url).await?;
+    let mut result = json!({ "bytes": image.len() });
+
+    if let Some(directory) = &snapshot.directory {
+        let name = format!("snapshot-{}.jpg", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let path = PathBuf::from(directory).join(name);
+        let write = async {
+            tokio::fs::create_dir_all(directory).await?;
+            tokio::fs::write(&path, &image).await
+        };
+        write.await.map_err(|e| ResponseStatus::ConnectionError(e.kind()))?;
+        info!("[{}] Saved snapshot to {}", correlation_id, path.display());
+        result["file"] = json!(path.display().to_string());
+    }
+    if let Some(webhook) = &snapshot.webhook {
+        client.upload(webhook, "image/jpeg", image).await?;
+        info!("[{}] Posted snapshot to {}", correlation_id, webhook);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_url() {
+        let webcams = json!({"webcams": [
+            {"name": "bed", "snapshot_url": "/webcam/?action=snapshot"},
+            {"name": "nozzle", "snapshot_url": "/webcam2/?action=snapshot"},
+        ]});
+        assert_eq!(snapshot_url(&webcams, None), Some("/webcam/?action=snapshot"));
+        assert_eq!(snapshot_url(&webcams, Some("nozzle")), Some("/webcam2/?action=snapshot"));
+        assert_eq!(snapshot_url(&webcams, Some("missing")), None);
+        assert_eq!(snapshot_url(&json!({"webcams": []}), None), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixListener as StdListener, UnixStream as StdStream};
+use std::path::Path;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Prefix of a unix socket in the Linux abstract namespace, e.g.
+/// `@spi-button-controller`. Such sockets have no file, so they need no
+/// shared directory between containers and vanish with their network
+/// namespace.
+pub const ABSTRACT_PREFIX: char = '@';
+
+/// Whether `address` names an abstract socket rather than a file.
+pub fn is_abstract(address: &str) -> bool {
+    address.starts_with(ABSTRACT_PREFIX)
+}
+
+/// The socket address `address` stands for: a path, or an abstract name
+/// after `@`.
+pub fn socket_addr(address: &str) -> io::Result<SocketAddr> {
+    match address.strip_prefix(ABSTRACT_PREFIX) {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(address),
+    }
+}
+
+/// Whether a file socket exists at `address`, e.g. left behind by a
+/// previous run. Abstract sockets never do.
+pub fn file_exists(address: &str) -> bool {
+    !is_abstract(address) && Path::new(address).exists()
+}
+
+/// Listen on a unix socket path or abstract name.
+pub fn bind(address: &str) -> io::Result<UnixListener> {
+    let listener = StdListener::bind_addr(&socket_addr(address)?)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+/// Connect to a unix socket path or abstract name, blocking.
+pub fn connect_blocking(address: &str) -> io::Result<StdStream> {
+    StdStream::connect_addr(&socket_addr(address)?)
+}
+
+/// Connect to a unix socket path or abstract name. Connecting to a local
+/// socket does not wait on the peer, so it is done in place.
+pub async fn connect(address: &str) -> io::Result<UnixStream> {
+    let stream = connect_blocking(address)?;
+    stream.set_nonblocking(true)?;
+    UnixStream::from_std(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_abstract_addresses() {
+        let addr = socket_addr("@spibtn-test").unwrap();
+        assert_eq!(addr.as_abstract_name(), Some(&b"spibtn-test"[..]));
+        assert_eq!(addr.as_pathname(), None);
+        let addr = socket_addr("/run/spibtn.sock").unwrap();
+        assert_eq!(addr.as_pathname(), Some(Path::new("/run/spibtn.sock")));
+        assert!(!file_exists("@spibtn-test"));
+
+        let name = format!("@spibtn-test-{}", std::process::id());
+        let listener = bind(&name).unwrap();
+        let _client = connect(&name).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use log::{debug, log_enabled, Level};
+use spidev::{Spidev, SpidevTransfer};
+use std::fmt::Write as _;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::SpiTraceConfig;
+
+/// Transfers dumped per second when `max_per_sec` is not set.
+const DEFAULT_MAX_PER_SEC: u32 = 10;
+
+/// A spidev device whose transfers are hexdumped at debug level when
+/// `spi.trace` is configured.
+pub struct TracedSpi {
+    spi: Spidev,
+    trace: Option<Tracer>,
+}
+
+impl TracedSpi {
+    pub fn new(spi: Spidev, trace: Option<&SpiTraceConfig>) -> Self {
+        TracedSpi {
+            spi,
+            trace: trace.map(|t| Tracer::new(t.max_per_sec.unwrap_or(DEFAULT_MAX_PER_SEC), Instant::now())),
+        }
+    }
+
+    pub fn read_write(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let result = self.spi.transfer(&mut SpidevTransfer::read_write(tx, rx));
+        self.dump(tx, Some(rx), &result);
+        result
+    }
+
+    pub fn write(&mut self, tx: &[u8]) -> io::Result<()> {
+        let result = self.spi.transfer(&mut SpidevTransfer::write(tx));
+        self.dump(tx, None, &result);
+        result
+    }
+
+    fn dump(&mut self, tx: &[u8], rx: Option<&[u8]>, result: &io::Result<()>) {
+        let Some(trace) = &mut self.trace else { return };
+        if !log_enabled!(Level::Debug) {
+            return;
+        }
+        if let Some(text) = trace.record(Instant::now(), tx, rx, result.as_ref().err()) {
+            debug!("{}", text);
+        }
+    }
+}
+
+/// Numbers the transfers and keeps at most `max_per_sec` dumps a second,
+/// counting the ones it leaves out.
+struct Tracer {
+    started: Instant,
+    max_per_sec: u32,
+    transfers: u64,
+    window: Instant,
+    dumped: u32,
+    skipped: u64,
+}
+
+impl Tracer {
+    fn new(max_per_sec: u32, now: Instant) -> Self {
+        Tracer { started: now, max_per_sec, transfers: 0, window: now, dumped: 0, skipped: 0 }
+    }
+
+    /// The dump of a transfer, or `None` if this second's are used up.
+    fn record(&mut self, now: Instant, tx: &[u8], rx: Option<&[u8]>, error: Option<&io::Error>) -> Option<String> {
+        self.transfers += 1;
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.dumped = 0;
+        }
+        if self.dumped >= self.max_per_sec {
+            self.skipped += 1;
+            return None;
+        }
+        self.dumped += 1;
+        let mut text = format!(
+            "SPI transfer #{} at +{:.6} s",
+            self.transfers,
+            now.duration_since(self.started).as_secs_f64()
+        );
+        if self.skipped > 0 {
+            let _ = write!(text, " ({} not dumped)", self.skipped);
+            self.skipped = 0;
+        }
+        hexdump(&mut text, "tx", tx);
+        match (error, rx) {
+            (Some(e), _) => {
+                let _ = write!(text, "\n  failed: {}", e);
+            }
+            (None, Some(rx)) => hexdump(&mut text, "rx", rx),
+            (None, None) => {}
+        }
+        Some(text)
+    }
+}
+
+/// Lines of 16 bytes, each with its offset, e.g. `  tx 0000: f0 00 01`.
+fn hexdump(text: &mut String, label: &str, bytes: &[u8]) {
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(text, "\n  {} {:04x}:", label, row * 16);
+        for byte in chunk {
+            let _ = write!(text, " {:02x}", byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfers_are_dumped_at_most_max_per_sec() {
+        let start = Instant::now();
+        let mut tracer = Tracer::new(2, start);
+        let tx: Vec<u8> = (0..18).collect();
+        let first = tracer.record(start, &tx, Some(&[0xff; 2]), None).unwrap();
+        assert_eq!(
+            first,
+            "SPI transfer #1 at +0.000000 s\n  tx 0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n  tx 0010: 10 11\n  rx 0000: ff ff"
+        );
+        assert!(tracer.record(start, &[1], None, None).is_some());
+        assert!(tracer.record(start + Duration::from_millis(500), &[2], None, None).is_none());
+        assert!(tracer.record(start + Duration::from_millis(900), &[3], None, None).is_none());
+
+        let error = io::Error::from(io::ErrorKind::TimedOut);
+        let later = tracer.record(start + Duration::from_millis(1500), &[4], Some(&[0]), Some(&error)).unwrap();
+        assert_eq!(
+            later,
+            format!("SPI transfer #5 at +1.500000 s (2 not dumped)\n  tx 0000: 04\n  failed: {}", error)
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::config::ThrottleConfig;
+
+/// How often the temperature and load are read.
+pub const THROTTLE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub const DEFAULT_TEMPERATURE_C: f64 = 75.0;
+pub const DEFAULT_LOAD: f64 = 3.0;
+pub const DEFAULT_LED_INTERVAL_MS: u64 = 500;
+
+/// How far below its threshold the temperature must fall to end throttling.
+const TEMPERATURE_HYSTERESIS_C: f64 = 5.0;
+
+/// Share of its threshold the load must fall below to end throttling.
+const LOAD_HYSTERESIS: f64 = 0.8;
+
+const THERMAL_ZONE: &str = "/sys/class/thermal/thermal_zone0/temp";
+const LOADAVG: &str = "/proc/loadavg";
+
+/// CPU temperature and 1 minute load average of the host, each `None` when
+/// it cannot be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reading {
+    pub temperature_c: Option<f64>,
+    pub load: Option<f64>,
+}
+
+/// The current reading of the host.
+pub fn read_host() -> Reading {
+    Reading {
+        temperature_c: fs::read_to_string(THERMAL_ZONE).ok().as_deref().and_then(parse_millidegrees),
+        load: fs::read_to_string(LOADAVG).ok().as_deref().and_then(parse_loadavg),
+    }
+}
+
+/// E.g. `temperature 78.0C, load 1.20`, leaving out what was not read.
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(t) = self.temperature_c {
+            parts.push(format!("temperature {:.1}C", t));
+        }
+        if let Some(l) = self.load {
+            parts.push(format!("load {:.2}", l));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+fn parse_millidegrees(text: &str) -> Option<f64> {
+    text.trim().parse::<f64>().ok().map(|m| m / 1000.0)
+}
+
+fn parse_loadavg(text: &str) -> Option<f64> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+/// Slows LED animations and indicator updates while the host runs hot or
+/// busy, leaving button reads alone. Throttling starts once the temperature
+/// or load reaches its threshold and ends once both are well below again.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    throttled: bool,
+    reading: Option<Reading>,
+    checked: Option<Instant>,
+    led_update: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Throttle::default()
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    /// The latest reading, `None` without a `throttle` config.
+    pub fn reading(&self) -> Option<Reading> {
+        self.reading
+    }
+
+    /// Read the host if `THROTTLE_CHECK_INTERVAL` passed since the last
+    /// check, returning the new state when it changed. Without a `config`
+    /// nothing is read and throttling ends.
+    pub fn check(&mut self, now: Instant, config: Option<&ThrottleConfig>, read: impl FnOnce() -> Reading) -> Option<bool> {
+        let throttled = match config {
+            None => {
+                self.reading = None;
+                false
+            }
+            Some(_) if self.checked.is_some_and(|t| now.duration_since(t) < THROTTLE_CHECK_INTERVAL) => return None,
+            Some(config) => {
+                self.checked = Some(now);
+                let reading = read();
+                self.reading = Some(reading);
+                self.exceeds(config, reading)
+            }
+        };
+        if throttled == self.throttled {
+            return None;
+        }
+        self.throttled = throttled;
+        self.led_update = None;
+        Some(throttled)
+    }
+
+    fn exceeds(&self, config: &ThrottleConfig, reading: Reading) -> bool {
+        let (temperature_limit, load_limit) = if self.throttled {
+            (config.temperature_c() - TEMPERATURE_HYSTERESIS_C, config.load() * LOAD_HYSTERESIS)
+        } else {
+            (config.temperature_c(), config.load())
+        };
+        reading.temperature_c.is_some_and(|t| t >= temperature_limit) || reading.load.is_some_and(|l| l >= load_limit)
+    }
+
+    /// Whether LED animations and indicators may be updated now: always
+    /// unless throttled, then once per `led_interval_ms`.
+    pub fn led_update_due(&mut self, now: Instant, config: Option<&ThrottleConfig>) -> bool {
+        if !self.throttled {
+            return true;
+        }
+        let interval = Duration::from_millis(config.map_or(DEFAULT_LED_INTERVAL_MS, ThrottleConfig::led_interval_ms));
+        if self.led_update.is_some_and(|t| now.duration_since(t) < interval) {
+            return false;
+        }
+        self.led_update = Some(now);
+        true
+    }
+}
+
+/// The state for `spibuttonctl stats`, e.g. `yes (temperature 78.0C, load 1.20)`.
+impl fmt::Display for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.throttled { "yes" } else { "no" })?;
+        match self.reading {
+            Some(reading) if reading != Reading::default() => write!(f, " ({})", reading),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ThrottleConfig {
+    pub fn temperature_c(&self) -> f64 {
+        self.temperature_c.unwrap_or(DEFAULT_TEMPERATURE_C)
+    }
+
+    pub fn load(&self) -> f64 {
+        self.load.unwrap_or(DEFAULT_LOAD)
+    }
+
+    pub fn led_interval_ms(&self) -> u64 {
+        self.led_interval_ms.unwrap_or(DEFAULT_LED_INTERVAL_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttles_hot_or_busy_host_with_hysteresis() {
+        let config = ThrottleConfig { temperature_c: Some(70.0), load: Some(2.0), led_interval_ms: Some(500) };
+        let reading = |t: f64, l: f64| Reading { temperature_c: Some(t), load: Some(l) };
+        let t0 = Instant::now();
+        let at = |s: u64| t0 + Duration::from_secs(s);
+        let mut throttle = Throttle::new();
+
+        assert_eq!(throttle.check(t0, Some(&config), || reading(50.0, 0.5)), None);
+        assert_eq!(throttle.check(at(1), Some(&config), || panic!("read within the interval")), None);
+        assert_eq!(throttle.check(at(2), Some(&config), || reading(71.0, 0.5)), Some(true));
+        assert_eq!(throttle.to_string(), "yes (temperature 71.0C, load 0.50)");
+        // Just below the threshold is not enough
+        assert_eq!(throttle.check(at(4), Some(&config), || reading(68.0, 0.5)), None);
+        assert_eq!(throttle.check(at(6), Some(&config), || reading(64.0, 1.7)), None);
+        assert_eq!(throttle.check(at(8), Some(&config), || reading(64.0, 1.5)), Some(false));
+        assert_eq!(throttle.check(at(10), Some(&config), || reading(40.0, 2.5)), Some(true));
+        assert_eq!(throttle.check(at(11), None, Reading::default), Some(false));
+        assert_eq!(throttle.to_string(), "no");
+
+        assert_eq!(parse_millidegrees("48312\n"), Some(48.312));
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/123 4567\n"), Some(0.52));
+    }
+
+    #[test]
+    fn test_led_updates_are_spaced_while_throttled() {
+        let config = ThrottleConfig { temperature_c: Some(70.0), load: None, led_interval_ms: Some(500) };
+        let t0 = Instant::now();
+        let ms = |n: u64| t0 + Duration::from_millis(n);
+        let mut throttle = Throttle::new();
+        assert!(throttle.led_update_due(t0, Some(&config)));
+        assert!(throttle.led_update_due(ms(1), Some(&config)));
+
+        throttle.check(t0, Some(&config), || Reading { temperature_c: Some(80.0), load: None });
+        let due: Vec<bool> = [0, 100, 499, 500, 900, 1000].iter().map(|t| throttle.led_update_due(ms(*t), Some(&config))).collect();
+        assert_eq!(due, vec![true, false, false, true, false, true]);
+    }
+}
@@ -0,0 +1,240 @@
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+/// Clock frequency in Hz, e.g. `spi.speed_hz`. Configs may also give it
+/// with a unit, e.g. `800kHz` or `1MHz`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct Hertz(pub u32);
+
+/// Number of a button on the panel, its input position counting from 0.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct ButtonId(pub u8);
+
+/// Address of a register in a panel chip, e.g. an MCP23S17's GPIOA.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct RegisterAddr(pub u8);
+
+impl ButtonId {
+    /// Position in per-button tables.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl FromStr for Hertz {
+    type Err = String;
+
+    /// A bare number of Hz or one with the unit `Hz`, `kHz` or `MHz`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (value, unit) = split_unit(s).ok_or_else(|| format!("invalid frequency: {:?}", s))?;
+        let scale = match unit.to_ascii_lowercase().as_str() {
+            "" | "hz" => 1.0,
+            "khz" => 1e3,
+            "mhz" => 1e6,
+            _ => return Err(format!("invalid frequency: {:?} (units are Hz, kHz and MHz)", s)),
+        };
+        whole(value * scale, u32::MAX as f64)
+            .map(|hz| Hertz(hz as u32))
+            .ok_or_else(|| format!("invalid frequency: {:?}", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hertz {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match NumberOrText::deserialize(deserializer)? {
+            NumberOrText::Number(hz) => u32::try_from(hz)
+                .map(Hertz)
+                .map_err(|_| serde::de::Error::custom(format!("invalid frequency: {}", hz))),
+            NumberOrText::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl JsonSchema for Hertz {
+    fn schema_name() -> Cow<'static, str> {
+        "Hertz".into()
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "Frequency in Hz, or with the unit Hz, kHz or MHz, e.g. 800kHz",
+            "anyOf": [
+                {"type": "integer", "minimum": 0, "maximum": u32::MAX},
+                {"type": "string", "pattern": r"^\s*[0-9_.]+\s*([kKmM]?[hH][zZ])?\s*$"}
+            ]
+        })
+    }
+}
+
+/// A duration in milliseconds: a bare number, or one with the unit `ms`,
+/// `s`, `min` or `h`, e.g. `250ms` or `1.5s`.
+pub fn parse_millis(s: &str) -> Result<u64, String> {
+    let (value, unit) = split_unit(s).ok_or_else(|| format!("invalid duration: {:?}", s))?;
+    let scale = match unit {
+        "" | "ms" => 1.0,
+        "s" => 1e3,
+        "min" => 60e3,
+        "h" => 3600e3,
+        _ => return Err(format!("invalid duration: {:?} (units are ms, s, min and h)", s)),
+    };
+    whole(value * scale, u64::MAX as f64)
+        .map(|ms| ms as u64)
+        .ok_or_else(|| format!("invalid duration: {:?} (not a whole number of milliseconds)", s))
+}
+
+/// For `#[serde(deserialize_with = "millis::deserialize")]` on millisecond
+/// fields, accepting a number or a string for `parse_millis`.
+pub mod millis {
+    use super::{parse_millis, NumberOrText};
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        match NumberOrText::deserialize(deserializer)? {
+            NumberOrText::Number(ms) => Ok(ms),
+            NumberOrText::Text(text) => parse_millis(&text).map_err(serde::de::Error::custom),
+        }
+    }
+
+    /// Stands in for the fields in the JSON schema, as
+    /// `#[schemars(with = "millis::Schema")]`.
+    pub struct Schema;
+
+    impl schemars::JsonSchema for Schema {
+        fn schema_name() -> std::borrow::Cow<'static, str> {
+            "Milliseconds".into()
+        }
+
+        fn json_schema(_generator: &mut schemars::SchemaGenerator) -> schemars::Schema {
+            schemars::json_schema!({
+                "description": "Milliseconds, or a duration with the unit ms, s, min or h, e.g. 1.5s",
+                "anyOf": [
+                    {"type": "integer", "minimum": 0},
+                    {"type": "string", "pattern": r"^\s*[0-9_.]+\s*(ms|s|min|h)?\s*$"}
+                ]
+            })
+        }
+    }
+
+    /// The same for optional fields, which also need `#[serde(default)]`.
+    pub fn option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        match Option::<NumberOrText>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(NumberOrText::Number(ms)) => Ok(Some(ms)),
+            Some(NumberOrText::Text(text)) => parse_millis(&text).map(Some).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(u64),
+    Text(String),
+}
+
+/// The number and the unit following it, e.g. `1.5` and `MHz`.
+fn split_unit(s: &str) -> Option<(f64, &str)> {
+    let s = s.trim();
+    let end = s.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '_')).unwrap_or(s.len());
+    let value = s[..end].replace('_', "").parse().ok()?;
+    Some((value, s[end..].trim_start()))
+}
+
+/// `value` if it is a whole number from 0 to `max`.
+fn whole(value: f64, max: f64) -> Option<f64> {
+    (value.fract() == 0.0 && (0.0..=max).contains(&value)).then_some(value)
+}
+
+// Shown as the bare number, the way the config writes them
+
+impl fmt::Display for Hertz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for Hertz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}Hz", self.0)
+    }
+}
+
+impl fmt::Display for ButtonId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for ButtonId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for RegisterAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+impl fmt::Debug for RegisterAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_units_read_as_plain_numbers() {
+        #[derive(Deserialize)]
+        struct Example {
+            speed_hz: Hertz,
+            button: ButtonId,
+            leds: BTreeMap<ButtonId, u8>,
+        }
+        let example: Example = serde_yaml::from_str("{speed_hz: 800000, button: 3, leds: {3: 9}}").unwrap();
+        assert_eq!(example.speed_hz, Hertz(800_000));
+        assert_eq!(example.button, ButtonId(3));
+        assert_eq!(example.leds[&ButtonId(3)], 9);
+
+        let example: Example = serde_json::from_str(r#"{"speed_hz": 1000, "button": 0, "leds": {"0": 1}}"#).unwrap();
+        assert_eq!(example.leds[&ButtonId(0)], 1);
+        assert_eq!(format!("button {}", example.button), "button 0");
+    }
+
+    #[test]
+    fn test_values_with_units() {
+        #[derive(Deserialize)]
+        struct Example {
+            speed_hz: Hertz,
+            #[serde(deserialize_with = "millis::deserialize")]
+            interval_ms: u64,
+            #[serde(default, deserialize_with = "millis::option")]
+            timeout_ms: Option<u64>,
+            #[serde(default, deserialize_with = "millis::option")]
+            delay_ms: Option<u64>,
+        }
+        let example: Example = serde_yaml::from_str("{speed_hz: 1.5MHz, interval_ms: 100ms, timeout_ms: 1.5s}").unwrap();
+        assert_eq!(example.speed_hz, Hertz(1_500_000));
+        assert_eq!((example.interval_ms, example.timeout_ms, example.delay_ms), (100, Some(1500), None));
+        let example: Example = serde_yaml::from_str("{speed_hz: 800 kHz, interval_ms: 20, delay_ms: 2min}").unwrap();
+        assert_eq!((example.speed_hz, example.interval_ms, example.delay_ms), (Hertz(800_000), 20, Some(120_000)));
+
+        assert_eq!(parse_millis("5x").unwrap_err(), r#"invalid duration: "5x" (units are ms, s, min and h)"#);
+        assert!(parse_millis("0.5ms").is_err());
+        assert!(parse_millis("-1s").is_err());
+        assert!("10GHz".parse::<Hertz>().is_err());
+        let err = serde_yaml::from_str::<Example>("{speed_hz: 1MHz, interval_ms: 1 sec}").err().unwrap();
+        assert!(err.to_string().starts_with(r#"invalid duration: "1 sec""#), "{}", err);
+    }
+}
@@ -0,0 +1,189 @@
+use log::{info, warn};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::VariablesConfig;
+use crate::error::{Error, Result};
+use crate::expr::{Context as ExprContext, Value};
+
+/// Button command prefix setting a variable: `set_var:NAME=VALUE`.
+pub const SET_VAR_PREFIX: &str = "set_var:";
+
+/// Variables `set_var:` may create when `limits.variables` is not
+/// configured.
+pub const DEFAULT_MAX_VARIABLES: usize = 256;
+
+/// Key/value store shared between actions, conditions and templates, e.g.
+/// `material = PETG`. Optionally persisted to a JSON file so values survive
+/// restarts.
+#[derive(Debug, Default)]
+pub struct Variables {
+    values: BTreeMap<String, String>,
+    persist_path: Option<PathBuf>,
+    /// Most variables `set` creates, `DEFAULT_MAX_VARIABLES` when unset
+    limit: Option<usize>,
+}
+
+impl Variables {
+    /// Start from the configured initial values, overridden by persisted ones.
+    pub fn new(config: Option<&VariablesConfig>) -> Self {
+        let mut vars = Variables::default();
+        let config = match config {
+            Some(config) => config,
+            None => return vars,
+        };
+        if let Some(initial) = &config.initial {
+            for (name, value) in initial {
+                vars.values.insert(name.clone(), scalar(value));
+            }
+        }
+        if let Some(path) = &config.persist_path {
+            let path = PathBuf::from(path);
+            match fs::read_to_string(&path) {
+                Ok(content) => match serde_json::from_str::<BTreeMap<String, String>>(&content) {
+                    Ok(saved) => {
+                        info!("Loaded {} variable(s) from {}", saved.len(), path.display());
+                        vars.values.extend(saved);
+                    }
+                    Err(e) => warn!("Ignoring unreadable variables file {}: {}", path.display(), e),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to read variables file {}: {}", path.display(), e),
+            }
+            vars.persist_path = Some(path);
+        }
+        vars
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|v| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.values.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_MAX_VARIABLES)
+    }
+
+    /// Cap the number of variables. Existing ones are kept, only creating
+    /// new ones beyond the cap fails.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Set a variable, writing the store to disk when persistence is on.
+    /// Creating a variable beyond the limit fails.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        if !self.values.contains_key(name) && self.values.len() >= self.limit() {
+            return Err(Error::action(format!(
+                "Not creating variable {}, the limit of {} variables is reached",
+                name,
+                self.limit()
+            )));
+        }
+        self.values.insert(name.to_string(), value.to_string());
+        if let Some(path) = &self.persist_path {
+            // Write then rename so a crash never leaves a truncated file
+            let tmp = path.with_extension("tmp");
+            let json = serde_json::to_string_pretty(&self.values).map_err(|e| Error::internal("Failed to serialize the variables").caused_by(e))?;
+            fs::write(&tmp, json).map_err(|e| Error::action(format!("Failed to write {}", tmp.display())).caused_by(e))?;
+            fs::rename(&tmp, path)
+                .map_err(|e| Error::action(format!("Failed to replace {}", path.display())).caused_by(e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Variables are read as `var.NAME`, or as a bare `NAME` in expressions.
+impl ExprContext for Variables {
+    fn lookup(&self, name: &str) -> Value {
+        let name = name.strip_prefix("var.").unwrap_or(name);
+        match self.get(name) {
+            Some(value) => Value::Str(value.to_string()),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Parse the `NAME=VALUE` part of a `set_var:` command.
+pub fn parse_assignment(command: &str) -> Result<(String, String)> {
+    let spec = command.trim().strip_prefix(SET_VAR_PREFIX).unwrap_or(command);
+    let (name, value) = spec
+        .split_once('=')
+        .ok_or_else(|| Error::action(format!("set_var must look like set_var:NAME=VALUE: {}", command)))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(Error::action(format!("Invalid variable name: {:?}", name)));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Config values may be numbers or booleans, they are stored as text.
+fn scalar(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::render;
+
+    #[test]
+    fn test_render_and_assignment() {
+        let mut vars = Variables::new(None);
+        let (name, value) = parse_assignment("set_var:material = PETG").unwrap();
+        vars.set(&name, &value).unwrap();
+
+        assert_eq!(
+            render("klipper:gcode/script|{\"script\":\"LOAD_{{var.material}}\"}", &vars),
+            "klipper:gcode/script|{\"script\":\"LOAD_PETG\"}"
+        );
+        assert_eq!(render("echo {{ var.unset }}!", &vars), "echo !");
+        // Other placeholders are left for their own substitution
+        assert_eq!(render("x={{val}}", &vars), "x={{val}}");
+
+        assert!(parse_assignment("set_var:material").is_err());
+        assert!(parse_assignment("set_var:bad name=1").is_err());
+
+        // At the limit existing variables still change, new ones are refused
+        vars.set_limit(Some(1));
+        vars.set("material", "ABS").unwrap();
+        assert!(matches!(vars.set("nozzle", "0.6"), Err(Error::Action { .. })));
+        assert_eq!(vars.len(), 1);
+    }
+
+    #[test]
+    fn test_persisted_values_override_initial() {
+        let path = std::env::temp_dir().join(format!("spibtn-vars-{}.json", std::process::id()));
+        let mut initial = BTreeMap::new();
+        initial.insert("material".to_string(), JsonValue::from("PLA"));
+        initial.insert("count".to_string(), JsonValue::from(3));
+        let config = VariablesConfig {
+            initial: Some(initial),
+            persist_path: Some(path.display().to_string()),
+        };
+
+        let mut vars = Variables::new(Some(&config));
+        assert_eq!(vars.get("count"), Some("3"));
+        vars.set("material", "PETG").unwrap();
+
+        let reloaded = Variables::new(Some(&config));
+        assert_eq!(reloaded.get("material"), Some("PETG"));
+        let _ = fs::remove_file(&path);
+    }
+}